@@ -0,0 +1,36 @@
+use async_trait::async_trait;
+
+use super::{mismatched_credentials, AuthProvider, Credentials, Identity};
+use crate::error::Error;
+use crate::firebase;
+use crate::firebase::{FirebaseID, ProviderID};
+
+pub struct FirebaseProvider {}
+
+impl FirebaseProvider {
+    pub fn new() -> Self {
+        FirebaseProvider {}
+    }
+}
+
+#[async_trait]
+impl AuthProvider for FirebaseProvider {
+    fn name(&self) -> &'static str {
+        super::FIREBASE
+    }
+
+    async fn authenticate(
+        &self,
+        creds: &Credentials,
+    ) -> Result<Identity, Error> {
+        let token = match creds {
+            Credentials::Firebase(token) => token,
+            _ => return Err(mismatched_credentials(self.name())),
+        };
+        let (FirebaseID(fid), ProviderID(provider_id), firebase_data) =
+            firebase::authenticate(token).await?;
+        println!("auth.uid: {}, provider_id: {}", &fid, provider_id);
+        let profile = firebase_data.map(|data| data.to_profile());
+        Ok(Identity::new(self.name(), &fid, profile))
+    }
+}
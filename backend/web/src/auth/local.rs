@@ -0,0 +1,164 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use super::{
+    mismatched_credentials, AuthProvider, Credentials, Identity, Profile,
+};
+use crate::db::Db;
+use crate::error::Error;
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 256;
+
+// Username/password accounts stored in `local_accounts` (argon2 hashes), for
+// instances that don't want to depend on Firebase.
+pub struct LocalProvider {
+    db: Arc<Db>,
+}
+
+impl LocalProvider {
+    pub fn new(db: Arc<Db>) -> Self {
+        LocalProvider { db }
+    }
+
+    // Usernames are case-insensitive
+    pub fn normalize(username: &str) -> String {
+        username.trim().to_lowercase()
+    }
+
+    fn validate(username: &str, password: &str) -> Result<(), Error> {
+        let reason = if username.len() < MIN_USERNAME_LEN
+            || username.len() > MAX_USERNAME_LEN
+        {
+            format!(
+                "Username must be {}-{} characters",
+                MIN_USERNAME_LEN, MAX_USERNAME_LEN
+            )
+        } else if !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            "Username may only contain letters, digits, '_' and '-'".into()
+        } else if password.len() < MIN_PASSWORD_LEN
+            || password.len() > MAX_PASSWORD_LEN
+        {
+            format!(
+                "Password must be {}-{} characters",
+                MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+            )
+        } else {
+            return Ok(());
+        };
+        Err(Error::AuthError { reason })
+    }
+
+    fn hash(password: &str) -> Result<String, Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| Error::Unexpected(format!("hash_password: {}", e)))
+    }
+
+    fn verify(password: &str, hash: &str) -> bool {
+        PasswordHash::new(hash)
+            .and_then(|parsed| {
+                Argon2::default().verify_password(password.as_bytes(), &parsed)
+            })
+            .is_ok()
+    }
+
+    // Argon2 is deliberately slow - keep it off the async workers
+    async fn blocking<T, F>(f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| Error::Unexpected(format!("spawn_blocking: {}", e)))
+    }
+
+    fn invalid() -> Error {
+        Error::AuthError {
+            reason: "Invalid username or password".to_string(),
+        }
+    }
+
+    fn identity(username: &str) -> Identity {
+        Identity::new(super::LOCAL, username, Some(Profile::default()))
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LocalProvider {
+    fn name(&self) -> &'static str {
+        super::LOCAL
+    }
+
+    async fn authenticate(
+        &self,
+        creds: &Credentials,
+    ) -> Result<Identity, Error> {
+        let (username, password) = match creds {
+            Credentials::Local { username, password } => {
+                (Self::normalize(username), password.clone())
+            }
+            _ => return Err(mismatched_credentials(self.name())),
+        };
+        let hash = self
+            .db
+            .get_local_password_hash(&username)
+            .await?
+            .ok_or_else(Self::invalid)?;
+        if !Self::blocking(move || Self::verify(&password, &hash)).await? {
+            return Err(Self::invalid());
+        }
+        Ok(Self::identity(&username))
+    }
+
+    async fn register(&self, creds: &Credentials) -> Result<Identity, Error> {
+        let (username, password) = match creds {
+            Credentials::Local { username, password } => {
+                (Self::normalize(username), password.clone())
+            }
+            _ => return Err(mismatched_credentials(self.name())),
+        };
+        Self::validate(&username, &password)?;
+        let hash = Self::blocking(move || Self::hash(&password)).await??;
+        if !self.db.insert_local_account(&username, &hash).await? {
+            return Err(Error::UsernameTaken(username));
+        }
+        Ok(Self::identity(&username))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(LocalProvider::validate("capablanca", "hunter22").is_ok());
+        assert!(LocalProvider::validate("jr", "hunter22").is_err());
+        assert!(LocalProvider::validate("jose raul", "hunter22").is_err());
+        assert!(LocalProvider::validate("capablanca", "short").is_err());
+        assert!(LocalProvider::normalize(" Capablanca ") == "capablanca");
+    }
+
+    #[test]
+    fn hash_and_verify() {
+        let hash = LocalProvider::hash("hunter22").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(LocalProvider::verify("hunter22", &hash));
+        assert!(!LocalProvider::verify("hunter23", &hash));
+        assert!(!LocalProvider::verify("hunter22", "not a hash"));
+    }
+}
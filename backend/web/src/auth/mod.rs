@@ -0,0 +1,195 @@
+// Pluggable authentication.  Each provider turns a set of credentials into an
+// Identity (provider, subject).  Identities are linked to users in the
+// `identities` table, so a single uid can sign in through several providers.
+
+pub mod dev;
+pub mod firebase;
+pub mod local;
+pub mod session;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::db::Db;
+use crate::error::Error;
+
+pub const DEV: &str = "dev";
+pub const FIREBASE: &str = "firebase";
+pub const LOCAL: &str = "local";
+
+#[derive(Clone)]
+pub enum Credentials {
+    Firebase(String), // ID token
+    Local { username: String, password: String },
+    Dev(String), // Any name.  Only accepted when running with DEV=1
+}
+
+impl Credentials {
+    pub fn provider(&self) -> &'static str {
+        match self {
+            Credentials::Firebase(_) => FIREBASE,
+            Credentials::Local { .. } => LOCAL,
+            Credentials::Dev(_) => DEV,
+        }
+    }
+
+    // {"firebase_token": "..."}
+    // {"provider": "local", "username": "...", "password": "..."}
+    // {"provider": "dev", "name": "..."}
+    pub fn from_json(val: &Value) -> Result<Self, Error> {
+        let field = |name: &str| -> Result<String, Error> {
+            val[name]
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| Error::AuthError {
+                    reason: format!("Missing '{}'", name),
+                })
+        };
+        match val["provider"].as_str().unwrap_or(FIREBASE) {
+            FIREBASE => {
+                let token = field("firebase_token")?;
+                // Legacy test logins (".fake_a", ".fake_b", etc)
+                if let Some(name) = token.strip_prefix('.') {
+                    return Ok(Credentials::Dev(name.to_string()));
+                }
+                Ok(Credentials::Firebase(token))
+            }
+            LOCAL => Ok(Credentials::Local {
+                username: field("username")?,
+                password: field("password")?,
+            }),
+            DEV => Ok(Credentials::Dev(field("name")?)),
+            other => Err(Error::UnknownAuthProvider(other.to_string())),
+        }
+    }
+}
+
+// What we know about a user from their provider, used to fill in a newly
+// created user row.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub photo_url: Option<String>,
+    pub guest: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    // None when the provider can't cheaply supply one at sign-in
+    pub profile: Option<Profile>,
+}
+
+impl Identity {
+    pub fn new(provider: &str, subject: &str, profile: Option<Profile>) -> Self {
+        Identity {
+            provider: provider.to_string(),
+            subject: subject.to_string(),
+            profile,
+        }
+    }
+}
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    // Identifier stored in `identities.provider`
+    fn name(&self) -> &'static str;
+
+    async fn authenticate(
+        &self,
+        creds: &Credentials,
+    ) -> Result<Identity, Error>;
+
+    // Only providers that own their credentials (i.e. local accounts) can
+    // create new identities.
+    async fn register(&self, _creds: &Credentials) -> Result<Identity, Error> {
+        Err(Error::AuthError {
+            reason: format!("{} doesn't support registration", self.name()),
+        })
+    }
+}
+
+pub fn mismatched_credentials(provider: &str) -> Error {
+    Error::AuthError {
+        reason: format!("Wrong credentials for {}", provider),
+    }
+}
+
+pub struct AuthProviders {
+    providers: HashMap<&'static str, Box<dyn AuthProvider>>,
+}
+
+impl AuthProviders {
+    pub fn new() -> Self {
+        AuthProviders {
+            providers: HashMap::new(),
+        }
+    }
+
+    pub fn from_env(db: Arc<Db>) -> Self {
+        let mut providers = AuthProviders::new();
+        providers.add(Box::new(firebase::FirebaseProvider::new()));
+        providers.add(Box::new(local::LocalProvider::new(db)));
+        if std::env::var("DEV").unwrap_or_default() == "1" {
+            providers.add(Box::new(dev::DevProvider::new()));
+        }
+        providers
+    }
+
+    pub fn add(&mut self, provider: Box<dyn AuthProvider>) {
+        self.providers.insert(provider.name(), provider);
+    }
+
+    fn get(&self, name: &str) -> Result<&dyn AuthProvider, Error> {
+        self.providers
+            .get(name)
+            .map(|p| p.as_ref())
+            .ok_or_else(|| Error::UnknownAuthProvider(name.to_string()))
+    }
+
+    pub async fn authenticate(
+        &self,
+        creds: &Credentials,
+    ) -> Result<Identity, Error> {
+        self.get(creds.provider())?.authenticate(creds).await
+    }
+
+    pub async fn register(
+        &self,
+        creds: &Credentials,
+    ) -> Result<Identity, Error> {
+        self.get(creds.provider())?.register(creds).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn credentials_from_json() {
+        let creds = Credentials::from_json(&json!({"firebase_token": "abc"}));
+        assert!(matches!(creds, Ok(Credentials::Firebase(t)) if t == "abc"));
+
+        let creds = Credentials::from_json(&json!({"firebase_token": ".fake_a"}));
+        assert!(matches!(creds, Ok(Credentials::Dev(n)) if n == "fake_a"));
+
+        let creds = Credentials::from_json(&json!({
+            "provider": "local",
+            "username": "capa",
+            "password": "hunter22",
+        }));
+        assert!(creds.unwrap().provider() == LOCAL);
+
+        let creds = Credentials::from_json(&json!({"provider": "local"}));
+        assert!(creds.is_err());
+
+        let creds = Credentials::from_json(&json!({"provider": "github"}));
+        assert!(matches!(creds, Err(Error::UnknownAuthProvider(_))));
+    }
+}
@@ -0,0 +1,34 @@
+// Web sessions, set by the /auth routes and shared by the socket and GraphQL
+// endpoints
+use actix_session::Session;
+
+use crate::b66::B66;
+use crate::db::Db;
+use crate::users::UserID;
+
+// (uid, session ID) of a signed in session that hasn't been revoked
+pub async fn session_user(
+    session: &Session,
+    db: &Db,
+) -> Result<Option<(UserID, String)>, actix_web::Error> {
+    let uid = session
+        .get::<String>("uid")?
+        .and_then(|b66_uid| B66::decode_uuid(&b66_uid));
+    let sid = session.get::<String>("sid")?;
+    let login_at = session.get::<i64>("login_at")?;
+    let (uid, sid, login_at) = match (uid, sid, login_at) {
+        (Some(uid), Some(sid), Some(login_at)) => (uid, sid, login_at),
+        _ => return Ok(None),
+    };
+    match db.sessions_revoked_at(&uid).await {
+        Ok(Some(revoked_at)) if revoked_at.num_milliseconds() >= login_at => {
+            session.purge();
+            Ok(None)
+        }
+        Ok(_) => Ok(Some((uid, sid))),
+        Err(e) => {
+            eprintln!("Couldn't check session revocation: {}", e);
+            Ok(None)
+        }
+    }
+}
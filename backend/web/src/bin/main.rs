@@ -6,58 +6,183 @@ use actix_web::cookie::Key;
 use actix_web::*;
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
+use async_graphql::dataloader::DataLoader;
+use async_graphql::Schema;
+use chrono::prelude::*;
 // use jsonwebtoken::decode_header;
-use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::io;
 use std::sync::Arc;
+use uuid::Uuid;
 
+use bughouse_app::auth::session::session_user;
+use bughouse_app::auth::{Credentials, Identity};
 use bughouse_app::b66::B66;
 use bughouse_app::bug_web_sock::{BugContext, BugWebSock};
 use bughouse_app::bughouse_server::{BughouseServer, ServerHandler};
 use bughouse_app::db::Db;
-use bughouse_app::firebase;
-use bughouse_app::firebase::FirebaseID;
+use bughouse_app::error::Error;
+use bughouse_app::graphql::mutation::MutationRoot;
 use bughouse_app::graphql::query::{gql_handle_schema_with_header, QueryRoot};
-use bughouse_app::users::Users;
+use bughouse_app::graphql::subscription::{gql_subscription, SubscriptionRoot};
+use bughouse_app::user_loader::HandleLoader;
+use bughouse_app::users::{User, Users};
 
-#[derive(Debug, Deserialize)]
-struct AuthPost {
-    firebase_token: String,
+fn login(
+    session: &Session,
+    user: &User,
+) -> Result<serde_json::Value, actix_web::Error> {
+    let b66_uid = B66::encode_uuid(user.get_uid());
+    session.renew();
+    session.insert("uid", &b66_uid)?;
+    session.insert("role", user.role)?;
+    // Identifies this session's sockets for logout, and lets revocation
+    // invalidate sessions created before it.
+    session.insert("sid", Uuid::new_v4().to_simple().to_string())?;
+    session.insert("login_at", Utc::now().timestamp_millis())?;
+    Ok(json!({ "uid": b66_uid, "role": user.role }))
 }
 
-// 1. Read the firebase token in JSON body,
-// 2. Validate it
+async fn login_identity(
+    identity: Result<Identity, Error>,
+    session: &Session,
+    context: &BugContext,
+) -> Result<serde_json::Value, actix_web::Error> {
+    let identity = match identity {
+        Ok(identity) => identity,
+        Err(e) => return Ok(json!({ "err": format!("{}", e) })),
+    };
+    let conns = context.server.get_conns();
+    match conns.user_from_identity(&identity).await {
+        Ok(user) => login(session, &user.read().unwrap()),
+        Err(e) => Ok(json!({ "err": format!("{}", e) })),
+    }
+}
+
+// 1. Read the credentials in JSON body (see Credentials::from_json),
+// 2. Validate them with their provider
 // 3. Store user session uid/role data
 async fn auth_post(
-    _req: HttpRequest,
-    info: web::Json<AuthPost>,
+    info: web::Json<Value>,
     session: Session,
     context: web::Data<BugContext>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let db = context.db.clone();
-    let resp = match firebase::authenticate(&info.firebase_token, db).await {
-        Ok((FirebaseID(fid), _)) => {
-            let conns = context.server.get_conns();
-            let res = conns.user_from_fid(&fid).await;
+    let auth = context.server.get_auth();
+    let identity = match Credentials::from_json(&info) {
+        Ok(creds) => auth.authenticate(&creds).await,
+        Err(e) => Err(e),
+    };
+    let resp = login_identity(identity, &session, &context).await?;
+    Ok(HttpResponse::Ok().body(format!("{}", resp)))
+}
+
+// Create a local (username/password) account and sign in as it
+async fn register_post(
+    info: web::Json<Value>,
+    session: Session,
+    context: web::Data<BugContext>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let auth = context.server.get_auth();
+    let identity = match Credentials::from_json(&info) {
+        Ok(creds) => auth.register(&creds).await,
+        Err(e) => Err(e),
+    };
+    let resp = login_identity(identity, &session, &context).await?;
+    Ok(HttpResponse::Ok().body(format!("{}", resp)))
+}
+
+// Link another provider's identity to the signed in user so either can be
+// used to sign in.
+async fn link_post(
+    info: web::Json<Value>,
+    session: Session,
+    context: web::Data<BugContext>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let resp = match session_user(&session, &context.db).await? {
+        None => json!({ "err": "Not signed in" }),
+        Some((uid, _)) => {
+            let auth = context.server.get_auth();
+            let res = match Credentials::from_json(&info) {
+                Ok(creds) => auth.authenticate(&creds).await,
+                Err(e) => Err(e),
+            };
+            let res = match res {
+                Ok(identity) => context.db.link_identity(uid, &identity).await,
+                Err(e) => Err(e),
+            };
             match res {
-                Ok(user) => {
-                    let ruser = user.read().unwrap();
-                    let b66_uid = B66::encode_uuid(ruser.get_uid());
-                    session.insert("uid", &b66_uid)?;
-                    session.insert("role", ruser.role)?;
-                    json!({ "uid": b66_uid, "role": ruser.role })
-                }
-                Err(e) => {
-                    json!({ "err": format!("{}", e) })
-                }
+                Ok(()) => json!({ "uid": B66::encode_uuid(&uid) }),
+                Err(e) => json!({ "err": format!("{}", e) }),
             }
         }
-        Err(e) => {
-            json!({ "err": format!("{}", e) })
+    };
+    Ok(HttpResponse::Ok().body(format!("{}", resp)))
+}
+
+// A signed in guest links a real account, keeping their games.  Optionally
+// claims a handle: {"provider": ..., "handle": "..."}
+async fn upgrade_post(
+    info: web::Json<Value>,
+    session: Session,
+    context: web::Data<BugContext>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let uid = match session_user(&session, &context.db).await? {
+        Some((uid, _)) => uid,
+        None => {
+            let resp = json!({ "err": "Not signed in" });
+            return Ok(HttpResponse::Ok().body(format!("{}", resp)));
+        }
+    };
+    let auth = context.server.get_auth();
+    let identity = match Credentials::from_json(&info) {
+        Ok(creds) => auth.authenticate(&creds).await,
+        Err(e) => Err(e),
+    };
+    let handle = info["handle"].as_str().map(|h| h.to_string());
+    let res = match identity {
+        Ok(identity) => {
+            context.server.upgrade_guest(uid, identity, handle).await
         }
+        Err(e) => Err(e),
+    };
+    let resp = match res {
+        Ok(user) => login(&session, &user.read().unwrap())?,
+        Err(e) => json!({ "err": format!("{}", e) }),
+    };
+    Ok(HttpResponse::Ok().body(format!("{}", resp)))
+}
+
+// End this session and close the sockets it opened
+async fn logout_post(
+    session: Session,
+    context: web::Data<BugContext>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let closed = match session_user(&session, &context.db).await? {
+        Some((uid, sid)) => context.server.close_user_conns(&uid, Some(&sid)),
+        None => 0,
+    };
+    session.purge();
+    let resp = json!({ "closed": closed });
+    Ok(HttpResponse::Ok().body(format!("{}", resp)))
+}
+
+// End every session of the signed in user ("sign out everywhere") and close
+// all of their sockets, however they were authenticated.
+async fn revoke_post(
+    session: Session,
+    context: web::Data<BugContext>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let resp = match session_user(&session, &context.db).await? {
+        None => json!({ "err": "Not signed in" }),
+        Some((uid, _)) => match context.db.revoke_sessions(&uid).await {
+            Ok(()) => {
+                let closed = context.server.close_user_conns(&uid, None);
+                json!({ "closed": closed })
+            }
+            Err(e) => json!({ "err": format!("{}", e) }),
+        },
     };
+    session.purge();
     Ok(HttpResponse::Ok().body(format!("{}", resp)))
 }
 
@@ -67,25 +192,61 @@ async fn test_get() -> Result<HttpResponse, actix_web::Error> {
     Ok(HttpResponse::Ok().body(format!("{}", json)))
 }
 
-async fn auth_get(session: Session) -> Result<HttpResponse, actix_web::Error> {
-    let uid = session.get::<String>("uid").ok();
-    let role = session.get::<i8>("role").ok();
-    let resp = json!({ "uid": uid, "role": role });
+async fn auth_get(
+    session: Session,
+    context: web::Data<BugContext>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let resp = match session_user(&session, &context.db).await? {
+        Some((uid, _)) => {
+            let role = session.get::<i8>("role")?;
+            json!({ "uid": B66::encode_uuid(&uid), "role": role })
+        }
+        None => json!({ "uid": null, "role": null }),
+    };
     Ok(HttpResponse::Ok().body(format!("{}", resp,)))
 }
 
+// Sockets opened with a session cookie are authenticated right away.
+// Otherwise the client authenticates with an "auth" message.  Browsers send
+// the cookie whichever site opens the socket, so only the app's own origins
+// may open one.
 async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
+    session: Session,
     context: web::Data<BugContext>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    ws::start(BugWebSock::new(context), &req, stream)
+    if !is_allowed_origin(&req) {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    let sess = session_user(&session, &context.db).await?;
+    ws::start(BugWebSock::new(context, sess), &req, stream)
 }
 
 fn env_or(env_var: &str, alt: &str) -> String {
     std::env::var(env_var).unwrap_or(alt.to_string())
 }
 
+// Where the app is served from.  In DEV, anywhere.
+const ALLOWED_ORIGINS: [&str; 6] = [
+    "http://localhost",
+    "http://localhost:7777",
+    "http://localhost:5000",
+    "http://127.0.0.1",
+    "https://ws.bughouse.app",
+    "https://bughouse.app",
+];
+
+fn is_allowed_origin(req: &HttpRequest) -> bool {
+    if env_or("DEV", "0") == "1" {
+        return true;
+    }
+    req.headers()
+        .get(http::header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+        .map_or(false, |origin| ALLOWED_ORIGINS.contains(&origin))
+}
+
 fn get_cors() -> Cors {
     if env_or("DEV", "0") == "1" {
         return Cors::default()
@@ -94,15 +255,13 @@ fn get_cors() -> Cors {
             .allow_any_method()
             .send_wildcard();
     }
-    Cors::default()
+    ALLOWED_ORIGINS
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
         .allow_any_header()
-        .allowed_origin("http://localhost")
-        .allowed_origin("http://localhost:7777")
-        .allowed_origin("http://localhost:5000")
-        .allowed_origin("http://127.0.0.1")
-        .allowed_origin("https://ws.bughouse.app")
-        .allowed_origin("https://bughouse.app")
         .allowed_methods(vec!["GET", "POST"])
+        // The app's session cookie comes with its /auth requests
+        .supports_credentials()
 }
 
 // TODO: read an env var
@@ -139,9 +298,14 @@ async fn main() -> io::Result<()> {
             users.clone(),
         );
         let redis = RedisActorSessionStore::new("127.0.0.1:6379");
-        let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        let handle_loader = DataLoader::new(
+            HandleLoader(server.get_user_loader()),
+            tokio::spawn,
+        );
+        let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
             .data(context.clone())
             .data(adb.clone())
+            .data(handle_loader)
             .finish();
         // let srv_cp = srv.clone();
         App::new()
@@ -160,14 +324,50 @@ async fn main() -> io::Result<()> {
                     .route(web::post().to(auth_post))
                     .route(web::get().to(auth_get)),
             )
+            .service(
+                web::resource("/auth/register")
+                    .wrap(get_cors())
+                    .route(web::post().to(register_post)),
+            )
+            .service(
+                web::resource("/auth/link")
+                    .wrap(get_cors())
+                    .route(web::post().to(link_post)),
+            )
+            .service(
+                web::resource("/auth/upgrade")
+                    .wrap(get_cors())
+                    .route(web::post().to(upgrade_post)),
+            )
+            .service(
+                web::resource("/auth/logout")
+                    .wrap(get_cors())
+                    .route(web::post().to(logout_post)),
+            )
+            .service(
+                web::resource("/auth/revoke")
+                    .wrap(get_cors())
+                    .route(web::post().to(revoke_post)),
+            )
             .service(
                 web::resource("/graphql")
                     .wrap(get_cors())
+                    .app_data(web::Data::new(schema.clone()))
+                    .route(web::post().to(gql_handle_schema_with_header::<
+                        QueryRoot,
+                        MutationRoot,
+                        SubscriptionRoot,
+                    >)),
+            )
+            // graphql-ws subscriptions
+            .service(
+                web::resource("/graphql/ws")
                     .app_data(web::Data::new(schema))
-                    .route(
-                        web::post()
-                            .to(gql_handle_schema_with_header::<QueryRoot>),
-                    ),
+                    .route(web::get().to(gql_subscription::<
+                        QueryRoot,
+                        MutationRoot,
+                        SubscriptionRoot,
+                    >)),
             )
             // static files
             .service(fs::Files::new("/", "./static").index_file("index.html"))
@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::process;
+
+use bughouse_app::db::Db;
+use bughouse_app::error::Error;
+use bughouse_app::rating::replay::Replay;
+use bughouse_app::rating::{self, RatingCategory, RatingGuard, UserRating};
+use bughouse_app::users::UserID;
+
+const USAGE: &str = "\
+Usage: recompute-ratings [--system fics|glicko2] [--apply] [--keyspace KS]
+                         [--limit N]
+
+Replays every finished, rated game in bughouse.games, oldest first, through
+a rating system starting from scratch, and prints how the recomputed
+ratings differ from the stored ones.
+
+  --system    rating system to replay with (default: $RATING_SYSTEM or
+              fics)
+  --apply     write the recomputed ratings, rating history and partnerships
+  --keyspace  keyspace --apply writes to (default: bughouse). Games are
+              always read from bughouse
+  --limit     how many of the biggest changes to print, 0 for all
+              (default: 50)
+
+Stop the server before applying to bughouse: it keeps ratings in memory.";
+
+struct Args {
+    system: Box<dyn rating::RatingSystem>,
+    apply: bool,
+    keyspace: String,
+    limit: usize,
+}
+
+fn usage(err: &str) -> ! {
+    eprintln!("{}\n\n{}", err, USAGE);
+    process::exit(1);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        system: rating::from_env(),
+        apply: false,
+        keyspace: "bughouse".to_string(),
+        limit: 50,
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--apply" => args.apply = true,
+            "--system" => {
+                let name = iter.next().unwrap_or_else(|| usage("--system?"));
+                args.system = rating::from_name(&name).unwrap_or_else(|| {
+                    usage(&format!("Unknown rating system: {}", name))
+                });
+            }
+            "--keyspace" => {
+                args.keyspace =
+                    iter.next().unwrap_or_else(|| usage("--keyspace?"));
+            }
+            "--limit" => {
+                args.limit = iter
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage("--limit needs a number"));
+            }
+            "-h" | "--help" => usage("Recompute ratings from game history"),
+            _ => usage(&format!("Unknown argument: {}", arg)),
+        }
+    }
+    args
+}
+
+struct Change {
+    uid: UserID,
+    category: RatingCategory,
+    old: Option<UserRating>,
+    new: Option<UserRating>,
+}
+
+impl Change {
+    fn delta(&self) -> i32 {
+        let rating = |r: &Option<UserRating>| {
+            r.map_or(rating::INIT_RATING, |r| r.rating.rating) as i32
+        };
+        rating(&self.new) - rating(&self.old)
+    }
+
+    fn describe(r: &Option<UserRating>) -> String {
+        match r {
+            Some(r) => format!(
+                "{:>4} ({:>3}) {:>5}g",
+                r.rating.rating, r.rating.deviation, r.games
+            ),
+            None => format!("{:>17}", "-"),
+        }
+    }
+}
+
+async fn get_handles(
+    db: &Db,
+    uids: Vec<UserID>,
+) -> Result<HashMap<UserID, String>, Error> {
+    let mut handles = HashMap::new();
+    for chunk in uids.chunks(100) {
+        handles.extend(db.get_handles(chunk.to_vec()).await?);
+    }
+    Ok(handles)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args = parse_args();
+    let db = Db::new().await?;
+
+    let mut games = db.get_rated_games().await?;
+    let guard = RatingGuard::from_env();
+    let mut replay = Replay::new(args.system.as_ref(), &guard);
+    replay.play_all(&mut games);
+    let stored = db.get_all_ratings().await?;
+
+    let keys: HashSet<&(UserID, RatingCategory)> =
+        stored.keys().chain(replay.ratings.keys()).collect();
+    let mut changes: Vec<Change> = keys
+        .into_iter()
+        .map(|key| Change {
+            uid: key.0,
+            category: key.1,
+            old: stored.get(key).copied(),
+            new: replay.ratings.get(key).copied(),
+        })
+        .filter(|c| match (c.old, c.new) {
+            (Some(old), Some(new)) => {
+                old.rating != new.rating || old.games != new.games
+            }
+            _ => true,
+        })
+        .collect();
+    changes.sort_by_key(|c| (-c.delta().abs(), c.uid, c.category));
+    let stale: Vec<(UserID, RatingCategory)> = changes
+        .iter()
+        .filter(|c| c.new.is_none())
+        .map(|c| (c.uid, c.category))
+        .collect();
+
+    println!(
+        "Replayed {} rated games with {}: {} ratings, {} partnerships",
+        replay.games,
+        args.system.name(),
+        replay.ratings.len(),
+        replay.partnerships.len(),
+    );
+    println!(
+        "{} of {} stored ratings change, {} are new, {} have no rated games",
+        changes.iter().filter(|c| c.old.is_some()).count() - stale.len(),
+        stored.len(),
+        changes.iter().filter(|c| c.old.is_none()).count(),
+        stale.len(),
+    );
+
+    let shown = if args.limit == 0 {
+        changes.len()
+    } else {
+        args.limit.min(changes.len())
+    };
+    if shown > 0 {
+        let uids = changes[..shown].iter().map(|c| c.uid).collect();
+        let handles = get_handles(&db, uids).await?;
+        println!(
+            "\n{:<20} {:<8} {:>17}    {:>17} {:>6}",
+            "handle", "category", "stored", "recomputed", "delta"
+        );
+        for change in changes[..shown].iter() {
+            let uid = change.uid.to_string();
+            let handle = handles.get(&change.uid).unwrap_or(&uid);
+            println!(
+                "{:<20} {:<8} {} -> {} {:>+6}",
+                handle,
+                change.category,
+                Change::describe(&change.old),
+                Change::describe(&change.new),
+                change.delta()
+            );
+        }
+    }
+
+    if args.apply {
+        println!("\nWriting to keyspace {}...", args.keyspace);
+        db.write_replay(&args.keyspace, &replay, &stale).await?;
+        println!("Done");
+    } else {
+        println!("\nDry run. Pass --apply to write these ratings");
+    }
+    Ok(())
+}
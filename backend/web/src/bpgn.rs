@@ -0,0 +1,157 @@
+// BPGN (bughouse PGN) export: the four players, both boards' moves in the
+// order they were made with the mover's clock, and partner signals as
+// comments where they were sent.  Moves are in long algebraic notation.
+use bughouse::{BoardID, Color};
+use chrono::prelude::*;
+
+use crate::db::SignalRow;
+use crate::game_row::GameRow;
+use crate::graphql::game::{decode_moves, DecodedMove};
+use crate::partner_signals::PartnerSignal;
+use crate::rating::Team;
+
+fn move_text(mv: &DecodedMove) -> String {
+    let dest = mv.mv.get_dest().to_string();
+    match mv.mv.get_source() {
+        Some(src) => {
+            let promo = mv.mv.get_piece().map(|p| p.to_string(Color::Black));
+            format!("{}{}{}", src, dest, promo.unwrap_or_default())
+        }
+        None => {
+            let piece = mv.mv.get_piece().map(|p| p.to_string(Color::White));
+            format!("{}@{}", piece.unwrap_or_default(), dest)
+        }
+    }
+}
+
+// "1A." for white on board A, "1a." for black, and likewise for board B
+fn move_label(mv: &DecodedMove) -> String {
+    let board = if mv.board == BoardID::A { "A" } else { "B" };
+    let board = if mv.color == Color::White {
+        board.to_string()
+    } else {
+        board.to_lowercase()
+    };
+    format!("{}{}.", mv.number, board)
+}
+
+// `handles` are board A white, board A black, board B white, board B black
+pub fn to_bpgn(
+    game: &GameRow,
+    handles: &[String; 4],
+    signals: &[SignalRow],
+) -> String {
+    let ((aw, ab), (bw, bb)) = &game.players;
+    let snaps = [aw, ab, bw, bb];
+    let start = Utc.timestamp_millis(game.start_time.num_milliseconds());
+    let result = if game.result < 0 {
+        "*"
+    } else if Team::winner(&GameRow::deserialize_result(game.result)) == Team::A
+    {
+        "1-0"
+    } else {
+        "0-1"
+    };
+    let mut out = String::new();
+    let mut tag = |name: &str, value: &str| {
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+        out.push_str(&format!("[{} \"{}\"]\n", name, value));
+    };
+    let event = if game.rated { "Rated" } else { "Casual" };
+    tag("Event", &format!("{} bughouse game", event));
+    tag("Site", "bughouse.app");
+    tag("Date", &start.format("%Y.%m.%d").to_string());
+    tag("Time", &start.format("%H:%M:%S").to_string());
+    let names = ["WhiteA", "BlackA", "WhiteB", "BlackB"];
+    for (seat, name) in names.iter().enumerate() {
+        tag(name, &handles[seat]);
+        tag(&format!("{}Elo", name), &snaps[seat].rating.to_string());
+    }
+    let base_secs = game.time_ctrl.get_base_ms() / 1000;
+    let inc_secs = game.time_ctrl.get_inc_ms() / 1000;
+    tag("TimeControl", &format!("{}+{}", base_secs, inc_secs));
+    tag("Result", result);
+    out.push('\n');
+
+    let moves = match &game.moves {
+        Some(moves) => decode_moves(moves, &game.time_ctrl),
+        None => vec![],
+    };
+    let mut tokens = vec![];
+    let mut signals = signals.iter().peekable();
+    let comment = |signal: &SignalRow| {
+        let seat = snaps.iter().position(|s| s.uid == signal.sender);
+        let sender = seat.map_or("?", |seat| handles[seat].as_str());
+        match PartnerSignal::from_parts(&signal.kind, &signal.payload) {
+            Some(parsed) => format!("{{{}: {}}}", sender, parsed.to_comment()),
+            None => format!("{{{}: ?}}", sender),
+        }
+    };
+    for mv in moves.iter() {
+        while let Some(signal) = signals.next_if(|s| s.ms < mv.ms) {
+            tokens.push(comment(signal));
+        }
+        let clock = format!("{:.3}", mv.clock as f64 / 1000.0);
+        tokens.push(format!(
+            "{} {}{{{}}}",
+            move_label(mv),
+            move_text(mv),
+            clock
+        ));
+    }
+    for signal in signals {
+        tokens.push(comment(signal));
+    }
+    tokens.push(result.to_string());
+    out.push_str(&tokens.join(" "));
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game::{GameResult, GameResultType};
+    use crate::game_row::fixtures;
+    use crate::time_control::TimeControl;
+    use bughouse::{BughouseMove, Square};
+    use std::collections::HashMap;
+
+    #[test]
+    fn bpgn() {
+        let uids = fixtures::uids();
+        let e4 = BughouseMove::new(Some(Square::E2), Square::E4, None);
+        let e5 = BughouseMove::new(Some(Square::E7), Square::E5, None);
+        let mut moves = HashMap::new();
+        moves.insert(1000 << 1, GameRow::serialize_move(&e4));
+        moves.insert((1500 << 1) | 1, GameRow::serialize_move(&e4));
+        moves.insert(2500 << 1, GameRow::serialize_move(&e5));
+        let result = GameResult {
+            board: BoardID::B,
+            winner: Color::White,
+            kind: GameResultType::Flagged,
+        };
+        let game = GameRow {
+            players: fixtures::players(&uids, 1500),
+            moves: Some(moves),
+            ..fixtures::game(&uids, &result, TimeControl::new(3, 2), true)
+        };
+        let handles = ["aw", "ab", "bw", "bb"].map(String::from);
+        let signal = |ms, seat: usize, kind: &str, payload: &str| SignalRow {
+            ms,
+            sender: uids[seat],
+            kind: kind.to_string(),
+            payload: payload.to_string(),
+        };
+        let signals =
+            vec![signal(1200, 3, "need", "n"), signal(3000, 0, "text", "ok")];
+        let bpgn = to_bpgn(&game, &handles, &signals);
+
+        assert!(bpgn.contains("[WhiteB \"bw\"]\n[WhiteBElo \"1500\"]\n"));
+        assert!(bpgn.contains("[TimeControl \"180+2\"]\n[Result \"0-1\"]\n"));
+        assert!(bpgn.ends_with(
+            "\n1A. e2e4{181.000} {bb: need N} 1B. e2e4{180.500} \
+             1a. e7e5{180.500} {aw: ok} 0-1\n"
+        ));
+    }
+}
@@ -10,18 +10,27 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use crate::auth::Credentials;
 use crate::b66::B66;
 use crate::bughouse_server::BughouseServer;
+use crate::channels::ChannelCommand;
 use crate::connection_mgr::{ConnID, ConnectionMgr};
 use crate::db::Db;
 use crate::error::Error;
+use crate::featured::CurrentGamesFilter;
 use crate::game::GameID;
+use crate::games::{
+    CURRENT_MAX, MAX_CURRENT_GAMES_PAGE, TOURNAMENT_OBSERVER_DELAY_SECS,
+};
 use crate::messages::{
     ClientMessage, ClientMessageKind, ServerMessage, ServerMessageKind,
 };
+use crate::rating::RatingCategory;
+use crate::seeks::seek_constraint::SeekConstraint;
 use crate::seeks::seeks::SeekPool;
+use crate::tells::TellRecipient;
 use crate::time_control::TimeControl;
-use crate::users::Users;
+use crate::users::{UserID, Users};
 
 pub fn get_timestamp_ns() -> u64 {
     Utc::now().timestamp_nanos() as u64
@@ -74,6 +83,8 @@ pub struct BugWebSock {
     // server: &'static BughouseServer,
     /// unique session id
     id: ConnID,
+    /// (uid, session ID) from the session cookie, if the client had one
+    session: Option<(UserID, String)>,
 }
 
 impl Actor for BugWebSock {
@@ -106,14 +117,16 @@ impl actix::Handler<ClientMessage> for BugWebSock {
                 ctx.text("authenticated".to_string());
                 if let Some(user) = maybe_user {
                     let ruser = user.read().unwrap();
+                    let rating = ruser.get_rating(RatingCategory::default());
                     // TODO - rethink - emulating old FICS login auth
                     let msg = json!({
                         "kind": "login",
                         "uid": B66::encode_uuid(&ruser.id),
                         "fid": ruser.firebase_id,
                         "handle": ruser.handle,
-                        "rating": ruser.rating,
-                        "deviation": ruser.deviation,
+                        "rating": rating.rating,
+                        "deviation": rating.deviation,
+                        "ratings": ruser.get_ratings_json(),
                         "guest": ruser.guest,
                         "role": ruser.role,
                     });
@@ -121,6 +134,13 @@ impl actix::Handler<ClientMessage> for BugWebSock {
                     ctx.text(msg.to_string());
                 }
             }
+            ClientMessageKind::Close(reason) => {
+                eprintln!("Closing {}: {}", self.id, reason);
+                let close_reason =
+                    ws::CloseReason::from((ws::CloseCode::Policy, reason));
+                ctx.close(Some(close_reason));
+                ctx.stop();
+            }
             ClientMessageKind::Text(json) => {
                 ctx.text(json.to_string());
             }
@@ -190,11 +210,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for BugWebSock {
 }
 
 impl BugWebSock {
-    pub fn new(data: web::Data<BugContext>) -> Self {
+    pub fn new(
+        data: web::Data<BugContext>,
+        session: Option<(UserID, String)>,
+    ) -> Self {
         Self {
             hb_instant: Instant::now(),
             data,
             id: 0,
+            session,
         }
     }
 
@@ -206,6 +230,19 @@ impl BugWebSock {
     /// Helper method that sends ENQ to client every N seconds.
     /// This method checks heartbeats from client
     fn on_start(&self, ctx: &mut <Self as Actor>::Context) {
+        // No need to wait on an "auth" message when we have a session cookie
+        if let Some((uid, sid)) = &self.session {
+            let res = self.data.srv_recipient.try_send(ServerMessage::new(
+                ServerMessageKind::SessionAuth(
+                    ctx.address().recipient(),
+                    *uid,
+                    sid.clone(),
+                ),
+            ));
+            if let Err(e) = res {
+                eprintln!("Couldn't send SessionAuth: {}", e);
+            }
+        }
         BugWebSock::send_enq(ctx);
         ctx.run_interval(ENQ_INTERVAL, |_act, ctx| {
             BugWebSock::send_enq(ctx);
@@ -282,6 +319,12 @@ impl BugWebSock {
         Ok(u_res)
     }
 
+    fn get_rating(val: &Value, field: &str) -> Option<i16> {
+        val[field]
+            .as_i64()
+            .map(|r| r.clamp(0, i16::MAX as i64) as i16)
+    }
+
     fn get_uuid(
         val: &Value,
         field: &str,
@@ -322,7 +365,26 @@ impl BugWebSock {
                 let time_ctrl = TimeControl::from_str(&time_str)?;
                 let rated = val["rated"].as_bool().or(Some(true)).unwrap();
                 let seek_pool = SeekPool::new(time_ctrl, rated);
-                let res = self.data.server.add_seek(seek_pool, recipient);
+                // Optional {"partner": uid, "team_rating": bool,
+                //           "min_rating": n, "max_rating": n}
+                let res = if val["partner"].is_string() {
+                    let partner = Self::get_uuid(val, "partner", kind)?;
+                    let team_rating =
+                        val["team_rating"].as_bool().unwrap_or(false);
+                    let constraint = SeekConstraint::new(
+                        Self::get_rating(val, "min_rating"),
+                        Self::get_rating(val, "max_rating"),
+                    );
+                    self.data.server.queue_partner_seek(
+                        seek_pool,
+                        partner,
+                        team_rating,
+                        constraint,
+                        &self.id,
+                    )
+                } else {
+                    self.data.server.add_seek(seek_pool, recipient)
+                };
                 if let Err(e) = res {
                     eprintln!("add_seek err: {}", e);
                 }
@@ -338,6 +400,19 @@ impl BugWebSock {
             "create_table" => {
                 let time_str = Self::get_field_str(val, "time", kind)?;
                 let public = Self::get_field_bool(val, "public", kind)?;
+                // Whether to keep observers out
+                let hidden = val["hidden"].as_bool().unwrap_or(false);
+                // Tournament games are delayed unless they ask not to be
+                let tournament = val["tournament"].as_bool().unwrap_or(false);
+                let default_delay = if tournament {
+                    TOURNAMENT_OBSERVER_DELAY_SECS
+                } else {
+                    0
+                };
+                // Seconds observers see the game behind the players
+                let delay =
+                    val["observer_delay"].as_i64().unwrap_or(default_delay);
+                let observer_delay = chrono::Duration::seconds(delay.max(0));
                 let time_ctrl = TimeControl::from_str(&time_str)?;
                 let rated = val["rated"].as_bool().ok_or_else(|| {
                     Error::MalformedClientMsg {
@@ -346,10 +421,14 @@ impl BugWebSock {
                     }
                 })?;
                 println!("form: {} {}", time_str, rated);
-                let res = self
-                    .data
-                    .server
-                    .queue_formation(time_ctrl, rated, public, &self.id);
+                let res = self.data.server.queue_formation(
+                    time_ctrl,
+                    rated,
+                    public,
+                    hidden,
+                    observer_delay,
+                    &self.id,
+                );
                 if let Err(e) = res {
                     eprintln!("table formation error: {}", e);
                 }
@@ -397,10 +476,26 @@ impl BugWebSock {
                     .server
                     .sub_current_games(ctx.address().recipient())
                     .ok();
-                let players_msg = self.data.server.get_current_games_json()?;
+                let players_msg = self.data.server.get_current_games_json(
+                    &CurrentGamesFilter::default(),
+                    0,
+                    CURRENT_MAX,
+                )?;
                 println!("sub_current_games: {}", players_msg);
                 ctx.text(players_msg);
             }
+            "current_games" => {
+                // A page of them, optionally filtered (see CurrentGamesFilter)
+                let filter = CurrentGamesFilter::from_json(val)?;
+                let offset = val["offset"].as_u64().unwrap_or(0) as usize;
+                let count = val["count"].as_u64().unwrap_or(CURRENT_MAX as u64);
+                let msg = self.data.server.get_current_games_json(
+                    &filter,
+                    offset,
+                    (count as usize).min(MAX_CURRENT_GAMES_PAGE),
+                )?;
+                ctx.text(msg);
+            }
             "unsub_current_games" => {
                 self.data
                     .server
@@ -464,7 +559,73 @@ impl BugWebSock {
                 println!("game_msg: {}", val);
                 let game_id: GameID = Self::get_uuid(val, "id", kind)?;
                 println!("game_msg: {}", game_id);
-                self.data.server.send_game_msg(game_id, val, &self.id).ok();
+                let res =
+                    self.data.server.send_game_msg(game_id, val, &self.id);
+                if let Err(e) = res {
+                    eprintln!("game_msg err: {}", e);
+                    ctx.text(e.to_json().to_string());
+                }
+            }
+            "kibitz" | "whisper" => {
+                let game_id: GameID = Self::get_uuid(val, "id", kind)?;
+                let body = Self::get_field_str(val, "body", kind)?;
+                self.data.server.queue_kibitz(
+                    game_id,
+                    body,
+                    kind == "whisper",
+                    recipient,
+                )?;
+            }
+            "tell" => {
+                // {"handle": handle} or {"uid": uid}, and "body"
+                let to = if val["uid"].is_string() {
+                    TellRecipient::Uid(Self::get_uuid(val, "uid", kind)?)
+                } else {
+                    TellRecipient::Handle(Self::get_field_str(
+                        val, "handle", kind,
+                    )?)
+                };
+                let body = Self::get_field_str(val, "body", kind)?;
+                let res = self.data.server.queue_tell(to, body, &self.id);
+                if let Err(e) = res {
+                    eprintln!("Couldn't send tell: {}", e);
+                }
+            }
+            "join_channel" | "leave_channel" => {
+                let name = Self::get_field_str(val, "channel", kind)?;
+                let cmd = if kind == "join_channel" {
+                    ChannelCommand::Join(name)
+                } else {
+                    ChannelCommand::Leave(name)
+                };
+                let res = self.data.server.queue_channel_command(cmd, &self.id);
+                if let Err(e) = res {
+                    eprintln!("{} err: {}", kind, e);
+                }
+            }
+            "channel_msg" => {
+                let name = Self::get_field_str(val, "channel", kind)?;
+                let body = Self::get_field_str(val, "body", kind)?;
+                let cmd = ChannelCommand::Send(name, body);
+                let res = self.data.server.queue_channel_command(cmd, &self.id);
+                if let Err(e) = res {
+                    eprintln!("channel_msg err: {}", e);
+                }
+            }
+            "channel_mute" | "channel_ban" => {
+                // {"channel": name, "uid": uid, "on": bool}
+                let name = Self::get_field_str(val, "channel", kind)?;
+                let target = Self::get_uuid(val, "uid", kind)?;
+                let on = Self::get_field_bool(val, "on", kind)?;
+                let cmd = if kind == "channel_mute" {
+                    ChannelCommand::Mute(name, target, on)
+                } else {
+                    ChannelCommand::Ban(name, target, on)
+                };
+                let res = self.data.server.queue_channel_command(cmd, &self.id);
+                if let Err(e) = res {
+                    eprintln!("{} err: {}", kind, e);
+                }
             }
             "premove" => {
                 let game_id: GameID = Self::get_uuid(val, "id", kind)?;
@@ -508,8 +669,8 @@ impl BugWebSock {
         &self,
         game_id: GameID,
         recipient: Recipient<ClientMessage>,
-    ) -> Result<ByteString, Error> {
-        let res = self.data.server.get_game_json_payload(game_id);
+    ) -> Result<Option<ByteString>, Error> {
+        let res = self.data.server.get_game_payload_for(game_id, &recipient);
         if let Err(e) = res {
             println!("queueing_game_row: {}", game_id);
             self.data.server.queue_send_game_row(&game_id, recipient)?;
@@ -560,10 +721,23 @@ impl BugWebSock {
                 let game_id: GameID = Self::get_uuid(&val, "id", kind)?;
                 self.data
                     .server
-                    .observe(&game_id, ctx.address().recipient());
+                    .observe(&game_id, ctx.address().recipient())?;
                 let msg = self
                     .get_game_or_send_row(game_id, ctx.address().recipient())?;
-                ctx.text(msg);
+                if let Some(msg) = msg {
+                    ctx.text(msg);
+                }
+            }
+            "observers" => {
+                let game_id: GameID = Self::get_uuid(&val, "id", kind)?;
+                let msg = self.data.server.observers_json(&game_id);
+                ctx.text(msg.to_string());
+            }
+            "sub_tv" => {
+                self.data.server.sub_tv(ctx.address().recipient());
+            }
+            "unsub_tv" => {
+                self.data.server.unsub_tv(ctx.address().recipient());
             }
             "unobserve" => {
                 let game_id: GameID = Self::get_uuid(&val, "id", kind)?;
@@ -575,20 +749,18 @@ impl BugWebSock {
                 let game_id: GameID = Self::get_uuid(&val, "id", kind)?;
                 let msg = self
                     .get_game_or_send_row(game_id, ctx.address().recipient())?;
-                ctx.text(msg);
+                if let Some(msg) = msg {
+                    ctx.text(msg);
+                }
             }
             "auth" => {
-                let token =
-                    val["firebase_token"].as_str().ok_or(Error::AuthError {
-                        reason: "Malformed token".to_string(),
-                    })?;
-
-                println!("firebase_token: {}", token);
+                let creds = Credentials::from_json(&val)?;
+                println!("auth: {}", creds.provider());
                 self.data
                     .srv_recipient
                     .try_send(ServerMessage::new(ServerMessageKind::Auth(
                         ctx.address().recipient(),
-                        token.to_string(),
+                        creds,
                     )))
                     .expect("WTF");
             }
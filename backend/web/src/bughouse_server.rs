@@ -4,37 +4,75 @@ use actix_web::*;
 use bughouse::{BoardID, BughouseMove, Color};
 use bytestring::ByteString;
 use chrono::prelude::*;
-use futures::join;
 use serde_json::{json, Value};
 // use actix_web_actors::ws::WebsocketContext;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 // use timer::Timer;
 // use std::thread;
 
+use crate::auth::{AuthProviders, Credentials, Identity};
 use crate::b66::B66;
+use crate::channels::{Channel, ChannelCommand, Channels, LOBBY};
 use crate::connection_mgr::{ConnID, ConnectionMgr};
-use crate::db::{Db, TableSnapshot, UserRatingSnapshot};
+use crate::db::{
+    ChannelState, Db, KibitzRow, TableSnapshot, UserRatingSnapshot,
+};
 use crate::error::Error;
-use crate::firebase;
-use crate::firebase::{FirebaseID, ProviderID};
+use crate::featured::CurrentGamesFilter;
 use crate::game::{Game, GameID, GamePlayers, GameStatus};
 use crate::game_json::GameJson;
 use crate::games::{GameUserHandler, Games};
+use crate::handle_policy::HandlePolicy;
+use crate::kibitz;
+use crate::leaderboards::Leaderboards;
 use crate::messages::{
     ClientMessage, ClientMessageKind, ServerMessage, ServerMessageKind,
 };
-use crate::rating::UserRating;
+use crate::partner_signals::{PartnerSignal, SignalLimiter};
+use crate::rating;
+use crate::rating::{
+    Partnership, RatingCategory, RatingGuard, RatingSystem, UserRating,
+};
+use crate::seeks::seek_constraint::SeekConstraint;
 use crate::seeks::seek_user_handler::SeekUserHandler;
 use crate::seeks::seeks::{SeekPool, Seeks};
+use crate::tells::{Tell, TellRecipient};
 use crate::time_control::TimeControl;
-use crate::users::{User, UserID, Users};
+use crate::user_loader::UserLoader;
+use crate::users::{User, UserID, UserRole, Users, DELETED_HANDLE};
 use once_cell::sync::OnceCell;
 
+// How often leaderboards are rebuilt from ratings
+const LEADERBOARD_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(5 * 60);
+
+// How often delayed updates are sent to observers
+const OBSERVER_FLUSH_INTERVAL: std::time::Duration =
+    std::time::Duration::from_millis(250);
+
+// How often current games are ranked again for observer counts that changed
+const RERANK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Partner signals a player may send per window
+const MAX_SIGNALS_PER_WINDOW: usize = 5;
+const SIGNAL_WINDOW_MS: i64 = 3000;
+const MAX_KIBITZES_PER_WINDOW: usize = 3;
+const KIBITZ_WINDOW_MS: i64 = 5000;
+
 // pub type ChanMsg = (Recipient<ClientMessage>, String);
 
 pub struct BughouseServer {
+    auth: Arc<AuthProviders>,
+    handle_policy: HandlePolicy,
+    rating_system: Box<dyn RatingSystem>,
+    rating_guard: RatingGuard,
+    leaderboards: Arc<Leaderboards>,
+    channels: Channels,
+    signal_limiter: SignalLimiter,
+    kibitz_limiter: SignalLimiter,
     users: Arc<Users>,
+    user_loader: Arc<UserLoader>,
     conns: Arc<ConnectionMgr>,
     seeks: Arc<Seeks>,
     loopback: Recipient<ServerMessage>,
@@ -71,6 +109,25 @@ impl ServerHandler {
 impl Actor for ServerHandler {
     /// Just need ability to communicate with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let refresh = |ctx: &mut Context<Self>| {
+            ctx.address().do_send(ServerMessage::new(
+                ServerMessageKind::RefreshLeaderboards,
+            ));
+        };
+        refresh(ctx);
+        ctx.run_interval(LEADERBOARD_INTERVAL, move |_act, ctx| refresh(ctx));
+        ctx.run_interval(OBSERVER_FLUSH_INTERVAL, |_act, ctx| {
+            ctx.address()
+                .do_send(ServerMessage::new(ServerMessageKind::FlushObservers));
+        });
+        ctx.run_interval(RERANK_INTERVAL, |_act, ctx| {
+            ctx.address().do_send(ServerMessage::new(
+                ServerMessageKind::RerankCurrentGames,
+            ));
+        });
+    }
 }
 
 impl ServerHandler {
@@ -142,9 +199,12 @@ impl Handler<ServerMessage> for ServerHandler {
         ctx: &mut Context<Self>,
     ) -> Self::Result {
         match msg.kind {
-            ServerMessageKind::Auth(recipient, token) => {
-                // Authenticate token uid
-                let fut = self.srv(ctx).authenticate(recipient, token);
+            ServerMessageKind::Auth(recipient, creds) => {
+                let fut = self.srv(ctx).authenticate(recipient, creds);
+                Box::pin(async move { fut.await })
+            }
+            ServerMessageKind::SessionAuth(recipient, uid, sid) => {
+                let fut = self.srv(ctx).session_authenticate(recipient, uid, sid);
                 Box::pin(async move { fut.await })
             }
             ServerMessageKind::CreateGame(time_ctrl, rated, players) => {
@@ -152,9 +212,42 @@ impl Handler<ServerMessage> for ServerHandler {
                     self.srv(ctx).start_new_game(time_ctrl, rated, players);
                 Box::pin(async move { fut.await })
             }
-            ServerMessageKind::FormTable(time_ctrl, rated, public, conn_id) => {
+            ServerMessageKind::FormTable(
+                time_ctrl,
+                rated,
+                public,
+                hidden,
+                observer_delay,
+                conn_id,
+            ) => {
                 let server = self.srv(ctx);
-                let fut = server.form_table(time_ctrl, rated, public, conn_id);
+                let fut = server.form_table(
+                    time_ctrl,
+                    rated,
+                    public,
+                    hidden,
+                    observer_delay,
+                    conn_id,
+                );
+                Box::pin(async move {
+                    Self::fwd_err(Box::pin(fut), server, conn_id).await
+                })
+            }
+            ServerMessageKind::PartnerSeek(
+                seek_pool,
+                partner,
+                team_rating,
+                constraint,
+                conn_id,
+            ) => {
+                let server = self.srv(ctx);
+                let fut = server.add_partner_seek(
+                    seek_pool,
+                    partner,
+                    team_rating,
+                    constraint,
+                    conn_id,
+                );
                 Box::pin(async move {
                     Self::fwd_err(Box::pin(fut), server, conn_id).await
                 })
@@ -163,6 +256,21 @@ impl Handler<ServerMessage> for ServerHandler {
                 let fut = self.srv(ctx).send_game_row(game_id, recipient);
                 Box::pin(async move { fut.await })
             }
+            ServerMessageKind::Kibitz(game_id, body, whisper, recipient) => {
+                let fut = self.srv(ctx).kibitz(
+                    game_id,
+                    body,
+                    whisper,
+                    recipient.clone(),
+                );
+                Box::pin(async move {
+                    let res = fut.await;
+                    if let Err(e) = &res {
+                        recipient.do_send(e.to_client_msg());
+                    }
+                    res
+                })
+            }
             ServerMessageKind::Sit(game_id, board_id, color, conn_id) => {
                 let server = self.srv(ctx);
                 let fut = server.sit(game_id, board_id, color, conn_id);
@@ -171,8 +279,37 @@ impl Handler<ServerMessage> for ServerHandler {
                 })
             }
             ServerMessageKind::SetHandle(handle, uid) => {
-                let fut = self.srv(ctx).set_handle(handle, uid);
-                Box::pin(async move { fut.await })
+                let server = self.srv(ctx);
+                let fut = server.set_handle(handle, uid);
+                Box::pin(async move {
+                    let res = fut.await;
+                    if let Err(e) = &res {
+                        server.conns.send_to_user(&uid, &e.to_client_msg());
+                    }
+                    res
+                })
+            }
+            ServerMessageKind::Channel(cmd, uid) => {
+                let server = self.srv(ctx);
+                let fut = server.channel_command(cmd, uid);
+                Box::pin(async move {
+                    let res = fut.await;
+                    if let Err(e) = &res {
+                        server.conns.send_to_user(&uid, &e.to_client_msg());
+                    }
+                    res
+                })
+            }
+            ServerMessageKind::Tell(recipient, body, uid) => {
+                let server = self.srv(ctx);
+                let fut = server.tell(recipient, body, uid);
+                Box::pin(async move {
+                    let res = fut.await;
+                    if let Err(e) = &res {
+                        server.conns.send_to_user(&uid, &e.to_client_msg());
+                    }
+                    res
+                })
             }
             ServerMessageKind::Vacate(game_id, board_id, color, recip) => {
                 let fut = self.srv(ctx).vacate(game_id, board_id, color, recip);
@@ -209,6 +346,29 @@ impl Handler<ServerMessage> for ServerHandler {
                     Ok(ClientMessage::new(ClientMessageKind::Empty))
                 })
             }
+            ServerMessageKind::FlushObservers => {
+                self.srv(ctx).flush_observers();
+                Box::pin(async {
+                    Ok(ClientMessage::new(ClientMessageKind::Empty))
+                })
+            }
+            ServerMessageKind::RerankCurrentGames => {
+                self.srv(ctx).rerank_current_games();
+                Box::pin(async {
+                    Ok(ClientMessage::new(ClientMessageKind::Empty))
+                })
+            }
+            ServerMessageKind::RefreshLeaderboards => {
+                let fut = self.srv(ctx).refresh_leaderboards();
+                Box::pin(async move { fut.await })
+            }
+            ServerMessageKind::RecordSignal(game_id, ms, uid, signal) => {
+                let db = self.db.clone();
+                Box::pin(async move {
+                    db.record_signal(&game_id, ms, &uid, &signal).await?;
+                    Ok(ClientMessage::new(ClientMessageKind::Empty))
+                })
+            }
             ServerMessageKind::RecordMove(duration, game_id, board_id, mv) => {
                 let fut =
                     self.srv(ctx).record_move(duration, game_id, board_id, mv);
@@ -236,6 +396,7 @@ impl BughouseServer {
         loopback: Recipient<ServerMessage>, // , timer: Arc<Timer>
         users: Arc<Users>,
     ) -> Self {
+        let auth = Arc::new(AuthProviders::from_env(db.clone()));
         let conns = Arc::new(ConnectionMgr::new(db.clone(), users.clone()));
         let games = Arc::new(Games::new(conns.clone()));
         let game_user_handler = GameUserHandler::new(games.clone());
@@ -246,7 +407,22 @@ impl BughouseServer {
         conns.add_user_handler(game_addr.recipient());
         conns.add_user_handler(seek_addr.recipient());
         BughouseServer {
+            auth,
+            handle_policy: HandlePolicy::from_env(),
+            rating_system: rating::from_env(),
+            rating_guard: RatingGuard::from_env(),
+            leaderboards: Arc::new(Leaderboards::from_env()),
+            channels: Channels::new(),
+            signal_limiter: SignalLimiter::new(
+                MAX_SIGNALS_PER_WINDOW,
+                SIGNAL_WINDOW_MS,
+            ),
+            kibitz_limiter: SignalLimiter::new(
+                MAX_KIBITZES_PER_WINDOW,
+                KIBITZ_WINDOW_MS,
+            ),
             conns,
+            user_loader: Arc::new(UserLoader::new(db.clone(), users.clone())),
             users,
             loopback,
             seeks,
@@ -259,6 +435,14 @@ impl BughouseServer {
         self.conns.clone()
     }
 
+    pub fn get_auth(&self) -> Arc<AuthProviders> {
+        self.auth.clone()
+    }
+
+    pub fn get_user_loader(&self) -> Arc<UserLoader> {
+        self.user_loader.clone()
+    }
+
     pub fn sub_public_tables(
         &'static self,
         recipient: Recipient<ClientMessage>,
@@ -277,11 +461,34 @@ impl BughouseServer {
         Ok(())
     }
 
-    pub fn get_current_games_json(&'static self) -> Result<ByteString, Error> {
-        let json = self.games.get_current_games_json();
+    // `count` of the ranked games from `offset`, and how many there are
+    pub fn current_games(
+        &'static self,
+        filter: &CurrentGamesFilter,
+        offset: usize,
+        count: usize,
+    ) -> (Vec<GameJson>, usize) {
+        self.games.current_games(filter, offset, count)
+    }
+
+    pub fn get_current_games_json(
+        &'static self,
+        filter: &CurrentGamesFilter,
+        offset: usize,
+        count: usize,
+    ) -> Result<ByteString, Error> {
+        let json = self.games.get_current_games_json(filter, offset, count);
         Ok(ByteString::from(json.to_string()))
     }
 
+    pub fn sub_tv(&'static self, recipient: Recipient<ClientMessage>) {
+        self.games.sub_tv(recipient);
+    }
+
+    pub fn unsub_tv(&'static self, recipient: Recipient<ClientMessage>) {
+        self.games.unsub_tv(recipient);
+    }
+
     pub fn sub_current_games(
         &'static self,
         recipient: Recipient<ClientMessage>,
@@ -359,6 +566,10 @@ impl BughouseServer {
         }
         println!("adding seeker");
         self.seeks.add_default_seeker(&seek_pool, user.id)?;
+        self.try_form_game(seek_pool)
+    }
+
+    fn try_form_game(&'static self, seek_pool: SeekPool) -> Result<(), Error> {
         if let Some(players) = self.seeks.form_game(&seek_pool) {
             println!("forming game...");
             // Send message to self and attempt async DB game creation
@@ -372,6 +583,61 @@ impl BughouseServer {
         Ok(())
     }
 
+    pub fn queue_partner_seek(
+        &'static self,
+        seek_pool: SeekPool,
+        partner: UserID,
+        team_rating: bool,
+        constraint: SeekConstraint,
+        conn_id: &ConnID,
+    ) -> Result<(), Error> {
+        self.loopback.try_send(ServerMessage::new(
+            ServerMessageKind::PartnerSeek(
+                seek_pool,
+                partner,
+                team_rating,
+                constraint,
+                *conn_id,
+            ),
+        ))?;
+        Ok(())
+    }
+
+    // Seek as a team with `partner`.  With `team_rating`, others' rating
+    // constraints are checked against the partnership's rating instead of
+    // each partner's own.
+    pub async fn add_partner_seek(
+        &'static self,
+        seek_pool: SeekPool,
+        partner: UserID,
+        team_rating: bool,
+        constraint: SeekConstraint,
+        conn_id: ConnID,
+    ) -> Result<ClientMessage, Error> {
+        let uid = self.uid_from_conn(&conn_id)?;
+        if partner == uid {
+            return Err(Error::SelfPartner);
+        }
+        // Fails for partners who don't exist
+        self.user_from_uid(&partner).await?;
+        if let Some(game) = self.games.get_user_game(&uid) {
+            let gid = B66::encode_uuid(game.read().unwrap().get_id());
+            return Err(Error::InGame(uid.to_string(), gid));
+        }
+        let rating = if team_rating {
+            let partners = Partnership::new(uid, partner);
+            let category = seek_pool.get_rating_category();
+            let partnership = self.db.get_partnership(&partners, category);
+            Some(partnership.await?.rating.rating)
+        } else {
+            None
+        };
+        self.seeks
+            .add_partner_seeker(&seek_pool, uid, partner, rating, constraint)?;
+        self.try_form_game(seek_pool)?;
+        Ok(ClientMessage::new(ClientMessageKind::Empty))
+    }
+
     pub fn update_game_observers(&'static self, game: Arc<RwLock<Game>>) {
         self.games.update_game_observers(game);
     }
@@ -383,21 +649,59 @@ impl BughouseServer {
     pub async fn authenticate(
         &'static self,
         recipient: Recipient<ClientMessage>,
-        token: String,
+        creds: Credentials,
     ) -> Result<ClientMessage, Error> {
-        let res = firebase::authenticate(&token, self.db.clone()).await;
-        if let Err(ref e) = res {
-            let err = json!({
-                "kind": "err",
-                "err": { "kind": "auth" },
-                "reason": format!("{}", e),
-            })
-            .to_string();
-            return Ok(Self::send_text_to_recipient(err, &recipient).await);
+        let res = self.auth.authenticate(&creds).await;
+        if let Err(e) = res {
+            return Ok(Self::send_auth_err(e, &recipient).await);
         }
-        let (FirebaseID(fid), ProviderID(provider_id)) = res.unwrap();
-        println!("auth.uid: {}, provider_id: {}", &fid, provider_id);
-        let conn_id = self.add_conn(recipient.clone(), &fid).await?;
+        let identity = res.unwrap();
+        let conn_id = self.add_conn(recipient.clone(), &identity).await?;
+        let msg = Self::on_authenticated(conn_id, &recipient).await;
+        self.send_pending_tells(&conn_id).await;
+        self.join_channels(&conn_id).await;
+        Ok(msg)
+    }
+
+    pub async fn session_authenticate(
+        &'static self,
+        recipient: Recipient<ClientMessage>,
+        uid: UserID,
+        sid: String,
+    ) -> Result<ClientMessage, Error> {
+        println!("session auth: {}", uid);
+        let res = self
+            .conns
+            .add_session_conn(recipient.clone(), uid, sid)
+            .await;
+        match res {
+            Ok(conn_id) => {
+                let msg = Self::on_authenticated(conn_id, &recipient).await;
+                self.send_pending_tells(&conn_id).await;
+                self.join_channels(&conn_id).await;
+                Ok(msg)
+            }
+            Err(e) => Ok(Self::send_auth_err(e, &recipient).await),
+        }
+    }
+
+    async fn send_auth_err(
+        e: Error,
+        recipient: &Recipient<ClientMessage>,
+    ) -> ClientMessage {
+        let err = json!({
+            "kind": "err",
+            "err": { "kind": "auth" },
+            "reason": format!("{}", e),
+        })
+        .to_string();
+        Self::send_text_to_recipient(err, recipient).await
+    }
+
+    async fn on_authenticated(
+        conn_id: ConnID,
+        recipient: &Recipient<ClientMessage>,
+    ) -> ClientMessage {
         println!("conn_id: {}", conn_id);
         let send_res = recipient
             .send(ClientMessage::new(ClientMessageKind::Auth(conn_id)))
@@ -405,7 +709,12 @@ impl BughouseServer {
         if let Err(e) = send_res {
             eprintln!("Couldn't send AUTH message: {}", e);
         }
-        return Ok(ClientMessage::new(ClientMessageKind::Auth(conn_id)));
+        ClientMessage::new(ClientMessageKind::Auth(conn_id))
+    }
+
+    // Logout / session revocation. `sid` limits it to one session's sockets
+    pub fn close_user_conns(&self, uid: &UserID, sid: Option<&str>) -> usize {
+        self.conns.close_user_conns(uid, sid, "Signed out")
     }
 
     pub fn user_from_conn(&self, conn_id: ConnID) -> Option<Arc<RwLock<User>>> {
@@ -427,23 +736,14 @@ impl BughouseServer {
         snaps: &TableSnapshot,
     ) -> Result<((String, String), (String, String)), Error> {
         let ((aw, ab), (bw, bb)) = snaps;
-        let (maybe_aw, maybe_ab, maybe_bw, maybe_bb) = join!(
-            self.users.maybe_user_from_uid(&aw.uid),
-            self.users.maybe_user_from_uid(&ab.uid),
-            self.users.maybe_user_from_uid(&bw.uid),
-            self.users.maybe_user_from_uid(&bb.uid),
-        );
-        let rawu = maybe_aw.unwrap();
-        let rabu = maybe_ab.unwrap();
-        let rbwu = maybe_bw.unwrap();
-        let rbbu = maybe_bb.unwrap();
-        let awu = rawu.read().unwrap();
-        let abu = rabu.read().unwrap();
-        let bwu = rbwu.read().unwrap();
-        let bbu = rbbu.read().unwrap();
+        let uids = [aw.uid, ab.uid, bw.uid, bb.uid];
+        let uid2handle = self.user_loader.get_handles(&uids).await?;
+        let handle = |uid: &UserID| {
+            uid2handle.get(uid).cloned().ok_or(Error::UnknownUID(*uid))
+        };
         Ok((
-            (awu.handle.clone(), abu.handle.clone()),
-            (bwu.handle.clone(), bbu.handle.clone()),
+            (handle(&aw.uid)?, handle(&ab.uid)?),
+            (handle(&bw.uid)?, handle(&bb.uid)?),
         ))
     }
 
@@ -491,7 +791,27 @@ impl BughouseServer {
             return Ok(Self::send_text_to_recipient(err, &recipient).await);
         }
         let handles = self.get_user_handles(&game_row.players).await?;
-        let payload = game_row.to_json(handles, None);
+        let mut payload = game_row.to_json(handles, None);
+        // Partner signals, to replay alongside the moves
+        let signals = self.db.get_signals(&game_id).await?;
+        payload["signals"] = signals
+            .iter()
+            .filter_map(|row| {
+                let signal =
+                    PartnerSignal::from_parts(&row.kind, &row.payload)?;
+                let mut json = signal.to_json();
+                json["ms"] = json!(row.ms);
+                json["sender"] = json!(B66::encode_uuid(&row.sender));
+                Some(json)
+            })
+            .collect();
+        // The game's over, so whispers are included
+        let mut kibitzes = vec![];
+        for row in self.db.get_kibitzes(&game_id).await?.iter() {
+            let handle = self.user_loader.get_handle(&row.sender).await?;
+            kibitzes.push(kibitz::to_json(&game_id, row, &handle));
+        }
+        payload["kibitzes"] = json!(kibitzes);
         let bytestr = Arc::new(ByteString::from(payload.to_string()));
         let msg = ClientMessage::new(ClientMessageKind::Text(bytestr));
         // let res = self.conns.send_to_conn(conn_id, msg.clone());
@@ -507,16 +827,18 @@ impl BughouseServer {
     pub async fn rating_snapshot_from_uid(
         &'static self,
         uid: &UserID,
+        category: RatingCategory,
     ) -> Result<UserRatingSnapshot, Error> {
         let user = self.user_from_uid(uid).await?;
-        Ok(UserRatingSnapshot::from(user))
+        Ok(UserRatingSnapshot::new(&user, category))
     }
 
     pub fn get_table_rating_snapshots(
         &'static self,
-        user: Arc<RwLock<User>>,
+        user: &Arc<RwLock<User>>,
+        category: RatingCategory,
     ) -> TableSnapshot {
-        let user_snap = UserRatingSnapshot::from(user);
+        let user_snap = UserRatingSnapshot::new(user, category);
         let nil = UserRatingSnapshot::nil();
         ((user_snap, nil.clone()), (nil.clone(), nil))
     }
@@ -538,12 +860,16 @@ impl BughouseServer {
         handle: String,
         uid: UserID,
     ) -> Result<ClientMessage, Error> {
+        self.handle_policy.validate(&handle)?;
         let user = self.user_from_uid(&uid).await?;
-        self.db.set_handle(&handle, user.clone()).await?;
+        self.db
+            .set_handle(&handle, user.clone(), &self.handle_policy)
+            .await?;
         {
             let mut wuser = user.write().unwrap();
             wuser.handle = handle;
         }
+        self.user_loader.invalidate(&uid);
         let ruser = user.read().unwrap();
         let json = json!({
             "kind": "login",
@@ -560,6 +886,122 @@ impl BughouseServer {
         Ok(self.send_text_to_user(hdl_json.to_string(), &ruser.id))
     }
 
+    // None leaves a field as is, empty clears it
+    pub async fn update_profile(
+        &'static self,
+        uid: UserID,
+        name: Option<String>,
+        photo_url: Option<String>,
+    ) -> Result<Arc<RwLock<User>>, Error> {
+        if let Some(name) = &name {
+            User::validate_name(name)?;
+        }
+        if let Some(photo_url) = &photo_url {
+            User::validate_photo_url(photo_url)?;
+        }
+        let user = self.user_from_uid(&uid).await?;
+        let (name, photo_url) = {
+            let ruser = user.read().unwrap();
+            let keep_or_clear = |new: Option<String>, old: &Option<String>| {
+                new.map_or_else(
+                    || old.clone(),
+                    |v| if v.is_empty() { None } else { Some(v) },
+                )
+            };
+            (
+                keep_or_clear(name, &ruser.name),
+                keep_or_clear(photo_url, &ruser.photo_url),
+            )
+        };
+        self.db
+            .update_profile(&uid, name.as_deref(), photo_url.as_deref())
+            .await?;
+        {
+            let mut wuser = user.write().unwrap();
+            wuser.name = name;
+            wuser.photo_url = photo_url;
+        }
+        Ok(user)
+    }
+
+    // Permanently deletes their account: they're signed out everywhere and
+    // can no longer sign in.  Their games are kept.  `confirm_handle` must be
+    // their current handle.
+    pub async fn delete_account(
+        &'static self,
+        uid: UserID,
+        confirm_handle: &str,
+    ) -> Result<(), Error> {
+        let user = self.user_from_uid(&uid).await?;
+        let handle = user.read().unwrap().handle.clone();
+        if handle != confirm_handle {
+            return Err(Error::ConfirmationMismatch(
+                confirm_handle.to_string(),
+            ));
+        }
+        if let Some(game) = self.games.get_user_game(&uid) {
+            let gid = B66::encode_uuid(game.read().unwrap().get_id());
+            return Err(Error::InGame(uid.to_string(), gid));
+        }
+        self.db.delete_user(&uid, DELETED_HANDLE).await?;
+        {
+            let mut wuser = user.write().unwrap();
+            wuser.handle = DELETED_HANDLE.to_string();
+            wuser.firebase_id = None;
+            wuser.email = None;
+            wuser.name = None;
+            wuser.photo_url = None;
+            wuser.ratings.clear();
+        }
+        self.user_loader.invalidate(&uid);
+        self.close_user_conns(&uid, None);
+        Ok(())
+    }
+
+    // Guest signs in with a real account.  Either the guest account becomes
+    // that account (same uid) or, if the account already existed, the
+    // guest's games move to it, the guest is deleted and its sockets are
+    // closed so they reconnect as the registered user.
+    pub async fn upgrade_guest(
+        &'static self,
+        uid: UserID,
+        identity: Identity,
+        handle: Option<String>,
+    ) -> Result<Arc<RwLock<User>>, Error> {
+        let user = self.user_from_uid(&uid).await?;
+        if !user.read().unwrap().guest {
+            return Err(Error::NotGuest(uid));
+        }
+        let new_uid = self.db.upgrade_guest(uid, &identity).await?;
+        if new_uid != uid {
+            self.user_loader.invalidate(&uid);
+            self.conns.close_user_conns(&uid, None, "Signed in elsewhere");
+            return self.conns.user_from_identity(&identity).await;
+        }
+        if let Some(updated) = self.db.get_user(&uid).await {
+            *user.write().unwrap() = updated;
+        }
+        self.user_loader.invalidate(&uid);
+        match handle {
+            Some(handle) => {
+                // Also notifies the user's sockets
+                self.set_handle(handle, uid).await?;
+            }
+            None => {
+                let ruser = user.read().unwrap();
+                let json = json!({
+                    "kind": "login",
+                    "uid": B66::encode_uuid(&ruser.id),
+                    "handle": ruser.handle,
+                    "guest": ruser.guest,
+                    "role": ruser.role,
+                });
+                self.send_text_to_user(json.to_string(), &ruser.id);
+            }
+        }
+        Ok(user)
+    }
+
     pub fn queue_sit(
         &'static self,
         game_id: &GameID,
@@ -592,7 +1034,10 @@ impl BughouseServer {
         game: Arc<RwLock<Game>>,
     ) -> Result<ClientMessage, Error> {
         let rgame = game.read().unwrap();
-        let rating_snapshots = Game::get_rating_snapshots(&rgame.players);
+        let rating_snapshots = Game::get_rating_snapshots(
+            &rgame.players,
+            rgame.get_rating_category(),
+        );
         self.db.sit(rgame.get_id(), &rating_snapshots).await?;
         self.update_game_observers(game.clone());
         println!("updated game observers");
@@ -626,7 +1071,9 @@ impl BughouseServer {
         let conn_id = ConnectionMgr::get_conn_id(&recipient);
         let uid = self.uid_from_conn(&conn_id)?;
         let game = self.games.vacate(game_id, board_id, color, uid)?;
-        self.observe(game.read().unwrap().get_id(), recipient);
+        if let Err(e) = self.observe(game.read().unwrap().get_id(), recipient) {
+            eprintln!("Couldn't observe after vacating: {}", e);
+        }
         self.update_seats(game).await
     }
 
@@ -635,10 +1082,19 @@ impl BughouseServer {
         time_ctrl: TimeControl,
         rated: bool,
         public: bool,
+        hidden: bool,
+        observer_delay: chrono::Duration,
         conn_id: &ConnID,
     ) -> Result<(), Error> {
         self.loopback.try_send(ServerMessage::new(
-            ServerMessageKind::FormTable(time_ctrl, rated, public, *conn_id),
+            ServerMessageKind::FormTable(
+                time_ctrl,
+                rated,
+                public,
+                hidden,
+                observer_delay,
+                *conn_id,
+            ),
         ))?;
         Ok(())
     }
@@ -648,6 +1104,8 @@ impl BughouseServer {
         time_ctrl: TimeControl,
         rated: bool,
         public: bool,
+        hidden: bool,
+        observer_delay: chrono::Duration,
         conn_id: ConnID,
     ) -> Result<ClientMessage, Error> {
         let uid = self.uid_from_conn(&conn_id)?;
@@ -661,15 +1119,22 @@ impl BughouseServer {
         }
 
         println!("form_table, user ID: {}", uid);
-        let snaps = self.get_table_rating_snapshots(user.clone());
+        let snaps = self
+            .get_table_rating_snapshots(&user, RatingCategory::of(&time_ctrl));
         println!("snaps: {:?}", snaps);
         let res = self.db.form_table(&time_ctrl, rated, public, &snaps).await;
         match res {
             Ok(id) => {
                 println!("id: {}", id);
-                let msg = self
-                    .games
-                    .form_table(id, time_ctrl, rated, public, user)?;
+                let msg = self.games.form_table(
+                    id,
+                    time_ctrl,
+                    rated,
+                    public,
+                    hidden,
+                    observer_delay,
+                    user,
+                )?;
                 Ok(msg)
             }
             Err(e) => {
@@ -700,7 +1165,10 @@ impl BughouseServer {
     ) -> Result<ClientMessage, Error> {
         println!("start_new_game");
         let start = Game::new_start();
-        let rating_snapshots = Game::get_rating_snapshots(&players);
+        let rating_snapshots = Game::get_rating_snapshots(
+            &players,
+            RatingCategory::of(&time_ctrl),
+        );
         println!("rating_snaps: {:?}", rating_snapshots);
         let id = self
             .db
@@ -823,29 +1291,42 @@ impl BughouseServer {
 
     fn send_new_rating(&self, user: Arc<RwLock<User>>) {
         let ruser = user.read().unwrap();
+        let rating = ruser.get_rating(RatingCategory::default());
         let json = json!({
             "kind": "login",
             "handle": ruser.handle, // TODO only needed for hacky SocketProxy.js logic
             "uid": ruser.id,
-            "rating": ruser.rating,
-            "deviation": ruser.deviation,
+            "rating": rating.rating,
+            "deviation": rating.deviation,
+            "ratings": ruser.get_ratings_json(),
         });
         self.send_text_to_user(json.to_string(), &ruser.id);
     }
 
-    fn update_user_rating(&self, rating: &UserRating) -> Result<(), Error> {
+    fn update_user_rating(
+        &self,
+        category: RatingCategory,
+        rating: &UserRating,
+    ) -> Result<(), Error> {
         let maybe_user = self.users.get(&rating.uid);
         if let Some(user) = maybe_user {
-            {
-                let mut wuser = user.write().unwrap();
-                wuser.rating = rating.rating.rating;
-                wuser.deviation = rating.rating.deviation;
-            }
+            user.write().unwrap().set_rating(category, *rating);
             self.send_new_rating(user);
         }
         Ok(())
     }
 
+    pub fn get_leaderboards(&self) -> Arc<Leaderboards> {
+        self.leaderboards.clone()
+    }
+
+    async fn refresh_leaderboards(
+        &'static self,
+    ) -> Result<ClientMessage, Error> {
+        self.leaderboards.refresh(&self.db).await?;
+        Ok(ClientMessage::new(ClientMessageKind::Empty))
+    }
+
     async fn record_game(
         &'static self,
         game: Arc<RwLock<Game>>,
@@ -855,29 +1336,50 @@ impl BughouseServer {
         println!("updated result");
         if game.read().unwrap().rated {
             println!("updating ratings...");
-            let ratings = UserRating::get_updated_ratings(game.clone());
-            self.db.record_ratings(&ratings).await?;
+            let category = game.read().unwrap().get_rating_category();
+            let mut ratings = UserRating::from_game(&game.read().unwrap());
+            self.db.load_rating_states(category, &mut ratings).await?;
+            let ratings = rating::get_updated_ratings(
+                self.rating_system.as_ref(),
+                &self.rating_guard,
+                game.clone(),
+                &ratings,
+            );
+            self.db.record_ratings(category, &ratings).await?;
             for rating_snapshot in ratings.iter() {
-                if self.update_user_rating(rating_snapshot).is_err() {
+                if self.update_user_rating(category, rating_snapshot).is_err() {
                     eprintln!(
                         "Failed updating in-memory user rating {}",
                         rating_snapshot.uid
                     )
                 }
             }
+            let partnerships = Partnership::from_game(&game.read().unwrap());
+            let partnership_ratings =
+                self.db.load_partnerships(category, &partnerships).await?;
+            let partnership_ratings = rating::get_updated_partnerships(
+                self.rating_system.as_ref(),
+                game.clone(),
+                &partnership_ratings,
+            );
+            self.db
+                .record_partnerships(category, &partnership_ratings)
+                .await?;
             println!("updated ratings.");
         }
         self.games.rm_game(game.read().unwrap().get_id());
         Ok(ClientMessage::new(ClientMessageKind::Empty))
     }
 
+    // Relays a partner signal (see PartnerSignal) to the sender's partner,
+    // with an ACK back to the sender, and records it with the game
     pub fn send_game_msg(
         &self,
         game_id: GameID,
         payload: &Value,
         conn_id: &ConnID,
     ) -> Result<(), Error> {
-        println!("send_game_msg({}, {}, {})", game_id, payload, conn_id);
+        let signal = PartnerSignal::from_json(payload)?;
         let uid = self.uid_from_conn(conn_id)?;
         let game = self
             .games
@@ -892,18 +1394,436 @@ impl BughouseServer {
                 *rgame.get_id(),
             ));
         }
-        if let Some(partner_uid) = rgame.get_partner(&uid) {
-            println!("sending {} to {}", payload, partner_uid);
-            self.send_text_to_user(payload.to_string(), &partner_uid);
-            // Send ACK back to sender
-            println!("sending same to {}", uid);
-            self.send_text_to_user(payload.to_string(), &uid);
-        } else {
-            println!("game_msg: no partner?");
+        let partner_uid = rgame
+            .get_partner(&uid)
+            .ok_or(Error::InvalidUserNotPlaying(uid, game_id))?;
+        let now = Utc::now();
+        if !self.signal_limiter.allow(&uid, now.timestamp_millis()) {
+            return Err(Error::SignalRateLimited);
         }
+        let ms = rgame
+            .get_start()
+            .map_or(0, |start| (now - start).num_milliseconds().max(0) as i32);
+        let mut json = signal.to_json();
+        json["kind"] = json!("game_msg");
+        json["id"] = json!(B66::encode_uuid(&game_id));
+        json["sender"] = json!(B66::encode_uuid(&uid));
+        json["ms"] = json!(ms);
+        self.send_text_to_user(json.to_string(), &partner_uid);
+        self.send_text_to_user(json.to_string(), &uid);
+        self.loopback.try_send(ServerMessage::new(
+            ServerMessageKind::RecordSignal(game_id, ms, uid, signal),
+        ))?;
         Ok(())
     }
 
+    pub fn queue_kibitz(
+        &'static self,
+        game_id: GameID,
+        body: String,
+        whisper: bool,
+        recipient: Recipient<ClientMessage>,
+    ) -> Result<(), Error> {
+        self.loopback.try_send(ServerMessage::new(
+            ServerMessageKind::Kibitz(game_id, body, whisper, recipient),
+        ))?;
+        Ok(())
+    }
+
+    // Observers may kibitz or whisper about the game they're watching, and
+    // its players may kibitz
+    pub async fn kibitz(
+        &'static self,
+        game_id: GameID,
+        body: String,
+        whisper: bool,
+        recipient: Recipient<ClientMessage>,
+    ) -> Result<ClientMessage, Error> {
+        let body = Tell::validate_body(&body)?;
+        let conn_id = ConnectionMgr::get_conn_id(&recipient);
+        let uid = self.uid_from_conn(&conn_id)?;
+        let game = self
+            .games
+            .get(&game_id)
+            .ok_or(Error::InvalidGameID(game_id))?;
+        let now = Utc::now();
+        let (is_player, ms) = {
+            let rgame = game.read().unwrap();
+            let ms = rgame.get_start().map_or(0, |start| {
+                (now - start).num_milliseconds().max(0) as i32
+            });
+            (rgame.get_board_id_for_user(&uid).is_some(), ms)
+        };
+        if is_player && whisper {
+            return Err(Error::InvalidKibitz("Players can't whisper".into()));
+        }
+        if !is_player && !self.games.is_observing(&game_id, &recipient) {
+            return Err(Error::InvalidKibitz("Not observing".into()));
+        }
+        if !self.kibitz_limiter.allow(&uid, now.timestamp_millis()) {
+            return Err(Error::KibitzRateLimited);
+        }
+        let row = KibitzRow {
+            id: self.db.uuid_from_time(now)?,
+            ms,
+            sender: uid,
+            whisper,
+            body,
+        };
+        self.db.record_kibitz(&game_id, &row).await?;
+        let handle = self.user_loader.get_handle(&uid).await?;
+        let json = kibitz::to_json(&game_id, &row, &handle);
+        let bytestr = Arc::new(ByteString::from(json.to_string()));
+        let msg = ClientMessage::new(ClientMessageKind::Text(bytestr));
+        self.games.notify_kibitz(game, &msg, whisper);
+        Ok(ClientMessage::new(ClientMessageKind::Empty))
+    }
+
+    pub fn queue_tell(
+        &'static self,
+        recipient: TellRecipient,
+        body: String,
+        conn_id: &ConnID,
+    ) -> Result<(), Error> {
+        let uid = self.uid_from_conn(conn_id)?;
+        self.loopback
+            .try_send(ServerMessage::new(ServerMessageKind::Tell(
+                recipient, body, uid,
+            )))?;
+        Ok(())
+    }
+
+    // Sends `body` to every connection of the recipient and, as an ACK, the
+    // sender.  It's kept for the recipient if they're offline.
+    pub async fn tell(
+        &'static self,
+        recipient: TellRecipient,
+        body: String,
+        sender: UserID,
+    ) -> Result<ClientMessage, Error> {
+        let body = Tell::validate_body(&body)?;
+        let recipient = match recipient {
+            TellRecipient::Uid(uid) => uid,
+            // Every deleted account has this handle
+            TellRecipient::Handle(handle) if handle == DELETED_HANDLE => {
+                return Err(Error::UnknownHandle(handle));
+            }
+            TellRecipient::Handle(handle) => self
+                .db
+                .uid_from_handle(&handle)
+                .await?
+                .ok_or(Error::UnknownHandle(handle))?,
+        };
+        let user = self.user_from_uid(&recipient).await?;
+        if user.read().unwrap().is_deleted() {
+            return Err(Error::UnknownUID(recipient));
+        }
+        if recipient == sender {
+            return Err(Error::InvalidTell("Can't tell yourself".into()));
+        }
+        let sent_at = Utc::now();
+        let tell = Tell {
+            id: self.db.uuid_from_time(sent_at)?,
+            sender,
+            recipient,
+            body,
+            sent_at,
+        };
+        let online = self.conns.is_online(&recipient);
+        self.db.record_tell(&tell, !online).await?;
+        let payload = self.tell_json(&tell).await?;
+        if online {
+            self.send_text_to_user(payload.clone(), &recipient);
+        }
+        Ok(self.send_text_to_user(payload, &sender))
+    }
+
+    async fn tell_json(&'static self, tell: &Tell) -> Result<String, Error> {
+        let handles = self
+            .user_loader
+            .get_handles(&[tell.sender, tell.recipient])
+            .await?;
+        let handle = |uid: &UserID| handles.get(uid).map_or("", |h| h.as_str());
+        let json = tell.to_json(handle(&tell.sender), handle(&tell.recipient));
+        Ok(json.to_string())
+    }
+
+    // Tells that arrived while `conn_id`'s user was offline
+    async fn send_pending_tells(&'static self, conn_id: &ConnID) {
+        let uid = match self.conns.uid_from_conn(conn_id) {
+            Some(uid) => uid,
+            None => return,
+        };
+        let tells = match self.db.take_pending_tells(&uid).await {
+            Ok(tells) => tells,
+            Err(e) => {
+                eprintln!("Couldn't read pending tells for {}: {}", uid, e);
+                return;
+            }
+        };
+        for tell in tells.iter() {
+            match self.tell_json(tell).await {
+                Ok(payload) => {
+                    self.send_text_to_user(payload, &uid);
+                }
+                Err(e) => eprintln!("Couldn't send tell {}: {}", tell.id, e),
+            }
+        }
+    }
+
+    pub fn queue_channel_command(
+        &'static self,
+        cmd: ChannelCommand,
+        conn_id: &ConnID,
+    ) -> Result<(), Error> {
+        let uid = self.uid_from_conn(conn_id)?;
+        self.loopback.try_send(ServerMessage::new(
+            ServerMessageKind::Channel(cmd, uid),
+        ))?;
+        Ok(())
+    }
+
+    pub async fn channel_command(
+        &'static self,
+        cmd: ChannelCommand,
+        uid: UserID,
+    ) -> Result<ClientMessage, Error> {
+        match cmd {
+            ChannelCommand::Join(name) => self.join_channel(&name, uid).await,
+            ChannelCommand::Leave(name) => self.leave_channel(&name, uid).await,
+            ChannelCommand::Send(name, body) => {
+                self.send_channel_msg(&name, body, uid).await
+            }
+            ChannelCommand::Mute(name, target, muted) => {
+                self.moderate_channel(&name, uid, target, Some(muted), None)
+                    .await
+            }
+            ChannelCommand::Ban(name, target, banned) => {
+                self.moderate_channel(&name, uid, target, None, Some(banned))
+                    .await
+            }
+        }
+    }
+
+    // Loads `name` into Channels.  Ok(false) if it doesn't exist
+    async fn load_channel(&'static self, name: &str) -> Result<bool, Error> {
+        if self.channels.is_loaded(name) {
+            return Ok(true);
+        }
+        let state = match self.db.get_channel(name).await? {
+            Some(state) => state,
+            None if name == LOBBY => {
+                let mut state = ChannelState {
+                    owner: None,
+                    members: HashSet::new(),
+                    muted: HashSet::new(),
+                    banned: HashSet::new(),
+                };
+                self.db.load_channel_moderation(name, &mut state).await?;
+                state
+            }
+            None => return Ok(false),
+        };
+        let channel =
+            Channel::new(state.owner, state.members, state.muted, state.banned);
+        self.channels.insert(name, channel);
+        if name == LOBBY {
+            for uid in self.conns.online_users().keys() {
+                self.channels.sub(name, self.conns.user_recipients(uid));
+            }
+        } else {
+            let members = self.channels.with(name, |c| c.members.clone())?;
+            for uid in members.iter() {
+                self.channels.sub(name, self.conns.user_recipients(uid));
+            }
+        }
+        Ok(true)
+    }
+
+    // Members who aren't banned may read a channel's history
+    pub async fn check_channel_reader(
+        &'static self,
+        name: &str,
+        uid: &UserID,
+    ) -> Result<(), Error> {
+        let name = Channels::validate_name(name)?;
+        if !self.load_channel(&name).await? {
+            return Err(Error::NotChannelMember(name));
+        }
+        self.channels.check_reader(&name, uid)
+    }
+
+    // Subscribes a new connection to the lobby and its user's channels
+    async fn join_channels(&'static self, conn_id: &ConnID) {
+        let (uid, recipient) = match (
+            self.conns.uid_from_conn(conn_id),
+            self.conns.recipient_from_conn(conn_id),
+        ) {
+            (Some(uid), Some(recipient)) => (uid, recipient),
+            _ => return,
+        };
+        let mut names = match self.db.get_user_channels(&uid).await {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("Couldn't read channels of {}: {}", uid, e);
+                vec![]
+            }
+        };
+        names.insert(0, LOBBY.to_string());
+        for name in names.iter() {
+            match self.load_channel(name).await {
+                Ok(true) => self.channels.sub(name, vec![recipient.clone()]),
+                Ok(false) => {}
+                Err(e) => eprintln!("Couldn't load channel {}: {}", name, e),
+            }
+        }
+        self.send_text_to_user(Self::channels_json(&names), &uid);
+    }
+
+    fn channels_json(names: &[String]) -> String {
+        json!({"kind": "channels", "channels": names}).to_string()
+    }
+
+    async fn send_user_channels(
+        &'static self,
+        uid: &UserID,
+    ) -> Result<ClientMessage, Error> {
+        let mut names = self.db.get_user_channels(uid).await?;
+        names.insert(0, LOBBY.to_string());
+        Ok(self.send_text_to_user(Self::channels_json(&names), uid))
+    }
+
+    // Creates `name` if it doesn't exist, owned by `uid`
+    async fn join_channel(
+        &'static self,
+        name: &str,
+        uid: UserID,
+    ) -> Result<ClientMessage, Error> {
+        let name = Channels::validate_name(name)?;
+        if name == LOBBY {
+            return self.send_user_channels(&uid).await;
+        }
+        if !self.load_channel(&name).await? {
+            self.db.create_channel(&name, &uid).await?;
+            self.load_channel(&name).await?;
+        }
+        if self.channels.with(&name, |c| c.banned.contains(&uid))? {
+            return Err(Error::ChannelBanned(name));
+        }
+        self.db.join_channel(&name, &uid).await?;
+        self.channels.with(&name, |c| c.members.insert(uid))?;
+        self.channels.sub(&name, self.conns.user_recipients(&uid));
+        self.send_user_channels(&uid).await
+    }
+
+    async fn leave_channel(
+        &'static self,
+        name: &str,
+        uid: UserID,
+    ) -> Result<ClientMessage, Error> {
+        let name = Channels::validate_name(name)?;
+        if name == LOBBY {
+            return Err(Error::InvalidChannel("Can't leave the lobby".into()));
+        }
+        self.db.leave_channel(&name, &uid).await?;
+        if self.channels.is_loaded(&name) {
+            self.channels.with(&name, |c| c.members.remove(&uid))?;
+            self.channels.unsub(&name, self.conns.user_recipients(&uid));
+        }
+        self.send_user_channels(&uid).await
+    }
+
+    async fn send_channel_msg(
+        &'static self,
+        name: &str,
+        body: String,
+        uid: UserID,
+    ) -> Result<ClientMessage, Error> {
+        let name = Channels::validate_name(name)?;
+        let body = Tell::validate_body(&body)?;
+        if !self.load_channel(&name).await?
+            || !self.channels.is_member(&name, &uid)?
+        {
+            return Err(Error::NotChannelMember(name));
+        }
+        let (muted, banned) = self.channels.with(&name, |c| {
+            (c.muted.contains(&uid), c.banned.contains(&uid))
+        })?;
+        if banned {
+            return Err(Error::ChannelBanned(name));
+        }
+        if muted {
+            return Err(Error::ChannelMuted(name));
+        }
+        let sent_at = Utc::now();
+        let id = self.db.uuid_from_time(sent_at)?;
+        self.db
+            .record_channel_message(&name, &id, &uid, &body, sent_at)
+            .await?;
+        let handle = self.user_loader.get_handle(&uid).await?;
+        let msg = Channels::msg_json(&name, &id, &uid, &handle, &body, sent_at);
+        self.channels.notify(&name, msg)?;
+        Ok(ClientMessage::new(ClientMessageKind::Empty))
+    }
+
+    // Admins moderate every channel, owners their own.  Banning also removes
+    // them from it.
+    async fn moderate_channel(
+        &'static self,
+        name: &str,
+        uid: UserID,
+        target: UserID,
+        muted: Option<bool>,
+        banned: Option<bool>,
+    ) -> Result<ClientMessage, Error> {
+        let name = Channels::validate_name(name)?;
+        if !self.load_channel(&name).await? {
+            return Err(Error::InvalidChannel(name));
+        }
+        let user = self.user_from_uid(&uid).await?;
+        let is_admin =
+            matches!(user.read().unwrap().get_role(), UserRole::Admin);
+        let owner = self.channels.with(&name, |c| c.owner)?;
+        if !is_admin && owner != Some(uid) {
+            return Err(Error::NotChannelModerator(name));
+        }
+        if owner == Some(target) {
+            return Err(Error::NotChannelModerator(name));
+        }
+        if let Some(muted) = muted {
+            self.db.set_channel_muted(&name, &target, muted).await?;
+            self.channels.with(&name, |c| {
+                if muted {
+                    c.muted.insert(target);
+                } else {
+                    c.muted.remove(&target);
+                }
+            })?;
+        }
+        if let Some(banned) = banned {
+            self.db.set_channel_banned(&name, &target, banned).await?;
+            self.channels.with(&name, |c| {
+                if banned {
+                    c.banned.insert(target);
+                } else {
+                    c.banned.remove(&target);
+                }
+            })?;
+            if banned && name != LOBBY {
+                self.leave_channel(&name, target).await?;
+            }
+        }
+        let json = json!({
+            "kind": "channel_moderation",
+            "channel": name,
+            "uid": B66::encode_uuid(&target),
+            "muted": muted,
+            "banned": banned,
+        });
+        self.send_text_to_user(json.to_string(), &target);
+        Ok(self.send_text_to_user(json.to_string(), &uid))
+    }
+
     pub fn get_game_json_payload(
         &self,
         game_id: GameID,
@@ -916,12 +1836,71 @@ impl BughouseServer {
             let mut wgame = game.write().unwrap();
             wgame.update_all_clocks();
         }
-        let game_json = GameJson::new(game.clone(), Games::get_kind(game));
+        let mut game_json = GameJson::new(game.clone(), Games::get_kind(game));
+        game_json.set_observers(self.games.observer_count(&game_id));
         Ok(ByteString::from(game_json.to_val().to_string()))
     }
 
+    // The game as observers see it, which may be behind (see
+    // Games::observer_payload)
+    pub fn get_observer_payload(
+        &self,
+        game_id: GameID,
+    ) -> Result<Option<ByteString>, Error> {
+        let game = self
+            .games
+            .get(&game_id)
+            .ok_or(Error::InvalidGameID(game_id))?;
+        game.write().unwrap().update_all_clocks();
+        Ok(self.games.observer_payload(game))
+    }
+
+    // Whether `game_id` is a hidden game in progress that `uid` isn't
+    // playing in
+    pub fn is_hidden_from(
+        &self,
+        game_id: &GameID,
+        uid: Option<UserID>,
+    ) -> bool {
+        self.games.get(game_id).map_or(false, |game| {
+            let game = game.read().unwrap();
+            game.hidden
+                && uid.map_or(true, |uid| {
+                    game.get_board_id_for_user(&uid).is_none()
+                })
+        })
+    }
+
+    // Players see their game as it is, everyone else as observers do
+    pub fn get_game_payload_for(
+        &self,
+        game_id: GameID,
+        recipient: &Recipient<ClientMessage>,
+    ) -> Result<Option<ByteString>, Error> {
+        let conn_id = ConnectionMgr::get_conn_id(recipient);
+        let is_player = match self.conns.uid_from_conn(&conn_id) {
+            Some(uid) => self.games.get(&game_id).map_or(false, |game| {
+                game.read().unwrap().get_board_id_for_user(&uid).is_some()
+            }),
+            None => false,
+        };
+        if is_player {
+            return self.get_game_json_payload(game_id).map(Some);
+        }
+        self.get_observer_payload(game_id)
+    }
+
+    pub fn flush_observers(&'static self) {
+        self.games.flush_observers();
+    }
+
+    pub fn rerank_current_games(&'static self) {
+        self.games.rerank_current();
+    }
+
     pub fn on_close(&'static self, recipient: &Recipient<ClientMessage>) {
         self.games.remove_recipient(recipient);
+        self.channels.remove_recipient(recipient);
         self.conns
             .on_close(recipient)
             .expect("Couldn't remove conn");
@@ -930,10 +1909,10 @@ impl BughouseServer {
     pub async fn add_conn(
         &'static self,
         recipient: Recipient<ClientMessage>,
-        fid: &str,
+        identity: &Identity,
     ) -> Result<ConnID, Error> {
-        println!("add_conn: {}", fid);
-        let conn_id = self.conns.add_conn(recipient, fid).await?;
+        println!("add_conn: {}:{}", identity.provider, identity.subject);
+        let conn_id = self.conns.add_conn(recipient, identity).await?;
         Ok(conn_id)
     }
 
@@ -941,10 +1920,14 @@ impl BughouseServer {
         &'static self,
         game_id: &GameID,
         recipient: Recipient<ClientMessage>,
-    ) {
+    ) -> Result<(), Error> {
         self.games.observe(game_id, recipient)
     }
 
+    pub fn observers_json(&'static self, game_id: &GameID) -> Value {
+        self.games.observers_json(game_id)
+    }
+
     pub fn unobserve(
         &'static self,
         game_id: &GameID,
@@ -0,0 +1,223 @@
+// Named chat channels (see docs/FICS/tells.md).  Membership, moderation and
+// history live in the DB.  A channel is loaded into Channels the first time
+// it's used, and every connection of its online members is subscribed to it,
+// so messages fan out the way table and game updates do.  Everyone is in the
+// lobby.
+use actix::prelude::*;
+use chrono::prelude::*;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use uuid::Uuid;
+
+use crate::b66::B66;
+use crate::error::Error;
+use crate::messages::ClientMessage;
+use crate::subscriptions::Subscriptions;
+use crate::users::UserID;
+
+pub const LOBBY: &str = "lobby";
+
+const MAX_NAME_LEN: usize = 24;
+
+pub enum ChannelCommand {
+    Join(String),
+    Leave(String),
+    Send(String, String), // channel, body
+    Mute(String, UserID, bool),
+    Ban(String, UserID, bool),
+}
+
+pub struct Channel {
+    // Creator, who moderates it along with admins.  None for the lobby
+    pub owner: Option<UserID>,
+    pub members: HashSet<UserID>,
+    pub muted: HashSet<UserID>,
+    pub banned: HashSet<UserID>,
+    subs: Subscriptions,
+}
+
+impl Channel {
+    pub fn new(
+        owner: Option<UserID>,
+        members: HashSet<UserID>,
+        muted: HashSet<UserID>,
+        banned: HashSet<UserID>,
+    ) -> Self {
+        Channel {
+            owner,
+            members,
+            muted,
+            banned,
+            subs: Subscriptions::new(),
+        }
+    }
+}
+
+pub struct Channels {
+    channels: RwLock<HashMap<String, Channel>>,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Channels {
+            channels: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Lowercase letters, digits, '-' and '_'.  Returns it lowercased
+    pub fn validate_name(name: &str) -> Result<String, Error> {
+        let name = name.trim().to_lowercase();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(Error::InvalidChannel(format!(
+                "Channel names are 1 to {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if !name.chars().all(valid) {
+            return Err(Error::InvalidChannel(name));
+        }
+        Ok(name)
+    }
+
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.channels.read().unwrap().contains_key(name)
+    }
+
+    pub fn insert(&self, name: &str, channel: Channel) {
+        let mut channels = self.channels.write().unwrap();
+        channels.entry(name.to_string()).or_insert(channel);
+    }
+
+    pub fn with<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&mut Channel) -> T,
+    ) -> Result<T, Error> {
+        let mut channels = self.channels.write().unwrap();
+        let channel = channels
+            .get_mut(name)
+            .ok_or_else(|| Error::InvalidChannel(name.to_string()))?;
+        Ok(f(channel))
+    }
+
+    pub fn is_member(&self, name: &str, uid: &UserID) -> Result<bool, Error> {
+        if name == LOBBY {
+            return Ok(true);
+        }
+        self.with(name, |channel| channel.members.contains(uid))
+    }
+
+    // Whether `uid` may read `name`'s history: members who aren't banned
+    pub fn check_reader(&self, name: &str, uid: &UserID) -> Result<(), Error> {
+        if !self.is_member(name, uid)? {
+            return Err(Error::NotChannelMember(name.to_string()));
+        }
+        if self.with(name, |channel| channel.banned.contains(uid))? {
+            return Err(Error::ChannelBanned(name.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn sub(&self, name: &str, recipients: Vec<Recipient<ClientMessage>>) {
+        let res = self.with(name, |channel| {
+            for recipient in recipients.into_iter() {
+                channel.subs.sub(recipient);
+            }
+        });
+        if let Err(e) = res {
+            eprintln!("Couldn't subscribe: {}", e);
+        }
+    }
+
+    pub fn unsub(&self, name: &str, recipients: Vec<Recipient<ClientMessage>>) {
+        let res = self.with(name, |channel| {
+            for recipient in recipients.into_iter() {
+                channel.subs.unsub(recipient);
+            }
+        });
+        if let Err(e) = res {
+            eprintln!("Couldn't unsubscribe: {}", e);
+        }
+    }
+
+    pub fn remove_recipient(&self, recipient: &Recipient<ClientMessage>) {
+        let mut channels = self.channels.write().unwrap();
+        for channel in channels.values_mut() {
+            channel.subs.unsub(recipient.clone());
+        }
+    }
+
+    pub fn notify(&self, name: &str, val: Value) -> Result<(), Error> {
+        self.with(name, |channel| channel.subs.notify_value(val))
+    }
+
+    pub fn msg_json(
+        name: &str,
+        id: &Uuid,
+        sender: &UserID,
+        handle: &str,
+        body: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Value {
+        json!({
+            "kind": "channel_msg",
+            "channel": name,
+            "id": B66::encode_uuid(id),
+            "from": {
+                "uid": B66::encode_uuid(sender),
+                "handle": handle,
+            },
+            "body": body,
+            "time": sent_at.timestamp_millis(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_name() {
+        assert!(
+            Channels::validate_name(" Bughouse_Club ").unwrap()
+                == "bughouse_club"
+        );
+        assert!(Channels::validate_name("24").is_ok());
+        assert!(Channels::validate_name("").is_err());
+        assert!(Channels::validate_name("no spaces").is_err());
+        assert!(Channels::validate_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn check_reader() {
+        let (member, banned, stranger) =
+            (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let channels = Channels::new();
+        let members: HashSet<UserID> =
+            [member, banned].iter().cloned().collect();
+        let bans: HashSet<UserID> = [banned].iter().cloned().collect();
+        channels.insert(
+            "club",
+            Channel::new(Some(member), members, HashSet::new(), bans.clone()),
+        );
+        channels.insert(
+            LOBBY,
+            Channel::new(None, HashSet::new(), HashSet::new(), bans),
+        );
+        assert!(channels.check_reader("club", &member).is_ok());
+        assert!(matches!(
+            channels.check_reader("club", &stranger),
+            Err(Error::NotChannelMember(_))
+        ));
+        assert!(matches!(
+            channels.check_reader("club", &banned),
+            Err(Error::ChannelBanned(_))
+        ));
+        assert!(channels.check_reader(LOBBY, &stranger).is_ok());
+        assert!(channels.check_reader(LOBBY, &banned).is_err());
+        assert!(channels.check_reader("nowhere", &member).is_err());
+    }
+}
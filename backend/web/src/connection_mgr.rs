@@ -1,3 +1,4 @@
+use crate::auth::Identity;
 use crate::db::Db;
 use crate::messages::{
     ClientMessage, ClientMessageKind, UserStateKind, UserStateMessage,
@@ -13,6 +14,7 @@ use uuid::Uuid;
 use crate::b66::B66;
 use crate::error::Error;
 use crate::hash::hash;
+use crate::rating::RatingCategory;
 use crate::users::{User, UserID, Users};
 
 pub type ConnID = u64;
@@ -20,11 +22,21 @@ pub type ConnID = u64;
 struct SockConn {
     recipient: Recipient<ClientMessage>,
     uid: UserID,
+    // Set when authenticated from a session cookie rather than credentials
+    sid: Option<String>,
 }
 
 impl SockConn {
-    pub fn new(recipient: Recipient<ClientMessage>, uid: UserID) -> Self {
-        SockConn { recipient, uid }
+    pub fn new(
+        recipient: Recipient<ClientMessage>,
+        uid: UserID,
+        sid: Option<String>,
+    ) -> Self {
+        SockConn {
+            recipient,
+            uid,
+            sid,
+        }
     }
 
     pub fn recipient(&self) -> &Recipient<ClientMessage> {
@@ -42,7 +54,7 @@ pub struct ConnectionMgr {
     conns: RwLock<HashMap<ConnID, SockConn>>,
     user_conns: RwLock<HashMap<UserID, HashSet<ConnID>>>,
     user_handlers: RwLock<HashSet<Recipient<UserStateMessage>>>,
-    fid_users: RwLock<HashMap<String, UserID>>,
+    identity_users: RwLock<HashMap<(String, String), UserID>>,
     subs: RwLock<HashSet<Recipient<ClientMessage>>>,
 }
 
@@ -54,7 +66,7 @@ impl ConnectionMgr {
             conns: RwLock::new(HashMap::new()),
             user_conns: RwLock::new(HashMap::new()),
             user_handlers: RwLock::new(HashSet::new()),
-            fid_users: RwLock::new(HashMap::new()),
+            identity_users: RwLock::new(HashMap::new()),
             subs: RwLock::new(HashSet::new()),
         }
     }
@@ -71,24 +83,27 @@ impl ConnectionMgr {
     //     None
     // }
 
-    pub async fn user_from_fid(
+    pub async fn user_from_identity(
         &self,
-        fid: &str, // firebase ID
+        identity: &Identity,
     ) -> Result<Arc<RwLock<User>>, Error> {
-        println!("user_from_fid: {}", fid);
+        println!(
+            "user_from_identity: {}:{}",
+            identity.provider, identity.subject
+        );
+        let key = (identity.provider.clone(), identity.subject.clone());
         {
-            let f2u = self.fid_users.read().unwrap();
-            if let Some(uid) = f2u.get(fid) {
+            let i2u = self.identity_users.read().unwrap();
+            if let Some(uid) = i2u.get(&key) {
                 if let Some(user) = self.users.get(uid) {
-                    //self.user_from_uid(uid) {
                     return Ok(user);
                 }
             }
         }
-        let user = self.db.user_from_firebase_id(fid).await?;
+        let user = self.db.user_from_identity(identity).await?;
         {
-            let mut f2u = self.fid_users.write().unwrap();
-            f2u.insert(fid.to_string(), *user.get_uid());
+            let mut i2u = self.identity_users.write().unwrap();
+            i2u.insert(key, *user.get_uid());
         }
         Ok(self.users.add(user.into()))
     }
@@ -96,20 +111,47 @@ impl ConnectionMgr {
     pub async fn add_conn(
         &self,
         recipient: Recipient<ClientMessage>,
-        fid: &str, // firebase ID
+        identity: &Identity,
+    ) -> Result<ConnID, Error> {
+        let user = self.user_from_identity(identity).await?;
+        let uid = *user.read().unwrap().get_uid();
+        self.add_user_conn(recipient, uid, None)
+    }
+
+    // Session cookies carry the uid, so there's nothing to authenticate
+    pub async fn add_session_conn(
+        &self,
+        recipient: Recipient<ClientMessage>,
+        uid: UserID,
+        sid: String,
+    ) -> Result<ConnID, Error> {
+        self.users
+            .maybe_user_from_uid(&uid)
+            .await
+            .ok_or(Error::UnknownUID(uid))?;
+        self.add_user_conn(recipient, uid, Some(sid))
+    }
+
+    fn add_user_conn(
+        &self,
+        recipient: Recipient<ClientMessage>,
+        uid: UserID,
+        sid: Option<String>,
     ) -> Result<ConnID, Error> {
-        let user = self.user_from_fid(fid).await?;
         println!("ConnectionMgr.add_conn ...");
-        let user = user.read().unwrap();
-        let uid = *user.get_uid();
         let conn_id = hash(&recipient);
         println!("inserting...");
         {
-            if self.conns.read().unwrap().contains_key(&conn_id) {
+            // A socket may authenticate from its session cookie and a token
+            // message at the same time.
+            if let Some(conn) = self.conns.read().unwrap().get(&conn_id) {
+                if conn.uid == uid && conn.recipient == recipient {
+                    return Ok(conn_id);
+                }
                 return Err(Error::Unexpected("Hash collision".to_string()));
             }
             let mut conns = self.conns.write().unwrap();
-            conns.insert(conn_id, SockConn::new(recipient, uid));
+            conns.insert(conn_id, SockConn::new(recipient, uid, sid));
         }
         {
             let mut u2c = self.user_conns.write().unwrap();
@@ -147,6 +189,21 @@ impl ConnectionMgr {
         Ok(())
     }
 
+    pub fn user_recipients(
+        &self,
+        uid: &UserID,
+    ) -> Vec<Recipient<ClientMessage>> {
+        let conns = self.conns.read().unwrap();
+        match self.user_conns.read().unwrap().get(uid) {
+            Some(conn_ids) => conn_ids
+                .iter()
+                .filter_map(|conn_id| conns.get(conn_id))
+                .map(|conn| conn.recipient().clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
     pub fn send_to_user(&self, uid: &UserID, msg: &ClientMessage) {
         let mut conn_id_to_remove: Option<ConnID> = None;
         {
@@ -183,6 +240,35 @@ impl ConnectionMgr {
         }
     }
 
+    // Close the user's sockets - only those opened from session `sid` if
+    // given. Returns how many were told to close.  Each socket removes its
+    // own conn once it's stopped (see on_close).
+    pub fn close_user_conns(
+        &self,
+        uid: &UserID,
+        sid: Option<&str>,
+        reason: &str,
+    ) -> usize {
+        let conns = self.conns.read().unwrap();
+        let mut closed = 0;
+        if let Some(conn_ids) = self.user_conns.read().unwrap().get(uid) {
+            for conn_id in conn_ids.iter() {
+                if let Some(conn) = conns.get(conn_id) {
+                    if sid.is_some() && conn.sid.as_deref() != sid {
+                        continue;
+                    }
+                    let msg = ClientMessage::new(ClientMessageKind::Close(
+                        reason.to_string(),
+                    ));
+                    if conn.recipient().try_send(msg).is_ok() {
+                        closed += 1;
+                    }
+                }
+            }
+        }
+        closed
+    }
+
     pub fn user_from_conn(&self, conn_id: ConnID) -> Option<Arc<RwLock<User>>> {
         let conns = self.conns.read().unwrap();
         match conns.get(&conn_id) {
@@ -210,6 +296,14 @@ impl ConnectionMgr {
         Some(*sock_conn.uid())
     }
 
+    pub fn is_online(&self, uid: &UserID) -> bool {
+        self.user_conns
+            .read()
+            .unwrap()
+            .get(uid)
+            .map_or(false, |conns| !conns.is_empty())
+    }
+
     pub fn online_users(&self) -> HashMap<UserID, Arc<RwLock<User>>> {
         let mut res: HashMap<UserID, Arc<RwLock<User>>> = HashMap::new();
         for (user_id, conns) in self.user_conns.read().unwrap().iter() {
@@ -227,19 +321,26 @@ impl ConnectionMgr {
         res
     }
 
+    // [uid, handle, rating, provisional]
     pub fn get_online_players(
         online_users: HashMap<UserID, Arc<RwLock<User>>>,
-    ) -> Vec<(String, String, Option<i16>)> {
+    ) -> Vec<(String, String, Option<i16>, bool)> {
         online_users
             .iter()
             .map(|(uid, user)| {
                 let ruser = user.read().unwrap();
+                let category = RatingCategory::default();
                 let rating: Option<i16> = if ruser.guest {
                     None
                 } else {
-                    Some(ruser.rating)
+                    Some(ruser.get_rating(category).rating)
                 };
-                (B66::encode_uuid(uid), ruser.handle.clone(), rating)
+                (
+                    B66::encode_uuid(uid),
+                    ruser.handle.clone(),
+                    rating,
+                    ruser.is_provisional(category),
+                )
             })
             .collect()
     }
@@ -296,7 +397,7 @@ impl ConnectionMgr {
         online: HashSet<UserID>,
         offline: HashSet<UserID>,
     ) {
-        let players: Vec<(String, String, Option<i16>)> =
+        let players: Vec<(String, String, Option<i16>, bool)> =
             Self::get_online_players(
                 online
                     .iter()
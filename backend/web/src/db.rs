@@ -1,10 +1,13 @@
 use bughouse::{BoardID, BughouseMove};
 use chrono::prelude::*;
 use chrono::Duration;
+use futures::StreamExt;
 use noneifempty::NoneIfEmpty;
 use scylla::batch::Batch;
 use scylla::cql_to_rust::FromCqlVal;
+use scylla::cql_to_rust::FromRow as _;
 use scylla::frame::value::Timestamp as ScyllaTimestamp;
+use scylla::frame::value::{SerializedValues, ValueList};
 use scylla::macros::{FromRow, FromUserType, IntoUserType};
 use scylla::prepared_statement::PreparedStatement;
 use scylla::query::Query;
@@ -12,7 +15,7 @@ use scylla::statement::{Consistency, SerialConsistency};
 use scylla::transport::session::{IntoTypedRows, Session};
 use scylla::QueryResult;
 use scylla::SessionBuilder;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::io::prelude::{Read, Write};
 use std::os::unix::net::UnixStream;
@@ -20,20 +23,35 @@ use std::sync::{Arc, RwLock};
 use uuid::v1::{Context, Timestamp};
 use uuid::Uuid;
 
+use crate::auth::{self, Identity, Profile};
 use crate::b66::B66;
 use crate::error::Error;
 use crate::firebase::*;
 use crate::game::{Game, GameID};
 use crate::game_row::{GameRow, IntoUserGameRow, UserGameRow};
 use crate::guest::guest_handle::GuestHandle;
+use crate::handle_policy::HandlePolicy;
+use crate::leaderboards::LeaderboardEntry;
+use crate::partner_signals::PartnerSignal;
 use crate::players::Players;
-use crate::rating::{Rating, UserRating};
+use crate::rating::replay::{Replay, ReplayGame};
+use crate::rating::{
+    Partnership, PartnershipRating, Rating, RatingCategory, UserRating,
+    INIT_VOLATILITY,
+};
+use crate::tells::{Tell, TellID};
 use crate::time_control::TimeControl;
-use crate::users::{User, UserID};
+use crate::users::{User, UserID, UserRow};
 
 const DEFAULT_URI: &str = "127.0.0.1:9042";
 
-// User's GLICKO rating snapshot before game start
+// How long a sign-in that lost the race to link an identity waits for the
+// winner to write the user (see identity_owner)
+const IDENTITY_OWNER_TRIES: usize = 5;
+const IDENTITY_OWNER_WAIT: std::time::Duration =
+    std::time::Duration::from_millis(100);
+
+// User's GLICKO rating snapshot, in the game's category, before game start
 #[derive(Copy, Clone, Debug, FromRow, IntoUserType, FromUserType)]
 pub struct UserRatingSnapshot {
     pub rating: i16,
@@ -42,7 +60,31 @@ pub struct UserRatingSnapshot {
 
 impl UserRatingSnapshot {
     pub fn nil() -> Self {
-        UserRatingSnapshot::from(None)
+        UserRatingSnapshot {
+            uid: Uuid::nil(),
+            rating: 0,
+        }
+    }
+
+    pub fn new(
+        user_lock: &Arc<RwLock<User>>,
+        category: RatingCategory,
+    ) -> Self {
+        let user = user_lock.read().unwrap();
+        UserRatingSnapshot {
+            uid: *user.get_uid(),
+            rating: user.get_rating(category).rating,
+        }
+    }
+
+    pub fn from_player(
+        maybe_user: &Option<Arc<RwLock<User>>>,
+        category: RatingCategory,
+    ) -> Self {
+        match maybe_user {
+            None => UserRatingSnapshot::nil(),
+            Some(user_lock) => UserRatingSnapshot::new(user_lock, category),
+        }
     }
 }
 
@@ -52,6 +94,73 @@ impl Default for UserRatingSnapshot {
     }
 }
 
+// uid, category, rating, deviation, volatility, games, last_rated
+type RatingRow = (
+    UserID,
+    String,
+    i16,
+    i16,
+    Option<f64>,
+    Option<i32>,
+    Option<Duration>,
+);
+
+// volatility, games, last_rated
+type RatingState = (Option<f64>, Option<i32>, Option<Duration>);
+
+// partner, category, rating, deviation, volatility, games, wins, last_rated
+type PartnershipRow = (
+    UserID,
+    String,
+    i16,
+    i16,
+    Option<f64>,
+    Option<i32>,
+    Option<i32>,
+    Option<Duration>,
+);
+
+// id, start_time, result, time_ctrl, rated, players
+type ReplayRow = (
+    GameID,
+    Option<Duration>,
+    Option<i16>,
+    Option<TimeControl>,
+    Option<bool>,
+    Option<TableSnapshot>,
+);
+
+// Games read at a time when replaying
+const REPLAY_PAGE_SIZE: i32 = 1000;
+
+// Each user's statements for Db::write_replay, and their values
+struct ReplayBatches(HashMap<UserID, (Batch, Vec<SerializedValues>)>);
+
+impl ReplayBatches {
+    fn new() -> Self {
+        ReplayBatches(HashMap::new())
+    }
+
+    fn add(
+        &mut self,
+        uid: &UserID,
+        statement: &PreparedStatement,
+        values: impl ValueList,
+    ) -> Result<(), Error> {
+        let (batch, batch_values) = self
+            .0
+            .entry(*uid)
+            .or_insert_with(|| (Batch::default(), vec![]));
+        batch.append_statement(statement.clone());
+        batch_values.push(values.serialized()?.into_owned());
+        Ok(())
+    }
+}
+
+fn to_utc(since_epoch: Duration) -> DateTime<Utc> {
+    Utc.timestamp_millis(since_epoch.num_milliseconds())
+}
+
 pub type BoardSnapshot = (UserRatingSnapshot, UserRatingSnapshot);
 pub type TableSnapshot = (BoardSnapshot, BoardSnapshot);
 
@@ -60,6 +169,57 @@ pub struct Db {
     ctx: Context,
 }
 
+#[derive(Clone, Debug, FromRow)]
+pub struct HandleChange {
+    pub changed_at: Duration,
+    pub handle: String,
+    pub previous: Option<String>,
+}
+
+// One user's copy of a tell
+#[derive(Clone, Debug, FromRow)]
+pub struct MessageRow {
+    pub id: TellID,
+    pub peer: UserID,
+    // Whether the user sent it (to `peer`) or received it
+    pub sent: bool,
+    pub body: String,
+    pub sent_at: Duration,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct SignalRow {
+    pub ms: i32,
+    pub sender: UserID,
+    pub kind: String,
+    pub payload: String,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct KibitzRow {
+    pub id: Uuid,
+    pub ms: i32,
+    pub sender: UserID,
+    pub whisper: bool,
+    pub body: String,
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct ChannelMessageRow {
+    pub id: Uuid,
+    pub sender: UserID,
+    pub body: String,
+    pub sent_at: Duration,
+}
+
+// A channel's members, muted and banned users, or None if it doesn't exist
+pub struct ChannelState {
+    pub owner: Option<UserID>,
+    pub members: HashSet<UserID>,
+    pub muted: HashSet<UserID>,
+    pub banned: HashSet<UserID>,
+}
+
 #[derive(Debug, FromRow)]
 pub struct FirebaseRowData {
     fid: String,
@@ -70,31 +230,52 @@ pub struct FirebaseRowData {
 }
 
 impl FirebaseRowData {
+    pub fn new(
+        fid: &str,
+        display_name: Option<String>,
+        email: Option<String>,
+        photo_url: Option<String>,
+        provider_id: Option<String>,
+    ) -> Self {
+        FirebaseRowData {
+            fid: fid.to_string(),
+            display_name,
+            email,
+            photo_url,
+            provider_id,
+        }
+    }
+
     pub fn is_guest(&self) -> bool {
         self.provider_id == Some("anonymous".to_string())
     }
-}
 
-impl From<Option<Arc<RwLock<User>>>> for UserRatingSnapshot {
-    fn from(maybe_user: Option<Arc<RwLock<User>>>) -> Self {
-        match maybe_user {
-            None => UserRatingSnapshot {
-                uid: Uuid::nil(),
-                rating: 0,
-            },
-            Some(user_lock) => UserRatingSnapshot::from(user_lock),
+    pub fn to_profile(&self) -> Profile {
+        Profile {
+            display_name: self.display_name.clone(),
+            email: self.email.clone(),
+            photo_url: self.photo_url.clone(),
+            guest: self.is_guest(),
         }
     }
 }
 
-impl From<Arc<RwLock<User>>> for UserRatingSnapshot {
-    fn from(user_lock: Arc<RwLock<User>>) -> Self {
-        let user = user_lock.read().unwrap();
-        UserRatingSnapshot {
-            uid: *user.get_uid(),
-            rating: user.rating,
-        }
-    }
+fn snapshot_uids(players: &TableSnapshot) -> [UserID; 4] {
+    let ((aw, ab), (bw, bb)) = players;
+    [aw.uid, ab.uid, bw.uid, bb.uid]
+}
+
+fn replace_uid(
+    players: &TableSnapshot,
+    from: &UserID,
+    to: &UserID,
+) -> TableSnapshot {
+    let swap = |snap: &UserRatingSnapshot| UserRatingSnapshot {
+        uid: if snap.uid == *from { *to } else { snap.uid },
+        rating: snap.rating,
+    };
+    let ((aw, ab), (bw, bb)) = players;
+    ((swap(aw), swap(ab)), (swap(bw), swap(bb)))
 }
 
 impl Db {
@@ -125,66 +306,196 @@ impl Db {
     //
     async fn new_player_handle(
         &self,
-        firebase_data: &FirebaseRowData,
-    ) -> Result<(Uuid, String), Error> {
-        let uuid = self.now()?;
-        println!("Inserting into firebase_user...");
-        self.session
-            .query(
-                "INSERT INTO bughouse.firebase_users
-               (firebase_id, uid)
-               VALUES (?, ?) IF NOT EXISTS",
-                (&firebase_data.fid, uuid),
-            )
-            .await?;
-        let handle = if firebase_data.is_guest() {
+        uuid: Uuid,
+        is_guest: bool,
+    ) -> Result<String, Error> {
+        let handle = if is_guest {
             GuestHandle::generate(&uuid)
         } else {
             format!("Player_{}", B66::encode_num(uuid.as_fields().0 as u128))
         };
         self.insert_handle(&handle, &uuid).await?;
-        Ok((uuid, handle))
+        Ok(handle)
     }
 
+    // Ok(false) if someone else has the handle
     async fn insert_handle(
         &self,
         handle: &str,
         uid: &UserID,
-    ) -> Result<(), Error> {
+    ) -> Result<bool, Error> {
         let mut query = Query::new(
             "INSERT INTO bughouse.handles (handle, uid) VALUES (?, ?) IF NOT EXISTS".to_string()
             );
         query.set_consistency(Consistency::One);
         query.set_serial_consistency(Some(SerialConsistency::Serial));
-        self.session.query(query, (handle, uid)).await?;
-        Ok(())
+        let res = self.session.query(query, (handle, uid)).await?;
+        if Self::lwt_applied(&res) {
+            return Ok(true);
+        }
+        // [applied], handle, uid
+        let owner = res
+            .rows
+            .and_then(|rows| rows.into_iter().next())
+            .and_then(|row| row.columns.get(2).cloned().flatten())
+            .and_then(|val| val.as_uuid());
+        Ok(owner.as_ref() == Some(uid))
     }
 
+    // Renamed users keep their old handle for the policy's quarantine period
+    // so nobody can "steal" it right away.  After that, it's released to
+    // whoever claims it next (lazily - see release_handle).
     async fn update_handle(
         &self,
         handle: &str,
+        old_handle: &str,
         uid: &UserID,
+        policy: &HandlePolicy,
     ) -> Result<(), Error> {
-        // Delete old handles periodically.
-        // Don't let people "steal" recently abandoned handles
-        self.insert_handle(handle, uid).await?;
+        if !self.insert_handle(handle, uid).await? {
+            if !self.release_handle(handle, policy).await?
+                || !self.insert_handle(handle, uid).await?
+            {
+                return Err(Error::HandleTaken(handle.to_string()));
+            }
+        }
+        let now = Self::to_timestamp(Utc::now());
+        let mut batch: Batch = Default::default();
+        batch.append_statement(
+            "UPDATE bughouse.users SET handle = ? WHERE id = ?",
+        );
+        batch.append_statement(
+            "DELETE FROM bughouse.handle_releases WHERE handle = ?",
+        );
+        batch.append_statement(
+            "INSERT INTO bughouse.handle_releases (handle, uid, released_at)
+             VALUES (?, ?, ?)",
+        );
+        batch.append_statement(
+            "INSERT INTO bughouse.handle_history
+             (uid, changed_at, handle, previous) VALUES (?, ?, ?, ?)",
+        );
         self.session
-            .query(
-                "UPDATE bughouse.users SET handle = ? WHERE id = ?",
-                (handle, uid),
+            .batch(
+                &batch,
+                (
+                    (handle, uid),
+                    (handle,),
+                    (old_handle, uid, now),
+                    (uid, now, handle, old_handle),
+                ),
             )
             .await?;
         Ok(())
     }
 
+    // Frees `handle` if its owner abandoned it long enough ago
+    async fn release_handle(
+        &self,
+        handle: &str,
+        policy: &HandlePolicy,
+    ) -> Result<bool, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT uid, released_at FROM bughouse.handle_releases
+                 WHERE handle = ?",
+                (handle,),
+            )
+            .await?;
+        let mut release = None;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(UserID, Duration)>() {
+                release = Some(row?);
+            }
+        }
+        let (owner, released_at) = match release {
+            Some(release) => release,
+            None => return Ok(false),
+        };
+        if !policy.quarantine_over(released_at, Self::to_duration(Utc::now())) {
+            return Ok(false);
+        }
+        let res = self
+            .session
+            .query(
+                "DELETE FROM bughouse.handles WHERE handle = ? IF uid = ?",
+                (handle, owner),
+            )
+            .await?;
+        self.session
+            .query(
+                "DELETE FROM bughouse.handle_releases WHERE handle = ?",
+                (handle,),
+            )
+            .await?;
+        println!("Released handle {} from {}", handle, owner);
+        Ok(Self::lwt_applied(&res))
+    }
+
+    pub async fn get_handle_history(
+        &self,
+        uid: &UserID,
+    ) -> Result<Vec<HandleChange>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT changed_at, handle, previous FROM bughouse.handle_history
+                 WHERE uid = ?",
+                (uid,),
+            )
+            .await?;
+        let mut history = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<HandleChange>() {
+                history.push(row?);
+            }
+        }
+        Ok(history)
+    }
+
+    async fn last_handle_change(
+        &self,
+        uid: &UserID,
+    ) -> Result<Option<Duration>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT changed_at FROM bughouse.handle_history
+                 WHERE uid = ? LIMIT 1",
+                (uid,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(Duration,)>() {
+                return Ok(Some(row?.0));
+            }
+        }
+        Ok(None)
+    }
+
+    // Callers validate `handle` against the policy first.
     pub async fn set_handle(
         &self,
         handle: &str,
         user: Arc<RwLock<User>>,
+        policy: &HandlePolicy,
     ) -> Result<(), Error> {
         println!("db.set_handle");
-        let uid = user.read().unwrap().id;
-        let res = self.update_handle(handle, &uid).await;
+        let (uid, old_handle) = {
+            let ruser = user.read().unwrap();
+            (ruser.id, ruser.handle.clone())
+        };
+        if handle == old_handle {
+            return Ok(());
+        }
+        if let Some(last_change) = self.last_handle_change(&uid).await? {
+            let now = Self::to_duration(Utc::now());
+            if let Some(left) = policy.cooldown_remaining(last_change, now) {
+                return Err(Error::HandleCooldown(left.num_hours() + 1));
+            }
+        }
+        let res = self.update_handle(handle, &old_handle, &uid, policy).await;
         if let Err(e) = res {
             eprintln!("err: {:?}", e);
             return Err(e);
@@ -215,29 +526,53 @@ impl Db {
         ScyllaTimestamp(Self::to_duration(time))
     }
 
-    pub async fn add_rating(
+    // Current rating in each category they've played a rated game in
+    pub async fn get_ratings(
         &self,
-        id: UserID,
-        rating: &Rating,
-    ) -> Result<(), Error> {
+        uid: &UserID,
+    ) -> Result<HashMap<RatingCategory, UserRating>, Error> {
         let res = self
             .session
             .query(
-                "INSERT INTO bughouse.rating_history
-                (uid, time, rating, deviation) VALUES (?, ?, ?, ?)",
-                (
-                    id,
-                    Self::to_timestamp(Utc::now()),
-                    rating.rating,
-                    rating.deviation,
-                ),
+                "SELECT uid, category, rating, deviation, volatility, games,
+                 last_rated FROM bughouse.ratings WHERE uid = ?",
+                (uid,),
             )
-            .await;
-        if res.is_err() {
-            eprintln!("Couldn't insert rating: {:?}", res);
+            .await?;
+        let mut ratings = HashMap::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<RatingRow>() {
+                if let Some((category, rating)) = Self::to_user_rating(row?) {
+                    ratings.insert(category, rating);
+                }
+            }
         }
-        res?;
-        Ok(())
+        Ok(ratings)
+    }
+
+    fn to_user_rating(row: RatingRow) -> Option<(RatingCategory, UserRating)> {
+        let (uid, category, rating, deviation, volatility, games, last) = row;
+        let category = match category.parse::<RatingCategory>() {
+            Ok(category) => category,
+            Err(e) => {
+                eprintln!("ratings for {}: {}", uid, e);
+                return None;
+            }
+        };
+        Some((
+            category,
+            UserRating {
+                volatility: volatility.unwrap_or(INIT_VOLATILITY),
+                games: games.unwrap_or(0),
+                last_rated: last.map(to_utc),
+                ..UserRating::new(uid, Rating::new(rating, deviation))
+            },
+        ))
+    }
+
+    async fn user_from_row(&self, row: UserRow) -> Result<User, Error> {
+        let ratings = self.get_ratings(&row.id).await?;
+        Ok(User::new(row, ratings))
     }
 
     pub async fn get_game_row(
@@ -269,7 +604,7 @@ impl Db {
         let res = self
             .session
             .query(
-                "SELECT id, firebase_id, deviation, email, guest, handle, name, photo_url, rating, role
+                "SELECT id, firebase_id, email, guest, handle, name, photo_url, role
             FROM bughouse.users
             WHERE id = ?",
                 (uid,),
@@ -277,127 +612,643 @@ impl Db {
             .await
             .ok()?;
         if let Some(rows) = res.rows {
-            for row in rows.into_typed::<User>() {
+            for row in rows.into_typed::<UserRow>() {
                 // return Some(row?);
-                return row.ok();
+                return self.user_from_row(row.ok()?).await.ok();
             }
         }
         eprintln!("DB.get_user failed: {}", uid);
         None
     }
 
-    pub async fn mk_user_for_fid(&self, fid: &str) -> Result<User, Error> {
-        println!("mk_user_for_fid {}", fid);
-        let firebase_data = Self::fetch_firebase_data(fid)?;
-        println!("firebase_data:\n{:?}", firebase_data);
-        let (id, handle) = self.new_player_handle(&firebase_data).await?;
-        let rating = Rating::default();
-        let role = User::get_default_role(firebase_data.is_guest()) as i8;
+    // [applied] is always the first column of an LWT result
+    fn lwt_applied(res: &QueryResult) -> bool {
+        let applied = res
+            .rows
+            .as_ref()
+            .and_then(|rows| rows.first())
+            .and_then(|row| row.columns.first().cloned().flatten())
+            .and_then(|val| val.as_boolean());
+        applied.unwrap_or(false)
+    }
+
+    pub async fn get_identity_uid(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<UserID>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT uid FROM bughouse.identities
+                 WHERE provider = ? AND subject = ?",
+                (provider, subject),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(UserID,)>() {
+                return Ok(Some(row?.0));
+            }
+        }
+        Ok(None)
+    }
+
+    // Identities can only ever belong to one user
+    pub async fn link_identity(
+        &self,
+        uid: UserID,
+        identity: &Identity,
+    ) -> Result<(), Error> {
+        let res = self
+            .session
+            .query(
+                "INSERT INTO bughouse.identities (provider, subject, uid)
+                 VALUES (?, ?, ?) IF NOT EXISTS",
+                (&identity.provider, &identity.subject, uid),
+            )
+            .await?;
+        if !Self::lwt_applied(&res) {
+            let owner = self
+                .get_identity_uid(&identity.provider, &identity.subject)
+                .await?;
+            if owner != Some(uid) {
+                return Err(Error::IdentityLinked(
+                    identity.provider.clone(),
+                    identity.subject.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn user_from_identity(
+        &self,
+        identity: &Identity,
+    ) -> Result<User, Error> {
+        let uid = self
+            .get_identity_uid(&identity.provider, &identity.subject)
+            .await?;
+        if let Some(uid) = uid {
+            return self.get_user(&uid).await.ok_or(Error::UnknownUID(uid));
+        }
+        // Firebase users created before the identities table
+        if identity.provider == auth::FIREBASE {
+            if let Ok(user) = self.get_user_from_fid(&identity.subject).await {
+                self.link_identity(user.id, identity).await?;
+                return Ok(user);
+            }
+        }
+        self.mk_user_for_identity(identity).await
+    }
+
+    async fn mk_user_for_identity(
+        &self,
+        identity: &Identity,
+    ) -> Result<User, Error> {
+        println!(
+            "mk_user_for_identity {}:{}",
+            identity.provider, identity.subject
+        );
+        let is_firebase = identity.provider == auth::FIREBASE;
+        let profile = match &identity.profile {
+            Some(profile) => profile.clone(),
+            None if is_firebase => {
+                Self::fetch_firebase_data(&identity.subject)?.to_profile()
+            }
+            None => Profile::default(),
+        };
+        println!("profile:\n{:?}", profile);
+        let id = self.now()?;
+        // Claim the identity first so concurrent sign-ins can't create two
+        // users for it.  Whoever loses the race signs in as the winner.
+        match self.link_identity(id, identity).await {
+            Err(Error::IdentityLinked(..)) => {
+                return self.identity_owner(identity).await;
+            }
+            res => res?,
+        }
+        let res = self.insert_identity_user(id, identity, profile).await;
+        if res.is_err() {
+            // Don't leave the identity pointing at a user that doesn't exist
+            self.unlink_identity(&id, identity).await?;
+        }
+        res
+    }
+
+    // The user an identity someone else just linked belongs to.  They may
+    // not have written the user yet, so this waits a little for it.
+    async fn identity_owner(&self, identity: &Identity) -> Result<User, Error> {
+        let owner = self
+            .get_identity_uid(&identity.provider, &identity.subject)
+            .await?
+            .ok_or_else(|| {
+                Error::IdentityLinked(
+                    identity.provider.clone(),
+                    identity.subject.clone(),
+                )
+            })?;
+        for _ in 0..IDENTITY_OWNER_TRIES {
+            if let Some(user) = self.get_user(&owner).await {
+                return Ok(user);
+            }
+            tokio::time::sleep(IDENTITY_OWNER_WAIT).await;
+        }
+        Err(Error::UnknownUID(owner))
+    }
+
+    async fn unlink_identity(
+        &self,
+        uid: &UserID,
+        identity: &Identity,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "DELETE FROM bughouse.identities
+                 WHERE provider = ? AND subject = ? IF uid = ?",
+                (&identity.provider, &identity.subject, uid),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_identity_user(
+        &self,
+        id: UserID,
+        identity: &Identity,
+        profile: Profile,
+    ) -> Result<User, Error> {
+        let is_firebase = identity.provider == auth::FIREBASE;
+        let handle = self.new_player_handle(id, profile.guest).await?;
+        let firebase_id = if is_firebase {
+            Some(identity.subject.clone())
+        } else {
+            None
+        };
+        let role = User::get_default_role(profile.guest) as i8;
         println!("Inserting into users...");
-        let res = self.session
+        let res = self
+            .session
             .query(
-              "INSERT INTO bughouse.users
-               (id, firebase_id, deviation, email, guest, handle, name, rating, role)
-               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                "INSERT INTO bughouse.users
+               (id, firebase_id, email, guest, handle, name, photo_url, role)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
                 (
                     id,
-                    &firebase_data.fid,
-                    rating.deviation,
-                    &firebase_data.email,
-                    firebase_data.is_guest(),
+                    &firebase_id,
+                    &profile.email,
+                    profile.guest,
                     &handle,
-                    &firebase_data.display_name,
-                    rating.rating,
+                    &profile.display_name,
+                    &profile.photo_url,
                     role,
                 ),
             )
             .await;
         if let Err(e) = res {
             println!("Insertion err: {:?}", e);
+            self.session
+                .query(
+                    "DELETE FROM bughouse.handles WHERE handle = ? IF uid = ?",
+                    (&handle, id),
+                )
+                .await?;
             return Err(e.into());
         }
         println!("Inserted: {}", id);
-        self.add_rating(id, &rating).await?;
-        let is_guest = firebase_data.is_guest();
         Ok(User {
             id,
-            firebase_id: firebase_data.fid,
+            firebase_id,
             handle,
-            deviation: rating.deviation,
-            email: firebase_data.email,
-            guest: is_guest,
-            name: firebase_data.display_name,
-            photo_url: firebase_data.photo_url,
-            rating: rating.rating,
+            email: profile.email,
+            guest: profile.guest,
+            name: profile.display_name,
+            photo_url: profile.photo_url,
             role,
+            ratings: HashMap::new(),
         })
     }
 
-    pub fn fetch_firebase_data(fid: &str) -> Result<FirebaseRowData, Error> {
-        println!("Fetch firebase data...");
-        let mut stream = UnixStream::connect(UNIX_SOCK.to_string())?;
-        write!(stream, "{}\n{}\n", FIRE_USER, fid)?;
-        let mut resp = String::new();
-        stream.read_to_string(&mut resp)?;
-        println!("Response: {}", resp);
-        let (kind, payload) = resp.trim_end().split_once(':').unwrap();
-        match kind {
-            "user" => {
-                let parts: Vec<&str> = payload.split('\x1e').collect();
-                if let [name, email, photo_url, provider_id] = parts[..] {
-                    println!(
-                        "name: {}\temail: {}, photo: {}, provider: {}",
-                        name, email, photo_url, provider_id
-                    );
-                    Ok(FirebaseRowData {
-                        fid: fid.to_string(),
-                        display_name: name.to_string().none_if_empty(),
-                        email: email.to_string().none_if_empty(),
-                        photo_url: photo_url.to_string().none_if_empty(),
-                        provider_id: provider_id.to_string().none_if_empty(),
-                    })
-                } else {
-                    let err = format!("Couldn't parse: {}", payload);
-                    Err(Error::FirebaseError(err))
-                }
-            }
-            "err" => {
-                eprintln!("err: {}", payload);
-                Err(Error::FirebaseError(payload.to_string()))
+    // Attach `identity` to guest `uid`.  If the identity already belongs to
+    // a registered user, the guest's games move to that user instead.
+    // Returns the uid the player should be signed in as.
+    pub async fn upgrade_guest(
+        &self,
+        uid: UserID,
+        identity: &Identity,
+    ) -> Result<UserID, Error> {
+        let owner = self
+            .get_identity_uid(&identity.provider, &identity.subject)
+            .await?;
+        match owner {
+            Some(owner) if owner != uid => {
+                self.migrate_user_games(&uid, &owner).await?;
+                self.delete_guest(&uid).await?;
+                return Ok(owner);
             }
-            _ => Err(Error::FirebaseError(format!(
-                "UnknownKind: {}",
-                payload.to_string()
-            ))),
+            Some(_) => {}
+            None => self.link_identity(uid, identity).await?,
         }
-    }
-
-    pub async fn get_user_from_fid(&self, fid: &str) -> Result<User, Error> {
-        let query_str = format!(
-            "SELECT id, firebase_id, deviation, email, guest, handle, name, photo_url, rating, role
-             FROM bughouse.users WHERE firebase_id = '{}'",
-            fid
-        );
-        let res = self.session.query(query_str, &[]).await?;
-        if let Some(rows) = res.rows {
-            for row in rows.into_typed::<User>() {
-                return Ok(row?);
+        let profile = match &identity.profile {
+            Some(profile) => profile.clone(),
+            None if identity.provider == auth::FIREBASE => {
+                Self::fetch_firebase_data(&identity.subject)?.to_profile()
             }
+            None => Profile::default(),
+        };
+        let firebase_id = if identity.provider == auth::FIREBASE {
+            Some(identity.subject.clone())
+        } else {
+            None
+        };
+        self.session
+            .query(
+                "UPDATE bughouse.users
+                 SET guest = false, role = ?, email = ?, name = ?,
+                     photo_url = ?, firebase_id = ?
+                 WHERE id = ?",
+                (
+                    User::get_default_role(false) as i8,
+                    &profile.email,
+                    &profile.display_name,
+                    &profile.photo_url,
+                    &firebase_id,
+                    uid,
+                ),
+            )
+            .await?;
+        Ok(uid)
+    }
+
+    // Removes a guest whose games moved to a registered user, freeing its
+    // handle right away since nobody else was ever known by it
+    async fn delete_guest(&self, uid: &UserID) -> Result<(), Error> {
+        self.revoke_sessions(uid).await?;
+        if let Some(guest) = self.get_user(uid).await {
+            self.session
+                .query(
+                    "DELETE FROM bughouse.handles WHERE handle = ? IF uid = ?",
+                    (&guest.handle, uid),
+                )
+                .await?;
         }
+        self.session
+            .query("DELETE FROM bughouse.users WHERE id = ?", (uid,))
+            .await?;
+        Ok(())
+    }
 
-        Err(Error::UnknownFirebaseID(fid.to_string()))
+    // Re-home `from`'s games to `to`, rewriting the snapshots of every copy
+    // of the game so they point at `to`.  Only used for guests, whose games
+    // are never rated, so there's no rating history to move.
+    pub async fn migrate_user_games(
+        &self,
+        from: &UserID,
+        to: &UserID,
+    ) -> Result<usize, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT uid, start_time, game_id, result, rated, players
+                 FROM bughouse.user_games WHERE uid = ?",
+                (from,),
+            )
+            .await?;
+        let mut rows = Vec::new();
+        if let Some(qrows) = res.rows {
+            for row in qrows.into_typed::<UserGameRow>() {
+                rows.push(row?);
+            }
+        }
+        let insert: PreparedStatement = self
+            .session
+            .prepare(
+                "INSERT INTO bughouse.user_games
+            (uid, start_time, game_id, result, rated, players) VALUES
+            (?,   ?,          ?,       ?,      ?,     ?)",
+            )
+            .await?;
+        let update: PreparedStatement = self
+            .session
+            .prepare(
+                "UPDATE bughouse.user_games SET players = ?
+                 WHERE uid = ? AND start_time = ? AND game_id = ?",
+            )
+            .await?;
+        for row in rows.iter() {
+            let players = replace_uid(&row.players, from, to);
+            self.session
+                .query(
+                    "UPDATE bughouse.games SET players = ? WHERE id = ?",
+                    (&players, row.game_id),
+                )
+                .await?;
+            let moved = UserGameRow {
+                uid: *to,
+                players,
+                ..row.clone()
+            };
+            self.session.execute(&insert, moved.into_row()).await?;
+            for uid in snapshot_uids(&players) {
+                if uid == *to || uid.is_nil() {
+                    continue;
+                }
+                self.session
+                    .execute(
+                        &update,
+                        (
+                            &players,
+                            uid,
+                            ScyllaTimestamp(row.start_time),
+                            row.game_id,
+                        ),
+                    )
+                    .await?;
+            }
+        }
+        self.session
+            .query("DELETE FROM bughouse.user_games WHERE uid = ?", (from,))
+            .await?;
+        println!("Migrated {} games: {} => {}", rows.len(), from, to);
+        Ok(rows.len())
+    }
+
+    // Sessions for `uid` created before now are no longer valid
+    pub async fn revoke_sessions(&self, uid: &UserID) -> Result<(), Error> {
+        self.session
+            .query(
+                "INSERT INTO bughouse.session_revocations (uid, revoked_at)
+                 VALUES (?, ?)",
+                (uid, Self::to_timestamp(Utc::now())),
+            )
+            .await?;
+        Ok(())
     }
 
-    pub async fn user_from_firebase_id(
+    pub async fn update_profile(
         &self,
-        fid: &str,
-    ) -> Result<User, Error> {
-        let result = self.get_user_from_fid(fid).await;
-        if let Err(Error::UnknownFirebaseID(_)) = result {
-            Ok(self.mk_user_for_fid(fid).await?)
-        } else {
-            return result;
+        uid: &UserID,
+        name: Option<&str>,
+        photo_url: Option<&str>,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "UPDATE bughouse.users SET name = ?, photo_url = ? WHERE id = ?",
+                (name, photo_url, uid),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_preferences(
+        &self,
+        uid: &UserID,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT preferences FROM bughouse.users WHERE id = ?",
+                (uid,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(Option<BTreeMap<String, String>>,)>()
+            {
+                return Ok(row?.0.unwrap_or_default());
+            }
         }
+        Ok(BTreeMap::new())
+    }
+
+    // Sets `set` and removes `unset`, leaving other preferences alone
+    pub async fn update_preferences(
+        &self,
+        uid: &UserID,
+        set: HashMap<String, String>,
+        unset: Vec<String>,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "UPDATE bughouse.users
+                 SET preferences = preferences + ?, preferences = preferences - ?
+                 WHERE id = ?",
+                (set, unset, uid),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Deleting an account keeps its games, and the users row so they still
+    // show who played, but signs it out everywhere, removes its ratings and
+    // every way to sign in as it, scrubs its profile and releases its
+    // handles (after the usual quarantine).
+    pub async fn delete_user(
+        &self,
+        uid: &UserID,
+        deleted_handle: &str,
+    ) -> Result<(), Error> {
+        self.revoke_sessions(uid).await?;
+        let res = self
+            .session
+            .query(
+                "SELECT provider, subject FROM bughouse.identities
+                 WHERE uid = ?",
+                (uid,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(String, String)>() {
+                let (provider, subject) = row?;
+                self.session
+                    .query(
+                        "DELETE FROM bughouse.identities
+                         WHERE provider = ? AND subject = ?",
+                        (&provider, &subject),
+                    )
+                    .await?;
+                if provider == auth::LOCAL {
+                    self.session
+                        .query(
+                            "DELETE FROM bughouse.local_accounts
+                             WHERE username = ?",
+                            (&subject,),
+                        )
+                        .await?;
+                }
+                if provider == auth::FIREBASE {
+                    self.session
+                        .query(
+                            "DELETE FROM bughouse.firebase_users
+                             WHERE firebase_id = ?",
+                            (&subject,),
+                        )
+                        .await?;
+                }
+            }
+        }
+
+        let res = self
+            .session
+            .query("SELECT handle FROM bughouse.handles WHERE uid = ?", (uid,))
+            .await?;
+        let now = Self::to_timestamp(Utc::now());
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(String,)>() {
+                let (handle,) = row?;
+                self.session
+                    .query(
+                        "INSERT INTO bughouse.handle_releases
+                         (handle, uid, released_at) VALUES (?, ?, ?)",
+                        (&handle, uid, now),
+                    )
+                    .await?;
+            }
+        }
+
+        for channel in self.get_user_channels(uid).await?.iter() {
+            self.session
+                .query(
+                    "DELETE FROM bughouse.channel_members
+                     WHERE channel = ? AND uid = ?",
+                    (channel, uid),
+                )
+                .await?;
+        }
+        let tables = [
+            "user_channels",
+            "ratings",
+            "category_rating_history",
+            "partnerships",
+            "messages",
+            "pending_messages",
+        ];
+        for table in tables.iter() {
+            let query = format!("DELETE FROM bughouse.{} WHERE uid = ?", table);
+            self.session.query(query, (uid,)).await?;
+        }
+        self.session
+            .query(
+                "UPDATE bughouse.users
+                 SET handle = ?, firebase_id = null, email = null, name = null,
+                 photo = null, photo_url = null, preferences = null
+                 WHERE id = ?",
+                (deleted_handle, uid),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn sessions_revoked_at(
+        &self,
+        uid: &UserID,
+    ) -> Result<Option<Duration>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT revoked_at FROM bughouse.session_revocations
+                 WHERE uid = ?",
+                (uid,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(Duration,)>() {
+                return Ok(Some(row?.0));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn get_local_password_hash(
+        &self,
+        username: &str,
+    ) -> Result<Option<String>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT password_hash FROM bughouse.local_accounts
+                 WHERE username = ?",
+                (username,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(String,)>() {
+                return Ok(Some(row?.0));
+            }
+        }
+        Ok(None)
+    }
+
+    // false if the username is already taken
+    pub async fn insert_local_account(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<bool, Error> {
+        let res = self
+            .session
+            .query(
+                "INSERT INTO bughouse.local_accounts (username, password_hash)
+                 VALUES (?, ?) IF NOT EXISTS",
+                (username, password_hash),
+            )
+            .await?;
+        Ok(Self::lwt_applied(&res))
+    }
+
+    pub fn fetch_firebase_data(fid: &str) -> Result<FirebaseRowData, Error> {
+        println!("Fetch firebase data...");
+        let mut stream = UnixStream::connect(UNIX_SOCK.to_string())?;
+        write!(stream, "{}\n{}\n", FIRE_USER, fid)?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp)?;
+        println!("Response: {}", resp);
+        let (kind, payload) = resp.trim_end().split_once(':').unwrap();
+        match kind {
+            "user" => {
+                let parts: Vec<&str> = payload.split('\x1e').collect();
+                if let [name, email, photo_url, provider_id] = parts[..] {
+                    println!(
+                        "name: {}\temail: {}, photo: {}, provider: {}",
+                        name, email, photo_url, provider_id
+                    );
+                    Ok(FirebaseRowData {
+                        fid: fid.to_string(),
+                        display_name: name.to_string().none_if_empty(),
+                        email: email.to_string().none_if_empty(),
+                        photo_url: photo_url.to_string().none_if_empty(),
+                        provider_id: provider_id.to_string().none_if_empty(),
+                    })
+                } else {
+                    let err = format!("Couldn't parse: {}", payload);
+                    Err(Error::FirebaseError(err))
+                }
+            }
+            "err" => {
+                eprintln!("err: {}", payload);
+                Err(Error::FirebaseError(payload.to_string()))
+            }
+            _ => Err(Error::FirebaseError(format!(
+                "UnknownKind: {}",
+                payload.to_string()
+            ))),
+        }
+    }
+
+    pub async fn get_user_from_fid(&self, fid: &str) -> Result<User, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT id, firebase_id, email, guest, handle, name, photo_url, role
+                 FROM bughouse.users WHERE firebase_id = ?",
+                (fid,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<UserRow>() {
+                return self.user_from_row(row?).await;
+            }
+        }
+
+        Err(Error::UnknownFirebaseID(fid.to_string()))
     }
 
     // async fn get_user_rating_snapshot(
@@ -520,51 +1371,404 @@ impl Db {
         Ok(())
     }
 
+    // Fills in what the in-memory users don't track: volatility, number of
+    // games and when they last played a rated game in `category`.
+    pub async fn load_rating_states(
+        &self,
+        category: RatingCategory,
+        ratings: &mut [UserRating; 4],
+    ) -> Result<(), Error> {
+        for rating in ratings.iter_mut() {
+            let res = self
+                .session
+                .query(
+                    "SELECT volatility, games, last_rated FROM bughouse.ratings
+                     WHERE uid = ? AND category = ?",
+                    (rating.uid, category.as_str()),
+                )
+                .await?;
+            if let Some(rows) = res.rows {
+                for row in rows.into_typed::<RatingState>() {
+                    let (volatility, games, last_rated) = row?;
+                    rating.volatility = volatility.unwrap_or(INIT_VOLATILITY);
+                    rating.games = games.unwrap_or(0);
+                    rating.last_rated = last_rated.map(to_utc);
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn record_ratings(
         &self,
+        category: RatingCategory,
         ratings: &[UserRating; 4],
     ) -> Result<(), Error> {
-        println!("new ratings: {:?}", ratings);
+        println!("new {} ratings: {:?}", category, ratings);
         let now = Self::to_timestamp(Utc::now());
-        let mut rows: [(UserID, ScyllaTimestamp, i16, i16); 4] =
-            [(UserID::nil(), now, 0, 0); 4];
+        let cat = category.as_str();
+        let mut rows: [(UserID, &str, ScyllaTimestamp, i16, i16, f64); 4] =
+            [(UserID::nil(), cat, now, 0, 0, 0f64); 4];
         for (i, user_rating) in ratings.iter().enumerate() {
             rows[i] = (
                 user_rating.uid,
-                now,
+                cat,
+                user_rating.last_rated.map_or(now, Self::to_timestamp),
                 user_rating.rating.rating,
                 user_rating.rating.deviation,
+                user_rating.volatility,
             );
         }
         // Add a prepared query to the batch
         let mut batch: Batch = Default::default();
         let prepared: PreparedStatement = self.session.prepare(
-            "INSERT INTO bughouse.rating_history (uid, time, rating, deviation) VALUES (?, ?, ?, ?)"
+            "INSERT INTO bughouse.category_rating_history (uid, category, time, rating, deviation, volatility) VALUES (?, ?, ?, ?, ?, ?)"
             ).await?;
         for _i in 0..4 {
             batch.append_statement(prepared.clone());
         }
         let res = self.session.batch(&batch, &rows[0..4]).await;
         if let Err(e) = res {
-            eprintln!("batch error rating_history: {:?}", e);
+            eprintln!("batch error category_rating_history: {:?}", e);
         }
 
         let mut batch2: Batch = Default::default();
         let prepared2: PreparedStatement = self.session.prepare(
-            "UPDATE bughouse.users SET rating = ?, deviation = ? WHERE id = ?"
+            "UPDATE bughouse.ratings SET rating = ?, deviation = ?, volatility = ?, games = ?, last_rated = ? WHERE uid = ? AND category = ?"
             ).await?;
         for _i in 0..4 {
             batch2.append_statement(prepared2.clone());
         }
-        let rating_rows: [(i16, i16, UserID); 4] = [
-            ratings[0].to_row(),
-            ratings[1].to_row(),
-            ratings[2].to_row(),
-            ratings[3].to_row(),
-        ];
-        let res2 = self.session.batch(&batch2, &rating_rows[0..4]).await;
-        if let Err(e) = res2 {
-            eprintln!("batch error users: {:?}", e);
+        let mut rating_rows: [(
+            i16,
+            i16,
+            f64,
+            i32,
+            ScyllaTimestamp,
+            UserID,
+            &str,
+        ); 4] = [(0, 0, 0f64, 0, now, UserID::nil(), cat); 4];
+        for (i, user_rating) in ratings.iter().enumerate() {
+            let (rating, deviation, uid) = user_rating.to_row();
+            let last_rated = user_rating
+                .last_rated
+                .map_or(now, |t| Self::to_timestamp(t));
+            rating_rows[i] = (
+                rating,
+                deviation,
+                user_rating.volatility,
+                user_rating.games,
+                last_rated,
+                uid,
+                cat,
+            );
+        }
+        let res2 = self.session.batch(&batch2, &rating_rows[0..4]).await;
+        if let Err(e) = res2 {
+            eprintln!("batch error ratings: {:?}", e);
+        }
+        Ok(())
+    }
+
+    // `uid`'s partnerships, in every category unless `category` is given
+    pub async fn get_partnerships(
+        &self,
+        uid: &UserID,
+        category: Option<RatingCategory>,
+    ) -> Result<Vec<(RatingCategory, PartnershipRating)>, Error> {
+        let select = "SELECT partner, category, rating, deviation, volatility,
+                      games, wins, last_rated
+                      FROM bughouse.partnerships WHERE uid = ?";
+        let res = match category {
+            Some(category) => {
+                let query = format!("{} AND category = ?", select);
+                self.session.query(query, (uid, category.as_str())).await?
+            }
+            None => self.session.query(select, (uid,)).await?,
+        };
+        let mut partnerships = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<PartnershipRow>() {
+                if let Some(partnership) = Self::to_partnership(uid, row?) {
+                    partnerships.push(partnership);
+                }
+            }
+        }
+        Ok(partnerships)
+    }
+
+    fn to_partnership(
+        uid: &UserID,
+        row: PartnershipRow,
+    ) -> Option<(RatingCategory, PartnershipRating)> {
+        let (
+            partner,
+            category,
+            rating,
+            deviation,
+            volatility,
+            games,
+            wins,
+            last,
+        ) = row;
+        let category = category.parse::<RatingCategory>().ok()?;
+        Some((
+            category,
+            PartnershipRating {
+                rating: Rating::new(rating, deviation),
+                volatility: volatility.unwrap_or(INIT_VOLATILITY),
+                games: games.unwrap_or(0),
+                wins: wins.unwrap_or(0),
+                last_rated: last.map(to_utc),
+                ..PartnershipRating::new(Partnership::new(*uid, partner))
+            },
+        ))
+    }
+
+    pub async fn get_partnership(
+        &self,
+        partners: &Partnership,
+        category: RatingCategory,
+    ) -> Result<PartnershipRating, Error> {
+        let [uid, partner] = partners.uids;
+        let res = self
+            .session
+            .query(
+                "SELECT partner, category, rating, deviation, volatility,
+                 games, wins, last_rated
+                 FROM bughouse.partnerships
+                 WHERE uid = ? AND category = ? AND partner = ?",
+                (uid, category.as_str(), partner),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<PartnershipRow>() {
+                if let Some((_, rating)) = Self::to_partnership(&uid, row?) {
+                    return Ok(rating);
+                }
+            }
+        }
+        Ok(PartnershipRating::new(*partners))
+    }
+
+    // Both partnerships' pre-game ratings in `category`
+    pub async fn load_partnerships(
+        &self,
+        category: RatingCategory,
+        partnerships: &[Partnership; 2],
+    ) -> Result<[PartnershipRating; 2], Error> {
+        Ok([
+            self.get_partnership(&partnerships[0], category).await?,
+            self.get_partnership(&partnerships[1], category).await?,
+        ])
+    }
+
+    // Each partnership is stored under both partners so either can list
+    // their partnerships.
+    pub async fn record_partnerships(
+        &self,
+        category: RatingCategory,
+        ratings: &[PartnershipRating; 2],
+    ) -> Result<(), Error> {
+        println!("new {} partnership ratings: {:?}", category, ratings);
+        let now = Self::to_timestamp(Utc::now());
+        let cat = category.as_str();
+        let mut batch: Batch = Default::default();
+        let prepared: PreparedStatement = self.session.prepare(
+            "INSERT INTO bughouse.partnerships (uid, partner, category, rating, deviation, volatility, games, wins, last_rated) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            ).await?;
+        let mut rows = Vec::with_capacity(4);
+        for pr in ratings.iter() {
+            let [a, b] = pr.partners.uids;
+            let last_rated = pr.last_rated.map_or(now, Self::to_timestamp);
+            for (uid, partner) in [(a, b), (b, a)].iter() {
+                batch.append_statement(prepared.clone());
+                rows.push((
+                    *uid,
+                    *partner,
+                    cat,
+                    pr.rating.rating,
+                    pr.rating.deviation,
+                    pr.volatility,
+                    pr.games,
+                    pr.wins,
+                    last_rated,
+                ));
+            }
+        }
+        self.session.batch(&batch, &rows[..]).await?;
+        Ok(())
+    }
+
+    // Every finished, rated game, in no particular order.  A full scan of
+    // bughouse.games, so only meant for offline tools.
+    pub async fn get_rated_games(&self) -> Result<Vec<ReplayGame>, Error> {
+        let mut query = Query::new(
+            "SELECT id, start_time, result, time_ctrl, rated, players
+             FROM bughouse.games"
+                .to_string(),
+        );
+        query.set_page_size(REPLAY_PAGE_SIZE);
+        let mut rows = self.session.query_iter(query, ()).await?;
+        let mut games = Vec::new();
+        while let Some(row) = rows.next().await {
+            let (id, start_time, result, time_ctrl, rated, players) =
+                ReplayRow::from_row(row?)?;
+            if rated != Some(true) {
+                continue;
+            }
+            if let (Some(start), Some(result), Some(tc), Some(players)) =
+                (start_time, result, time_ctrl, players)
+            {
+                if let Some(game) =
+                    ReplayGame::new(id, start, result, &tc, &players)
+                {
+                    games.push(game);
+                }
+            }
+        }
+        Ok(games)
+    }
+
+    // Every stored rating, keyed by user and category
+    pub async fn get_all_ratings(
+        &self,
+    ) -> Result<HashMap<(UserID, RatingCategory), UserRating>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT uid, category, rating, deviation, volatility, games,
+                 last_rated FROM bughouse.ratings",
+                (),
+            )
+            .await?;
+        let mut ratings = HashMap::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<RatingRow>() {
+                if let Some((category, rating)) = Self::to_user_rating(row?) {
+                    ratings.insert((rating.uid, category), rating);
+                }
+            }
+        }
+        Ok(ratings)
+    }
+
+    // Replaces ratings, rating history and partnerships in `keyspace` with
+    // a replay's.  `stale` are ratings that exist but weren't replayed (no
+    // rated games behind them) and are deleted.  Each user's rows are
+    // replaced in one logged batch, so a failure never leaves a user half
+    // written, but run it with the server stopped all the same.
+    pub async fn write_replay(
+        &self,
+        keyspace: &str,
+        replay: &Replay<'_>,
+        stale: &[(UserID, RatingCategory)],
+    ) -> Result<(), Error> {
+        // A batch's statements share a timestamp, and a delete wins over an
+        // insert with the same one, so the deletes are written just before
+        let inserted_at = Utc::now().timestamp_nanos() / 1000;
+        let deleted_at = inserted_at - 1;
+        let mut batches = ReplayBatches::new();
+
+        let clear_history = self
+            .session
+            .prepare(format!(
+                "DELETE FROM {}.category_rating_history USING TIMESTAMP ?
+                 WHERE uid = ?",
+                keyspace
+            ))
+            .await?;
+        let clear_partnerships = self
+            .session
+            .prepare(format!(
+                "DELETE FROM {}.partnerships USING TIMESTAMP ? WHERE uid = ?",
+                keyspace
+            ))
+            .await?;
+        let uids: HashSet<UserID> = replay
+            .ratings
+            .keys()
+            .chain(stale.iter())
+            .map(|(uid, _)| *uid)
+            .collect();
+        for uid in uids.iter() {
+            batches.add(uid, &clear_history, (deleted_at, uid))?;
+            batches.add(uid, &clear_partnerships, (deleted_at, uid))?;
+        }
+
+        let delete_rating = self
+            .session
+            .prepare(format!(
+                "DELETE FROM {}.ratings USING TIMESTAMP ?
+                 WHERE uid = ? AND category = ?",
+                keyspace
+            ))
+            .await?;
+        for (uid, category) in stale.iter() {
+            let values = (deleted_at, uid, category.as_str());
+            batches.add(uid, &delete_rating, values)?;
+        }
+
+        let insert_history = self.session.prepare(format!(
+            "INSERT INTO {}.category_rating_history (uid, category, time, rating, deviation, volatility) VALUES (?, ?, ?, ?, ?, ?) USING TIMESTAMP ?",
+            keyspace
+            )).await?;
+        for entry in replay.history.iter() {
+            let values = (
+                entry.uid,
+                entry.category.as_str(),
+                Self::to_timestamp(entry.time),
+                entry.rating.rating,
+                entry.rating.deviation,
+                entry.volatility,
+                inserted_at,
+            );
+            batches.add(&entry.uid, &insert_history, values)?;
+        }
+
+        let insert_rating = self.session.prepare(format!(
+            "INSERT INTO {}.ratings (uid, category, rating, deviation, volatility, games, last_rated) VALUES (?, ?, ?, ?, ?, ?, ?) USING TIMESTAMP ?",
+            keyspace
+            )).await?;
+        for ((uid, category), rating) in replay.ratings.iter() {
+            let values = (
+                uid,
+                category.as_str(),
+                rating.rating.rating,
+                rating.rating.deviation,
+                rating.volatility,
+                rating.games,
+                rating.last_rated.map(Self::to_timestamp),
+                inserted_at,
+            );
+            batches.add(uid, &insert_rating, values)?;
+        }
+
+        let insert_partnership = self.session.prepare(format!(
+            "INSERT INTO {}.partnerships (uid, partner, category, rating, deviation, volatility, games, wins, last_rated) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) USING TIMESTAMP ?",
+            keyspace
+            )).await?;
+        for ((partners, category), pr) in replay.partnerships.iter() {
+            let [a, b] = partners.uids;
+            for (uid, partner) in [(a, b), (b, a)].iter() {
+                let values = (
+                    uid,
+                    partner,
+                    category.as_str(),
+                    pr.rating.rating,
+                    pr.rating.deviation,
+                    pr.volatility,
+                    pr.games,
+                    pr.wins,
+                    pr.last_rated.map(Self::to_timestamp),
+                    inserted_at,
+                );
+                batches.add(uid, &insert_partnership, values)?;
+            }
+        }
+
+        for (batch, values) in batches.0.values() {
+            self.session.batch(batch, &values[..]).await?;
         }
         Ok(())
     }
@@ -593,6 +1797,93 @@ impl Db {
         Ok(())
     }
 
+    pub async fn record_signal(
+        &self,
+        game_id: &GameID,
+        ms: i32,
+        sender: &UserID,
+        signal: &PartnerSignal,
+    ) -> Result<(), Error> {
+        // A sender can signal twice in one millisecond
+        let id = self.now()?;
+        self.session
+            .query(
+                "INSERT INTO bughouse.game_signals
+                 (game_id, ms, id, sender, kind, payload)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+                (game_id, ms, id, sender, signal.kind(), signal.payload()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // In the order they were sent
+    pub async fn get_signals(
+        &self,
+        game_id: &GameID,
+    ) -> Result<Vec<SignalRow>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT ms, sender, kind, payload FROM bughouse.game_signals
+                 WHERE game_id = ?",
+                (game_id,),
+            )
+            .await?;
+        let mut signals = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<SignalRow>() {
+                signals.push(row?);
+            }
+        }
+        Ok(signals)
+    }
+
+    pub async fn record_kibitz(
+        &self,
+        game_id: &GameID,
+        kibitz: &KibitzRow,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "INSERT INTO bughouse.game_kibitzes
+                 (game_id, id, ms, sender, whisper, body)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+                (
+                    game_id,
+                    kibitz.id,
+                    kibitz.ms,
+                    kibitz.sender,
+                    kibitz.whisper,
+                    &kibitz.body,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // In the order they were sent
+    pub async fn get_kibitzes(
+        &self,
+        game_id: &GameID,
+    ) -> Result<Vec<KibitzRow>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT id, ms, sender, whisper, body
+                 FROM bughouse.game_kibitzes WHERE game_id = ?",
+                (game_id,),
+            )
+            .await?;
+        let mut kibitzes = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<KibitzRow>() {
+                kibitzes.push(row?);
+            }
+        }
+        Ok(kibitzes)
+    }
+
     async fn insert_game(
         &self,
         id: GameID,
@@ -655,11 +1946,120 @@ impl Db {
         return Err(Error::Unexpected("Could not get user handles".into()));
     }
 
+    // Handles of everyone but guests.  A full scan of bughouse.users
+    pub async fn get_registered_handles(
+        &self,
+    ) -> Result<HashMap<UserID, String>, Error> {
+        let res = self
+            .session
+            .query("SELECT id, handle, guest FROM bughouse.users", ())
+            .await?;
+        let mut handles = HashMap::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(UserID, String, Option<bool>)>() {
+                let (uid, handle, guest) = row?;
+                if guest != Some(true) {
+                    handles.insert(uid, handle);
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    fn to_day(day: Date<Utc>) -> ScyllaTimestamp {
+        Self::to_timestamp(day.and_hms(0, 0, 0))
+    }
+
+    pub async fn has_leaderboard_snapshot(
+        &self,
+        category: RatingCategory,
+        day: Date<Utc>,
+    ) -> Result<bool, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT rank FROM bughouse.leaderboard_snapshots
+                 WHERE category = ? AND day = ? LIMIT 1",
+                (category.as_str(), Self::to_day(day)),
+            )
+            .await?;
+        Ok(res.rows.map_or(false, |rows| !rows.is_empty()))
+    }
+
+    pub async fn record_leaderboard_snapshot(
+        &self,
+        category: RatingCategory,
+        day: Date<Utc>,
+        entries: &[LeaderboardEntry],
+    ) -> Result<(), Error> {
+        let prepared = self.session.prepare(
+            "INSERT INTO bughouse.leaderboard_snapshots (category, day, rank, uid, handle, rating, deviation, games) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+            ).await?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        // One batch, on one partition, so it's all there or not at all (see
+        // has_leaderboard_snapshot)
+        let mut batch: Batch = Default::default();
+        let day = Self::to_day(day);
+        let mut rows = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            batch.append_statement(prepared.clone());
+            rows.push((
+                category.as_str(),
+                day,
+                entry.rank,
+                entry.uid,
+                &entry.handle,
+                entry.rating.rating,
+                entry.rating.deviation,
+                entry.games,
+            ));
+        }
+        self.session.batch(&batch, &rows[..]).await?;
+        Ok(())
+    }
+
+    // Best ranked first.  Empty if there's no snapshot for `day`
+    pub async fn get_leaderboard_snapshot(
+        &self,
+        category: RatingCategory,
+        day: Date<Utc>,
+    ) -> Result<Vec<LeaderboardEntry>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT rank, uid, handle, rating, deviation, games
+                 FROM bughouse.leaderboard_snapshots
+                 WHERE category = ? AND day = ?",
+                (category.as_str(), Self::to_day(day)),
+            )
+            .await?;
+        let mut entries = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(i32, UserID, String, i16, i16, i32)>()
+            {
+                let (rank, uid, handle, rating, deviation, games) = row?;
+                entries.push(LeaderboardEntry {
+                    rank,
+                    uid,
+                    handle,
+                    rating: Rating::new(rating, deviation),
+                    games,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
     fn user_rows(game: Arc<RwLock<Game>>) -> [IntoUserGameRow; 4] {
         let rgame = game.read().unwrap();
         let start = rgame.get_start().unwrap();
         let game_players = rgame.get_players();
-        let players = Game::get_rating_snapshots(game_players);
+        let players = Game::get_rating_snapshots(
+            game_players,
+            rgame.get_rating_category(),
+        );
         let mut rows: [IntoUserGameRow; 4] =
             [UserGameRow::default().into_row(); 4];
         for (idx, player) in Players::new(rgame.get_players())
@@ -752,4 +2152,379 @@ impl Db {
         )
         .await
     }
+
+    // Current owner of `handle`.  Renamed users' old handles don't count
+    pub async fn uid_from_handle(
+        &self,
+        handle: &str,
+    ) -> Result<Option<UserID>, Error> {
+        let res = self
+            .session
+            .query("SELECT id FROM bughouse.users WHERE handle = ?", (handle,))
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(UserID,)>() {
+                return Ok(Some(row?.0));
+            }
+        }
+        Ok(None)
+    }
+
+    // `pending` queues it for the recipient's next connection
+    pub async fn record_tell(
+        &self,
+        tell: &Tell,
+        pending: bool,
+    ) -> Result<(), Error> {
+        let sent_at = Self::to_timestamp(tell.sent_at);
+        let mut batch: Batch = Default::default();
+        let insert = "INSERT INTO bughouse.messages
+                      (uid, id, peer, sent, body, sent_at)
+                      VALUES (?, ?, ?, ?, ?, ?)";
+        batch.append_statement(insert);
+        batch.append_statement(insert);
+        let values = (
+            (
+                tell.sender,
+                tell.id,
+                tell.recipient,
+                true,
+                &tell.body,
+                sent_at,
+            ),
+            (
+                tell.recipient,
+                tell.id,
+                tell.sender,
+                false,
+                &tell.body,
+                sent_at,
+            ),
+        );
+        self.session.batch(&batch, values).await?;
+        if pending {
+            self.session
+                .query(
+                    "INSERT INTO bughouse.pending_messages
+                     (uid, id, sender, body, sent_at) VALUES (?, ?, ?, ?, ?)",
+                    (tell.recipient, tell.id, tell.sender, &tell.body, sent_at),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    // `uid`'s tells before `before`, newest first
+    pub async fn get_messages(
+        &self,
+        uid: &UserID,
+        before: Option<TellID>,
+        limit: i32,
+    ) -> Result<Vec<MessageRow>, Error> {
+        let res = match before {
+            Some(before) => {
+                self.session
+                    .query(
+                        "SELECT id, peer, sent, body, sent_at
+                         FROM bughouse.messages
+                         WHERE uid = ? AND id < ? LIMIT ?",
+                        (uid, before, limit),
+                    )
+                    .await?
+            }
+            None => {
+                self.session
+                    .query(
+                        "SELECT id, peer, sent, body, sent_at
+                         FROM bughouse.messages WHERE uid = ? LIMIT ?",
+                        (uid, limit),
+                    )
+                    .await?
+            }
+        };
+        let mut messages = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<MessageRow>() {
+                messages.push(row?);
+            }
+        }
+        Ok(messages)
+    }
+
+    // Tells `uid` missed while offline, oldest first.  They're removed from
+    // the queue.
+    pub async fn take_pending_tells(
+        &self,
+        uid: &UserID,
+    ) -> Result<Vec<Tell>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT id, sender, body, sent_at
+                 FROM bughouse.pending_messages WHERE uid = ?",
+                (uid,),
+            )
+            .await?;
+        let mut tells = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(TellID, UserID, String, Duration)>() {
+                let (id, sender, body, sent_at) = row?;
+                tells.push(Tell {
+                    id,
+                    sender,
+                    recipient: *uid,
+                    body,
+                    sent_at: Utc.timestamp_millis(sent_at.num_milliseconds()),
+                });
+            }
+        }
+        // Only the tells read, so none that arrive meanwhile are lost
+        if !tells.is_empty() {
+            let mut batch: Batch = Default::default();
+            let mut keys = Vec::with_capacity(tells.len());
+            for tell in tells.iter() {
+                batch.append_statement(
+                    "DELETE FROM bughouse.pending_messages
+                     WHERE uid = ? AND id = ?",
+                );
+                keys.push((uid, tell.id));
+            }
+            self.session.batch(&batch, &keys[..]).await?;
+        }
+        Ok(tells)
+    }
+
+    // Ok(false) if it already exists
+    pub async fn create_channel(
+        &self,
+        name: &str,
+        owner: &UserID,
+    ) -> Result<bool, Error> {
+        let mut query = Query::new(
+            "INSERT INTO bughouse.channels (name, owner, created_at)
+             VALUES (?, ?, ?) IF NOT EXISTS"
+                .to_string(),
+        );
+        query.set_consistency(Consistency::One);
+        query.set_serial_consistency(Some(SerialConsistency::Serial));
+        let now = Self::to_timestamp(Utc::now());
+        let res = self.session.query(query, (name, owner, now)).await?;
+        Ok(Self::lwt_applied(&res))
+    }
+
+    pub async fn get_channel(
+        &self,
+        name: &str,
+    ) -> Result<Option<ChannelState>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT owner FROM bughouse.channels WHERE name = ?",
+                (name,),
+            )
+            .await?;
+        let mut owner = None;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(Option<UserID>,)>() {
+                owner = Some(row?.0);
+            }
+        }
+        let owner = match owner {
+            Some(owner) => owner,
+            None => return Ok(None),
+        };
+        let mut state = ChannelState {
+            owner,
+            members: HashSet::new(),
+            muted: HashSet::new(),
+            banned: HashSet::new(),
+        };
+        let res = self
+            .session
+            .query(
+                "SELECT uid FROM bughouse.channel_members WHERE channel = ?",
+                (name,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(UserID,)>() {
+                state.members.insert(row?.0);
+            }
+        }
+        self.load_channel_moderation(name, &mut state).await?;
+        Ok(Some(state))
+    }
+
+    pub async fn load_channel_moderation(
+        &self,
+        name: &str,
+        state: &mut ChannelState,
+    ) -> Result<(), Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT uid, muted, banned FROM bughouse.channel_moderation
+                 WHERE channel = ?",
+                (name,),
+            )
+            .await?;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(UserID, Option<bool>, Option<bool>)>()
+            {
+                let (uid, muted, banned) = row?;
+                if muted == Some(true) {
+                    state.muted.insert(uid);
+                }
+                if banned == Some(true) {
+                    state.banned.insert(uid);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_channel_muted(
+        &self,
+        name: &str,
+        uid: &UserID,
+        muted: bool,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "UPDATE bughouse.channel_moderation SET muted = ?
+                 WHERE channel = ? AND uid = ?",
+                (muted, name, uid),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_channel_banned(
+        &self,
+        name: &str,
+        uid: &UserID,
+        banned: bool,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "UPDATE bughouse.channel_moderation SET banned = ?
+                 WHERE channel = ? AND uid = ?",
+                (banned, name, uid),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn join_channel(
+        &self,
+        name: &str,
+        uid: &UserID,
+    ) -> Result<(), Error> {
+        let mut batch: Batch = Default::default();
+        batch.append_statement(
+            "INSERT INTO bughouse.channel_members (channel, uid) VALUES (?, ?)",
+        );
+        batch.append_statement(
+            "INSERT INTO bughouse.user_channels (uid, channel) VALUES (?, ?)",
+        );
+        self.session
+            .batch(&batch, ((name, uid), (uid, name)))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn leave_channel(
+        &self,
+        name: &str,
+        uid: &UserID,
+    ) -> Result<(), Error> {
+        let mut batch: Batch = Default::default();
+        batch.append_statement(
+            "DELETE FROM bughouse.channel_members WHERE channel = ? AND uid = ?",
+        );
+        batch.append_statement(
+            "DELETE FROM bughouse.user_channels WHERE uid = ? AND channel = ?",
+        );
+        self.session
+            .batch(&batch, ((name, uid), (uid, name)))
+            .await?;
+        Ok(())
+    }
+
+    // Not including the lobby
+    pub async fn get_user_channels(
+        &self,
+        uid: &UserID,
+    ) -> Result<Vec<String>, Error> {
+        let res = self
+            .session
+            .query(
+                "SELECT channel FROM bughouse.user_channels WHERE uid = ?",
+                (uid,),
+            )
+            .await?;
+        let mut channels = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(String,)>() {
+                channels.push(row?.0);
+            }
+        }
+        Ok(channels)
+    }
+
+    pub async fn record_channel_message(
+        &self,
+        name: &str,
+        id: &Uuid,
+        sender: &UserID,
+        body: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.session
+            .query(
+                "INSERT INTO bughouse.channel_messages
+                 (channel, id, sender, body, sent_at) VALUES (?, ?, ?, ?, ?)",
+                (name, id, sender, body, Self::to_timestamp(sent_at)),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // `name`'s messages before `before`, newest first
+    pub async fn get_channel_messages(
+        &self,
+        name: &str,
+        before: Option<Uuid>,
+        limit: i32,
+    ) -> Result<Vec<ChannelMessageRow>, Error> {
+        let res = match before {
+            Some(before) => {
+                self.session
+                    .query(
+                        "SELECT id, sender, body, sent_at
+                         FROM bughouse.channel_messages
+                         WHERE channel = ? AND id < ? LIMIT ?",
+                        (name, before, limit),
+                    )
+                    .await?
+            }
+            None => {
+                self.session
+                    .query(
+                        "SELECT id, sender, body, sent_at
+                         FROM bughouse.channel_messages
+                         WHERE channel = ? LIMIT ?",
+                        (name, limit),
+                    )
+                    .await?
+            }
+        };
+        let mut messages = Vec::new();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<ChannelMessageRow>() {
+                messages.push(row?);
+            }
+        }
+        Ok(messages)
+    }
 }
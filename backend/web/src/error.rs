@@ -1,6 +1,7 @@
 use bughouse::{BoardID, Error as BugError};
 use bytestring::ByteString;
 use scylla::cql_to_rust::FromRowError;
+use scylla::frame::value::SerializeValuesError;
 use scylla::transport::errors::{NewSessionError, QueryError};
 use serde_json;
 use serde_json::json;
@@ -42,6 +43,72 @@ pub enum Error {
     #[error("Authentication Error: {}", reason)]
     AuthError { reason: String },
 
+    #[error("Unknown auth provider: {0}")]
+    UnknownAuthProvider(String),
+
+    #[error("Identity already linked to another user: {0}:{1}")]
+    IdentityLinked(String, String),
+
+    #[error("Username taken: {0}")]
+    UsernameTaken(String),
+
+    #[error("Handle taken: {0}")]
+    HandleTaken(String),
+
+    #[error("Invalid handle: {0}")]
+    InvalidHandle(String),
+
+    #[error("Handle changed too recently. Try again in {0} hours")]
+    HandleCooldown(i64),
+
+    #[error("Invalid profile: {0}")]
+    InvalidProfile(String),
+
+    #[error("Invalid preference: {0}")]
+    InvalidPreference(String),
+
+    #[error("Confirmation doesn't match: {0}")]
+    ConfirmationMismatch(String),
+
+    #[error("Unknown handle: {0}")]
+    UnknownHandle(String),
+
+    #[error("Invalid tell: {0}")]
+    InvalidTell(String),
+
+    #[error("Invalid channel: {0}")]
+    InvalidChannel(String),
+
+    #[error("Not in channel: {0}")]
+    NotChannelMember(String),
+
+    #[error("Muted in channel: {0}")]
+    ChannelMuted(String),
+
+    #[error("Banned from channel: {0}")]
+    ChannelBanned(String),
+
+    #[error("Can't moderate channel: {0}")]
+    NotChannelModerator(String),
+
+    #[error("Invalid partner signal: {0}")]
+    InvalidSignal(String),
+
+    #[error("Too many partner signals")]
+    SignalRateLimited,
+
+    #[error("Can't kibitz: {0}")]
+    InvalidKibitz(String),
+
+    #[error("Too many kibitzes")]
+    KibitzRateLimited,
+
+    #[error("Not a guest: {0}")]
+    NotGuest(UserID),
+
+    #[error("Unknown rating category: {0}")]
+    UnknownRatingCategory(String),
+
     #[error("BugError: {0}")]
     BugError(BugError),
 
@@ -54,12 +121,21 @@ pub enum Error {
     #[error("User already seeking")]
     AlreadySeeking(),
 
+    #[error("Can't partner with yourself")]
+    SelfPartner,
+
     #[error("InvalidMove InvalidUser: {0}")]
     InvalidMoveUser(UserID),
 
     #[error("Invalid game ID - doesn't exist {0}")]
     InvalidGameID(GameID),
 
+    #[error("Can't observe more than {0} games at once")]
+    TooManyObserved(usize),
+
+    #[error("Game is hidden from observers: {0}")]
+    GameHidden(GameID),
+
     #[error("InvalidMove wrong gameID: {0}, {1} != {2}")]
     InvalidGameIDForUser(UserID, GameID, GameID),
 
@@ -90,6 +166,12 @@ pub enum Error {
     #[error("Firebase err {0}")]
     FirebaseError(String),
 
+    #[error("JWT Error: {0}")]
+    Jwt(jsonwebtoken::errors::Error),
+
+    #[error("HTTP Error: {0}")]
+    Http(reqwest::Error),
+
     #[error("Can't create rated game as guest")]
     CreateRatedGameGuest(),
 
@@ -123,6 +205,9 @@ pub enum Error {
     #[error("FromRowError: {0}")]
     FromRowError(FromRowError),
 
+    #[error("SerializeValuesError: {0}")]
+    SerializeValuesError(SerializeValuesError),
+
     // #[error("NoneError: {0}")]
     // NoneError(NoneError),
     #[error("UuidError: {0}")]
@@ -141,6 +226,18 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        Error::Jwt(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
 impl From<std::sync::mpsc::SendError<String>> for Error {
     fn from(err: std::sync::mpsc::SendError<String>) -> Self {
         Error::SendError(err)
@@ -171,6 +268,12 @@ impl From<FromRowError> for Error {
     }
 }
 
+impl From<SerializeValuesError> for Error {
+    fn from(err: SerializeValuesError) -> Self {
+        Error::SerializeValuesError(err)
+    }
+}
+
 impl From<UuidError> for Error {
     fn from(err: UuidError) -> Self {
         Error::UuidError(err)
@@ -0,0 +1,124 @@
+// Ranking of live games for the current games list and TV, which follows
+// the top one.  Stronger games rank higher, as do games people are already
+// watching, up to a point, and blitz, which is the easiest to follow.
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::game::GameID;
+use crate::rating::RatingCategory;
+
+const POINTS_PER_OBSERVER: f64 = 25.0;
+const MAX_OBSERVER_POINTS: f64 = 500.0;
+
+fn category_points(category: RatingCategory) -> f64 {
+    match category {
+        RatingCategory::Bullet => -100.0,
+        RatingCategory::Blitz => 50.0,
+        RatingCategory::Standard => 0.0,
+    }
+}
+
+pub fn score(
+    avg_rating: f64,
+    observers: usize,
+    category: RatingCategory,
+) -> f64 {
+    let observer_points =
+        (observers as f64 * POINTS_PER_OBSERVER).min(MAX_OBSERVER_POINTS);
+    avg_rating + observer_points + category_points(category)
+}
+
+// The games that left and entered the top page from one ranking to the
+// next.  Games that only moved around in it are in neither, since current
+// games subscribers aren't told about the order
+pub fn page_changes(
+    old: &[GameID],
+    new: &[GameID],
+) -> (Vec<GameID>, Vec<GameID>) {
+    let left = old.iter().filter(|id| !new.contains(id)).cloned().collect();
+    let entered = new.iter().filter(|id| !old.contains(id)).cloned().collect();
+    (left, entered)
+}
+
+// Which live games to list
+#[derive(Clone, Debug, Default)]
+pub struct CurrentGamesFilter {
+    pub rated: Option<bool>,
+    pub category: Option<RatingCategory>,
+    // Of the average rating
+    pub min_rating: Option<i16>,
+}
+
+impl CurrentGamesFilter {
+    // {"rated": bool, "category": "blitz", "min_rating": int}, all optional
+    pub fn from_json(val: &Value) -> Result<Self, Error> {
+        let category = match val["category"].as_str() {
+            Some(category) => Some(RatingCategory::from_str(category)?),
+            None => None,
+        };
+        Ok(CurrentGamesFilter {
+            rated: val["rated"].as_bool(),
+            category,
+            min_rating: val["min_rating"].as_i64().map(|r| r as i16),
+        })
+    }
+
+    pub fn matches(
+        &self,
+        rated: bool,
+        category: RatingCategory,
+        avg_rating: f64,
+    ) -> bool {
+        self.rated.map_or(true, |r| r == rated)
+            && self.category.map_or(true, |c| c == category)
+            && self.min_rating.map_or(true, |r| avg_rating >= r as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ranking() {
+        let blitz = RatingCategory::Blitz;
+        assert!(score(1800.0, 0, blitz) > score(1500.0, 0, blitz));
+        assert!(score(1500.0, 10, blitz) > score(1700.0, 0, blitz));
+        assert!(score(1500.0, 1000, blitz) < score(2100.0, 0, blitz));
+        assert!(
+            score(1500.0, 0, blitz) > score(1500.0, 0, RatingCategory::Bullet)
+        );
+    }
+
+    #[test]
+    fn page_changes_ignore_order() {
+        let ids: Vec<GameID> = (0..4).map(|_| uuid::Uuid::new_v4()).collect();
+        // Observers moving the games around inside the page
+        let reordered = [ids[2], ids[0], ids[1]];
+        let (left, entered) = page_changes(&ids[..3], &reordered);
+        assert!(left.is_empty() && entered.is_empty());
+        let (left, entered) = page_changes(&ids[..3], &ids[1..]);
+        assert!(left == vec![ids[0]] && entered == vec![ids[3]]);
+    }
+
+    #[test]
+    fn filter() {
+        let filter = CurrentGamesFilter::from_json(
+            &json!({"category": "blitz", "min_rating": 1600}),
+        )
+        .unwrap();
+        assert!(filter.matches(true, RatingCategory::Blitz, 1650.0));
+        assert!(!filter.matches(false, RatingCategory::Blitz, 1550.0));
+        assert!(!filter.matches(false, RatingCategory::Standard, 1650.0));
+        assert!(
+            CurrentGamesFilter::from_json(&json!({"category": "x"})).is_err()
+        );
+        assert!(CurrentGamesFilter::from_json(&json!({})).unwrap().matches(
+            false,
+            RatingCategory::Bullet,
+            0.0
+        ));
+    }
+}
@@ -0,0 +1,294 @@
+// Local verification of Firebase ID tokens (RS256 JWTs). See:
+// https://firebase.google.com/docs/auth/admin/verify-id-tokens#verify_id_tokens_using_a_third-party_jwt_library
+//
+// Configured via:
+//   FIREBASE_PROJECT_ID  Required. Expected `aud` (and suffix of `iss`)
+//   FIREBASE_KEYS        URL or file path of the JWK set to verify against.
+//                        Defaults to Google's published securetoken keys.
+
+use chrono::prelude::*;
+use chrono::Duration;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::db::FirebaseRowData;
+use crate::error::Error;
+
+const GOOGLE_JWKS_URL: &str = "https://www.googleapis.com/service_accounts/v1/jwk/securetoken@system.gserviceaccount.com";
+const ISSUER_PREFIX: &str = "https://securetoken.google.com/";
+
+// Key lifetime when the source doesn't specify one (files, or a response
+// without a Cache-Control max-age)
+const DEFAULT_KEY_TTL_SECS: i64 = 60 * 60;
+// Unknown `kid`s trigger a refetch, but no more often than this
+const MIN_REFRESH_SECS: i64 = 60;
+// Tolerated clock skew between us and Google
+const LEEWAY_SECS: i64 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    File(PathBuf),
+    Url(String),
+}
+
+impl KeySource {
+    pub fn parse(s: &str) -> Self {
+        if s.starts_with("https://") || s.starts_with("http://") {
+            KeySource::Url(s.to_string())
+        } else {
+            KeySource::File(PathBuf::from(s))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String, // base64url modulus
+    e: String, // base64url exponent
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+struct KeySet {
+    keys: HashMap<String, (String, String)>,
+    fetched: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+// Cached public keys, reloaded from `source` when they expire or when a
+// token is signed with a key we haven't seen (i.e. Google rotated keys).
+pub struct KeyStore {
+    source: KeySource,
+    key_set: RwLock<Option<KeySet>>,
+}
+
+impl KeyStore {
+    pub fn new(source: KeySource) -> Self {
+        KeyStore {
+            source,
+            key_set: RwLock::new(None),
+        }
+    }
+
+    fn lookup(&self, kid: &str) -> Option<(String, String)> {
+        let rkey_set = self.key_set.read().unwrap();
+        let key_set = rkey_set.as_ref()?;
+        if key_set.expires < Utc::now() {
+            return None;
+        }
+        key_set.keys.get(kid).cloned()
+    }
+
+    fn recently_fetched(&self) -> bool {
+        let rkey_set = self.key_set.read().unwrap();
+        if let Some(key_set) = rkey_set.as_ref() {
+            let now = Utc::now();
+            return key_set.expires > now
+                && now - key_set.fetched < Duration::seconds(MIN_REFRESH_SECS);
+        }
+        false
+    }
+
+    async fn fetch(&self) -> Result<(String, Option<i64>), Error> {
+        match &self.source {
+            KeySource::File(path) => {
+                Ok((tokio::fs::read_to_string(path).await?, None))
+            }
+            KeySource::Url(url) => {
+                let resp = reqwest::get(url).await?.error_for_status()?;
+                let max_age = resp
+                    .headers()
+                    .get(reqwest::header::CACHE_CONTROL)
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(parse_max_age);
+                Ok((resp.text().await?, max_age))
+            }
+        }
+    }
+
+    pub async fn refresh(&self) -> Result<(), Error> {
+        let (body, max_age) = self.fetch().await?;
+        let jwks: JwkSet = serde_json::from_str(&body)?;
+        let keys: HashMap<String, (String, String)> = jwks
+            .keys
+            .into_iter()
+            .map(|jwk| (jwk.kid, (jwk.n, jwk.e)))
+            .collect();
+        println!("firebase: loaded {} keys from {:?}", keys.len(), self.source);
+        let now = Utc::now();
+        let ttl = max_age.unwrap_or(DEFAULT_KEY_TTL_SECS);
+        let mut wkey_set = self.key_set.write().unwrap();
+        *wkey_set = Some(KeySet {
+            keys,
+            fetched: now,
+            expires: now + Duration::seconds(ttl),
+        });
+        Ok(())
+    }
+
+    pub async fn get(&self, kid: &str) -> Result<(String, String), Error> {
+        if let Some(key) = self.lookup(kid) {
+            return Ok(key);
+        }
+        if !self.recently_fetched() {
+            self.refresh().await?;
+        }
+        self.lookup(kid).ok_or_else(|| Error::AuthError {
+            reason: format!("Unknown signing key: {}", kid),
+        })
+    }
+}
+
+// Cache-Control: public, max-age=19204, must-revalidate, no-transform
+fn parse_max_age(cache_control: &str) -> Option<i64> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("max-age"))
+        .and_then(|(_, secs)| secs.trim().parse::<i64>().ok())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FirebaseInfo {
+    pub sign_in_provider: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FirebaseClaims {
+    pub sub: String,
+    pub iat: i64,
+    pub auth_time: i64,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub picture: Option<String>,
+    pub firebase: FirebaseInfo,
+}
+
+impl FirebaseClaims {
+    // `exp`, `aud` and `iss` are checked by jsonwebtoken's Validation
+    fn check(&self, now: i64) -> Result<(), Error> {
+        let reason = if self.sub.is_empty() || self.sub.len() > 128 {
+            "Invalid subject"
+        } else if self.iat > now + LEEWAY_SECS {
+            "Token issued in the future"
+        } else if self.auth_time > now + LEEWAY_SECS {
+            "Authenticated in the future"
+        } else {
+            return Ok(());
+        };
+        Err(Error::AuthError {
+            reason: reason.to_string(),
+        })
+    }
+
+    pub fn to_row_data(&self) -> FirebaseRowData {
+        FirebaseRowData::new(
+            &self.sub,
+            self.name.clone(),
+            self.email.clone(),
+            self.picture.clone(),
+            Some(self.firebase.sign_in_provider.clone()),
+        )
+    }
+}
+
+pub struct TokenVerifier {
+    project_id: Option<String>,
+    keys: KeyStore,
+}
+
+impl TokenVerifier {
+    pub fn new(project_id: Option<String>, source: KeySource) -> Self {
+        TokenVerifier {
+            project_id,
+            keys: KeyStore::new(source),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let project_id = std::env::var("FIREBASE_PROJECT_ID").ok();
+        let keys = std::env::var("FIREBASE_KEYS")
+            .unwrap_or(GOOGLE_JWKS_URL.to_string());
+        TokenVerifier::new(project_id, KeySource::parse(&keys))
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<FirebaseClaims, Error> {
+        let project_id =
+            self.project_id.as_ref().ok_or_else(|| Error::AuthError {
+                reason: "FIREBASE_PROJECT_ID not set".to_string(),
+            })?;
+        let header = decode_header(token)?;
+        if header.alg != Algorithm::RS256 {
+            return Err(Error::AuthError {
+                reason: format!("Unexpected algorithm: {:?}", header.alg),
+            });
+        }
+        let kid = header.kid.ok_or_else(|| Error::AuthError {
+            reason: "Missing 'kid' header".to_string(),
+        })?;
+        let (n, e) = self.keys.get(&kid).await?;
+        let key = DecodingKey::from_rsa_components(&n, &e);
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = LEEWAY_SECS as u64;
+        validation.set_audience(&[project_id]);
+        validation.iss = Some(format!("{}{}", ISSUER_PREFIX, project_id));
+        let data = decode::<FirebaseClaims>(token, &key, &validation)?;
+        data.claims.check(Utc::now().timestamp())?;
+        Ok(data.claims)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn claims(sub: &str, iat: i64, auth_time: i64) -> FirebaseClaims {
+        FirebaseClaims {
+            sub: sub.to_string(),
+            iat,
+            auth_time,
+            name: None,
+            email: None,
+            picture: None,
+            firebase: FirebaseInfo {
+                sign_in_provider: "anonymous".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn max_age() {
+        let header = "public, max-age=19204, must-revalidate, no-transform";
+        assert!(parse_max_age(header) == Some(19204));
+        assert!(parse_max_age("no-cache") == None);
+        assert!(parse_max_age("MAX-AGE=5") == Some(5));
+    }
+
+    #[test]
+    fn key_source() {
+        assert!(
+            KeySource::parse(GOOGLE_JWKS_URL)
+                == KeySource::Url(GOOGLE_JWKS_URL.to_string())
+        );
+        assert!(
+            KeySource::parse("/etc/bughouse/jwks.json")
+                == KeySource::File(PathBuf::from("/etc/bughouse/jwks.json"))
+        );
+    }
+
+    #[test]
+    fn claim_times() {
+        let now = 1_600_000_000;
+        assert!(claims("fid", now, now).check(now).is_ok());
+        assert!(claims("", now, now).check(now).is_err());
+        assert!(claims("fid", now + 3600, now).check(now).is_err());
+        assert!(claims("fid", now, now + 3600).check(now).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+// Shared module houses all Firebase-related constants.
+// Tokens are verified locally (see jwt.rs) unless FIREBASE_AUTH=sidecar, in
+// which case they're handed off to firebase-go-srv (see sidecar.rs).
+
+pub mod jwt;
+pub mod sidecar;
+
+use crate::db::FirebaseRowData;
+use crate::error::Error;
+
+pub use sidecar::{FIRE_AUTH, FIRE_USER, UNIX_SOCK};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthBackend {
+    Jwt,
+    Sidecar,
+}
+
+lazy_static! {
+    pub static ref AUTH_BACKEND: AuthBackend =
+        match std::env::var("FIREBASE_AUTH").as_deref() {
+            Ok("sidecar") => AuthBackend::Sidecar,
+            _ => AuthBackend::Jwt,
+        };
+    static ref VERIFIER: jwt::TokenVerifier = jwt::TokenVerifier::from_env();
+}
+
+pub struct FirebaseID(pub String);
+pub struct ProviderID(pub String);
+
+// The FirebaseRowData is only available when the token itself carried the
+// user's profile (local verification).  Otherwise it's fetched from the
+// sidecar on demand.
+pub async fn authenticate(
+    token: &str,
+) -> Result<(FirebaseID, ProviderID, Option<FirebaseRowData>), Error> {
+    eprintln!("authenticate...");
+    match *AUTH_BACKEND {
+        AuthBackend::Jwt => {
+            let claims = VERIFIER.verify(token).await.map_err(|e| {
+                eprintln!("auth error: {}", e);
+                e
+            })?;
+            let row_data = claims.to_row_data();
+            Ok((
+                FirebaseID(claims.sub),
+                ProviderID(claims.firebase.sign_in_provider),
+                Some(row_data),
+            ))
+        }
+        AuthBackend::Sidecar => {
+            let (fid, provider_id) = sidecar::authenticate(token)?;
+            Ok((fid, provider_id, None))
+        }
+    }
+}
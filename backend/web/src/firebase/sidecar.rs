@@ -1,12 +1,13 @@
-// Shared module houses all Firebase-related constants.
-// See firebase-go-srv
+// Client for the Unix-socket firebase-go-srv (see backend/firebase/server.go).
+// Only used when FIREBASE_AUTH=sidecar, or to look up user metadata that
+// isn't present in a locally verified token.
 
-use crate::db::Db;
-use crate::error::Error;
 use std::io::prelude::{Read, Write};
 use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
-use std::sync::Arc;
+
+use super::{FirebaseID, ProviderID};
+use crate::error::Error;
 
 pub const FIRE_AUTH: u8 = 1;
 pub const FIRE_USER: u8 = 2;
@@ -19,24 +20,7 @@ lazy_static! {
         std::env::var("SOCK").unwrap_or(DEFAULT_SOCK.to_string());
 }
 
-pub struct FirebaseID(pub String);
-pub struct ProviderID(pub String);
-
-pub async fn authenticate(
-    token: &str,
-    db: Arc<Db>,
-) -> Result<(FirebaseID, ProviderID), Error> {
-    eprintln!("authenticate...");
-    if token.starts_with(".fake") {
-        let res = db.get_user_from_fid(token).await;
-        if res.is_err() {
-            let reason = format!("{} not found", token);
-            eprintln!("auth error: {}", reason);
-            return Err(Error::AuthError { reason });
-        }
-        let user = res.unwrap();
-        return Ok((FirebaseID(user.firebase_id), ProviderID("fake".into())));
-    }
+pub fn authenticate(token: &str) -> Result<(FirebaseID, ProviderID), Error> {
     let mut stream = UnixStream::connect(UNIX_SOCK.to_string())?;
     write!(stream, "{}\n{}\n", FIRE_AUTH, token)?;
     stream.flush()?;
@@ -9,6 +9,7 @@ use std::sync::{Arc, RwLock};
 
 use crate::db::{TableSnapshot, UserRatingSnapshot};
 use crate::error::Error;
+use crate::rating::RatingCategory;
 use crate::time_control::TimeControl;
 use crate::users::User;
 use crate::users::UserID;
@@ -69,6 +70,10 @@ pub struct Game {
     clocks: GameClocks,
     pub rated: bool,
     pub public: bool,
+    // Only the players can watch it
+    pub hidden: bool,
+    // How far behind the players observers see it
+    pub observer_delay: Duration,
     result: Option<GameResult>,
     last_move_time: [DateTime<Utc>; 2], // Time of last move on either board
     pub last_moves: [Option<BughouseMove>; 2], // Time of last move on either board
@@ -94,6 +99,8 @@ impl Game {
             last_moves: [None; 2],
             rated,
             public: false,
+            hidden: false,
+            observer_delay: Duration::zero(),
             result: None,
         }
     }
@@ -104,6 +111,7 @@ impl Game {
         time_ctrl: TimeControl,
         rated: bool,
         public: bool,
+        hidden: bool,
         user: Arc<RwLock<User>>,
     ) -> Self {
         let base = time_ctrl.get_base_ms();
@@ -119,6 +127,8 @@ impl Game {
             last_moves: [None; 2],
             rated,
             public,
+            hidden,
+            observer_delay: Duration::zero(),
             result: None,
         }
     }
@@ -136,13 +146,16 @@ impl Game {
         None
     }
 
-    pub fn get_rating_snapshots(players: &GamePlayers) -> TableSnapshot {
+    pub fn get_rating_snapshots(
+        players: &GamePlayers,
+        category: RatingCategory,
+    ) -> TableSnapshot {
         let [[aw, ab], [bw, bb]] = players;
         let (aws, abs, bws, bbs) = (
-            UserRatingSnapshot::from(aw.clone()),
-            UserRatingSnapshot::from(ab.clone()),
-            UserRatingSnapshot::from(bw.clone()),
-            UserRatingSnapshot::from(bb.clone()),
+            UserRatingSnapshot::from_player(aw, category),
+            UserRatingSnapshot::from_player(ab, category),
+            UserRatingSnapshot::from_player(bw, category),
+            UserRatingSnapshot::from_player(bb, category),
         );
         ((aws, abs), (bws, bbs))
     }
@@ -179,6 +192,10 @@ impl Game {
         &self.id
     }
 
+    pub fn get_rating_category(&self) -> RatingCategory {
+        RatingCategory::of(&self.time_ctrl)
+    }
+
     pub fn new_start() -> DateTime<Utc> {
         Utc::now() + Duration::milliseconds(GAME_MS_IN_FUTURE)
     }
@@ -6,6 +6,9 @@ use std::sync::{Arc, RwLock};
 
 use crate::b66::B66;
 use crate::game::{Game, GameID, GameResult};
+use crate::graphql::subscription::{
+    LiveBoard, LiveBoardState, LiveGame, LiveGameResult, LivePlayer,
+};
 use crate::time_control::TimeControl;
 
 #[derive(Clone, Copy, Debug)]
@@ -48,13 +51,13 @@ impl Serialize for GameJsonKind {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PlayerJson {
     handle: Option<String>,
     ms: i32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BoardFenJson {
     fen: String,
     last_move: Option<BughouseMove>,
@@ -62,14 +65,14 @@ pub struct BoardFenJson {
     black: PlayerJson,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BoardJson {
     // kind: GameJsonKind,
     holdings: String,
     board: BoardFenJson,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct GameJson {
     id: GameID,
     kind: GameJsonKind,
@@ -77,6 +80,7 @@ pub struct GameJson {
     time_ctrl: TimeControl,
     result: Option<GameResult>,
     start_in_ms: i32,
+    observers: usize,
     a: BoardJson,
     b: BoardJson,
 }
@@ -117,11 +121,42 @@ impl GameJson {
             rated: game.rated,
             result: game.get_result(),
             start_in_ms: Self::start_in_ms(game.get_start()),
+            observers: 0,
             a: get_board_json(&game, BoardID::A), // kind),
             b: get_board_json(&game, BoardID::B), // kind),
         }
     }
 
+    pub fn set_observers(&mut self, count: usize) {
+        self.observers = count;
+    }
+
+    pub fn set_kind(&mut self, kind: GameJsonKind) {
+        self.kind = kind;
+    }
+
+    pub fn get_id(&self) -> &GameID {
+        &self.id
+    }
+
+    // The GraphQL type with the same fields as to_val()
+    pub fn to_live_game(&self) -> LiveGame {
+        LiveGame {
+            id: B66::encode_uuid(&self.id),
+            rated: self.rated,
+            result: self.result.map(|result| LiveGameResult {
+                board: result.board as i32,
+                winner: result.winner as i32,
+                kind: result.kind as i32,
+            }),
+            time_ctrl: self.time_ctrl.to_string(),
+            delay_start_millis: self.start_in_ms,
+            observers: self.observers as i32,
+            a: self.a.to_live_board(),
+            b: self.b.to_live_board(),
+        }
+    }
+
     pub fn to_val(&self) -> Value {
         json!({
             "kind": self.kind,
@@ -130,6 +165,7 @@ impl GameJson {
             "result": self.result,
             "timeCtrl": self.time_ctrl,
             "delayStartMillis": self.start_in_ms,
+            "observers": self.observers,
             "a": {
                 "holdings": self.a.holdings,
                 "board": {
@@ -164,6 +200,29 @@ impl GameJson {
     }
 }
 
+impl PlayerJson {
+    fn to_live_player(&self) -> LivePlayer {
+        LivePlayer {
+            handle: self.handle.clone(),
+            ms: self.ms,
+        }
+    }
+}
+
+impl BoardJson {
+    fn to_live_board(&self) -> LiveBoard {
+        LiveBoard {
+            holdings: self.holdings.clone(),
+            board: LiveBoardState {
+                fen: self.board.fen.clone(),
+                last_move: get_squares(&self.board.last_move),
+                white: self.board.white.to_live_player(),
+                black: self.board.black.to_live_player(),
+            },
+        }
+    }
+}
+
 fn get_board_json(
     game: &Game,
     board_id: BoardID,
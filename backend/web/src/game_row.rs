@@ -233,3 +233,48 @@ mod test {
         }
     }
 }
+
+// Rows for tests of the code built on them (stats, searches, exports)
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+    use uuid::Uuid;
+
+    // Four new players, in seat order: board A white, board A black, board
+    // B white, board B black
+    pub fn uids() -> [UserID; 4] {
+        [
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        ]
+    }
+
+    // `uids` seated in order, all rated `rating`
+    pub fn players(uids: &[UserID; 4], rating: i16) -> TableSnapshot {
+        let snap = |i: usize| UserRatingSnapshot {
+            uid: uids[i],
+            rating,
+        };
+        ((snap(0), snap(1)), (snap(2), snap(3)))
+    }
+
+    // A finished game between `uids` without moves
+    pub fn game(
+        uids: &[UserID; 4],
+        result: &GameResult,
+        time_ctrl: TimeControl,
+        rated: bool,
+    ) -> GameRow {
+        GameRow {
+            id: Uuid::new_v4(),
+            start_time: Duration::zero(),
+            result: GameRow::serialize_result(result),
+            time_ctrl,
+            rated,
+            players: players(uids, 0),
+            moves: None,
+        }
+    }
+}
@@ -2,34 +2,55 @@ use actix::prelude::*;
 use bughouse::{BoardID, BughouseMove, Color};
 use bytestring::ByteString;
 use chrono::prelude::*;
+use chrono::Duration;
 use num_integer::div_rem;
 use serde_json::{json, Value};
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::atomic::{self, AtomicBool};
 use std::sync::{Arc, RwLock};
 
 use crate::b66::B66;
 use crate::connection_mgr::ConnectionMgr;
 use crate::error::Error;
+use crate::featured::{self, CurrentGamesFilter};
 use crate::game::{Game, GameID, GamePlayers};
 use crate::game_json::{GameJson, GameJsonKind};
+use crate::hash::hash;
 use crate::messages::{
     ClientMessage, ClientMessageKind, UserStateKind, UserStateMessage,
 };
-use crate::observers::Observers;
+use crate::observers::{Observers, Recipients};
 use crate::players::Players;
+use crate::rating::RatingCategory;
 use crate::subscriptions::Subscriptions;
 use crate::time_control::TimeControl;
 use crate::users::{User, UserID};
 // use crate::db::Db;
 // use crate::bughouse_server::BughouseServer;
 
+// The game TV is showing and the connections watching it
+#[derive(Default)]
+struct Tv {
+    game_id: Option<GameID>,
+    viewers: Recipients,
+}
+
 // Ongoing games
 pub struct Games {
     games: RwLock<HashMap<GameID, Arc<RwLock<Game>>>>,
     user_games: RwLock<HashMap<UserID, GameID>>,
     game_observers: Observers,
+    // At least this for every game, from OBSERVER_DELAY_SECS
+    observer_delay: Duration,
     public_table_subs: RwLock<Subscriptions>,
     current_game_subs: RwLock<Subscriptions>,
+    // The top CURRENT_MAX games as last sent to current_game_subs
+    current: RwLock<Vec<GameID>>,
+    tv: RwLock<Tv>,
+    // Observer counts changed since the games were last ranked
+    rerank: AtomicBool,
     conns: Arc<ConnectionMgr>,
 }
 
@@ -76,6 +97,13 @@ impl GameUserHandler {
 }
 
 pub const CURRENT_MAX: usize = 10;
+// Most current games sent in one page
+pub const MAX_CURRENT_GAMES_PAGE: usize = 50;
+
+// Longest observer delay a table can ask for
+pub const MAX_OBSERVER_DELAY_SECS: i64 = 60;
+// Observer delay of tournament tables that don't ask for one
+pub const TOURNAMENT_OBSERVER_DELAY_SECS: i64 = 15;
 impl Games {
     pub fn new(
         // db: Arc<Db>,
@@ -86,9 +114,18 @@ impl Games {
             // server,
             games: RwLock::new(HashMap::new()),
             user_games: RwLock::new(HashMap::new()),
-            game_observers: Observers::new(conns.clone()),
+            game_observers: Observers::from_env(conns.clone()),
+            observer_delay: Duration::seconds(
+                env::var("OBSERVER_DELAY_SECS")
+                    .ok()
+                    .and_then(|val| val.parse().ok())
+                    .unwrap_or(0),
+            ),
             public_table_subs: RwLock::new(Subscriptions::new()),
             current_game_subs: RwLock::new(Subscriptions::new()),
+            current: RwLock::new(Vec::new()),
+            tv: RwLock::new(Tv::default()),
+            rerank: AtomicBool::new(false),
             conns,
         }
     }
@@ -99,9 +136,15 @@ impl Games {
         time_ctrl: TimeControl,
         rated: bool,
         public: bool,
+        hidden: bool,
+        observer_delay: Duration,
         user: Arc<RwLock<User>>,
     ) -> Result<ClientMessage, Error> {
-        let game = Game::table(id, time_ctrl, rated, public, user.clone());
+        let mut game =
+            Game::table(id, time_ctrl, rated, public, hidden, user.clone());
+        let max_delay = Duration::seconds(MAX_OBSERVER_DELAY_SECS);
+        game.observer_delay =
+            observer_delay.min(max_delay).max(self.observer_delay);
         let locked_game = Arc::new(RwLock::new(game));
         {
             let mut games = self.games.write().unwrap();
@@ -256,8 +299,9 @@ impl Games {
     ) -> Result<(Arc<RwLock<Game>>, ClientMessage), Error> {
         println!("Games::start_game");
         // let (id, start) = self.server.insert_game(&time_ctrl, &players).await?;
-        let game =
+        let mut game =
             Game::start_new(id, start, time_ctrl, rated, players.clone());
+        game.observer_delay = self.observer_delay;
         let locked_game = Arc::new(RwLock::new(game));
         {
             let mut games = self.games.write().unwrap();
@@ -295,14 +339,14 @@ impl Games {
     }
 
     pub fn rm_game(&self, game_id: &GameID) {
-        if let Some(game) = self.rm_from_user_games(game_id) {
-            println!("notifying current subs rm: {}", game_id);
-            self.notify_current_subs(TableUpdateType::Remove, game);
+        if self.rm_from_user_games(game_id).is_some() {
             {
                 let mut wgames = self.games.write().unwrap();
                 wgames.remove(game_id);
             }
-            self.notify_next_current();
+            println!("notifying current subs rm: {}", game_id);
+            self.refresh_current();
+            self.game_observers.end_game(game_id);
         }
     }
 
@@ -344,10 +388,11 @@ impl Games {
     pub fn notify_game_observers(
         &self,
         ar_game: Arc<RwLock<Game>>,
-        game_json: GameJson,
+        mut game_json: GameJson,
     ) -> ClientMessage {
         let game = ar_game.read().unwrap();
         let players = game.get_players();
+        game_json.set_observers(self.observer_count(game.get_id()));
         let msg_val = game_json.to_val();
         println!("notify msg: {}", msg_val);
         let bytestr = Arc::new(ByteString::from(msg_val.to_string()));
@@ -356,10 +401,64 @@ impl Games {
             self.conns.send_to_user(&player.get_uid(), &msg);
         }
         let players = Self::get_player_set(ar_game.clone());
-        self.game_observers.notify(game.get_id(), &msg, players);
+        if Self::is_delayed(&game) {
+            self.game_observers.notify_later(
+                game_json,
+                &msg,
+                players,
+                game.observer_delay,
+            );
+        } else {
+            self.game_observers.notify(game.get_id(), &msg, players);
+        }
         msg
     }
 
+    // Whether observers see the game late.  Tables waiting for players
+    // aren't held back
+    fn is_delayed(game: &Game) -> bool {
+        game.get_start().is_some() && game.observer_delay > Duration::zero()
+    }
+
+    // The game as observers see it.  None if they're due to see it late and
+    // haven't been sent anything yet
+    pub fn observer_payload(
+        &self,
+        ar_game: Arc<RwLock<Game>>,
+    ) -> Option<ByteString> {
+        let (game_id, delayed) = {
+            let game = ar_game.read().unwrap();
+            (*game.get_id(), Self::is_delayed(&game))
+        };
+        if delayed {
+            return self.game_observers.released(&game_id);
+        }
+        let mut game_json =
+            GameJson::new(ar_game.clone(), Self::get_kind(ar_game));
+        game_json.set_observers(self.observer_count(&game_id));
+        Some(ByteString::from(game_json.to_val().to_string()))
+    }
+
+    // Sends observers the delayed updates that are due, and current games
+    // subscribers the games observers now see differently.  Games observers
+    // see for the first time may rank
+    pub fn flush_observers(&self) {
+        let released = self
+            .game_observers
+            .flush(Utc::now(), |game_id| self.get(game_id).is_some());
+        if released.iter().any(|(_, first)| *first) {
+            self.refresh_current();
+        }
+        for (game_id, first) in released.into_iter() {
+            if first || !self.is_current(&game_id) {
+                continue;
+            }
+            if let Some(game) = self.get(&game_id) {
+                self.send_current(TableUpdateType::Update, game);
+            }
+        }
+    }
+
     fn debug_print_clocks(ar_game: Arc<RwLock<Game>>) {
         let game = ar_game.read().unwrap();
         let board_clocks = game.get_clocks();
@@ -415,24 +514,32 @@ impl Games {
         &self,
         game_id: &GameID,
         recipient: Recipient<ClientMessage>,
-    ) {
+    ) -> Result<(), Error> {
         // Only observe if the user ISN'T playing a game
         let maybe_game = self.get(&game_id);
         if maybe_game.is_none() {
-            return;
+            return Ok(());
         }
         let locked_game = maybe_game.unwrap();
         let conn_id = ConnectionMgr::get_conn_id(&recipient);
-        if let Some(uid) = self.conns.uid_from_conn(&conn_id) {
+        {
             let game = locked_game.read().unwrap();
-            for player in Players::new(game.get_players()).get_players().iter()
-            {
-                if player.get_uid() == uid {
-                    return;
+            if let Some(uid) = self.conns.uid_from_conn(&conn_id) {
+                for player in
+                    Players::new(game.get_players()).get_players().iter()
+                {
+                    if player.get_uid() == uid {
+                        return Ok(());
+                    }
                 }
             }
+            if game.hidden {
+                return Err(Error::GameHidden(*game_id));
+            }
         }
-        self.game_observers.observe(*game_id, recipient);
+        self.game_observers.observe(*game_id, recipient)?;
+        self.notify_observer_count(locked_game);
+        Ok(())
     }
 
     pub fn unobserve(
@@ -440,11 +547,79 @@ impl Games {
         game_id: &GameID,
         recipient: Recipient<ClientMessage>,
     ) {
-        self.game_observers.unobserve(game_id, recipient);
+        if self.game_observers.unobserve(game_id, recipient) {
+            if let Some(game) = self.get(game_id) {
+                self.notify_observer_count(game);
+            }
+        }
     }
 
     pub fn remove_recipient(&self, recipient: &Recipient<ClientMessage>) {
-        self.game_observers.remove_recipient(recipient);
+        self.tv.write().unwrap().viewers.remove(&hash(recipient));
+        for game_id in self.game_observers.remove_recipient(recipient).iter() {
+            if let Some(game) = self.get(game_id) {
+                self.notify_observer_count(game);
+            }
+        }
+    }
+
+    pub fn observer_count(&self, game_id: &GameID) -> usize {
+        self.game_observers.count(game_id)
+    }
+
+    // {"kind": "observers", "id", "handles", "anon"}: who's watching, with
+    // anonymous observers only counted
+    pub fn observers_json(&self, game_id: &GameID) -> Value {
+        let (handles, anon) = self.game_observers.handles(game_id);
+        json!({
+            "kind": "observers",
+            "id": B66::encode_uuid(game_id),
+            "handles": handles,
+            "anon": anon,
+        })
+    }
+
+    // Tells the players and observers how many are watching
+    fn notify_observer_count(&self, ar_game: Arc<RwLock<Game>>) {
+        let game_id = *ar_game.read().unwrap().get_id();
+        let val = json!({
+            "kind": "observer_count",
+            "id": B66::encode_uuid(&game_id),
+            "count": self.observer_count(&game_id),
+        });
+        let bytestr = Arc::new(ByteString::from(val.to_string()));
+        let msg = ClientMessage::new(ClientMessageKind::Text(bytestr));
+        let players = Self::get_player_set(ar_game);
+        for uid in players.iter() {
+            self.conns.send_to_user(uid, &msg);
+        }
+        self.game_observers.notify(&game_id, &msg, players);
+        self.rerank.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_observing(
+        &self,
+        game_id: &GameID,
+        recipient: &Recipient<ClientMessage>,
+    ) -> bool {
+        self.game_observers.is_observing(game_id, recipient)
+    }
+
+    // Kibitzes go to the observers and players, whispers only the observers
+    pub fn notify_kibitz(
+        &self,
+        ar_game: Arc<RwLock<Game>>,
+        msg: &ClientMessage,
+        whisper: bool,
+    ) {
+        let game_id = *ar_game.read().unwrap().get_id();
+        let players = Self::get_player_set(ar_game);
+        if !whisper {
+            for uid in players.iter() {
+                self.conns.send_to_user(uid, msg);
+            }
+        }
+        self.game_observers.notify(&game_id, msg, players);
     }
 
     pub fn get_user_game(&self, uid: &UserID) -> Option<Arc<RwLock<Game>>> {
@@ -467,84 +642,287 @@ impl Games {
         wsubs.unsub(recipient);
     }
 
-    pub fn is_current(&self, gid: &GameID) -> bool {
-        let games = self.games.read().unwrap();
-        let mut idx = 0;
-        for (top_gid, _game) in
-            games.iter().filter(|g| !Self::is_table(g.1.clone()))
-        {
-            if top_gid == gid {
-                return true;
-            }
-            idx += 1;
-            if idx > CURRENT_MAX {
-                return false;
+    // Live games matching `filter`, best first (see featured::score).  Tables,
+    // hidden games and delayed games observers haven't been sent yet are left
+    // out
+    pub fn ranked_games(
+        &self,
+        filter: &CurrentGamesFilter,
+    ) -> Vec<Arc<RwLock<Game>>> {
+        let games: Vec<Arc<RwLock<Game>>> =
+            self.games.read().unwrap().values().cloned().collect();
+        let mut scored = vec![];
+        for ar_game in games.into_iter() {
+            let (game_id, rated, category, avg_rating) = {
+                let game = ar_game.read().unwrap();
+                if game.has_empty_seat() || game.hidden {
+                    continue;
+                }
+                if Self::is_delayed(&game)
+                    && self.game_observers.released(game.get_id()).is_none()
+                {
+                    continue;
+                }
+                let category = RatingCategory::of(&game.time_ctrl);
+                let avg_rating = Self::avg_rating(&game, category);
+                (*game.get_id(), game.rated, category, avg_rating)
+            };
+            if !filter.matches(rated, category, avg_rating) {
+                continue;
             }
+            let observers = self.observer_count(&game_id);
+            let score = featured::score(avg_rating, observers, category);
+            scored.push((score, game_id, ar_game));
+        }
+        // Equal scores are ordered by ID so the order doesn't flicker
+        scored.sort_by(|a, b| {
+            b.0.partial_cmp(&a.0)
+                .unwrap_or(Ordering::Equal)
+                .then(a.1.cmp(&b.1))
+        });
+        scored.into_iter().map(|(_, _, game)| game).collect()
+    }
+
+    fn avg_rating(game: &Game, category: RatingCategory) -> f64 {
+        let ratings: Vec<f64> = game
+            .players
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|user| user.read().unwrap().get_rating(category).rating as f64)
+            .collect();
+        if ratings.is_empty() {
+            return 0.0;
         }
-        return false;
+        ratings.iter().sum::<f64>() / ratings.len() as f64
     }
 
-    pub fn get_current_games_json(&self) -> Value {
-        let games = self.games.read().unwrap();
-        let mut jsons: HashMap<String, Value> = HashMap::new();
-        for (_gid, game) in
-            games.iter().filter(|g| !Self::is_table(g.1.clone()))
-        {
-            let (id, value) =
-                Self::get_table_json(game.clone(), GameJsonKind::Currents);
-            jsons.insert(id, value);
-            if jsons.len() >= CURRENT_MAX {
-                break;
-            }
-        }
-        json!({ "kind": "current_games", "games": jsons })
+    // In the top CURRENT_MAX, which current games subscribers are sent
+    pub fn is_current(&self, gid: &GameID) -> bool {
+        self.current.read().unwrap().contains(gid)
     }
 
-    // After removing a game, see if there is one to add (at the end)
-    fn notify_next_current(&self) {
-        let games = self.games.read().unwrap();
-        if let Some((_gid, game)) = games
+    // `count` of the ranked games from `offset`, in order, and how many there
+    // are in all
+    pub fn current_games(
+        &self,
+        filter: &CurrentGamesFilter,
+        offset: usize,
+        count: usize,
+    ) -> (Vec<GameJson>, usize) {
+        let ranked = self.ranked_games(filter);
+        let total = ranked.len();
+        let games = ranked
+            .into_iter()
+            .skip(offset)
+            .take(count)
+            .filter_map(|game| self.current_json(game, GameJsonKind::Currents))
+            .collect();
+        (games, total)
+    }
+
+    // current_games() as one message, with the order they rank in
+    pub fn get_current_games_json(
+        &self,
+        filter: &CurrentGamesFilter,
+        offset: usize,
+        count: usize,
+    ) -> Value {
+        let (games, total) = self.current_games(filter, offset, count);
+        let order: Vec<String> = games
             .iter()
-            .filter(|g| !Self::is_table(g.1.clone()))
-            .nth(CURRENT_MAX - 1)
-        {
-            return self
-                .notify_current_subs(TableUpdateType::Add, game.clone());
+            .map(|game| B66::encode_uuid(game.get_id()))
+            .collect();
+        let jsons: HashMap<String, Value> = order
+            .iter()
+            .cloned()
+            .zip(games.iter().map(GameJson::to_val))
+            .collect();
+        json!({
+            "kind": "current_games",
+            "games": jsons,
+            "order": order,
+            "total": total,
+        })
+    }
+
+    // Ranks the games again if observer counts have changed since they last
+    // were.  Observers come and go much more often than games start and end,
+    // so they're only counted every so often
+    pub fn rerank_current(&self) {
+        if self.rerank.swap(false, atomic::Ordering::Relaxed) {
+            self.refresh_current();
         }
     }
 
+    // Tells current games subscribers about games entering and leaving the
+    // top CURRENT_MAX, then moves TV on if its game is over
+    fn refresh_current(&self) {
+        self.rerank.store(false, atomic::Ordering::Relaxed);
+        let top: Vec<Arc<RwLock<Game>>> = self
+            .ranked_games(&CurrentGamesFilter::default())
+            .into_iter()
+            .take(CURRENT_MAX)
+            .collect();
+        let top_ids: Vec<GameID> =
+            top.iter().map(|g| *g.read().unwrap().get_id()).collect();
+        let old = {
+            let mut current = self.current.write().unwrap();
+            std::mem::replace(&mut *current, top_ids.clone())
+        };
+        let (left, entered) = featured::page_changes(&old, &top_ids);
+        for game_id in left.iter() {
+            let msg = json!({
+                "kind": GameJsonKind::Current,
+                "id": B66::encode_uuid(game_id),
+                "rm": true,
+            });
+            self.current_game_subs.write().unwrap().notify_value(msg);
+        }
+        for game in top.into_iter() {
+            let game_id = *game.read().unwrap().get_id();
+            if entered.contains(&game_id) {
+                self.send_current(TableUpdateType::Add, game);
+            }
+        }
+        self.refresh_tv(top_ids.first().copied());
+    }
+
+    // Moves don't change the ranking, so only games starting re-rank it.
+    // Updates to delayed games go out as observers are sent them (see
+    // flush_observers())
     fn notify_current_subs(
         &self,
         kind: TableUpdateType,
         game: Arc<RwLock<Game>>,
     ) {
-        if !self.is_current(game.read().unwrap().get_id()) {
+        if kind != TableUpdateType::Update {
+            self.refresh_current();
             return;
         }
-        let msg = match kind {
-            TableUpdateType::Add | TableUpdateType::Update => {
-                let (id, json) =
-                    Self::get_table_json(game, GameJsonKind::Current);
-                json!({
-                    "kind": GameJsonKind::Current,
-                    "id": id,
-                    "add": kind == TableUpdateType::Add,
-                    "update": kind == TableUpdateType::Update,
-                    "game": json,
-                })
-            }
-            TableUpdateType::Remove => {
-                json!({
-                    "kind": GameJsonKind::Current,
-                    "id": B66::encode_uuid(game.read().unwrap().get_id()),
-                    "rm": true,
-                })
-            }
+        let (game_id, delayed) = {
+            let game = game.read().unwrap();
+            (*game.get_id(), Self::is_delayed(&game))
         };
+        if !delayed && self.is_current(&game_id) {
+            self.send_current(kind, game);
+        }
+    }
+
+    // The game as current games subscribers see it: delayed games as their
+    // observers do (see observer_payload())
+    fn current_json(
+        &self,
+        game: Arc<RwLock<Game>>,
+        kind: GameJsonKind,
+    ) -> Option<GameJson> {
+        let (game_id, delayed) = {
+            let game = game.read().unwrap();
+            (*game.get_id(), Self::is_delayed(&game))
+        };
+        if !delayed {
+            return Some(GameJson::new(game, kind));
+        }
+        let mut game_json = self.game_observers.released_game(&game_id)?;
+        game_json.set_kind(kind);
+        Some(game_json)
+    }
+
+    fn send_current(&self, kind: TableUpdateType, game: Arc<RwLock<Game>>) {
+        let json = match self.current_json(game, GameJsonKind::Current) {
+            Some(current) => current.to_val(),
+            None => return,
+        };
+        let id = json["id"].clone();
+        let msg = json!({
+            "kind": GameJsonKind::Current,
+            "id": id,
+            "add": kind == TableUpdateType::Add,
+            "update": kind == TableUpdateType::Update,
+            "game": json,
+        });
         let mut wsubs = self.current_game_subs.write().unwrap();
         wsubs.notify_value(msg);
     }
 
+    // Shows `recipient` the top game, and the next top game whenever the one
+    // it's showing ends
+    pub fn sub_tv(&self, recipient: Recipient<ClientMessage>) {
+        let game_id = {
+            let mut tv = self.tv.write().unwrap();
+            tv.viewers.insert(hash(&recipient), recipient.clone());
+            tv.game_id
+        };
+        self.tune_in(game_id, &recipient);
+        if game_id.is_none() {
+            self.refresh_current();
+        }
+    }
+
+    pub fn unsub_tv(&self, recipient: Recipient<ClientMessage>) {
+        let game_id = {
+            let mut tv = self.tv.write().unwrap();
+            tv.viewers.remove(&hash(&recipient));
+            tv.game_id
+        };
+        if let Some(game_id) = game_id {
+            if self.is_observing(&game_id, &recipient) {
+                self.unobserve(&game_id, recipient);
+            }
+        }
+    }
+
+    // Switches TV to `top` if the game it's showing is over
+    fn refresh_tv(&self, top: Option<GameID>) {
+        let showing = self.tv.read().unwrap().game_id;
+        if showing == top || showing.map_or(false, |id| self.get(&id).is_some())
+        {
+            return;
+        }
+        let viewers: Vec<Recipient<ClientMessage>> = {
+            let mut tv = self.tv.write().unwrap();
+            tv.game_id = top;
+            tv.viewers.values().cloned().collect()
+        };
+        for recipient in viewers.iter() {
+            self.tune_in(top, recipient);
+        }
+    }
+
+    // {"kind": "tv", "id"}, then the game as observers see it
+    fn tune_in(
+        &self,
+        game_id: Option<GameID>,
+        recipient: &Recipient<ClientMessage>,
+    ) {
+        let val = json!({
+            "kind": "tv",
+            "id": game_id.map(|id| B66::encode_uuid(&id)),
+        });
+        let send = |text: ByteString| {
+            let msg =
+                ClientMessage::new(ClientMessageKind::Text(Arc::new(text)));
+            if let Err(e) = recipient.try_send(msg) {
+                eprintln!("Failed sending to TV viewer: {}", e);
+            }
+        };
+        send(ByteString::from(val.to_string()));
+        let game_id = match game_id {
+            Some(game_id) => game_id,
+            None => return,
+        };
+        if let Err(e) = self.observe(&game_id, recipient.clone()) {
+            eprintln!("TV couldn't observe {}: {}", game_id, e);
+            return;
+        }
+        if let Some(payload) = self
+            .get(&game_id)
+            .and_then(|game| self.observer_payload(game))
+        {
+            send(payload);
+        }
+    }
+
     pub fn sub_public_tables(&self, recipient: Recipient<ClientMessage>) {
         let mut wsubs = self.public_table_subs.write().unwrap();
         wsubs.sub(recipient);
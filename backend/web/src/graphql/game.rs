@@ -0,0 +1,393 @@
+use super::query::GraphQLGameResult;
+use crate::b66::B66;
+use crate::bpgn;
+use crate::bug_web_sock::BugContext;
+use crate::db::{KibitzRow, SignalRow};
+use crate::game_row::GameRow;
+use crate::kibitz;
+use crate::partner_signals::PartnerSignal;
+use crate::time_control::TimeControl;
+use crate::user_loader::HandleLoader;
+use async_graphql::dataloader::DataLoader;
+use async_graphql::{Context, Object};
+use bughouse::{BoardID, BughouseMove, Color, ALL_COLORS, BOARD_IDS};
+use chrono::prelude::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct DecodedMove {
+    pub board: BoardID,
+    pub color: Color,
+    // Full move number on its board, starting at 1
+    pub number: i32,
+    // Since the game started
+    pub ms: i32,
+    // Mover's clock right after the move
+    pub clock: i32,
+    pub mv: BughouseMove,
+}
+
+// Moves in the order they were made.  Keys are (ms since start << 1) | board
+// (see Db::to_move_key), and clocks are replayed the way Game::update_clocks
+// runs them.
+pub fn decode_moves(
+    moves: &HashMap<i32, i16>,
+    time_ctrl: &TimeControl,
+) -> Vec<DecodedMove> {
+    let mut keys: Vec<i32> = moves.keys().cloned().collect();
+    keys.sort_unstable();
+    let base = time_ctrl.get_base_ms();
+    let inc = time_ctrl.get_inc_ms() as i32;
+    let mut clocks = [[base; 2]; 2];
+    let mut last_move_ms = [0; 2];
+    let mut plies = [0; 2];
+    keys.into_iter()
+        .map(|key| {
+            let board_idx = (key & 1) as usize;
+            let ms = key >> 1;
+            let color_idx = plies[board_idx] % 2;
+            clocks[board_idx][color_idx] +=
+                inc - (ms - last_move_ms[board_idx]);
+            last_move_ms[board_idx] = ms;
+            plies[board_idx] += 1;
+            DecodedMove {
+                board: BOARD_IDS[board_idx],
+                color: ALL_COLORS[color_idx],
+                number: ((plies[board_idx] + 1) / 2) as i32,
+                ms,
+                clock: clocks[board_idx][color_idx],
+                mv: GameRow::deserialize_move(moves[&key]),
+            }
+        })
+        .collect()
+}
+
+pub struct GraphQLMove(DecodedMove);
+
+#[Object(name = "Move")]
+impl GraphQLMove {
+    async fn board(&self) -> i32 {
+        self.0.board as i32
+    }
+
+    async fn color(&self) -> i32 {
+        self.0.color as i32
+    }
+
+    async fn number(&self) -> i32 {
+        self.0.number
+    }
+
+    /// Milliseconds since the game started
+    async fn ms(&self) -> i32 {
+        self.0.ms
+    }
+
+    /// Mover's remaining time, in milliseconds, right after the move
+    async fn clock(&self) -> i32 {
+        self.0.clock
+    }
+
+    /// Null for drops
+    async fn source(&self) -> Option<String> {
+        self.0.mv.get_source().map(|sq| sq.to_string())
+    }
+
+    async fn dest(&self) -> String {
+        self.0.mv.get_dest().to_string()
+    }
+
+    /// Dropped piece or promotion, if any
+    async fn piece(&self) -> Option<String> {
+        self.0.mv.get_piece().map(|p| p.to_string(Color::Black))
+    }
+}
+
+pub struct GamePlayer {
+    seat: usize,
+    uid: String,
+    handle: String,
+    rating: i16,
+}
+
+#[Object]
+impl GamePlayer {
+    async fn board(&self) -> i32 {
+        BOARD_IDS[self.seat / 2] as i32
+    }
+
+    async fn color(&self) -> i32 {
+        ALL_COLORS[self.seat % 2] as i32
+    }
+
+    async fn uid(&self) -> String {
+        self.uid.to_string()
+    }
+
+    async fn handle(&self) -> String {
+        self.handle.to_string()
+    }
+
+    /// Rating when the game started
+    async fn rating(&self) -> i16 {
+        self.rating
+    }
+}
+
+pub struct GraphQLSignal(SignalRow);
+
+#[Object(name = "PartnerSignal")]
+impl GraphQLSignal {
+    /// Milliseconds since the game started
+    async fn ms(&self) -> i32 {
+        self.0.ms
+    }
+
+    async fn sender(&self) -> String {
+        B66::encode_uuid(&self.0.sender)
+    }
+
+    /// "need", "no", "sit", "go", "trade_ok", "mates", "watch_time" or
+    /// "text"
+    async fn kind(&self) -> String {
+        self.0.kind.to_string()
+    }
+
+    /// Piece (p, n, b, r or q) for "need" and "no"
+    async fn piece(&self) -> Option<String> {
+        match PartnerSignal::from_parts(&self.0.kind, &self.0.payload) {
+            Some(PartnerSignal::Need(piece))
+            | Some(PartnerSignal::No(piece)) => Some(piece.to_string()),
+            _ => None,
+        }
+    }
+
+    async fn text(&self) -> Option<String> {
+        match PartnerSignal::from_parts(&self.0.kind, &self.0.payload) {
+            Some(PartnerSignal::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+pub struct GraphQLKibitz(KibitzRow);
+
+#[Object(name = "Kibitz")]
+impl GraphQLKibitz {
+    /// Milliseconds since the game started
+    async fn ms(&self) -> i32 {
+        self.0.ms
+    }
+
+    async fn sender(&self) -> String {
+        B66::encode_uuid(&self.0.sender)
+    }
+
+    async fn sender_handle<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Option<String>> {
+        let loader = ctx.data::<DataLoader<HandleLoader>>()?;
+        Ok(loader.load_one(self.0.sender).await?)
+    }
+
+    /// Whether only observers saw it during the game
+    async fn whisper(&self) -> bool {
+        self.0.whisper
+    }
+
+    async fn body(&self) -> String {
+        self.0.body.to_string()
+    }
+}
+
+pub struct GraphQLGame(pub GameRow);
+
+impl GraphQLGame {
+    async fn handles<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<[String; 4]> {
+        let loader = ctx.data::<DataLoader<HandleLoader>>()?;
+        let ((aw, ab), (bw, bb)) = &self.0.players;
+        let uids = [aw.uid, ab.uid, bw.uid, bb.uid];
+        let uid2handle = loader.load_many(uids.iter().cloned()).await?;
+        let handle = |i: usize| {
+            uid2handle
+                .get(&uids[i])
+                .cloned()
+                .unwrap_or_else(|| "<None>".into())
+        };
+        Ok([handle(0), handle(1), handle(2), handle(3)])
+    }
+
+    // Observers may be seeing the game late (see Games::is_delayed), so
+    // its moves aren't given out while it's in progress
+    fn finished_moves(&self) -> Option<&HashMap<i32, i16>> {
+        if self.0.result < 0 {
+            return None;
+        }
+        self.0.moves.as_ref()
+    }
+
+    // Signals are private to each team until the game is over, so none
+    // while it's in progress
+    async fn finished_signals<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Vec<SignalRow>> {
+        if self.0.result < 0 {
+            return Ok(vec![]);
+        }
+        let bug_ctx = ctx.data::<BugContext>()?;
+        Ok(bug_ctx.db.get_signals(&self.0.id).await?)
+    }
+}
+
+#[Object(name = "Game")]
+impl GraphQLGame {
+    // GraphQL ID
+    async fn id(&self) -> String {
+        format!("{}:{}", "game", B66::encode_uuid(&self.0.id))
+    }
+
+    async fn gid(&self) -> String {
+        B66::encode_uuid(&self.0.id)
+    }
+
+    async fn start_time(&self) -> String {
+        let ms = self.0.start_time.num_milliseconds();
+        Utc.timestamp_millis(ms).to_rfc3339()
+    }
+
+    /// "base|inc"
+    async fn time_ctrl(&self) -> String {
+        self.0.time_ctrl.to_string()
+    }
+
+    async fn rated(&self) -> bool {
+        self.0.rated
+    }
+
+    /// Null while the game is in progress
+    async fn result(&self) -> Option<GraphQLGameResult> {
+        if self.0.result < 0 {
+            return None;
+        }
+        Some(GraphQLGameResult(GameRow::deserialize_result(
+            self.0.result,
+        )))
+    }
+
+    /// Board A white, board A black, board B white, board B black
+    async fn players<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Vec<GamePlayer>> {
+        let handles = self.handles(ctx).await?;
+        let ((aw, ab), (bw, bb)) = &self.0.players;
+        let snaps = [aw, ab, bw, bb];
+        Ok(snaps
+            .iter()
+            .zip(handles.iter())
+            .enumerate()
+            .map(|(seat, (s, handle))| GamePlayer {
+                seat,
+                uid: B66::encode_uuid(&s.uid),
+                handle: handle.to_string(),
+                rating: s.rating,
+            })
+            .collect())
+    }
+
+    /// Both boards' moves, in the order they were made.  Empty until the
+    /// game is over
+    async fn moves(&self) -> Vec<GraphQLMove> {
+        let moves = match self.finished_moves() {
+            Some(moves) => moves,
+            None => return vec![],
+        };
+        decode_moves(moves, &self.0.time_ctrl)
+            .into_iter()
+            .map(GraphQLMove)
+            .collect()
+    }
+
+    /// Partner signals, in the order they were sent.  Empty until the game
+    /// is over
+    async fn signals<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Vec<GraphQLSignal>> {
+        let signals = self.finished_signals(ctx).await?;
+        Ok(signals.into_iter().map(GraphQLSignal).collect())
+    }
+
+    /// Spectator chat, in the order it was sent.  Whispers are left out
+    /// until the game is over
+    async fn kibitzes<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Vec<GraphQLKibitz>> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let is_over = self.0.result >= 0;
+        let kibitzes = bug_ctx.db.get_kibitzes(&self.0.id).await?;
+        Ok(kibitzes
+            .into_iter()
+            .filter(|row| kibitz::is_visible(row, is_over))
+            .map(GraphQLKibitz)
+            .collect())
+    }
+
+    /// The game in BPGN, with partner signals as comments.  Only the
+    /// headers until it's over
+    async fn bpgn<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<String> {
+        let handles = self.handles(ctx).await?;
+        let signals = self.finished_signals(ctx).await?;
+        if self.0.result < 0 {
+            let row = GameRow {
+                moves: None,
+                ..self.0.clone()
+            };
+            return Ok(bpgn::to_bpgn(&row, &handles, &signals));
+        }
+        Ok(bpgn::to_bpgn(&self.0, &handles, &signals))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bughouse::{Piece, Square};
+
+    #[test]
+    fn decode() {
+        let e4 = BughouseMove::new(Some(Square::E2), Square::E4, None);
+        let e5 = BughouseMove::new(Some(Square::E7), Square::E5, None);
+        let drop = BughouseMove::new(None, Square::F7, Some(Piece::Knight));
+        let mut moves = HashMap::new();
+        moves.insert(2500 << 1, GameRow::serialize_move(&e5));
+        moves.insert(1000 << 1, GameRow::serialize_move(&e4));
+        moves.insert((1500 << 1) | 1, GameRow::serialize_move(&e4));
+        moves.insert(4000 << 1, GameRow::serialize_move(&drop));
+        let decoded = decode_moves(&moves, &TimeControl::new(3, 2));
+
+        let summary: Vec<(BoardID, Color, i32, i32)> = decoded
+            .iter()
+            .map(|m| (m.board, m.color, m.number, m.clock))
+            .collect();
+        assert!(
+            summary
+                == vec![
+                    (BoardID::A, Color::White, 1, 181_000),
+                    (BoardID::B, Color::White, 1, 180_500),
+                    (BoardID::A, Color::Black, 1, 180_500),
+                    (BoardID::A, Color::White, 2, 181_500),
+                ]
+        );
+        assert!(decoded[2].mv == e5 && decoded[3].mv == drop);
+    }
+}
@@ -0,0 +1,249 @@
+use super::user_stats::GraphQLGameResultType;
+use crate::b66::B66;
+use crate::db::Db;
+use crate::error::Error;
+use crate::game::GameResultType;
+use crate::game_row::{GameRow, UserGameRow};
+use crate::rating::Team;
+use crate::time_control::TimeControl;
+use crate::users::UserID;
+use async_graphql::InputObject;
+use chrono::prelude::*;
+use futures::future::join_all;
+use scylla::transport::session::IntoTypedRows;
+use std::sync::Arc;
+
+// user_games rows read at a time while searching
+const SEARCH_PAGE: i32 = 200;
+
+#[derive(InputObject)]
+pub struct GameFilter {
+    /// uid of a player in the game
+    pub player: String,
+    /// uid of `player`'s partner
+    pub partner: Option<String>,
+    /// uid of one of `player`'s opponents
+    pub opponent: Option<String>,
+    /// Started at or after, RFC 3339
+    pub from: Option<String>,
+    /// Started before, RFC 3339
+    pub to: Option<String>,
+    pub result: Option<GraphQLGameResultType>,
+    pub rated: Option<bool>,
+    /// "base|inc"
+    pub time_ctrl: Option<String>,
+}
+
+// A GameFilter, parsed.  Only finished games match
+#[derive(Default)]
+pub struct GameSearch {
+    pub player: UserID,
+    pub partner: Option<UserID>,
+    pub opponent: Option<UserID>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub result: Option<GameResultType>,
+    pub rated: Option<bool>,
+    pub time_ctrl: Option<TimeControl>,
+}
+
+pub fn parse_uid(field: &str, b66: &str) -> Result<UserID, Error> {
+    B66::decode_uuid(b66).ok_or_else(|| {
+        Error::Unexpected(format!("Invalid {} uid: {}", field, b66))
+    })
+}
+
+fn parse_time(field: &str, rfc3339: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_rfc3339(rfc3339)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| {
+            Error::Unexpected(format!("Invalid {} time: {}", field, rfc3339))
+        })
+}
+
+impl GameSearch {
+    pub fn from_filter(filter: GameFilter) -> Result<Self, Error> {
+        Ok(GameSearch {
+            player: parse_uid("player", &filter.player)?,
+            partner: filter
+                .partner
+                .map(|p| parse_uid("partner", &p))
+                .transpose()?,
+            opponent: filter
+                .opponent
+                .map(|o| parse_uid("opponent", &o))
+                .transpose()?,
+            from: filter.from.map(|t| parse_time("from", &t)).transpose()?,
+            to: filter.to.map(|t| parse_time("to", &t)).transpose()?,
+            result: filter.result.map(|r| r.into()),
+            rated: filter.rated,
+            time_ctrl: filter
+                .time_ctrl
+                .map(|tc| tc.parse::<TimeControl>())
+                .transpose()?,
+        })
+    }
+
+    // Everything but the time control, which user_games doesn't have
+    pub fn matches(&self, row: &UserGameRow) -> bool {
+        if row.result < 0 || self.rated.map_or(false, |r| r != row.rated) {
+            return false;
+        }
+        if let Some(kind) = self.result {
+            if GameRow::deserialize_result(row.result).kind != kind {
+                return false;
+            }
+        }
+        let ((aw, ab), (bw, bb)) = &row.players;
+        let uids = [aw.uid, ab.uid, bw.uid, bb.uid];
+        let seat = match uids.iter().position(|u| *u == self.player) {
+            Some(seat) => seat,
+            None => return false,
+        };
+        let team = Team::of(seat);
+        let on_team = |uid: &UserID, same: bool| {
+            uids.iter().enumerate().any(|(i, u)| {
+                i != seat && u == uid && (Team::of(i) == team) == same
+            })
+        };
+        if let Some(partner) = &self.partner {
+            if !on_team(partner, true) {
+                return false;
+            }
+        }
+        if let Some(opponent) = &self.opponent {
+            if !on_team(opponent, false) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn matches_time_ctrl(&self, row: &GameRow) -> bool {
+        self.time_ctrl
+            .as_ref()
+            .map_or(true, |tc| *tc == row.time_ctrl)
+    }
+}
+
+pub struct GamesFetcher {
+    db: Arc<Db>,
+}
+
+impl GamesFetcher {
+    pub fn new(db: Arc<Db>) -> Self {
+        Self { db }
+    }
+
+    // Up to `count` matching games that started before `cursor` (and `to`),
+    // most recent first, and whether there are more.  Reads `player`'s
+    // user_games until it has enough.
+    pub async fn search(
+        &self,
+        search: &GameSearch,
+        cursor: Option<DateTime<Utc>>,
+        count: usize,
+    ) -> Result<(Vec<GameRow>, bool), Error> {
+        let mut before = match (cursor, search.to) {
+            (Some(c), Some(to)) => c.min(to),
+            (c, to) => c.or(to).unwrap_or_else(Utc::now),
+        };
+        let from = search.from.unwrap_or_else(|| Utc.timestamp_millis(0));
+        let mut found = vec![];
+        loop {
+            let res = self
+                .db
+                .session()
+                .query(
+                    "SELECT uid, start_time, game_id, result, rated, players
+                    FROM bughouse.user_games
+                    WHERE uid = ? AND start_time < ? AND start_time >= ?
+                    ORDER BY start_time DESC
+                    LIMIT ?",
+                    (
+                        &search.player,
+                        Db::to_timestamp(before),
+                        Db::to_timestamp(from),
+                        SEARCH_PAGE,
+                    ),
+                )
+                .await?;
+            let mut rows = vec![];
+            if let Some(res_rows) = res.rows {
+                for row in res_rows.into_typed::<UserGameRow>() {
+                    rows.push(row?);
+                }
+            }
+            let candidates = rows
+                .iter()
+                .filter(|row| search.matches(row))
+                .map(|row| self.db.get_game_row(&row.game_id));
+            for game_row in join_all(candidates).await.into_iter() {
+                let game_row = game_row?;
+                if search.matches_time_ctrl(&game_row) {
+                    found.push(game_row);
+                }
+            }
+            if found.len() > count || rows.len() < SEARCH_PAGE as usize {
+                break;
+            }
+            let last = rows.last().unwrap().start_time.num_milliseconds();
+            before = Utc.timestamp_millis(last);
+        }
+        let has_more = found.len() > count;
+        found.truncate(count);
+        Ok((found, has_more))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game::GameResult;
+    use crate::game_row::fixtures;
+    use bughouse::{BoardID, Color};
+
+    #[test]
+    fn matches() {
+        let uids = fixtures::uids();
+        let row = UserGameRow {
+            uid: uids[0],
+            result: GameRow::serialize_result(&GameResult {
+                board: BoardID::A,
+                winner: Color::White,
+                kind: GameResultType::Checkmate,
+            }),
+            rated: true,
+            players: fixtures::players(&uids, 0),
+            ..UserGameRow::default()
+        };
+        // aw's partner is bb
+        let search = |partner, opponent| GameSearch {
+            player: uids[0],
+            partner,
+            opponent,
+            ..GameSearch::default()
+        };
+        assert!(search(None, None).matches(&row));
+        assert!(search(Some(uids[3]), Some(uids[1])).matches(&row));
+        assert!(!search(Some(uids[1]), None).matches(&row));
+        assert!(!search(None, Some(uids[3])).matches(&row));
+        assert!(!search(None, Some(uids[0])).matches(&row));
+
+        let flagged = GameSearch {
+            result: Some(GameResultType::Flagged),
+            ..search(None, None)
+        };
+        assert!(!flagged.matches(&row));
+        let unrated = GameSearch {
+            rated: Some(false),
+            ..search(None, None)
+        };
+        assert!(!unrated.matches(&row));
+        let in_progress = UserGameRow {
+            result: -1,
+            ..row.clone()
+        };
+        assert!(!search(None, None).matches(&in_progress));
+    }
+}
@@ -0,0 +1,271 @@
+use super::game::GraphQLGame;
+use super::user_stats::Record;
+use crate::b66::B66;
+use crate::db::{Db, TableSnapshot};
+use crate::error::Error;
+use crate::game_row::{GameRow, UserGameRow};
+use crate::rating::Team;
+use crate::time_control::TimeControl;
+use crate::users::UserID;
+use async_graphql::Object;
+use futures::future::join_all;
+use scylla::transport::session::IntoTypedRows;
+
+// Game rows read at a time
+const GAME_BATCH: usize = 50;
+
+// The team `side` (one player or a partnership) played on against `other`,
+// if they all played in the game, partners together and against each other
+pub fn side_team(
+    players: &TableSnapshot,
+    side: &[UserID],
+    other: &[UserID],
+) -> Option<Team> {
+    let ((aw, ab), (bw, bb)) = players;
+    let uids = [aw.uid, ab.uid, bw.uid, bb.uid];
+    let team_of =
+        |uid: &UserID| uids.iter().position(|u| u == uid).map(Team::of);
+    let team = team_of(side.first()?)?;
+    let together = |uids: &[UserID], team: Team| {
+        uids.iter().all(|uid| team_of(uid) == Some(team))
+    };
+    let other_team = if team == Team::A { Team::B } else { Team::A };
+    if together(side, team) && together(other, other_team) {
+        Some(team)
+    } else {
+        None
+    }
+}
+
+pub struct Bucket {
+    pub rated: bool,
+    pub time_ctrl: TimeControl,
+    pub record: Record,
+    // Indexes into HeadToHead::games
+    pub games: Vec<usize>,
+}
+
+// Record of `side` against `other`, overall and by rated/unrated and time
+// control, over finished games
+#[derive(Default)]
+pub struct HeadToHead {
+    pub total: Record,
+    pub buckets: Vec<Bucket>,
+    // Most recent first
+    pub games: Vec<GameRow>,
+}
+
+impl HeadToHead {
+    // Games must be added most recent first
+    pub fn add_game(
+        &mut self,
+        game: GameRow,
+        side: &[UserID],
+        other: &[UserID],
+    ) {
+        if game.result < 0 {
+            return;
+        }
+        let team = match side_team(&game.players, side, other) {
+            Some(team) => team,
+            None => return,
+        };
+        let won =
+            team == Team::winner(&GameRow::deserialize_result(game.result));
+        self.total.add(won);
+        let idx = self.games.len();
+        let bucket = self.buckets.iter().position(|b| {
+            b.rated == game.rated && b.time_ctrl == game.time_ctrl
+        });
+        let bucket = match bucket {
+            Some(i) => &mut self.buckets[i],
+            None => {
+                self.buckets.push(Bucket {
+                    rated: game.rated,
+                    time_ctrl: game.time_ctrl.clone(),
+                    record: Record::default(),
+                    games: vec![],
+                });
+                self.buckets.last_mut().unwrap()
+            }
+        };
+        bucket.record.add(won);
+        bucket.games.push(idx);
+        self.games.push(game);
+    }
+
+    // Reads the first of `side`'s user_games, then the games with `other`
+    pub async fn fetch(
+        db: &Db,
+        side: &[UserID],
+        other: &[UserID],
+    ) -> Result<HeadToHead, Error> {
+        let uid = side
+            .first()
+            .ok_or_else(|| Error::Unexpected("Empty side".into()))?;
+        let res = db
+            .session()
+            .query(
+                "SELECT uid, start_time, game_id, result, rated, players
+                FROM bughouse.user_games
+                WHERE uid = ?
+                ORDER BY start_time DESC",
+                (uid,),
+            )
+            .await?;
+        let mut game_ids = vec![];
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<UserGameRow>() {
+                let row = row?;
+                if row.result >= 0
+                    && side_team(&row.players, side, other).is_some()
+                {
+                    game_ids.push(row.game_id);
+                }
+            }
+        }
+        let mut h2h = HeadToHead::default();
+        for chunk in game_ids.chunks(GAME_BATCH) {
+            let games = join_all(chunk.iter().map(|id| db.get_game_row(id)));
+            for game in games.await.into_iter() {
+                h2h.add_game(game?, side, other);
+            }
+        }
+        h2h.buckets
+            .sort_by_key(|b| (!b.rated, b.time_ctrl.to_string()));
+        Ok(h2h)
+    }
+}
+
+pub struct GraphQLHeadToHeadBucket<'a> {
+    bucket: &'a Bucket,
+    games: &'a [GameRow],
+}
+
+#[Object(name = "HeadToHeadBucket")]
+impl<'a> GraphQLHeadToHeadBucket<'a> {
+    async fn rated(&self) -> bool {
+        self.bucket.rated
+    }
+
+    /// "base|inc"
+    async fn time_ctrl(&self) -> String {
+        self.bucket.time_ctrl.to_string()
+    }
+
+    async fn record(&self) -> Record {
+        self.bucket.record
+    }
+
+    /// Most recent first
+    async fn games(&self, first: Option<i32>) -> Vec<GraphQLGame> {
+        let count = first.map_or(20, |n| n.max(0) as usize);
+        self.bucket
+            .games
+            .iter()
+            .take(count)
+            .map(|i| GraphQLGame(self.games[*i].clone()))
+            .collect()
+    }
+}
+
+pub struct GraphQLHeadToHead {
+    pub side: Vec<UserID>,
+    pub other: Vec<UserID>,
+    pub h2h: HeadToHead,
+}
+
+impl GraphQLHeadToHead {
+    pub async fn fetch(
+        db: &Db,
+        side: Vec<UserID>,
+        other: Vec<UserID>,
+    ) -> Result<Self, Error> {
+        let mut all: Vec<&UserID> = side.iter().chain(other.iter()).collect();
+        all.sort_unstable();
+        all.dedup();
+        if all.len() != side.len() + other.len() {
+            return Err(Error::Unexpected("Players must be distinct".into()));
+        }
+        let h2h = HeadToHead::fetch(db, &side, &other).await?;
+        Ok(GraphQLHeadToHead { side, other, h2h })
+    }
+}
+
+#[Object(name = "HeadToHead")]
+impl GraphQLHeadToHead {
+    /// The player (or partnership) the records are for
+    async fn side(&self) -> Vec<String> {
+        self.side.iter().map(|uid| B66::encode_uuid(uid)).collect()
+    }
+
+    /// Their opponent (or opposing partnership)
+    async fn opponents(&self) -> Vec<String> {
+        self.other.iter().map(|uid| B66::encode_uuid(uid)).collect()
+    }
+
+    async fn total(&self) -> Record {
+        self.h2h.total
+    }
+
+    /// Rated first, then by time control
+    async fn buckets(&self) -> Vec<GraphQLHeadToHeadBucket<'_>> {
+        self.h2h
+            .buckets
+            .iter()
+            .map(|bucket| GraphQLHeadToHeadBucket {
+                bucket,
+                games: &self.h2h.games,
+            })
+            .collect()
+    }
+
+    /// Most recent first
+    async fn games(&self, first: Option<i32>) -> Vec<GraphQLGame> {
+        let count = first.map_or(20, |n| n.max(0) as usize);
+        self.h2h
+            .games
+            .iter()
+            .take(count)
+            .cloned()
+            .map(GraphQLGame)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game::{GameResult, GameResultType};
+    use crate::game_row::fixtures;
+    use bughouse::{BoardID, Color};
+
+    #[test]
+    fn add_game() {
+        let uids = fixtures::uids();
+        // Team A (aw, bb) wins
+        let checkmate = GameResult {
+            board: BoardID::A,
+            winner: Color::White,
+            kind: GameResultType::Checkmate,
+        };
+        let game = |rated: bool, base: i16| {
+            fixtures::game(&uids, &checkmate, TimeControl::new(base, 0), rated)
+        };
+        let (aw, ab, bw, bb) = (uids[0], uids[1], uids[2], uids[3]);
+
+        let mut h2h = HeadToHead::default();
+        h2h.add_game(game(true, 3), &[ab], &[aw]);
+        h2h.add_game(game(true, 3), &[ab], &[aw]);
+        h2h.add_game(game(false, 1), &[ab], &[aw]);
+        // Partners, not opponents
+        h2h.add_game(game(true, 3), &[ab], &[bw]);
+        assert!(h2h.total.wins == 0 && h2h.total.losses == 3);
+        assert!(h2h.buckets.len() == 2 && h2h.buckets[0].games == vec![0, 1]);
+
+        let mut h2h = HeadToHead::default();
+        h2h.add_game(game(true, 3), &[aw, bb], &[bw, ab]);
+        h2h.add_game(game(true, 3), &[aw, bw], &[ab, bb]);
+        assert!(h2h.total.wins == 1 && h2h.games.len() == 1);
+    }
+}
@@ -0,0 +1,86 @@
+use super::query::{GraphQLRatingCategory, User};
+use crate::b66::B66;
+use crate::bug_web_sock::BugContext;
+use crate::leaderboards::{Leaderboard, LeaderboardEntry};
+use crate::rating::RatingCategory;
+use crate::users::UserID;
+use async_graphql::{Context, Object};
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub struct GraphQLLeaderboardEntry {
+    pub entry: LeaderboardEntry,
+    // Rank in the snapshot being compared against.  None if they weren't on
+    // it, or there's nothing to compare against
+    pub previous_rank: Option<i32>,
+}
+
+#[Object(name = "LeaderboardEntry")]
+impl GraphQLLeaderboardEntry {
+    async fn rank(&self) -> i32 {
+        self.entry.rank
+    }
+
+    /// Rank as of `since`, if they were on that day's leaderboard
+    async fn previous_rank(&self) -> Option<i32> {
+        self.previous_rank
+    }
+
+    async fn uid(&self) -> String {
+        B66::encode_uuid(&self.entry.uid)
+    }
+
+    async fn handle(&self) -> String {
+        self.entry.handle.to_string()
+    }
+
+    async fn user<'a>(&self, ctx: &Context<'a>) -> Option<User> {
+        let bug_ctx = ctx.data::<BugContext>().ok()?;
+        let user = bug_ctx.users.maybe_user_from_uid(&self.entry.uid).await?;
+        Some(User::new(user))
+    }
+
+    async fn rating(&self) -> i16 {
+        self.entry.rating.rating
+    }
+
+    async fn deviation(&self) -> i16 {
+        self.entry.rating.deviation
+    }
+
+    async fn games(&self) -> i32 {
+        self.entry.games
+    }
+}
+
+pub struct LeaderboardFields {
+    pub category: RatingCategory,
+    pub board: Arc<Leaderboard>,
+}
+
+#[Object]
+impl LeaderboardFields {
+    async fn category(&self) -> GraphQLRatingCategory {
+        self.category.into()
+    }
+
+    async fn total_count(&self) -> usize {
+        self.board.entries.len()
+    }
+
+    /// When the leaderboard was last rebuilt, RFC 3339
+    async fn updated_at(&self) -> Option<String> {
+        self.board.updated_at.map(|t| t.to_rfc3339())
+    }
+}
+
+// `since` is a day, YYYY-MM-DD
+pub fn parse_day(since: &str) -> Result<Date<Utc>, chrono::ParseError> {
+    let day = NaiveDate::parse_from_str(since, "%Y-%m-%d")?;
+    Ok(Date::from_utc(day, Utc))
+}
+
+pub fn rank_map(snapshot: &[LeaderboardEntry]) -> HashMap<UserID, i32> {
+    snapshot.iter().map(|e| (e.uid, e.rank)).collect()
+}
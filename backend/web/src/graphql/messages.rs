@@ -0,0 +1,135 @@
+use crate::b66::B66;
+use crate::channels::Channels;
+use crate::db::{ChannelMessageRow, Db, MessageRow};
+use crate::error::Error;
+use crate::tells::TellID;
+use crate::user_loader::HandleLoader;
+use crate::users::UserID;
+use async_graphql::dataloader::DataLoader;
+use async_graphql::{Context, Object};
+use chrono::prelude::*;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// messages rows read at a time while filtering by peer
+const PAGE: i32 = 100;
+
+pub struct GraphQLMessage(pub MessageRow);
+
+#[Object(name = "Message")]
+impl GraphQLMessage {
+    async fn id(&self) -> String {
+        B66::encode_uuid(&self.0.id)
+    }
+
+    /// Who the viewer sent it to or received it from
+    async fn peer(&self) -> String {
+        B66::encode_uuid(&self.0.peer)
+    }
+
+    async fn peer_handle<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Option<String>> {
+        let loader = ctx.data::<DataLoader<HandleLoader>>()?;
+        Ok(loader.load_one(self.0.peer).await?)
+    }
+
+    /// Whether the viewer sent it
+    async fn sent(&self) -> bool {
+        self.0.sent
+    }
+
+    async fn body(&self) -> String {
+        self.0.body.to_string()
+    }
+
+    async fn sent_at(&self) -> String {
+        let ms = self.0.sent_at.num_milliseconds();
+        Utc.timestamp_millis(ms).to_rfc3339()
+    }
+}
+
+pub struct GraphQLChannelMessage(pub ChannelMessageRow);
+
+#[Object(name = "ChannelMessage")]
+impl GraphQLChannelMessage {
+    async fn id(&self) -> String {
+        B66::encode_uuid(&self.0.id)
+    }
+
+    async fn sender(&self) -> String {
+        B66::encode_uuid(&self.0.sender)
+    }
+
+    async fn sender_handle<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Option<String>> {
+        let loader = ctx.data::<DataLoader<HandleLoader>>()?;
+        Ok(loader.load_one(self.0.sender).await?)
+    }
+
+    async fn body(&self) -> String {
+        self.0.body.to_string()
+    }
+
+    async fn sent_at(&self) -> String {
+        let ms = self.0.sent_at.num_milliseconds();
+        Utc.timestamp_millis(ms).to_rfc3339()
+    }
+}
+
+pub struct MessagesFetcher {
+    db: Arc<Db>,
+}
+
+impl MessagesFetcher {
+    pub fn new(db: Arc<Db>) -> Self {
+        Self { db }
+    }
+
+    // Up to `count` of `uid`'s tells before `cursor`, newest first, only
+    // those with `peer` if given, and whether there are more
+    pub async fn fetch(
+        &self,
+        uid: &UserID,
+        peer: Option<UserID>,
+        mut cursor: Option<TellID>,
+        count: usize,
+    ) -> Result<(Vec<MessageRow>, bool), Error> {
+        let mut found = vec![];
+        loop {
+            let rows = self.db.get_messages(uid, cursor, PAGE).await?;
+            let full_page = rows.len() == PAGE as usize;
+            cursor = rows.last().map(|row| row.id);
+            found.extend(
+                rows.into_iter()
+                    .filter(|row| peer.map_or(true, |p| p == row.peer)),
+            );
+            if found.len() > count || !full_page {
+                break;
+            }
+        }
+        let has_more = found.len() > count;
+        found.truncate(count);
+        Ok((found, has_more))
+    }
+
+    // Up to `count` of channel `name`'s messages before `cursor`, newest
+    // first, and whether there are more
+    pub async fn fetch_channel(
+        &self,
+        name: &str,
+        cursor: Option<Uuid>,
+        count: usize,
+    ) -> Result<(Vec<ChannelMessageRow>, bool), Error> {
+        let name = Channels::validate_name(name)?;
+        let limit = count as i32 + 1;
+        let mut rows =
+            self.db.get_channel_messages(&name, cursor, limit).await?;
+        let has_more = rows.len() > count;
+        rows.truncate(count);
+        Ok((rows, has_more))
+    }
+}
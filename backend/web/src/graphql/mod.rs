@@ -1,3 +1,12 @@
+pub mod game;
+pub mod games_fetcher;
+pub mod head_to_head;
+pub mod leaderboard;
+pub mod messages;
+pub mod mutation;
 pub mod query;
+pub mod rating_history_fetcher;
 pub mod rfc_3339;
+pub mod subscription;
 pub mod user_games_fetcher;
+pub mod user_stats;
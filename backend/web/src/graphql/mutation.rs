@@ -0,0 +1,117 @@
+use super::query::User;
+use crate::bug_web_sock::BugContext;
+use crate::users::{User as BackingUser, UserID};
+use async_graphql::{Context, InputObject, Object, SimpleObject};
+use std::collections::HashMap;
+
+// The signed in user making the request, from their web session (see
+// gql_handle_schema_with_header).  None if they aren't signed in.
+#[derive(Clone, Copy)]
+pub struct Viewer(pub Option<UserID>);
+
+pub fn viewer_uid(ctx: &Context<'_>) -> async_graphql::Result<UserID> {
+    ctx.data_opt::<Viewer>()
+        .and_then(|viewer| viewer.0)
+        .ok_or_else(|| "Not signed in".into())
+}
+
+#[derive(InputObject)]
+pub struct PreferenceInput {
+    key: String,
+    /// Null removes the preference
+    value: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct Preference {
+    pub key: String,
+    pub value: String,
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Same rules as renaming over the socket: handle policy, rename
+    /// cooldown and quarantine of the old handle
+    async fn set_handle<'a>(
+        &self,
+        ctx: &Context<'a>,
+        handle: String,
+    ) -> async_graphql::Result<User> {
+        let uid = viewer_uid(ctx)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        bug_ctx.server.set_handle(handle, uid).await?;
+        let user = bug_ctx.server.user_from_uid(&uid).await?;
+        Ok(User::new(user))
+    }
+
+    /// Omitted fields are left as they are, empty strings clear them.
+    /// `photoUrl` must be https.
+    async fn update_profile<'a>(
+        &self,
+        ctx: &Context<'a>,
+        name: Option<String>,
+        photo_url: Option<String>,
+    ) -> async_graphql::Result<User> {
+        let uid = viewer_uid(ctx)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let user = bug_ctx.server.update_profile(uid, name, photo_url).await?;
+        Ok(User::new(user))
+    }
+
+    /// Sets (or, with a null value, removes) the given preferences, leaving
+    /// the rest alone.  Returns all of them afterwards.
+    async fn set_preferences<'a>(
+        &self,
+        ctx: &Context<'a>,
+        preferences: Vec<PreferenceInput>,
+    ) -> async_graphql::Result<Vec<Preference>> {
+        let uid = viewer_uid(ctx)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let mut current = bug_ctx.db.get_preferences(&uid).await?;
+        let mut set = HashMap::new();
+        let mut unset = vec![];
+        for pref in preferences.into_iter() {
+            match pref.value {
+                Some(value) => {
+                    current.insert(pref.key.clone(), value.clone());
+                    BackingUser::validate_preference(
+                        &pref.key,
+                        Some(&value),
+                        current.len(),
+                    )?;
+                    set.insert(pref.key, value);
+                }
+                None => {
+                    current.remove(&pref.key);
+                    BackingUser::validate_preference(
+                        &pref.key,
+                        None,
+                        current.len(),
+                    )?;
+                    set.remove(&pref.key);
+                    unset.push(pref.key);
+                }
+            }
+        }
+        bug_ctx.db.update_preferences(&uid, set, unset).await?;
+        Ok(current
+            .into_iter()
+            .map(|(key, value)| Preference { key, value })
+            .collect())
+    }
+
+    /// Permanently deletes the signed in user's account.  `confirmHandle`
+    /// must be their handle.  Not allowed during a game.
+    async fn delete_account<'a>(
+        &self,
+        ctx: &Context<'a>,
+        confirm_handle: String,
+    ) -> async_graphql::Result<bool> {
+        let uid = viewer_uid(ctx)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        bug_ctx.server.delete_account(uid, &confirm_handle).await?;
+        Ok(true)
+    }
+}
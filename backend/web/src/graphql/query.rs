@@ -1,25 +1,135 @@
+use super::game::GraphQLGame;
+use super::games_fetcher::{parse_uid, GameFilter, GameSearch, GamesFetcher};
+use super::head_to_head::GraphQLHeadToHead;
+use super::leaderboard::{
+    parse_day, rank_map, GraphQLLeaderboardEntry, LeaderboardFields,
+};
+use super::messages::{
+    GraphQLChannelMessage, GraphQLMessage, MessagesFetcher,
+};
+use super::mutation::{viewer_uid, Preference, Viewer};
+use super::rating_history_fetcher::{
+    RatingHistoryEntry, RatingHistoryFetcher,
+};
 use super::rfc_3339::Rfc3339;
+use super::subscription::LiveGame;
 use super::user_games_fetcher::{
     CompleteGameRow, CompleteRatingSnapshot, TimeComp, UserGamesFetcher,
 };
+use super::user_stats::{GraphQLUserStats, UserStats};
 use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use crate::auth::session::session_user;
 use crate::b66::B66;
 use crate::bug_web_sock::BugContext;
+use crate::channels::LOBBY;
+use crate::error::Error;
+use crate::featured::CurrentGamesFilter;
 use crate::game::GameResult;
 use crate::game_row::GameRow;
+use crate::games::{CURRENT_MAX, MAX_CURRENT_GAMES_PAGE};
+use crate::rating::{PartnershipRating, RatingCategory, UserRating};
 use crate::users::{User as BackingUser, UserID};
 use actix_web::*;
+use actix_session::Session;
 use actix_web::{HttpRequest, HttpResponse, Responder};
 use async_graphql::connection::{query, Connection, Edge, EmptyFields};
 use async_graphql::{
-    Context, EmptyMutation, EmptySubscription, Interface,
-    Object, ObjectType, Schema
+    Context, Enum, Interface, Object, ObjectType, Schema, SubscriptionType,
 };
 use chrono::prelude::*;
 use std::sync::{Arc, RwLock};
 
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[graphql(name = "RatingCategory", remote = "RatingCategory")]
+pub enum GraphQLRatingCategory {
+    Bullet,
+    Blitz,
+    Standard,
+}
+
+pub struct GraphQLRating(RatingCategory, UserRating);
+
+#[Object(name = "Rating")]
+impl GraphQLRating {
+    async fn category(&self) -> GraphQLRatingCategory {
+        self.0.into()
+    }
+
+    async fn rating(&self) -> i16 {
+        self.1.rating.rating
+    }
+
+    async fn deviation(&self) -> i16 {
+        self.1.rating.deviation
+    }
+
+    /// Rated games played in this category
+    async fn games(&self) -> i32 {
+        self.1.games
+    }
+
+    /// Still in their first few rated games.  Usually shown as a "?"
+    async fn provisional(&self) -> bool {
+        self.1.is_provisional()
+    }
+}
+
+pub struct GraphQLPartnership {
+    partner: UserID,
+    category: RatingCategory,
+    rating: PartnershipRating,
+}
+
+#[Object(name = "Partnership")]
+impl GraphQLPartnership {
+    async fn partner_uid(&self) -> String {
+        B66::encode_uuid(&self.partner)
+    }
+
+    async fn partner<'a>(&self, ctx: &Context<'a>) -> Option<User> {
+        let bug_ctx = ctx.data::<BugContext>().ok()?;
+        let user = bug_ctx.users.maybe_user_from_uid(&self.partner).await?;
+        Some(User(user))
+    }
+
+    async fn category(&self) -> GraphQLRatingCategory {
+        self.category.into()
+    }
+
+    async fn rating(&self) -> i16 {
+        self.rating.rating.rating
+    }
+
+    async fn deviation(&self) -> i16 {
+        self.rating.rating.deviation
+    }
+
+    async fn games(&self) -> i32 {
+        self.rating.games
+    }
+
+    async fn wins(&self) -> i32 {
+        self.rating.wins
+    }
+}
+
 pub struct User(Arc<RwLock<BackingUser>>);
 
+impl User {
+    pub fn new(user: Arc<RwLock<BackingUser>>) -> Self {
+        User(user)
+    }
+
+    fn get_rating(
+        &self,
+        category: Option<GraphQLRatingCategory>,
+    ) -> UserRating {
+        let category =
+            category.map_or_else(RatingCategory::default, |c| c.into());
+        self.0.read().unwrap().get_user_rating(category)
+    }
+}
+
 #[Object]
 impl User {
     // GraphQL ID
@@ -50,12 +160,144 @@ impl User {
         self.0.read().unwrap().email.clone()
     }
 
-    async fn rating(&self) -> i16 {
-        self.0.read().unwrap().rating
+    /// Rating in `category`, blitz by default
+    async fn rating(&self, category: Option<GraphQLRatingCategory>) -> i16 {
+        self.get_rating(category).rating.rating
     }
 
-    async fn deviation(&self) -> i16 {
-        self.0.read().unwrap().deviation
+    async fn deviation(&self, category: Option<GraphQLRatingCategory>) -> i16 {
+        self.get_rating(category).rating.deviation
+    }
+
+    async fn provisional(
+        &self,
+        category: Option<GraphQLRatingCategory>,
+    ) -> bool {
+        self.get_rating(category).is_provisional()
+    }
+
+    /// One rating per category, including ones they haven't played yet
+    async fn ratings(&self) -> Vec<GraphQLRating> {
+        let user = self.0.read().unwrap();
+        RatingCategory::ALL
+            .iter()
+            .map(|category| {
+                GraphQLRating(*category, user.get_user_rating(*category))
+            })
+            .collect()
+    }
+
+    /// Rated partnerships, best rated first
+    async fn partnerships<'a>(
+        &self,
+        ctx: &Context<'a>,
+        category: Option<GraphQLRatingCategory>,
+    ) -> async_graphql::Result<Vec<GraphQLPartnership>> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let uid = self.0.read().unwrap().id;
+        let rows = bug_ctx
+            .db
+            .get_partnerships(&uid, category.map(|c| c.into()))
+            .await?;
+        let mut partnerships: Vec<GraphQLPartnership> = rows
+            .into_iter()
+            .filter_map(|(category, rating)| {
+                Some(GraphQLPartnership {
+                    partner: rating.partners.partner_of(&uid)?,
+                    category,
+                    rating,
+                })
+            })
+            .collect();
+        partnerships.sort_by(|a, b| b.rating.rating.cmp(&a.rating.rating));
+        Ok(partnerships)
+    }
+
+    /// Rating after each rated game in `category` (blitz by default), most
+    /// recent first, optionally limited to [from, to).  `from` and `to` are
+    /// RFC 3339 times.  Paged like `games`.
+    async fn rating_history<'a>(
+        &self,
+        ctx: &Context<'a>,
+        category: Option<GraphQLRatingCategory>,
+        from: Option<String>,
+        to: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<
+        Connection<Rfc3339, RatingHistoryEntry, EmptyFields, EmptyFields>,
+        async_graphql::Error,
+    > {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let db = bug_ctx.db.clone();
+        let uid = self.0.read().unwrap().id;
+        let category =
+            category.map_or_else(RatingCategory::default, |c| c.into());
+        let parse = |time: Option<String>| {
+            time.map(|t| DateTime::parse_from_rfc3339(&t))
+                .transpose()
+                .map(|t| t.map(|t| t.with_timezone(&Utc)))
+        };
+        let (from, to) = (parse(from)?, parse(to)?);
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after: Option<Rfc3339>, before: Option<Rfc3339>, first, last| async move {
+                let fetcher = RatingHistoryFetcher::new(db);
+                let direction = if before.is_some() || last.is_some() {
+                    TimeComp::Newer
+                } else {
+                    TimeComp::Older
+                };
+                let cursor = if direction == TimeComp::Newer {
+                    before.map(|b| b.0)
+                } else {
+                    after.map(|a| a.0)
+                };
+                let (entries, has_more) = fetcher
+                    .get_chunk(&uid, category, from, to, cursor, last.or(first), direction)
+                    .await?;
+                let has_previous_page = direction == TimeComp::Newer && has_more;
+                let has_next_page = direction == TimeComp::Older && has_more;
+                let mut conn = Connection::new(has_previous_page, has_next_page);
+                conn.edges = entries
+                    .into_iter()
+                    .map(|entry| Edge::new(Rfc3339(entry.time), entry))
+                    .collect();
+                Ok::<_, async_graphql::Error>(conn)
+        }).await
+    }
+
+    /// Only visible to the user themselves
+    async fn preferences<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Vec<Preference>> {
+        let uid = self.0.read().unwrap().id;
+        if viewer_uid(ctx)? != uid {
+            return Err("Not your preferences".into());
+        }
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let prefs = bug_ctx.db.get_preferences(&uid).await?;
+        Ok(prefs
+            .into_iter()
+            .map(|(key, value)| Preference { key, value })
+            .collect())
+    }
+
+    /// Win/loss statistics over all their finished games
+    async fn stats<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<GraphQLUserStats> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let uid = self.0.read().unwrap().id;
+        let stats = UserStats::fetch(&bug_ctx.db, &uid).await?;
+        Ok(GraphQLUserStats { uid, stats })
     }
 
     /// Fetch games in descending (most-recent) order.
@@ -74,6 +316,7 @@ impl User {
     > {
         let bug_ctx = ctx.data::<BugContext>().unwrap();
         let db = bug_ctx.db.clone();
+        let user_loader = bug_ctx.server.get_user_loader();
         eprintln!("after: {:?}, first: {:?}", after, first);
         query(
             after,
@@ -81,7 +324,7 @@ impl User {
             first,
             last,
             |after: Option<Rfc3339>, before: Option<Rfc3339>, first, last| async move {
-                let fetcher = UserGamesFetcher::new(db);
+                let fetcher = UserGamesFetcher::new(db, user_loader);
                 let id = self.0.read().unwrap().id;
                 let direction = if before.is_some() || last.is_some() {
                     TimeComp::Newer
@@ -115,9 +358,10 @@ impl User {
 #[graphql(field(name = "id", type = "String"))]
 enum Node {
     User(User),
+    Game(GraphQLGame),
 }
 
-pub struct GraphQLGameResult(GameResult);
+pub struct GraphQLGameResult(pub GameResult);
 
 #[Object]
 impl GraphQLGameResult {
@@ -180,7 +424,8 @@ struct GraphQLUserGamesFields {
 impl<'a> GraphQLUserGamesFields {
     pub async fn total_count(&self, ctx: &Context<'a>) -> usize {
         let bug_ctx = ctx.data::<BugContext>().unwrap();
-        UserGamesFetcher::new(bug_ctx.db.clone())
+        let user_loader = bug_ctx.server.get_user_loader();
+        UserGamesFetcher::new(bug_ctx.db.clone(), user_loader)
             .total_count(&self.uid)
             .await
             .unwrap()
@@ -214,10 +459,147 @@ impl QueryRoot {
                 // let node_id = User::id(&user, ctx).await.ok()?;
                 // Some(Node { id: node_id })
             }
+            "game" => {
+                let game = self.game(ctx, sub_id.to_string()).await.ok()?;
+                Some(Node::Game(game?))
+            }
             _ => None,
         }
     }
 
+    /// The signed in user
+    async fn viewer<'a>(&self, ctx: &Context<'a>) -> Option<User> {
+        let uid = viewer_uid(ctx).ok()?;
+        let bug_ctx = ctx.data::<BugContext>().ok()?;
+        let user = bug_ctx.users.maybe_user_from_uid(&uid).await?;
+        Some(User(user))
+    }
+
+    /// The viewer's tells, sent and received, newest first.  `with` limits
+    /// them to one other user's.
+    async fn messages<'a>(
+        &self,
+        ctx: &Context<'a>,
+        with: Option<String>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<String, GraphQLMessage>> {
+        let uid = viewer_uid(ctx)?;
+        let peer = with.map(|p| parse_uid("with", &p)).transpose()?;
+        let db = ctx.data::<BugContext>()?.db.clone();
+        query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<String>, _before: Option<String>, first, _last| async move {
+                let cursor = after.and_then(|a| B66::decode_uuid(&a));
+                let count = first.unwrap_or(20).min(100);
+                let (messages, has_more) = MessagesFetcher::new(db)
+                    .fetch(&uid, peer, cursor, count)
+                    .await?;
+                let mut conn = Connection::new(false, has_more);
+                for row in messages {
+                    let cursor = B66::encode_uuid(&row.id);
+                    conn.edges.push(Edge::new(cursor, GraphQLMessage(row)));
+                }
+                Ok::<_, async_graphql::Error>(conn)
+            },
+        )
+        .await
+    }
+
+    /// A channel's messages, newest first.  Only for its members, and
+    /// anyone in the lobby, who aren't banned from it
+    async fn channel_messages<'a>(
+        &self,
+        ctx: &Context<'a>,
+        channel: String,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<String, GraphQLChannelMessage>> {
+        let uid = viewer_uid(ctx)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        bug_ctx.server.check_channel_reader(&channel, &uid).await?;
+        let db = bug_ctx.db.clone();
+        query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<String>, _before: Option<String>, first, _last| async move {
+                let cursor = after.and_then(|a| B66::decode_uuid(&a));
+                let count = first.unwrap_or(50).min(200);
+                let (messages, has_more) = MessagesFetcher::new(db)
+                    .fetch_channel(&channel, cursor, count)
+                    .await?;
+                let mut conn = Connection::new(false, has_more);
+                for row in messages {
+                    let cursor = B66::encode_uuid(&row.id);
+                    conn.edges.push(Edge::new(cursor, GraphQLChannelMessage(row)));
+                }
+                Ok::<_, async_graphql::Error>(conn)
+            },
+        )
+        .await
+    }
+
+    /// The viewer's channels, starting with the lobby
+    async fn channels<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<Vec<String>> {
+        let uid = viewer_uid(ctx)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let mut channels = bug_ctx.db.get_user_channels(&uid).await?;
+        channels.insert(0, LOBBY.to_string());
+        Ok(channels)
+    }
+
+    /// Games in progress, best first.  Stronger games rank higher, as do
+    /// games people are watching and blitz games
+    async fn current_games<'a>(
+        &self,
+        ctx: &Context<'a>,
+        rated: Option<bool>,
+        category: Option<GraphQLRatingCategory>,
+        #[graphql(desc = "of the average rating")] min_rating: Option<i16>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<String, LiveGame>> {
+        let server = ctx.data::<BugContext>()?.server;
+        let filter = CurrentGamesFilter {
+            rated,
+            category: category.map(|c| c.into()),
+            min_rating,
+        };
+        query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<String>, _before: Option<String>, first, _last| async move {
+                // Cursors are positions in the ranking
+                let offset = after
+                    .and_then(|a| a.parse::<usize>().ok())
+                    .map_or(0, |a| a.saturating_add(1));
+                let count =
+                    first.unwrap_or(CURRENT_MAX).min(MAX_CURRENT_GAMES_PAGE);
+                let (games, total) =
+                    server.current_games(&filter, offset, count);
+                let has_more = offset.saturating_add(count) < total;
+                let mut conn = Connection::new(offset > 0, has_more);
+                for (i, game) in games.iter().enumerate() {
+                    let game = game.to_live_game();
+                    let cursor = (offset + i).to_string();
+                    conn.edges.push(Edge::new(cursor, game));
+                }
+                Ok::<_, async_graphql::Error>(conn)
+            },
+        )
+        .await
+    }
+
     async fn user<'a>(
         &self,
         ctx: &Context<'a>,
@@ -236,6 +618,169 @@ impl QueryRoot {
         Some(User(user))
     }
 
+    /// A game, finished or in progress, with its moves
+    /// Hidden games in progress are only found by their players
+    async fn game<'a>(
+        &self,
+        ctx: &Context<'a>,
+        #[graphql(desc = "decoded id of the game")] id: String,
+    ) -> async_graphql::Result<Option<GraphQLGame>> {
+        let game_id = match B66::decode_uuid(&id) {
+            Some(game_id) => game_id,
+            None => return Ok(None),
+        };
+        let bug_ctx = ctx.data::<BugContext>()?;
+        if bug_ctx.server.is_hidden_from(&game_id, viewer_uid(ctx).ok()) {
+            return Ok(None);
+        }
+        match bug_ctx.db.get_game_row(&game_id).await {
+            Ok(row) => Ok(Some(GraphQLGame(row))),
+            Err(Error::InvalidGameID(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Finished games of `filter.player` matching `filter`, most recent
+    /// first
+    async fn games<'a>(
+        &self,
+        ctx: &Context<'a>,
+        filter: GameFilter,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<Rfc3339, GraphQLGame>> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let db = bug_ctx.db.clone();
+        let search = GameSearch::from_filter(filter)?;
+        query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<Rfc3339>, _before: Option<Rfc3339>, first, _last| async move {
+                let count = first.unwrap_or(20).min(100);
+                let (games, has_more) = GamesFetcher::new(db)
+                    .search(&search, after.map(|a| a.0), count)
+                    .await?;
+                let mut conn = Connection::new(false, has_more);
+                for row in games {
+                    let ms = row.start_time.num_milliseconds();
+                    let date = Utc.timestamp_millis(ms);
+                    conn.edges.push(Edge::new(Rfc3339(date), GraphQLGame(row)));
+                }
+                Ok::<_, async_graphql::Error>(conn)
+            },
+        )
+        .await
+    }
+
+    /// `uid`'s finished games against `opponent`, overall and by rated and
+    /// time control
+    async fn head_to_head<'a>(
+        &self,
+        ctx: &Context<'a>,
+        uid: String,
+        opponent: String,
+    ) -> async_graphql::Result<GraphQLHeadToHead> {
+        let side = vec![parse_uid("uid", &uid)?];
+        let other = vec![parse_uid("opponent", &opponent)?];
+        let bug_ctx = ctx.data::<BugContext>()?;
+        Ok(GraphQLHeadToHead::fetch(&bug_ctx.db, side, other).await?)
+    }
+
+    /// `partners`' finished games together against `opponents` (both pairs
+    /// of uids), overall and by rated and time control
+    async fn partnership_head_to_head<'a>(
+        &self,
+        ctx: &Context<'a>,
+        partners: Vec<String>,
+        opponents: Vec<String>,
+    ) -> async_graphql::Result<GraphQLHeadToHead> {
+        if partners.len() != 2 || opponents.len() != 2 {
+            return Err("Partnerships are two players".into());
+        }
+        let parse = |field: &str, uids: &[String]| {
+            uids.iter()
+                .map(|uid| parse_uid(field, uid))
+                .collect::<Result<Vec<UserID>, Error>>()
+        };
+        let side = parse("partners", &partners)?;
+        let other = parse("opponents", &opponents)?;
+        let bug_ctx = ctx.data::<BugContext>()?;
+        Ok(GraphQLHeadToHead::fetch(&bug_ctx.db, side, other).await?)
+    }
+
+    /// Top registered, established, active players in `category` (blitz by
+    /// default), paged by rank.  With `since` (YYYY-MM-DD), entries include
+    /// their rank on that day's snapshot.
+    async fn leaderboard<'a>(
+        &self,
+        ctx: &Context<'a>,
+        category: Option<GraphQLRatingCategory>,
+        since: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<
+        Connection<usize, GraphQLLeaderboardEntry, LeaderboardFields, EmptyFields>,
+        async_graphql::Error,
+    > {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let category =
+            category.map_or_else(RatingCategory::default, |c| c.into());
+        let board = bug_ctx.server.get_leaderboards().get(category);
+        let previous = match since {
+            Some(since) => {
+                let day = parse_day(&since)?;
+                let snapshot =
+                    bug_ctx.db.get_leaderboard_snapshot(category, day).await?;
+                Some(rank_map(&snapshot))
+            }
+            None => None,
+        };
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after: Option<usize>, before: Option<usize>, first, last| async move {
+                let len = board.entries.len();
+                let end = before.unwrap_or(len).min(len);
+                let mut start =
+                    after.map_or(0, |after| after.saturating_add(1)).min(end);
+                let mut end = end;
+                if let Some(first) = first {
+                    end = start.saturating_add(first).min(end);
+                }
+                if let Some(last) = last {
+                    start = if last > end - start { start } else { end - last };
+                }
+                let entries: Vec<Edge<usize, GraphQLLeaderboardEntry, EmptyFields>> =
+                    board.entries[start..end]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, entry)| {
+                            let previous_rank = previous
+                                .as_ref()
+                                .and_then(|ranks| ranks.get(&entry.uid).copied());
+                            Edge::new(
+                                start + i,
+                                GraphQLLeaderboardEntry {
+                                    entry: entry.clone(),
+                                    previous_rank,
+                                },
+                            )
+                        })
+                        .collect();
+                let fields = LeaderboardFields { category, board: board.clone() };
+                let mut conn =
+                    Connection::with_additional_fields(start > 0, end < len, fields);
+                conn.edges = entries;
+                Ok::<_, async_graphql::Error>(conn)
+        }).await
+    }
+
     // async fn users(&self,
     //     after: Option<String>,
     //     before: Option<String>,
@@ -265,12 +810,16 @@ impl QueryRoot {
     // }
 }
 
-pub async fn gql_handle_schema_with_header<T: ObjectType + 'static>(
-    schema: actix_web::web::Data<Schema<T, EmptyMutation, EmptySubscription>>,
+pub async fn gql_handle_schema_with_header<
+    Q: ObjectType + 'static,
+    M: ObjectType + 'static,
+    S: SubscriptionType + 'static,
+>(
+    schema: actix_web::web::Data<Schema<Q, M, S>>,
     req: HttpRequest,
     gql_request: GraphQLRequest,
-    // session: Session,
-    // context: web::Data<BugContext>,
+    session: Session,
+    context: web::Data<BugContext>,
 ) -> Result<HttpResponse, actix_web::Error> {
     // eprintln!("gql_handle_schema_with_header");
     // eprintln!("headers: {:?}", req.headers());
@@ -283,6 +832,8 @@ pub async fn gql_handle_schema_with_header<T: ObjectType + 'static>(
     if let Some(name) = name {
         request = request.data(name);
     }
+    let viewer = session_user(&session, &context.db).await?;
+    request = request.data(Viewer(viewer.map(|(uid, _)| uid)));
     let response: GraphQLResponse = schema.execute(request).await.into();
     Ok(response.respond_to(&req))
 }
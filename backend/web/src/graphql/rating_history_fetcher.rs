@@ -0,0 +1,164 @@
+use crate::db::Db;
+use crate::error::Error;
+use crate::rating::RatingCategory;
+use crate::users::UserID;
+use chrono::prelude::*;
+use chrono::Duration;
+use scylla::transport::session::IntoTypedRows;
+use std::sync::Arc;
+
+use super::user_games_fetcher::TimeComp;
+use async_graphql::Object;
+
+#[derive(Clone, Debug)]
+pub struct RatingHistoryEntry {
+    pub time: DateTime<Utc>,
+    pub rating: i16,
+    pub deviation: i16,
+}
+
+#[Object(name = "RatingHistoryEntry")]
+impl RatingHistoryEntry {
+    async fn timestamp(&self) -> i64 {
+        self.time.timestamp()
+    }
+
+    async fn time(&self) -> String {
+        self.time.to_rfc3339()
+    }
+
+    async fn rating(&self) -> i16 {
+        self.rating
+    }
+
+    async fn deviation(&self) -> i16 {
+        self.deviation
+    }
+}
+
+pub struct RatingHistoryFetcher {
+    db: Arc<Db>,
+}
+
+impl RatingHistoryFetcher {
+    pub fn new(db: Arc<Db>) -> Self {
+        Self { db }
+    }
+
+    // Entries in [from, to), newest first, starting past `cursor` in the
+    // direction of `comparison`.  Like UserGamesFetcher::get_chunk, returns
+    // whether there are more.
+    pub async fn get_chunk(
+        &self,
+        uid: &UserID,
+        category: RatingCategory,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        cursor: Option<DateTime<Utc>>,
+        maybe_count: Option<usize>,
+        comparison: TimeComp,
+    ) -> Result<(Vec<RatingHistoryEntry>, bool), Error> {
+        let (mut lower, mut upper) = (from, to);
+        match comparison {
+            TimeComp::Older => upper = Self::earliest(upper, cursor),
+            TimeComp::Newer => {
+                // `from` is inclusive, cursors aren't
+                let after = cursor.map(|c| c + Duration::milliseconds(1));
+                lower = lower.max(after);
+            }
+        }
+        let lower = lower.unwrap_or_else(|| Utc.timestamp_millis(0));
+        let upper = upper.unwrap_or_else(Utc::now);
+        let count = maybe_count.map_or(100, |u| u as i32);
+        // Newer pages are the `count` oldest past the cursor
+        let order = match comparison {
+            TimeComp::Older => "DESC",
+            TimeComp::Newer => "ASC",
+        };
+        let query = format!(
+            "SELECT time, rating, deviation
+            FROM bughouse.category_rating_history
+            WHERE uid = ? AND category = ?
+            AND time >= ? AND time < ?
+            ORDER BY category {}, time {}
+            LIMIT ?",
+            order, order
+        );
+
+        // Over-fetch by 1 to calculate "has more"
+        let res = self
+            .db
+            .session()
+            .query(
+                query,
+                (
+                    uid,
+                    category.as_str(),
+                    Db::to_timestamp(lower),
+                    Db::to_timestamp(upper),
+                    count + 1,
+                ),
+            )
+            .await?;
+        let mut entries = Vec::new();
+        let mut has_more = false;
+        if let Some(rows) = res.rows {
+            has_more = rows.len() > count as usize;
+            for row in rows.into_typed::<(Duration, i16, i16)>() {
+                let (time, rating, deviation) = row?;
+                entries.push(RatingHistoryEntry {
+                    time: Utc.timestamp_millis(time.num_milliseconds()),
+                    rating,
+                    deviation,
+                });
+            }
+        }
+        entries.truncate(count as usize);
+        if comparison == TimeComp::Newer {
+            entries.reverse();
+        }
+        Ok((entries, has_more))
+    }
+
+    fn earliest(
+        a: Option<DateTime<Utc>>,
+        b: Option<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // Highest rating they've had in `category`, and when
+    pub async fn get_peak(
+        &self,
+        uid: &UserID,
+        category: RatingCategory,
+    ) -> Result<Option<RatingHistoryEntry>, Error> {
+        let res = self
+            .db
+            .session()
+            .query(
+                "SELECT time, rating, deviation
+                FROM bughouse.category_rating_history
+                WHERE uid = ? AND category = ?",
+                (uid, category.as_str()),
+            )
+            .await?;
+        let mut peak: Option<RatingHistoryEntry> = None;
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(Duration, i16, i16)>() {
+                let (time, rating, deviation) = row?;
+                if peak.as_ref().map_or(true, |p| rating > p.rating) {
+                    peak = Some(RatingHistoryEntry {
+                        time: Utc.timestamp_millis(time.num_milliseconds()),
+                        rating,
+                        deviation,
+                    });
+                }
+            }
+        }
+        Ok(peak)
+    }
+}
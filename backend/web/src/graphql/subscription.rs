@@ -0,0 +1,456 @@
+// GraphQL subscriptions (graphql-ws) over the same subscriber lists the
+// websocket's sub_* messages use.  Each subscription starts a Bridge actor,
+// subscribes it like a socket would, and turns the JSON it receives into
+// typed events.
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::{
+    Context, Enum, ObjectType, Schema, SimpleObject, Subscription,
+    SubscriptionType,
+};
+use async_graphql_actix_web::GraphQLSubscription;
+use bytestring::ByteString;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::b66::B66;
+use crate::bug_web_sock::BugContext;
+use crate::error::Error;
+use crate::featured::CurrentGamesFilter;
+use crate::games::CURRENT_MAX;
+use crate::messages::{ClientMessage, ClientMessageKind};
+
+// Updates are dropped for subscribers this far behind
+const BRIDGE_CAPACITY: usize = 256;
+
+#[derive(Clone, Deserialize, SimpleObject)]
+pub struct LivePlayer {
+    pub handle: Option<String>,
+    /// Clock, in milliseconds
+    pub ms: i32,
+}
+
+#[derive(Clone, Deserialize, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveBoardState {
+    pub fen: String,
+    /// [source, destination] squares ([destination] for drops)
+    pub last_move: Option<Vec<String>>,
+    pub white: LivePlayer,
+    pub black: LivePlayer,
+}
+
+#[derive(Clone, Deserialize, SimpleObject)]
+pub struct LiveBoard {
+    pub holdings: String,
+    pub board: LiveBoardState,
+}
+
+#[derive(Clone, Deserialize, SimpleObject)]
+pub struct LiveGameResult {
+    pub board: i32,
+    pub winner: i32,
+    pub kind: i32,
+}
+
+// A game (or table) as sent to sockets, see GameJson::to_val
+#[derive(Clone, Deserialize, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveGame {
+    pub id: String,
+    pub rated: bool,
+    pub result: Option<LiveGameResult>,
+    /// "base|inc"
+    pub time_ctrl: String,
+    /// Until the game starts.  -1 if it hasn't been scheduled yet
+    pub delay_start_millis: i32,
+    /// Connections observing it
+    #[serde(default)]
+    pub observers: i32,
+    pub a: LiveBoard,
+    pub b: LiveBoard,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum LiveEventKind {
+    Add,
+    Update,
+    Remove,
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct LiveGameEvent {
+    pub kind: LiveEventKind,
+    pub id: String,
+    /// Missing when removed
+    pub game: Option<LiveGame>,
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct OnlinePlayer {
+    pub uid: String,
+    pub handle: String,
+    /// Blitz rating.  Null for guests
+    pub rating: Option<i16>,
+    pub provisional: bool,
+}
+
+#[derive(Clone, SimpleObject)]
+pub struct OnlinePlayersEvent {
+    /// Players who came online (everyone online in the first event)
+    pub online: Vec<OnlinePlayer>,
+    /// uids of players who went offline
+    pub offline: Vec<String>,
+}
+
+// {"kind": ..., "id": ..., "add"|"update"|"rm": true, <field>: game}
+pub fn parse_game_event(val: &Value, field: &str) -> Option<LiveGameEvent> {
+    let id = val["id"].as_str()?.to_string();
+    if val["rm"].as_bool() == Some(true) {
+        return Some(LiveGameEvent {
+            kind: LiveEventKind::Remove,
+            id,
+            game: None,
+        });
+    }
+    let kind = if val["add"].as_bool() == Some(true) {
+        LiveEventKind::Add
+    } else {
+        LiveEventKind::Update
+    };
+    let game = serde_json::from_value(val[field].clone()).ok()?;
+    Some(LiveGameEvent {
+        kind,
+        id,
+        game: Some(game),
+    })
+}
+
+// {<field>: {id: game, ...}} snapshots become Add events
+pub fn parse_game_snapshot(val: &Value, field: &str) -> Vec<LiveGameEvent> {
+    let games = match val[field].as_object() {
+        Some(games) => games,
+        None => return vec![],
+    };
+    games
+        .iter()
+        .filter_map(|(id, game)| {
+            Some(LiveGameEvent {
+                kind: LiveEventKind::Add,
+                id: id.to_string(),
+                game: Some(serde_json::from_value(game.clone()).ok()?),
+            })
+        })
+        .collect()
+}
+
+// [uid, handle, rating, provisional], see ConnectionMgr::get_online_players
+fn parse_online_player(val: &Value) -> Option<OnlinePlayer> {
+    Some(OnlinePlayer {
+        uid: val[0].as_str()?.to_string(),
+        handle: val[1].as_str()?.to_string(),
+        rating: val[2].as_i64().map(|r| r as i16),
+        provisional: val[3].as_bool().unwrap_or(false),
+    })
+}
+
+// Either "online_players" (everyone) or "online_players_update"
+pub fn parse_online_players(val: &Value) -> Option<OnlinePlayersEvent> {
+    let players = match val["kind"].as_str()? {
+        "online_players" => &val["players"],
+        "online_players_update" => &val["online"],
+        _ => return None,
+    };
+    let online = players
+        .as_array()?
+        .iter()
+        .filter_map(parse_online_player)
+        .collect();
+    let offline = val["offline"]
+        .as_array()
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|id| id.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(OnlinePlayersEvent { online, offline })
+}
+
+fn parse(text: &ByteString) -> Option<Value> {
+    serde_json::from_str(text).ok()
+}
+
+// Forwards the text messages a subscriber list sends it to a stream
+struct Bridge {
+    tx: async_channel::Sender<Arc<ByteString>>,
+}
+
+impl Actor for Bridge {
+    type Context = actix::Context<Self>;
+}
+
+impl Handler<ClientMessage> for Bridge {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Self::Context) {
+        match msg.kind {
+            ClientMessageKind::Text(text) => match self.tx.try_send(text) {
+                Err(async_channel::TrySendError::Closed(_)) => ctx.stop(),
+                Err(async_channel::TrySendError::Full(_)) => {
+                    eprintln!("GraphQL subscriber is behind. Dropped update");
+                }
+                Ok(()) => (),
+            },
+            ClientMessageKind::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+// Unsubscribes the bridge when its stream is dropped.  The actor stops once
+// nothing holds its address.
+struct Unsubscriber {
+    recipient: Recipient<ClientMessage>,
+    unsub: Option<Box<dyn FnOnce(Recipient<ClientMessage>) + Send + Sync>>,
+}
+
+impl Drop for Unsubscriber {
+    fn drop(&mut self) {
+        if let Some(unsub) = self.unsub.take() {
+            unsub(self.recipient.clone());
+        }
+    }
+}
+
+fn bridge(
+    sub: impl FnOnce(Recipient<ClientMessage>),
+    unsub: impl FnOnce(Recipient<ClientMessage>) + Send + Sync + 'static,
+) -> impl Stream<Item = Value> {
+    let (tx, rx) = async_channel::bounded(BRIDGE_CAPACITY);
+    let recipient = Bridge { tx }.start().recipient();
+    sub(recipient.clone());
+    let unsubscriber = Unsubscriber {
+        recipient,
+        unsub: Some(Box::new(unsub)),
+    };
+    rx.filter_map(move |text| {
+        let _ = &unsubscriber;
+        future::ready(parse(&text))
+    })
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Games in progress (as on the home page): each of them as added, then
+    /// changes
+    async fn current_games<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<impl Stream<Item = LiveGameEvent>> {
+        let server = ctx.data::<BugContext>()?.server;
+        // Subscribe first so nothing between the snapshot and it is missed
+        let updates = bridge(
+            |r| {
+                server.sub_current_games(r).ok();
+            },
+            move |r| {
+                server.unsub_current_games(r).ok();
+            },
+        );
+        let snapshot = server.get_current_games_json(
+            &CurrentGamesFilter::default(),
+            0,
+            CURRENT_MAX,
+        )?;
+        let snapshot = parse(&snapshot)
+            .ok_or_else(|| Error::Unexpected("current games".into()))?;
+        Ok(stream::iter(parse_game_snapshot(&snapshot, "games")).chain(
+            updates.filter_map(|val| {
+                future::ready(parse_game_event(&val, "game"))
+            }),
+        ))
+    }
+
+    /// The top game (see currentGames) as it is now, then after every move.
+    /// Moves on to the next top game when it ends, which shows as a new id.
+    async fn tv<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<impl Stream<Item = LiveGame>> {
+        let server = ctx.data::<BugContext>()?.server;
+        let updates = bridge(|r| server.sub_tv(r), move |r| server.unsub_tv(r));
+        Ok(updates.filter_map(|val| {
+            future::ready(serde_json::from_value::<LiveGame>(val).ok())
+        }))
+    }
+
+    /// Public tables waiting for players: each of them as added, then changes
+    async fn public_tables<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<impl Stream<Item = LiveGameEvent>> {
+        let server = ctx.data::<BugContext>()?.server;
+        let updates = bridge(
+            |r| {
+                server.sub_public_tables(r).ok();
+            },
+            move |r| {
+                server.unsub_public_tables(r).ok();
+            },
+        );
+        let snapshot = parse(&server.get_public_tables_msg()?)
+            .ok_or_else(|| Error::Unexpected("public tables".into()))?;
+        Ok(
+            stream::iter(parse_game_snapshot(&snapshot, "tables")).chain(
+                updates.filter_map(|val| {
+                    future::ready(parse_game_event(&val, "table"))
+                }),
+            ),
+        )
+    }
+
+    /// Everyone online, then who comes online and goes offline
+    async fn online_players<'a>(
+        &self,
+        ctx: &Context<'a>,
+    ) -> async_graphql::Result<impl Stream<Item = OnlinePlayersEvent>> {
+        let server = ctx.data::<BugContext>()?.server;
+        let updates = bridge(
+            |r| {
+                server.sub_online_players(r).ok();
+            },
+            move |r| {
+                server.unsub_online_players(r).ok();
+            },
+        );
+        let snapshot = server.get_online_players_msg(None, u64::MAX, None)?;
+        let first = parse(&snapshot).and_then(|v| parse_online_players(&v));
+        Ok(stream::iter(first).chain(
+            updates.filter_map(|val| future::ready(parse_online_players(&val))),
+        ))
+    }
+
+    /// A game in progress as it is now, then after every move.  Ends after
+    /// the game does.
+    async fn game<'a>(
+        &self,
+        ctx: &Context<'a>,
+        id: String,
+    ) -> async_graphql::Result<impl Stream<Item = LiveGame>> {
+        let server = ctx.data::<BugContext>()?.server;
+        let game_id = B66::decode_uuid(&id).ok_or("Invalid game id")?;
+        // GameHidden and TooManyObserved go back to the client
+        let mut observed = Ok(());
+        let updates = bridge(
+            |r| observed = server.observe(&game_id, r),
+            move |r| server.unobserve(&game_id, r),
+        );
+        observed?;
+        let current = server
+            .get_observer_payload(game_id)?
+            .and_then(|payload| parse(&payload))
+            .and_then(|val| serde_json::from_value::<LiveGame>(val).ok());
+        let updates = updates.filter_map(|val| {
+            future::ready(serde_json::from_value::<LiveGame>(val).ok())
+        });
+        Ok(stream::iter(current)
+            .chain(updates)
+            .scan(false, |over, game| {
+                if *over {
+                    return future::ready(None);
+                }
+                *over = game.result.is_some();
+                future::ready(Some(game))
+            }))
+    }
+}
+
+// graphql-ws (and graphql-transport-ws) websocket endpoint
+pub async fn gql_subscription<
+    Q: ObjectType + 'static,
+    M: ObjectType + 'static,
+    S: SubscriptionType + 'static,
+>(
+    schema: web::Data<Schema<Q, M, S>>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    GraphQLSubscription::new(Schema::clone(&*schema)).start(&req, payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn game_json(id: &str) -> Value {
+        let board = json!({
+            "holdings": "",
+            "board": {
+                "fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "lastMove": null,
+                "white": {"handle": "a", "ms": 180000},
+                "black": {"handle": null, "ms": 180000},
+            },
+        });
+        json!({
+            "kind": "current_game",
+            "id": id,
+            "rated": true,
+            "result": null,
+            "timeCtrl": "3|0",
+            "delayStartMillis": -1,
+            "a": board,
+            "b": board,
+        })
+    }
+
+    #[test]
+    fn game_events() {
+        let add = json!({"id": "g1", "add": true, "game": game_json("g1")});
+        let event = parse_game_event(&add, "game").unwrap();
+        assert!(event.kind == LiveEventKind::Add);
+        let game = event.game.unwrap();
+        assert!(game.time_ctrl == "3|0" && game.a.board.black.handle == None);
+
+        let update =
+            json!({"id": "g1", "update": true, "game": game_json("g1")});
+        let event = parse_game_event(&update, "game").unwrap();
+        assert!(event.kind == LiveEventKind::Update);
+
+        let rm = json!({"id": "g1", "rm": true});
+        let event = parse_game_event(&rm, "game").unwrap();
+        assert!(event.kind == LiveEventKind::Remove && event.game.is_none());
+
+        let snapshot = json!({
+            "kind": "current_games",
+            "games": {"g1": game_json("g1"), "g2": game_json("g2")},
+        });
+        assert!(parse_game_snapshot(&snapshot, "games").len() == 2);
+    }
+
+    #[test]
+    fn online_players() {
+        let all = json!({
+            "kind": "online_players",
+            "players": [["u1", "alice", 1600, false], ["u2", "guest1", null, true]],
+        });
+        let event = parse_online_players(&all).unwrap();
+        assert!(event.online.len() == 2 && event.offline.is_empty());
+        assert!(event.online[1].rating.is_none());
+
+        let update = json!({
+            "kind": "online_players_update",
+            "online": [],
+            "offline": ["u1"],
+        });
+        let event = parse_online_players(&update).unwrap();
+        assert!(event.online.is_empty() && event.offline == vec!["u1"]);
+    }
+}
@@ -3,6 +3,7 @@ use crate::db::{Db, UserRatingSnapshot};
 use crate::error::Error;
 use crate::game::GameID;
 use crate::game_row::UserGameRow;
+use crate::user_loader::UserLoader;
 use crate::users::UserID;
 use chrono::prelude::*;
 use chrono::Duration;
@@ -54,6 +55,7 @@ pub struct CompleteGameRow {
 
 pub struct UserGamesFetcher {
     db: Arc<Db>,
+    user_loader: Arc<UserLoader>,
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -72,8 +74,8 @@ impl TimeComp {
 }
 
 impl UserGamesFetcher {
-    pub fn new(db: Arc<Db>) -> Self {
-        Self { db }
+    pub fn new(db: Arc<Db>, user_loader: Arc<UserLoader>) -> Self {
+        Self { db, user_loader }
     }
 
     pub fn complete_game_row(
@@ -152,7 +154,6 @@ impl UserGamesFetcher {
                 return Ok((vec![], false));
             }
 
-            // TODO: Cache uid => handle in REDIS to avoid double hop?
             let mut huids: HashSet<UserID> = HashSet::new();
             for user_game in &user_games {
                 let ((aw, ab), (bw, bb)) = user_game.players;
@@ -161,7 +162,7 @@ impl UserGamesFetcher {
                 }
             }
             let uids = huids.into_iter().collect::<Vec<_>>();
-            let uid2handle = self.db.get_handles(uids).await?;
+            let uid2handle = self.user_loader.get_handles(&uids).await?;
             let complete_games = user_games
                 .iter()
                 .map(|g| Self::complete_game_row(g, &uid2handle))
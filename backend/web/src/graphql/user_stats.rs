@@ -0,0 +1,347 @@
+use super::query::GraphQLRatingCategory;
+use super::rating_history_fetcher::{RatingHistoryEntry, RatingHistoryFetcher};
+use crate::b66::B66;
+use crate::bug_web_sock::BugContext;
+use crate::db::{Db, TableSnapshot};
+use crate::error::Error;
+use crate::game::{GameResult, GameResultType};
+use crate::game_row::GameRow;
+use crate::rating::{RatingCategory, Team};
+use crate::user_loader::UserLoader;
+use crate::users::UserID;
+use async_graphql::{Context, Enum, Object};
+use bughouse::{BoardID, Color};
+use scylla::transport::session::IntoTypedRows;
+use std::collections::HashMap;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[graphql(name = "GameResultType", remote = "GameResultType")]
+pub enum GraphQLGameResultType {
+    Flagged,
+    Checkmate,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Record {
+    pub wins: i32,
+    pub losses: i32,
+}
+
+impl Record {
+    pub fn add(&mut self, won: bool) {
+        if won {
+            self.wins += 1;
+        } else {
+            self.losses += 1;
+        }
+    }
+}
+
+#[Object]
+impl Record {
+    async fn games(&self) -> i32 {
+        self.wins + self.losses
+    }
+
+    async fn wins(&self) -> i32 {
+        self.wins
+    }
+
+    async fn losses(&self) -> i32 {
+        self.losses
+    }
+}
+
+pub struct SeatRecord {
+    board: BoardID,
+    color: Color,
+    record: Record,
+}
+
+#[Object]
+impl SeatRecord {
+    async fn board(&self) -> i32 {
+        self.board as i32
+    }
+
+    async fn color(&self) -> i32 {
+        self.color as i32
+    }
+
+    async fn record(&self) -> Record {
+        self.record
+    }
+}
+
+pub struct ResultTypeRecord {
+    kind: GameResultType,
+    record: Record,
+}
+
+#[Object]
+impl ResultTypeRecord {
+    async fn kind(&self) -> GraphQLGameResultType {
+        self.kind.into()
+    }
+
+    async fn record(&self) -> Record {
+        self.record
+    }
+}
+
+pub struct PlayerRecord {
+    uid: UserID,
+    handle: String,
+    record: Record,
+}
+
+#[Object]
+impl PlayerRecord {
+    async fn uid(&self) -> String {
+        B66::encode_uuid(&self.uid)
+    }
+
+    async fn handle(&self) -> String {
+        self.handle.to_string()
+    }
+
+    async fn record(&self) -> Record {
+        self.record
+    }
+}
+
+// Win/loss aggregates over a user's finished games in bughouse.user_games
+#[derive(Default)]
+pub struct UserStats {
+    pub total: Record,
+    // [aw, ab, bw, bb]
+    pub seats: [Record; 4],
+    pub flagged: Record,
+    pub checkmate: Record,
+    pub partners: HashMap<UserID, Record>,
+    pub opponents: HashMap<UserID, Record>,
+}
+
+const SEATS: [(BoardID, Color); 4] = [
+    (BoardID::A, Color::White),
+    (BoardID::A, Color::Black),
+    (BoardID::B, Color::White),
+    (BoardID::B, Color::Black),
+];
+
+impl UserStats {
+    pub fn add_game(
+        &mut self,
+        uid: &UserID,
+        result: &GameResult,
+        players: &TableSnapshot,
+    ) {
+        let ((aw, ab), (bw, bb)) = players;
+        let uids = [aw.uid, ab.uid, bw.uid, bb.uid];
+        let seat = match uids.iter().position(|u| u == uid) {
+            Some(seat) => seat,
+            None => return,
+        };
+        let won = Team::of(seat) == Team::winner(result);
+        self.total.add(won);
+        self.seats[seat].add(won);
+        match result.kind {
+            GameResultType::Flagged => self.flagged.add(won),
+            GameResultType::Checkmate => self.checkmate.add(won),
+        }
+        for (i, other) in uids.iter().enumerate() {
+            if i == seat || other.is_nil() {
+                continue;
+            }
+            let records = if Team::of(i) == Team::of(seat) {
+                &mut self.partners
+            } else {
+                &mut self.opponents
+            };
+            records.entry(*other).or_default().add(won);
+        }
+    }
+
+    pub async fn fetch(db: &Db, uid: &UserID) -> Result<UserStats, Error> {
+        let res = db
+            .session()
+            .query(
+                "SELECT result, players FROM bughouse.user_games
+                WHERE uid = ?",
+                (uid,),
+            )
+            .await?;
+        let mut stats = UserStats::default();
+        if let Some(rows) = res.rows {
+            for row in rows.into_typed::<(i16, TableSnapshot)>() {
+                let (result, players) = row?;
+                if result >= 0 {
+                    let result = GameRow::deserialize_result(result);
+                    stats.add_game(uid, &result, &players);
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn get_seats(&self) -> Vec<SeatRecord> {
+        SEATS
+            .iter()
+            .zip(self.seats.iter())
+            .map(|((board, color), record)| SeatRecord {
+                board: *board,
+                color: *color,
+                record: *record,
+            })
+            .collect()
+    }
+
+    pub fn get_result_types(&self) -> Vec<ResultTypeRecord> {
+        vec![
+            ResultTypeRecord {
+                kind: GameResultType::Flagged,
+                record: self.flagged,
+            },
+            ResultTypeRecord {
+                kind: GameResultType::Checkmate,
+                record: self.checkmate,
+            },
+        ]
+    }
+
+    // The `count` players they've played most with (or against), most games
+    // first
+    pub async fn most_frequent(
+        user_loader: &UserLoader,
+        records: &HashMap<UserID, Record>,
+        count: usize,
+    ) -> Result<Vec<PlayerRecord>, Error> {
+        let mut top: Vec<(&UserID, &Record)> = records.iter().collect();
+        top.sort_by_key(|(uid, r)| (-(r.wins + r.losses), -r.wins, **uid));
+        top.truncate(count);
+        if top.is_empty() {
+            return Ok(vec![]);
+        }
+        let uids: Vec<UserID> = top.iter().map(|(uid, _)| **uid).collect();
+        let uid2handle = user_loader.get_handles(&uids).await?;
+        Ok(top
+            .into_iter()
+            .map(|(uid, record)| PlayerRecord {
+                uid: *uid,
+                handle: uid2handle
+                    .get(uid)
+                    .cloned()
+                    .unwrap_or_else(|| "<None>".into()),
+                record: *record,
+            })
+            .collect())
+    }
+}
+
+pub struct GraphQLUserStats {
+    pub uid: UserID,
+    pub stats: UserStats,
+}
+
+#[Object(name = "UserStats")]
+impl GraphQLUserStats {
+    /// Every finished game
+    async fn total(&self) -> Record {
+        self.stats.total
+    }
+
+    /// By board and color: board A white, board A black, board B white,
+    /// board B black
+    async fn seats(&self) -> Vec<SeatRecord> {
+        self.stats.get_seats()
+    }
+
+    /// By how the game ended
+    async fn result_types(&self) -> Vec<ResultTypeRecord> {
+        self.stats.get_result_types()
+    }
+
+    /// Most frequent partners, most games first
+    async fn partners<'a>(
+        &self,
+        ctx: &Context<'a>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Vec<PlayerRecord>> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let count = first.map_or(10, |n| n.max(0) as usize);
+        let user_loader = bug_ctx.server.get_user_loader();
+        Ok(
+            UserStats::most_frequent(&user_loader, &self.stats.partners, count)
+                .await?,
+        )
+    }
+
+    /// Most frequent opponents, most games first
+    async fn opponents<'a>(
+        &self,
+        ctx: &Context<'a>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Vec<PlayerRecord>> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let count = first.map_or(10, |n| n.max(0) as usize);
+        let user_loader = bug_ctx.server.get_user_loader();
+        Ok(
+            UserStats::most_frequent(
+                &user_loader,
+                &self.stats.opponents,
+                count,
+            )
+            .await?,
+        )
+    }
+
+    /// Highest rating in `category` (blitz by default), if they've played a
+    /// rated game in it
+    async fn peak_rating<'a>(
+        &self,
+        ctx: &Context<'a>,
+        category: Option<GraphQLRatingCategory>,
+    ) -> async_graphql::Result<Option<RatingHistoryEntry>> {
+        let bug_ctx = ctx.data::<BugContext>()?;
+        let category =
+            category.map_or_else(RatingCategory::default, |c| c.into());
+        Ok(RatingHistoryFetcher::new(bug_ctx.db.clone())
+            .get_peak(&self.uid, category)
+            .await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game_row::fixtures;
+
+    #[test]
+    fn add_game() {
+        let uids = fixtures::uids();
+        let players = fixtures::players(&uids, 0);
+        let mut stats = UserStats::default();
+        // Team A (aw, bb) wins on board A, then loses on time on board B
+        let checkmate = GameResult {
+            board: BoardID::A,
+            winner: Color::White,
+            kind: GameResultType::Checkmate,
+        };
+        let flagged = GameResult {
+            board: BoardID::B,
+            winner: Color::White,
+            kind: GameResultType::Flagged,
+        };
+        stats.add_game(&uids[3], &checkmate, &players);
+        stats.add_game(&uids[3], &flagged, &players);
+
+        assert!(stats.total.wins == 1 && stats.total.losses == 1);
+        assert!(stats.seats[3].wins == 1 && stats.seats[3].losses == 1);
+        assert!(stats.seats[0].wins + stats.seats[0].losses == 0);
+        assert!(stats.checkmate.wins == 1 && stats.flagged.losses == 1);
+        assert!(stats.partners.len() == 1);
+        assert!(stats.partners[&uids[0]].wins == 1);
+        assert!(stats.opponents.len() == 2);
+        assert!(stats.opponents[&uids[1]].losses == 1);
+    }
+}
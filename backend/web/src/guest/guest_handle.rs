@@ -16,4 +16,18 @@ impl GuestHandle {
         let noun = noun_arr[(noun_idx % noun_arr.len() as u16) as usize];
         format!("\u{00bf}{}_{}?", adjective, noun)
     }
+
+    // Whether `handle` could be mistaken for a generated guest handle, with or
+    // without the surrounding "\u{00bf}" and "?"
+    pub fn is_guest_like(handle: &str) -> bool {
+        let lower = handle.to_lowercase();
+        let bare = lower.trim_start_matches('\u{00bf}').trim_end_matches('?');
+        match bare.split_once('_') {
+            Some((adjective, noun)) => {
+                adjectives::adjectives().binary_search(&adjective).is_ok()
+                    && nouns::nouns().binary_search(&noun).is_ok()
+            }
+            None => false,
+        }
+    }
 }
@@ -0,0 +1,238 @@
+// Rules for user chosen handles.  Configurable via env:
+//   HANDLE_MIN_LEN, HANDLE_MAX_LEN
+//   HANDLE_COOLDOWN_DAYS   - minimum time between renames
+//   HANDLE_QUARANTINE_DAYS - how long an abandoned handle stays with its
+//                            previous owner before anyone can claim it
+//   HANDLE_BLOCKLIST       - file of additional blocked words, one per line
+use chrono::Duration;
+use std::env;
+use std::fs;
+
+use crate::error::Error;
+use crate::guest::guest_handle::GuestHandle;
+
+const DEFAULT_MIN_LEN: usize = 3;
+const DEFAULT_MAX_LEN: usize = 20;
+const DEFAULT_COOLDOWN_DAYS: i64 = 30;
+const DEFAULT_QUARANTINE_DAYS: i64 = 90;
+
+// Prefixes of generated handles (see Db::new_player_handle) and staff
+const RESERVED_PREFIXES: [&str; 4] = ["player_", "guest", "admin", "moderator"];
+
+// Words nobody should be able to put in their handle.  Matched anywhere in
+// the handle, with separators dropped and common digit substitutions undone
+// (see normalize), so "B.a_d", "8adWord" and "xbadx" are all blocked.
+const BLOCKLIST: &str = include_str!("handle_blocklist.txt");
+// Innocent names that contain a blocked word, like "Scunthorpe"
+const ALLOWLIST: &str = include_str!("handle_allowlist.txt");
+
+#[derive(Clone, Debug)]
+pub struct HandlePolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    pub cooldown: Duration,
+    pub quarantine: Duration,
+    blocklist: Vec<String>,
+    allowlist: Vec<String>,
+}
+
+impl Default for HandlePolicy {
+    fn default() -> Self {
+        HandlePolicy {
+            min_len: DEFAULT_MIN_LEN,
+            max_len: DEFAULT_MAX_LEN,
+            cooldown: Duration::days(DEFAULT_COOLDOWN_DAYS),
+            quarantine: Duration::days(DEFAULT_QUARANTINE_DAYS),
+            blocklist: Self::parse_blocklist(BLOCKLIST),
+            allowlist: Self::parse_blocklist(ALLOWLIST),
+        }
+    }
+}
+
+fn env_num<T: std::str::FromStr>(var: &str, default: T) -> T {
+    env::var(var)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
+
+impl HandlePolicy {
+    pub fn from_env() -> Self {
+        let mut policy = HandlePolicy {
+            min_len: env_num("HANDLE_MIN_LEN", DEFAULT_MIN_LEN),
+            max_len: env_num("HANDLE_MAX_LEN", DEFAULT_MAX_LEN),
+            cooldown: Duration::days(env_num(
+                "HANDLE_COOLDOWN_DAYS",
+                DEFAULT_COOLDOWN_DAYS,
+            )),
+            quarantine: Duration::days(env_num(
+                "HANDLE_QUARANTINE_DAYS",
+                DEFAULT_QUARANTINE_DAYS,
+            )),
+            ..HandlePolicy::default()
+        };
+        if let Ok(path) = env::var("HANDLE_BLOCKLIST") {
+            match fs::read_to_string(&path) {
+                Ok(words) => policy.add_blocked(&words),
+                Err(e) => eprintln!("Couldn't read {}: {}", path, e),
+            }
+        }
+        policy
+    }
+
+    fn parse_blocklist(words: &str) -> Vec<String> {
+        words
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Self::normalize)
+            .collect()
+    }
+
+    pub fn add_blocked(&mut self, words: &str) {
+        self.blocklist.extend(Self::parse_blocklist(words));
+    }
+
+    // Lowercase, drop separators, and undo l33t-speak so that "B.a_d" and
+    // "8ad" both match "bad"
+    fn normalize(handle: &str) -> String {
+        handle
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| match c.to_ascii_lowercase() {
+                '0' => 'o',
+                '1' => 'i',
+                '3' => 'e',
+                '4' => 'a',
+                '5' => 's',
+                '7' => 't',
+                '8' => 'b',
+                c => c,
+            })
+            .collect()
+    }
+
+    fn is_blocked(&self, handle: &str) -> bool {
+        let mut handle = Self::normalize(handle);
+        // Allowed names are cut out first so what's around them still counts
+        for allowed in self.allowlist.iter() {
+            handle = handle.replace(allowed.as_str(), "|");
+        }
+        self.blocklist
+            .iter()
+            .any(|word| handle.contains(word.as_str()))
+    }
+
+    fn invalid(reason: &str) -> Error {
+        Error::InvalidHandle(reason.to_string())
+    }
+
+    pub fn validate(&self, handle: &str) -> Result<(), Error> {
+        let len = handle.chars().count();
+        if len < self.min_len || len > self.max_len {
+            return Err(Error::InvalidHandle(format!(
+                "Handles must be {}-{} characters",
+                self.min_len, self.max_len
+            )));
+        }
+        if !handle.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+        }) {
+            return Err(Self::invalid(
+                "Handles may only contain letters, digits, '_', '-' and '.'",
+            ));
+        }
+        if !handle.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err(Self::invalid(
+                "Handles must start with a letter or digit",
+            ));
+        }
+        let lower = handle.to_lowercase();
+        if RESERVED_PREFIXES.iter().any(|p| lower.starts_with(p))
+            || GuestHandle::is_guest_like(handle)
+        {
+            return Err(Self::invalid("Handle looks like a generated handle"));
+        }
+        if self.is_blocked(handle) {
+            return Err(Self::invalid("Handle isn't allowed"));
+        }
+        Ok(())
+    }
+
+    // Time left before `last_change` allows another rename
+    pub fn cooldown_remaining(
+        &self,
+        last_change: Duration,
+        now: Duration,
+    ) -> Option<Duration> {
+        let ready = last_change + self.cooldown;
+        if ready > now {
+            Some(ready - now)
+        } else {
+            None
+        }
+    }
+
+    pub fn quarantine_over(
+        &self,
+        released_at: Duration,
+        now: Duration,
+    ) -> bool {
+        released_at + self.quarantine <= now
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let mut policy = HandlePolicy::default();
+        policy.add_blocked("# comment\nbadword\n");
+        let valid = ["Capab1anca", "E.Lask3r", "jrwats", "H-1", "Scunthorpe"];
+        for handle in valid.iter() {
+            assert!(policy.validate(handle).is_ok(), "{}", handle);
+        }
+        let invalid = [
+            "ab",
+            "waytoolonghandlenameforanyone",
+            "spaced out",
+            "_underscore",
+            "\u{00bf}abased_aardvark?",
+            "Abased_Aardvark",
+            "Player_abc",
+            "Guest123",
+            "8AD.w0rd",
+            "xx_BadWord",
+            "xxbadwordxx",
+            "badword1",
+            "mybadword",
+            "badword88",
+            "scunthorpe_cunt",
+            "hitler1",
+            "nazi88",
+            "fuckyou",
+            "shitlord",
+        ];
+        for handle in invalid.iter() {
+            assert!(policy.validate(handle).is_err(), "{}", handle);
+        }
+    }
+
+    #[test]
+    fn cooldown() {
+        let policy = HandlePolicy::default();
+        let day = Duration::days(1);
+        let now = Duration::days(100);
+        assert!(
+            policy.cooldown_remaining(now - day, now)
+                == Some(policy.cooldown - day)
+        );
+        assert!(policy
+            .cooldown_remaining(now - policy.cooldown, now)
+            .is_none());
+        assert!(!policy.quarantine_over(now - day, now));
+        assert!(policy.quarantine_over(now - policy.quarantine, now));
+    }
+}
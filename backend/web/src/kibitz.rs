@@ -0,0 +1,53 @@
+// Spectator chat about a game.  Kibitzes go to its observers and players,
+// whispers only to its observers.  Both are stored with the game, stamped
+// with the milliseconds since it started like partner signals, and players
+// can read the whispers once it's over.
+use serde_json::{json, Value};
+
+use crate::b66::B66;
+use crate::db::KibitzRow;
+use crate::game::GameID;
+
+// The "kibitz" message observers (and for kibitzes, players) get
+pub fn to_json(game_id: &GameID, row: &KibitzRow, handle: &str) -> Value {
+    json!({
+        "kind": "kibitz",
+        "id": B66::encode_uuid(game_id),
+        "msg_id": B66::encode_uuid(&row.id),
+        "whisper": row.whisper,
+        "from": {
+            "uid": B66::encode_uuid(&row.sender),
+            "handle": handle,
+        },
+        "body": row.body,
+        "ms": row.ms,
+    })
+}
+
+// Whether `row` can be shown from the archive.  Whispers are hidden from
+// everyone until the game is over, since anyone could be a player's partner
+pub fn is_visible(row: &KibitzRow, is_over: bool) -> bool {
+    !row.whisper || is_over
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn whispers_hidden_until_over() {
+        let row = |whisper| KibitzRow {
+            id: Uuid::new_v4(),
+            ms: 1000,
+            sender: Uuid::new_v4(),
+            whisper,
+            body: "nice".to_string(),
+        };
+        assert!(is_visible(&row(false), false));
+        assert!(!is_visible(&row(true), false));
+        assert!(is_visible(&row(true), true));
+        let json = to_json(&Uuid::new_v4(), &row(true), "kib");
+        assert!(json["whisper"] == json!(true) && json["ms"] == json!(1000));
+    }
+}
@@ -0,0 +1,170 @@
+// Ranked lists of players per rating category, rebuilt from bughouse.ratings
+// every few minutes (see ServerHandler::started) and snapshotted once a day
+// so ranks can be compared over time.  Configurable via env:
+//   LEADERBOARD_INACTIVE_DAYS - players without a rated game in the category
+//                               for this long are left off
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, RwLock};
+
+use crate::db::Db;
+use crate::error::Error;
+use crate::rating::{Rating, RatingCategory, UserRating};
+use crate::users::UserID;
+
+const DEFAULT_INACTIVE_DAYS: i64 = 90;
+
+#[derive(Clone, Debug)]
+pub struct LeaderboardEntry {
+    // 1 is the best
+    pub rank: i32,
+    pub uid: UserID,
+    pub handle: String,
+    pub rating: Rating,
+    pub games: i32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Leaderboard {
+    pub entries: Vec<LeaderboardEntry>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+pub struct Leaderboards {
+    inactive: Duration,
+    boards: RwLock<HashMap<RatingCategory, Arc<Leaderboard>>>,
+    // Last day a snapshot was recorded for
+    snapshot_day: RwLock<Option<Date<Utc>>>,
+}
+
+impl Leaderboards {
+    pub fn new(inactive: Duration) -> Self {
+        Leaderboards {
+            inactive,
+            boards: RwLock::new(HashMap::new()),
+            snapshot_day: RwLock::new(None),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let days = env::var("LEADERBOARD_INACTIVE_DAYS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(DEFAULT_INACTIVE_DAYS);
+        Leaderboards::new(Duration::days(days))
+    }
+
+    pub fn get(&self, category: RatingCategory) -> Arc<Leaderboard> {
+        let boards = self.boards.read().unwrap();
+        boards.get(&category).cloned().unwrap_or_default()
+    }
+
+    // Guests, provisional ratings and players inactive since before
+    // `now - inactive` are left off.  Ties go to the lower deviation, then
+    // more games.
+    pub fn rank<'a>(
+        ratings: impl Iterator<Item = &'a UserRating>,
+        handles: &HashMap<UserID, String>,
+        now: DateTime<Utc>,
+        inactive: Duration,
+    ) -> Vec<LeaderboardEntry> {
+        let mut ranked: Vec<&UserRating> = ratings
+            .filter(|r| handles.contains_key(&r.uid))
+            .filter(|r| !r.is_provisional())
+            .filter(|r| r.last_rated.map_or(false, |t| now - t < inactive))
+            .collect();
+        ranked.sort_by_key(|r| {
+            (-r.rating.rating, r.rating.deviation, -r.games, r.uid)
+        });
+        ranked
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| LeaderboardEntry {
+                rank: i as i32 + 1,
+                uid: r.uid,
+                handle: handles[&r.uid].clone(),
+                rating: r.rating,
+                games: r.games,
+            })
+            .collect()
+    }
+
+    // Rebuilds every category's leaderboard, and records today's snapshot if
+    // it hasn't been yet.
+    pub async fn refresh(&self, db: &Db) -> Result<(), Error> {
+        let now = Utc::now();
+        let ratings = db.get_all_ratings().await?;
+        let handles = db.get_registered_handles().await?;
+        let today = now.date();
+        let snapshotted = *self.snapshot_day.read().unwrap() == Some(today);
+        for category in RatingCategory::ALL.iter() {
+            let entries = Self::rank(
+                ratings
+                    .iter()
+                    .filter(|((_, c), _)| c == category)
+                    .map(|(_, r)| r),
+                &handles,
+                now,
+                self.inactive,
+            );
+            if !snapshotted
+                && !db.has_leaderboard_snapshot(*category, today).await?
+            {
+                db.record_leaderboard_snapshot(*category, today, &entries)
+                    .await?;
+            }
+            let board = Leaderboard {
+                entries,
+                updated_at: Some(now),
+            };
+            let mut boards = self.boards.write().unwrap();
+            boards.insert(*category, Arc::new(board));
+        }
+        *self.snapshot_day.write().unwrap() = Some(today);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rating::PROVISIONAL_GAMES;
+    use uuid::Uuid;
+
+    fn rating(r: i16, rd: i16, games: i32, days_ago: i64) -> UserRating {
+        UserRating {
+            games,
+            last_rated: Some(Utc::now() - Duration::days(days_ago)),
+            ..UserRating::new(Uuid::new_v4(), Rating::new(r, rd))
+        }
+    }
+
+    #[test]
+    fn rank() {
+        let ratings = vec![
+            rating(1600, 80, 50, 1),
+            rating(1900, 80, 50, 1),
+            rating(1600, 60, 50, 1),
+            rating(2000, 80, PROVISIONAL_GAMES - 1, 1),
+            rating(2100, 80, 50, 100),
+            rating(2200, 80, 50, 1), // guest
+        ];
+        let mut handles = HashMap::new();
+        for (i, r) in ratings.iter().take(5).enumerate() {
+            handles.insert(r.uid, format!("player{}", i));
+        }
+        let entries = Leaderboards::rank(
+            ratings.iter(),
+            &handles,
+            Utc::now(),
+            Duration::days(DEFAULT_INACTIVE_DAYS),
+        );
+        let ranked: Vec<(i32, &str)> = entries
+            .iter()
+            .map(|e| (e.rank, e.handle.as_str()))
+            .collect();
+        assert!(ranked == vec![(1, "player1"), (2, "player2"), (3, "player0")]);
+    }
+}
@@ -5,12 +5,16 @@ extern crate lazy_static;
 extern crate num_derive;
 
 // pub mod async_graphql_actix_web;
+pub mod auth;
 pub mod b66;
+pub mod bpgn;
 pub mod bug_web_sock;
 pub mod bughouse_server;
+pub mod channels;
 pub mod connection_mgr;
 pub mod db;
 pub mod error;
+pub mod featured;
 pub mod firebase;
 pub mod game;
 pub mod game_json;
@@ -18,12 +22,18 @@ pub mod game_row;
 pub mod games;
 pub mod graphql;
 pub mod guest;
+pub mod handle_policy;
 pub mod hash;
+pub mod kibitz;
+pub mod leaderboards;
 pub mod messages;
 pub mod observers;
+pub mod partner_signals;
 pub mod players;
 pub mod rating;
 pub mod seeks;
 pub mod subscriptions;
+pub mod tells;
 pub mod time_control;
+pub mod user_loader;
 pub mod users;
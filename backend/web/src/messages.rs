@@ -4,9 +4,15 @@ use bytestring::ByteString;
 use chrono::Duration;
 use std::sync::Arc;
 
+use crate::auth::Credentials;
+use crate::channels::ChannelCommand;
 use crate::connection_mgr::ConnID;
 use crate::error::Error;
 use crate::game::{GameID, GamePlayers};
+use crate::partner_signals::PartnerSignal;
+use crate::seeks::seek_constraint::SeekConstraint;
+use crate::seeks::seeks::SeekPool;
+use crate::tells::TellRecipient;
 use crate::time_control::TimeControl;
 use crate::users::UserID;
 
@@ -14,6 +20,7 @@ use crate::users::UserID;
 #[derive(Debug, Clone)]
 pub enum ClientMessageKind {
     Auth(ConnID),
+    Close(String), // reason
     Text(Arc<ByteString>),
     Empty,
 }
@@ -32,14 +39,25 @@ impl ClientMessage {
 }
 
 pub enum ServerMessageKind {
-    Auth(Recipient<ClientMessage>, String),
+    Auth(Recipient<ClientMessage>, Credentials),
+    Channel(ChannelCommand, UserID),
+    SessionAuth(Recipient<ClientMessage>, UserID, String), // uid, session ID
     CheckGame(GameID),
     CreateGame(TimeControl, bool, GamePlayers),
-    FormTable(TimeControl, bool, bool, ConnID),
+    // rated, public, hidden, observer delay
+    FormTable(TimeControl, bool, bool, bool, Duration, ConnID),
+    // partner, match on team rating
+    PartnerSeek(SeekPool, UserID, bool, SeekConstraint, ConnID),
+    FlushObservers,
     GetGameRow(GameID, Recipient<ClientMessage>),
+    Kibitz(GameID, String, bool, Recipient<ClientMessage>), // body, whisper
     RecordMove(Duration, GameID, BoardID, BughouseMove),
+    RecordSignal(GameID, i32, UserID, PartnerSignal), // ms since start
+    RefreshLeaderboards,
+    RerankCurrentGames,
     SetHandle(String, UserID),
     Sit(GameID, BoardID, Color, ConnID),
+    Tell(TellRecipient, String, UserID), // recipient, body, sender
     Vacate(GameID, BoardID, Color, Recipient<ClientMessage>),
 }
 
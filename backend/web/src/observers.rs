@@ -1,73 +1,147 @@
 use actix::prelude::*;
-use std::collections::{HashMap, HashSet};
+use bytestring::ByteString;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::sync::{Arc, RwLock};
 
 use crate::connection_mgr::ConnectionMgr;
+use crate::error::Error;
 use crate::game::GameID;
+use crate::game_json::GameJson;
 use crate::hash::hash;
-use crate::messages::ClientMessage;
+use crate::messages::{ClientMessage, ClientMessageKind};
 use crate::users::UserID;
 
 pub type AnonConnID = u64;
 pub type Recipients = HashMap<AnonConnID, Recipient<ClientMessage>>;
 
+// Games a connection can observe at once, unless OBSERVE_LIMIT is set
+const DEFAULT_OBSERVE_LIMIT: usize = 8;
+
+// An update held back from the observers of a game with an observer delay:
+// when to send it, the game it shows, the message, and the players to leave
+// out
+type DelayedUpdate = (DateTime<Utc>, GameJson, ClientMessage, HashSet<UserID>);
+
+#[derive(Default)]
+struct Delayed {
+    queue: VecDeque<DelayedUpdate>,
+    // The last update sent, which is what the game looks like to observers
+    released: Option<(GameJson, ClientMessage)>,
+}
+
 pub struct Observers {
     game_to_observers: RwLock<HashMap<GameID, Recipients>>,
-    observer_to_games: RwLock<HashMap<AnonConnID, GameID>>,
+    observer_to_games: RwLock<HashMap<AnonConnID, HashSet<GameID>>>,
+    delayed: RwLock<HashMap<GameID, Delayed>>,
+    limit: usize,
     conns: Arc<ConnectionMgr>,
 }
 
 impl Observers {
-    pub fn new(conns: Arc<ConnectionMgr>) -> Self {
+    pub fn new(conns: Arc<ConnectionMgr>, limit: usize) -> Self {
         Observers {
             game_to_observers: RwLock::new(HashMap::new()),
             observer_to_games: RwLock::new(HashMap::new()),
+            delayed: RwLock::new(HashMap::new()),
+            limit,
             conns,
         }
     }
 
+    pub fn from_env(conns: Arc<ConnectionMgr>) -> Self {
+        let limit = env::var("OBSERVE_LIMIT")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(DEFAULT_OBSERVE_LIMIT);
+        Observers::new(conns, limit)
+    }
+
+    // Adds `game_id` to the games `recipient` is observing, up to the limit
     pub fn observe(
         &self,
         game_id: GameID,
         recipient: Recipient<ClientMessage>,
-    ) {
+    ) -> Result<(), Error> {
         let anon_id = hash(&recipient);
         let mut observing = self.observer_to_games.write().unwrap();
-
-        // You can observe one game at a time
-        if let Some(old_game_id) = observing.insert(anon_id, game_id) {
-            self.remove_from_game(&anon_id, &old_game_id);
+        let game_ids = observing.entry(anon_id).or_insert_with(HashSet::new);
+        if game_ids.contains(&game_id) {
+            return Ok(());
         }
-
-        {
-            let mut games = self.game_to_observers.write().unwrap();
-            if let Some(r) = games.get_mut(&game_id) {
-                r.insert(hash(&recipient), recipient);
-            } else {
-                let mut new_map = HashMap::new();
-                new_map.insert(anon_id, recipient);
-                games.insert(game_id, new_map);
-            }
+        if game_ids.len() >= self.limit {
+            return Err(Error::TooManyObserved(self.limit));
         }
+        game_ids.insert(game_id);
+
+        let mut games = self.game_to_observers.write().unwrap();
+        games
+            .entry(game_id)
+            .or_insert_with(HashMap::new)
+            .insert(anon_id, recipient);
+        Ok(())
     }
 
     pub fn unobserve(
         &self,
         game_id: &GameID,
         recipient: Recipient<ClientMessage>,
-    ) {
+    ) -> bool {
         let anon_id = hash(&recipient);
         let mut observing = self.observer_to_games.write().unwrap();
-        if let Some(observing_game_id) = observing.remove(&anon_id) {
-            if *game_id != observing_game_id {
-                eprintln!("unsubserve({}) doesn't match observing: {}", game_id, observing_game_id);
-                return;
+        let removed = match observing.get_mut(&anon_id) {
+            Some(game_ids) => {
+                let removed = game_ids.remove(game_id);
+                if game_ids.is_empty() {
+                    observing.remove(&anon_id);
+                }
+                removed
             }
-            let mut games = self.game_to_observers.write().unwrap();
-            if let Some(recipients) = games.get_mut(&game_id) {
-                recipients.remove(&anon_id);
+            None => false,
+        };
+        if !removed {
+            eprintln!("unobserve({}) isn't being observed", game_id);
+            return false;
+        }
+        self.remove_from_game(&anon_id, game_id);
+        true
+    }
+
+    pub fn count(&self, game_id: &GameID) -> usize {
+        let games = self.game_to_observers.read().unwrap();
+        games.get(game_id).map_or(0, |recipients| recipients.len())
+    }
+
+    // Handles of the signed in users observing `game_id`, and how many
+    // connections are observing anonymously
+    pub fn handles(&self, game_id: &GameID) -> (Vec<String>, usize) {
+        let games = self.game_to_observers.read().unwrap();
+        let mut handles = HashSet::new();
+        let mut anon = 0;
+        for conn_id in games.get(game_id).into_iter().flat_map(|r| r.keys()) {
+            match self.conns.user_from_conn(*conn_id) {
+                Some(user) => {
+                    handles.insert(user.read().unwrap().handle.to_string());
+                }
+                None => anon += 1,
             }
         }
+        let mut handles: Vec<String> = handles.into_iter().collect();
+        handles.sort();
+        (handles, anon)
+    }
+
+    pub fn is_observing(
+        &self,
+        game_id: &GameID,
+        recipient: &Recipient<ClientMessage>,
+    ) -> bool {
+        let observing = self.observer_to_games.read().unwrap();
+        observing
+            .get(&hash(recipient))
+            .map_or(false, |game_ids| game_ids.contains(game_id))
     }
 
     fn remove_from_game(&self, anon_id: &AnonConnID, game_id: &GameID) {
@@ -76,6 +150,9 @@ impl Observers {
             if recipients.remove(anon_id).is_none() {
                 eprintln!("Tried removing non-existent anonymous observer");
             }
+            if recipients.is_empty() {
+                games.remove(game_id);
+            }
         }
     }
 
@@ -101,15 +178,132 @@ impl Observers {
         }
     }
 
-    pub fn remove_recipient(&self, recipient: &Recipient<ClientMessage>) {
-        let anon_id = hash(&recipient);
+    // Like notify(), but `delay` from now (see flush()).  `msg` shows
+    // `game_json`
+    pub fn notify_later(
+        &self,
+        game_json: GameJson,
+        msg: &ClientMessage,
+        players: HashSet<UserID>,
+        delay: Duration,
+    ) {
+        let mut delayed = self.delayed.write().unwrap();
+        let game = delayed
+            .entry(*game_json.get_id())
+            .or_insert_with(Delayed::default);
+        game.queue.push_back((
+            Utc::now() + delay,
+            game_json,
+            msg.clone(),
+            players,
+        ));
+    }
+
+    // Sends the delayed updates due by `now`.  Games that are no longer
+    // `is_live` are forgotten once their updates have all gone out.  Returns
+    // the games updates went out for, and whether it was their first
+    pub fn flush(
+        &self,
+        now: DateTime<Utc>,
+        is_live: impl Fn(&GameID) -> bool,
+    ) -> Vec<(GameID, bool)> {
+        // Checked before locking, since is_live() may lock the games
+        let game_ids: Vec<GameID> =
+            self.delayed.read().unwrap().keys().cloned().collect();
+        let live: HashSet<GameID> =
+            game_ids.into_iter().filter(|id| is_live(id)).collect();
+        let mut due = vec![];
+        let mut released = vec![];
+        let mut ended = vec![];
         {
-            let observers = self.observer_to_games.read().unwrap();
-            if let Some(game_id) = observers.get(&anon_id) {
-                self.remove_from_game(&anon_id, &game_id);
+            let mut delayed = self.delayed.write().unwrap();
+            for (game_id, game) in delayed.iter_mut() {
+                let first = game.released.is_none();
+                let due_before = due.len();
+                while game.queue.front().map_or(false, |(at, ..)| *at <= now) {
+                    let (_, game_json, msg, players) =
+                        game.queue.pop_front().unwrap();
+                    game.released = Some((game_json, msg.clone()));
+                    due.push((*game_id, msg, players));
+                }
+                if due.len() > due_before {
+                    released.push((*game_id, first));
+                }
             }
+            delayed.retain(|game_id, game| {
+                let keep = !game.queue.is_empty() || live.contains(game_id);
+                if !keep {
+                    ended.push(*game_id);
+                }
+                keep
+            });
+        }
+        for (game_id, msg, players) in due.into_iter() {
+            self.notify(&game_id, &msg, players);
+        }
+        for game_id in ended.iter() {
+            self.forget_game(game_id);
+        }
+        released
+    }
+
+    // Stops tracking the observers of a game that's over, once any delayed
+    // updates have gone out to them (see flush())
+    pub fn end_game(&self, game_id: &GameID) {
+        let pending = self
+            .delayed
+            .read()
+            .unwrap()
+            .get(game_id)
+            .map_or(false, |game| !game.queue.is_empty());
+        if !pending {
+            self.delayed.write().unwrap().remove(game_id);
+            self.forget_game(game_id);
+        }
+    }
+
+    fn forget_game(&self, game_id: &GameID) {
+        let mut observing = self.observer_to_games.write().unwrap();
+        let recipients =
+            self.game_to_observers.write().unwrap().remove(game_id);
+        for anon_id in recipients.iter().flat_map(|r| r.keys()) {
+            if let Some(game_ids) = observing.get_mut(anon_id) {
+                game_ids.remove(game_id);
+                if game_ids.is_empty() {
+                    observing.remove(anon_id);
+                }
+            }
+        }
+    }
+
+    // The last delayed update sent to `game_id`'s observers, if any
+    pub fn released(&self, game_id: &GameID) -> Option<ByteString> {
+        let delayed = self.delayed.read().unwrap();
+        let (_, msg) = delayed.get(game_id)?.released.as_ref()?;
+        match &msg.kind {
+            ClientMessageKind::Text(text) => Some((**text).clone()),
+            _ => None,
+        }
+    }
+
+    // The game that update showed
+    pub fn released_game(&self, game_id: &GameID) -> Option<GameJson> {
+        let delayed = self.delayed.read().unwrap();
+        let (game_json, _) = delayed.get(game_id)?.released.as_ref()?;
+        Some(game_json.clone())
+    }
+
+    // Returns the games it was observing
+    pub fn remove_recipient(
+        &self,
+        recipient: &Recipient<ClientMessage>,
+    ) -> HashSet<GameID> {
+        let anon_id = hash(&recipient);
+        let mut observing = self.observer_to_games.write().unwrap();
+        let game_ids = observing.remove(&anon_id).unwrap_or_default();
+        for game_id in game_ids.iter() {
+            self.remove_from_game(&anon_id, game_id);
         }
-        let mut observers = self.observer_to_games.write().unwrap();
-        observers.remove(&anon_id);
+        game_ids
     }
 }
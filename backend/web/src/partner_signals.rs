@@ -0,0 +1,264 @@
+// Messages between partners during a game.  Only these signals are relayed.
+// Each is stamped with the milliseconds since the game started, like moves
+// (see Db::to_move_key), and stored with the game so analysis and BPGN
+// exports can show them alongside the moves.
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::error::Error;
+use crate::tells::Tell;
+use crate::users::UserID;
+
+// Pieces that can be asked for: pawn, knight, bishop, rook, queen
+const PIECES: &str = "pnbrq";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PartnerSignal {
+    Need(char),
+    // Don't give the opponents this piece
+    No(char),
+    Sit,
+    Go,
+    TradeOk,
+    // There's a mate on the board
+    Mates,
+    WatchTime,
+    Text(String),
+}
+
+impl PartnerSignal {
+    // {"type": "need" | "no", "piece": "n"}, {"type": "sit" | "go" |
+    // "trade_ok" | "mates" | "watch_time"} or {"type": "text", "text": ...}.
+    // Older clients' {"type": "quick"} buttons map onto these, STALL onto
+    // sit and EXCHANGE onto trade_ok.
+    pub fn from_json(val: &Value) -> Result<Self, Error> {
+        let kind = val["type"].as_str().unwrap_or("");
+        let signal = match kind {
+            "quick" => {
+                let quick = val["quick"].as_str().unwrap_or("");
+                Self::from_quick(quick)
+                    .ok_or_else(|| Error::InvalidSignal(quick.to_string()))?
+            }
+            "text" => {
+                let text = val["text"].as_str().unwrap_or("");
+                PartnerSignal::Text(Tell::validate_body(text)?)
+            }
+            _ => {
+                let payload = val["piece"].as_str().unwrap_or("");
+                Self::from_parts(kind, payload)
+                    .ok_or_else(|| Error::InvalidSignal(kind.to_string()))?
+            }
+        };
+        Ok(signal)
+    }
+
+    fn from_quick(quick: &str) -> Option<Self> {
+        let signal = match quick {
+            "STALL" => PartnerSignal::Sit,
+            "EXCHANGE" => PartnerSignal::TradeOk,
+            "MATES" => PartnerSignal::Mates,
+            "WATCH_TIME" => PartnerSignal::WatchTime,
+            _ => {
+                let (kind, piece) = quick.split_once('_')?;
+                let piece = match piece {
+                    "PAWN" => 'p',
+                    "KNIGHT" => 'n',
+                    "BISHOP" => 'b',
+                    "ROOK" => 'r',
+                    "QUEEN" => 'q',
+                    _ => return None,
+                };
+                match kind {
+                    "NEED" => PartnerSignal::Need(piece),
+                    "NO" => PartnerSignal::No(piece),
+                    _ => return None,
+                }
+            }
+        };
+        Some(signal)
+    }
+
+    // Inverse of kind() and payload()
+    pub fn from_parts(kind: &str, payload: &str) -> Option<Self> {
+        let piece = || {
+            let mut chars = payload.chars();
+            let piece = chars.next()?.to_ascii_lowercase();
+            if chars.next().is_some() || !PIECES.contains(piece) {
+                return None;
+            }
+            Some(piece)
+        };
+        match kind {
+            "need" => Some(PartnerSignal::Need(piece()?)),
+            "no" => Some(PartnerSignal::No(piece()?)),
+            "sit" => Some(PartnerSignal::Sit),
+            "go" => Some(PartnerSignal::Go),
+            "trade_ok" => Some(PartnerSignal::TradeOk),
+            "mates" => Some(PartnerSignal::Mates),
+            "watch_time" => Some(PartnerSignal::WatchTime),
+            "text" => Some(PartnerSignal::Text(payload.to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PartnerSignal::Need(_) => "need",
+            PartnerSignal::No(_) => "no",
+            PartnerSignal::Sit => "sit",
+            PartnerSignal::Go => "go",
+            PartnerSignal::TradeOk => "trade_ok",
+            PartnerSignal::Mates => "mates",
+            PartnerSignal::WatchTime => "watch_time",
+            PartnerSignal::Text(_) => "text",
+        }
+    }
+
+    pub fn payload(&self) -> String {
+        match self {
+            PartnerSignal::Need(piece) | PartnerSignal::No(piece) => {
+                piece.to_string()
+            }
+            PartnerSignal::Text(text) => text.to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    // Fields of the "game_msg" sent to both partners
+    pub fn to_json(&self) -> Value {
+        match self {
+            PartnerSignal::Need(piece) | PartnerSignal::No(piece) => {
+                json!({"type": self.kind(), "piece": piece.to_string()})
+            }
+            PartnerSignal::Text(text) => json!({"type": "text", "text": text}),
+            _ => json!({ "type": self.kind() }),
+        }
+    }
+
+    // How it reads in a BPGN comment.  Braces would end the comment
+    pub fn to_comment(&self) -> String {
+        match self {
+            PartnerSignal::Need(piece) => {
+                format!("need {}", piece.to_ascii_uppercase())
+            }
+            PartnerSignal::No(piece) => {
+                format!("no {}", piece.to_ascii_uppercase())
+            }
+            PartnerSignal::Sit => "sit".to_string(),
+            PartnerSignal::Go => "go".to_string(),
+            PartnerSignal::TradeOk => "trade ok".to_string(),
+            PartnerSignal::Mates => "mates".to_string(),
+            PartnerSignal::WatchTime => "watch time".to_string(),
+            PartnerSignal::Text(text) => text
+                .chars()
+                .map(|c| match c {
+                    '{' => '(',
+                    '}' => ')',
+                    '\n' => ' ',
+                    c => c,
+                })
+                .collect(),
+        }
+    }
+}
+
+// At most `max` signals per user per `window_ms`.  Only users who sent one
+// in the last window are tracked.
+pub struct SignalLimiter {
+    max: usize,
+    window_ms: i64,
+    sent: Mutex<HashMap<UserID, VecDeque<i64>>>,
+}
+
+impl SignalLimiter {
+    pub fn new(max: usize, window_ms: i64) -> Self {
+        SignalLimiter {
+            max,
+            window_ms,
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts the signal if it's allowed
+    pub fn allow(&self, uid: &UserID, now_ms: i64) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, times| {
+            times.back().map_or(false, |t| now_ms - t < self.window_ms)
+        });
+        let times = sent.entry(*uid).or_insert_with(VecDeque::new);
+        while times
+            .front()
+            .map_or(false, |t| now_ms - t >= self.window_ms)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.max {
+            return false;
+        }
+        times.push_back(now_ms);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn from_json() {
+        let need = json!({"type": "need", "piece": "N"});
+        assert!(
+            PartnerSignal::from_json(&need).unwrap()
+                == PartnerSignal::Need('n')
+        );
+        let quick = json!({"type": "quick", "quick": "STALL"});
+        assert!(
+            PartnerSignal::from_json(&quick).unwrap() == PartnerSignal::Sit
+        );
+        let text = json!({"type": "text", "text": " go {now} "});
+        let text = PartnerSignal::from_json(&text).unwrap();
+        assert!(text.to_comment() == "go (now)");
+        assert!(PartnerSignal::from_json(
+            &json!({"type": "need", "piece": "k"})
+        )
+        .is_err());
+        assert!(PartnerSignal::from_json(
+            &json!({"type": "quick", "quick": "NO_KING"})
+        )
+        .is_err());
+        let no = json!({"type": "quick", "quick": "NO_QUEEN"});
+        assert!(
+            PartnerSignal::from_json(&no).unwrap() == PartnerSignal::No('q')
+        );
+        assert!(PartnerSignal::from_json(&json!({"foo": "bar"})).is_err());
+        let signals = [
+            PartnerSignal::Need('q'),
+            PartnerSignal::No('p'),
+            PartnerSignal::Go,
+            PartnerSignal::WatchTime,
+            text,
+        ];
+        for signal in signals.iter() {
+            let parsed =
+                PartnerSignal::from_parts(signal.kind(), &signal.payload());
+            assert!(parsed.as_ref() == Some(signal));
+        }
+    }
+
+    #[test]
+    fn limiter() {
+        let limiter = SignalLimiter::new(2, 1000);
+        let uid = Uuid::new_v4();
+        assert!(limiter.allow(&uid, 0));
+        assert!(limiter.allow(&uid, 100));
+        assert!(!limiter.allow(&uid, 900));
+        assert!(limiter.allow(&uid, 1000));
+        assert!(!limiter.allow(&uid, 1050));
+        assert!(limiter.allow(&Uuid::new_v4(), 1050));
+        // Both users' windows are empty by now
+        assert!(limiter.allow(&uid, 5000));
+        assert!(limiter.sent.lock().unwrap().len() == 1);
+    }
+}
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::error::Error;
+use crate::time_control::TimeControl;
+
+// Estimated game length (see TimeControl::get_estimated_secs) below which a
+// game falls in each category.
+const BULLET_MAX_SECS: i32 = 180; // 1|0, 2|0, 1|2
+const BLITZ_MAX_SECS: i32 = 480; // 3|0, 5|0, 2|5
+
+// Ratings are kept separately for each category.  Bullet bughouse and slow
+// bughouse are different enough skills that one number mixes them badly.
+#[derive(
+    Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingCategory {
+    Bullet,
+    Blitz,
+    Standard,
+}
+
+impl RatingCategory {
+    pub const ALL: [RatingCategory; 3] = [
+        RatingCategory::Bullet,
+        RatingCategory::Blitz,
+        RatingCategory::Standard,
+    ];
+
+    pub fn of(time_ctrl: &TimeControl) -> Self {
+        let secs = time_ctrl.get_estimated_secs();
+        if secs < BULLET_MAX_SECS {
+            RatingCategory::Bullet
+        } else if secs < BLITZ_MAX_SECS {
+            RatingCategory::Blitz
+        } else {
+            RatingCategory::Standard
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RatingCategory::Bullet => "bullet",
+            RatingCategory::Blitz => "blitz",
+            RatingCategory::Standard => "standard",
+        }
+    }
+}
+
+// The category shown when there's no game to pick one, e.g. the online
+// players list.
+impl Default for RatingCategory {
+    fn default() -> Self {
+        RatingCategory::Blitz
+    }
+}
+
+impl fmt::Display for RatingCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for RatingCategory {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RatingCategory::ALL
+            .iter()
+            .find(|category| category.as_str() == s)
+            .copied()
+            .ok_or_else(|| Error::UnknownRatingCategory(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn categories() {
+        let category =
+            |s| RatingCategory::of(&TimeControl::from_str(s).unwrap());
+        assert!(category("1|0") == RatingCategory::Bullet);
+        assert!(category("2|0") == RatingCategory::Bullet);
+        assert!(category("3|0") == RatingCategory::Blitz);
+        assert!(category("5|0") == RatingCategory::Blitz);
+        assert!(category("2|12") == RatingCategory::Standard);
+        assert!(category("15|0") == RatingCategory::Standard);
+        for category in RatingCategory::ALL.iter() {
+            assert!(
+                RatingCategory::from_str(category.as_str()).unwrap()
+                    == *category
+            );
+        }
+        assert!(RatingCategory::from_str("crazyhouse").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::prelude::*;
+
+use super::{
+    PartnershipRating, Rating, RatingSystem, Team, UserRating, INIT_DEVIATION,
+};
+
+pub const NAME: &str = "fics";
+
+// q = (ln 10)/800 for bughouse
+const Q: f64 = 0.00287823136624255730697807820206435280852019786834716796875;
+
+// q = (ln 10)/400 for partnerships, which are rated like a single player
+const Q_PARTNERSHIP: f64 = 2f64 * Q;
+
+//        3 (ln 10)^2
+// p =  -------------
+//       Pi^2 400^2   .
+const P: f64 =
+    0.0000025180996504909952245720126950967454604324302636086940765380859375;
+
+// #[cached]
+// fn p() -> f64 {
+//     3.0f64 * 10.0_f64.ln().powi(2) / consts::PI.powi(2) * 400.0f64.powi(2)
+// }
+
+// RD' = Sqrt(RD^2 + ct), t in days.  Takes an RD of 50 back up to 350 after a
+// year away.
+const C: f64 = (350f64 * 350f64 - 50f64 * 50f64) / 365f64;
+
+// The one-step Glicko variant FICS uses for bughouse
+pub struct Fics {
+    pub c: f64,
+}
+
+impl Default for Fics {
+    fn default() -> Self {
+        Fics { c: C }
+    }
+}
+
+impl Fics {
+    fn inflate(&self, rating: &mut Rating, idle_days: f64) {
+        let rd = rating.deviation as f64;
+        let inflated = (rd.powi(2) + self.c * idle_days).sqrt();
+        rating.deviation = inflated.min(INIT_DEVIATION as f64).round() as i16;
+    }
+
+    // f =  1/Sqrt(1 + p (RD1^2 + RD2^2 + RD3^2))
+    fn get_attenuating_factors(flat_snaps: &[UserRating; 4]) -> [f64; 4] {
+        let mut attenuating_factors = [0f64; 4];
+        for i in 0..flat_snaps.len() {
+            let mut sum = 0f64;
+            for (j, other) in flat_snaps.iter().enumerate() {
+                if j == i {
+                    continue;
+                }
+                sum += (other.rating.deviation as f64).powi(2);
+            }
+            attenuating_factors[i] = (P * sum + 1f64).sqrt().powi(-1);
+        }
+        attenuating_factors
+    }
+
+    fn get_expecteds(
+        flat_ratings: &[UserRating; 4],
+        attenuating_factors: &[f64; 4],
+    ) -> [f64; 4] {
+        // a_rating, b_rating = team ratings (average)
+        let a_rating = (flat_ratings[0].rating.rating as f64
+            + flat_ratings[3].rating.rating as f64)
+            / 2f64;
+        let b_rating = (flat_ratings[1].rating.rating as f64
+            + flat_ratings[2].rating.rating as f64)
+            / 2f64;
+        let mut expecteds = [0f64; 4];
+        for (i, f) in attenuating_factors.iter().enumerate() {
+            let (rating, opp_rating) = if Team::of(i) == Team::A {
+                (a_rating, b_rating)
+            } else {
+                (b_rating, a_rating)
+            };
+            expecteds[i] =
+                1f64 / (1f64 + 10f64.powf((opp_rating - rating) * f / 400f64));
+        }
+        expecteds
+    }
+
+    fn update_ratings(ratings: &mut [UserRating; 4], winners: Team) -> () {
+        let attenuating_factors = Self::get_attenuating_factors(ratings);
+        let expecteds = Self::get_expecteds(ratings, &attenuating_factors);
+        for (i, rating) in ratings.iter_mut().enumerate() {
+            let f = attenuating_factors[i];
+            let e = expecteds[i];
+            let denom = (rating.rating.deviation as f64).powi(-2)
+                + (Q.powi(2) * f.powi(2) * e * (1f64 - e));
+            let k_factor = (Q * f / denom).max(16_f64);
+            let w = if Team::of(i) == winners { 1f64 } else { 0f64 };
+            rating.rating.rating += (k_factor * (w - e)).round() as i16;
+            rating.rating.deviation = denom.sqrt().powi(-1).round() as i16;
+        }
+    }
+
+    // Plain one-on-one Glicko between the two partnerships
+    fn update_partnerships(
+        ratings: &mut [PartnershipRating; 2],
+        winners: Team,
+    ) {
+        let pre = [ratings[0].rating, ratings[1].rating];
+        for (i, rating) in ratings.iter_mut().enumerate() {
+            let opp = pre[1 - i];
+            let f = (P * (opp.deviation as f64).powi(2) + 1f64).sqrt().powi(-1);
+            let diff = (opp.rating - pre[i].rating) as f64;
+            let e = 1f64 / (1f64 + 10f64.powf(diff * f / 400f64));
+            let denom = (pre[i].deviation as f64).powi(-2)
+                + (Q_PARTNERSHIP.powi(2) * f.powi(2) * e * (1f64 - e));
+            let k_factor = (Q_PARTNERSHIP * f / denom).max(16_f64);
+            let w = if Team::of_partnership(i) == winners {
+                1f64
+            } else {
+                0f64
+            };
+            rating.rating.rating += (k_factor * (w - e)).round() as i16;
+            rating.rating.deviation = denom.sqrt().powi(-1).round() as i16;
+        }
+    }
+}
+
+impl RatingSystem for Fics {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn rate(
+        &self,
+        ratings: &[UserRating; 4],
+        winners: Team,
+        now: DateTime<Utc>,
+    ) -> [UserRating; 4] {
+        let mut updated = *ratings;
+        for rating in updated.iter_mut() {
+            let idle_days = rating.idle_days(now);
+            self.inflate(&mut rating.rating, idle_days);
+        }
+        Self::update_ratings(&mut updated, winners);
+        for rating in updated.iter_mut() {
+            rating.last_rated = Some(now);
+        }
+        updated
+    }
+
+    fn rate_partnerships(
+        &self,
+        ratings: &[PartnershipRating; 2],
+        winners: Team,
+        now: DateTime<Utc>,
+    ) -> [PartnershipRating; 2] {
+        let mut updated = *ratings;
+        for rating in updated.iter_mut() {
+            let idle_days = rating.idle_days(now);
+            self.inflate(&mut rating.rating, idle_days);
+        }
+        Self::update_partnerships(&mut updated, winners);
+        for rating in updated.iter_mut() {
+            rating.last_rated = Some(now);
+        }
+        updated
+    }
+}
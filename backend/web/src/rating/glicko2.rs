@@ -0,0 +1,327 @@
+use chrono::prelude::*;
+use std::f64::consts::PI;
+
+use super::{
+    PartnershipRating, Rating, RatingSystem, Team, UserRating, INIT_DEVIATION,
+    INIT_RATING,
+};
+
+pub const NAME: &str = "glicko2";
+
+// Glicko-2 works on a scale where 1500 => 0 and 1 unit == 173.7178 points
+const SCALE: f64 = 173.7178;
+const MIN_DEVIATION: f64 = 45f64;
+const EPSILON: f64 = 0.000001;
+
+// Glicko-2 (Glickman, "Example of the Glicko-2 system") with two changes:
+//
+// 1. Every game is its own rating period.  Instead of inflating RD once per
+//    period, RD is inflated by the number of periods (`period_days` long) a
+//    player sat out since their last rated game.
+// 2. Team play: your side's strength is the average of you and your partner,
+//    against the average of your opponents. The uncertainty of the other
+//    three players plays the role of the opponent's RD.  Your own rating only
+//    makes up half of your side's, so it moves half as fast per unit of
+//    surprise (the same reason FICS uses q = ln(10)/800 for bughouse).
+//    Partnerships are rated as a single player against the other team, with
+//    plain one-on-one Glicko-2.
+pub struct Glicko2 {
+    // Constrains volatility changes.  Glickman suggests 0.3 - 1.2
+    pub tau: f64,
+    pub period_days: f64,
+}
+
+impl Default for Glicko2 {
+    fn default() -> Self {
+        Glicko2 {
+            tau: 0.5,
+            period_days: 1f64,
+        }
+    }
+}
+
+struct Scaled {
+    mu: f64,
+    phi: f64,
+    sigma: f64,
+}
+
+impl Scaled {
+    fn new(rating: &Rating, volatility: f64) -> Self {
+        Scaled {
+            mu: (rating.rating - INIT_RATING) as f64 / SCALE,
+            phi: rating.deviation as f64 / SCALE,
+            sigma: volatility,
+        }
+    }
+
+    fn to_rating(&self) -> Rating {
+        Rating::new(
+            (self.mu * SCALE + INIT_RATING as f64).round() as i16,
+            (self.phi * SCALE)
+                .max(MIN_DEVIATION)
+                .min(INIT_DEVIATION as f64)
+                .round() as i16,
+        )
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    1f64 / (1f64 + 3f64 * phi.powi(2) / PI.powi(2)).sqrt()
+}
+
+impl Glicko2 {
+    // New volatility via the Illinois algorithm (step 5 of the paper)
+    fn volatility(&self, phi: f64, sigma: f64, v: f64, delta: f64) -> f64 {
+        let tau = self.tau;
+        let a = sigma.powi(2).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            let denom = 2f64 * (phi.powi(2) + v + ex).powi(2);
+            ex * (delta.powi(2) - phi.powi(2) - v - ex) / denom
+                - (x - a) / tau.powi(2)
+        };
+        let mut big_a = a;
+        let mut big_b = if delta.powi(2) > phi.powi(2) + v {
+            (delta.powi(2) - phi.powi(2) - v).ln()
+        } else {
+            let mut k = 1f64;
+            while f(a - k * tau) < 0f64 {
+                k += 1f64;
+            }
+            a - k * tau
+        };
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        while (big_b - big_a).abs() > EPSILON {
+            let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(big_c);
+            if f_c * f_b <= 0f64 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2f64;
+            }
+            big_b = big_c;
+            f_b = f_c;
+        }
+        (big_a / 2f64).exp()
+    }
+
+    // Pre-period RD inflation for `idle_days` without a rated game
+    fn inflate(&self, s: &mut Scaled, idle_days: f64) {
+        let max_phi = INIT_DEVIATION as f64 / SCALE;
+        let periods = idle_days / self.period_days;
+        s.phi = (s.phi.powi(2) + periods * s.sigma.powi(2))
+            .sqrt()
+            .min(max_phi);
+    }
+
+    // Steps 3-7 of the paper for a single game.  `diff` is our side's mu
+    // minus the opponents', `others_phi` the uncertainty of `diff` not
+    // counting our own RD, and `weight` d(diff)/d(mu).
+    fn update(
+        &self,
+        me: &Scaled,
+        diff: f64,
+        others_phi: f64,
+        weight: f64,
+        s: f64,
+    ) -> Scaled {
+        let g_i = g(others_phi) * weight;
+        let e = 1f64 / (1f64 + (-g(others_phi) * diff).exp());
+        let v = 1f64 / (g_i.powi(2) * e * (1f64 - e));
+        let delta = v * g_i * (s - e);
+        let sigma = self.volatility(me.phi, me.sigma, v, delta);
+        let phi_star = (me.phi.powi(2) + sigma.powi(2)).sqrt();
+        let phi = 1f64 / (1f64 / phi_star.powi(2) + 1f64 / v).sqrt();
+        let mu = me.mu + phi.powi(2) * g_i * (s - e);
+        Scaled { mu, phi, sigma }
+    }
+
+    // Index of partner and opponents in [aw, ab, bw, bb]
+    fn others(i: usize) -> (usize, [usize; 2]) {
+        match i {
+            0 => (3, [1, 2]),
+            1 => (2, [0, 3]),
+            2 => (1, [0, 3]),
+            _ => (0, [1, 2]),
+        }
+    }
+}
+
+impl RatingSystem for Glicko2 {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn rate(
+        &self,
+        ratings: &[UserRating; 4],
+        winners: Team,
+        now: DateTime<Utc>,
+    ) -> [UserRating; 4] {
+        let scaled: Vec<Scaled> = ratings
+            .iter()
+            .map(|rating| {
+                let mut s = Scaled::new(&rating.rating, rating.volatility);
+                self.inflate(&mut s, rating.idle_days(now));
+                s
+            })
+            .collect();
+        let mut updated = *ratings;
+        for (i, rating) in updated.iter_mut().enumerate() {
+            let me = &scaled[i];
+            let (partner_idx, opp_idxs) = Self::others(i);
+            let partner = &scaled[partner_idx];
+            let [o1, o2] = [&scaled[opp_idxs[0]], &scaled[opp_idxs[1]]];
+            // Difference between the sides' averages, and its uncertainty
+            // not counting our own RD.
+            let diff = (me.mu + partner.mu - o1.mu - o2.mu) / 2f64;
+            let others_phi =
+                ((partner.phi.powi(2) + o1.phi.powi(2) + o2.phi.powi(2))
+                    / 4f64)
+                    .sqrt();
+            let s = if Team::of(i) == winners { 1f64 } else { 0f64 };
+            // d(diff)/d(mu) = 1/2
+            let new = self.update(me, diff, others_phi, 0.5f64, s);
+            rating.rating = new.to_rating();
+            rating.volatility = new.sigma;
+            rating.last_rated = Some(now);
+        }
+        updated
+    }
+
+    fn rate_partnerships(
+        &self,
+        ratings: &[PartnershipRating; 2],
+        winners: Team,
+        now: DateTime<Utc>,
+    ) -> [PartnershipRating; 2] {
+        let scaled: Vec<Scaled> = ratings
+            .iter()
+            .map(|rating| {
+                let mut s = Scaled::new(&rating.rating, rating.volatility);
+                self.inflate(&mut s, rating.idle_days(now));
+                s
+            })
+            .collect();
+        let mut updated = *ratings;
+        for (i, rating) in updated.iter_mut().enumerate() {
+            let (me, opp) = (&scaled[i], &scaled[1 - i]);
+            let s = if Team::of_partnership(i) == winners {
+                1f64
+            } else {
+                0f64
+            };
+            let new = self.update(me, me.mu - opp.mu, opp.phi, 1f64, s);
+            rating.rating = new.to_rating();
+            rating.volatility = new.sigma;
+            rating.last_rated = Some(now);
+        }
+        updated
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rating::Partnership;
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    fn rating(r: i16, rd: i16, last: Option<DateTime<Utc>>) -> UserRating {
+        UserRating {
+            last_rated: last,
+            ..UserRating::new(Uuid::new_v4(), Rating::new(r, rd))
+        }
+    }
+
+    #[test]
+    fn winners_gain() {
+        let now = Utc::now();
+        let ratings = [
+            rating(1500, 200, None),
+            rating(1500, 200, None),
+            rating(1500, 200, None),
+            rating(1500, 200, None),
+        ];
+        let updated = Glicko2::default().rate(&ratings, Team::A, now);
+        assert!(updated[0].rating.rating > 1500);
+        assert!(updated[3].rating.rating > 1500);
+        assert!(updated[1].rating.rating < 1500);
+        assert!(updated[2].rating.rating < 1500);
+        assert!(
+            updated[0].rating.rating - 1500 == 1500 - updated[1].rating.rating
+        );
+        for rating in updated.iter() {
+            assert!(rating.rating.deviation < 200);
+            assert!(rating.last_rated == Some(now));
+        }
+    }
+
+    #[test]
+    fn upset_moves_more() {
+        let now = Utc::now();
+        let ratings = [
+            rating(1400, 100, None),
+            rating(1600, 100, None),
+            rating(1600, 100, None),
+            rating(1400, 100, None),
+        ];
+        let system = Glicko2::default();
+        let upset = system.rate(&ratings, Team::A, now);
+        let expected = system.rate(&ratings, Team::B, now);
+        let upset_gain = upset[0].rating.rating - 1400;
+        let expected_gain = expected[1].rating.rating - 1600;
+        assert!(upset_gain > expected_gain);
+    }
+
+    #[test]
+    fn partnerships() {
+        let now = Utc::now();
+        let mk = |r, rd| PartnershipRating {
+            rating: Rating::new(r, rd),
+            ..PartnershipRating::new(Partnership::new(
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+            ))
+        };
+        let system = Glicko2::default();
+        let even = system.rate_partnerships(
+            &[mk(1500, 200), mk(1500, 200)],
+            Team::B,
+            now,
+        );
+        assert!(even[0].rating.rating < 1500);
+        assert!(even[1].rating.rating > 1500);
+        assert!(even[1].rating.rating - 1500 == 1500 - even[0].rating.rating);
+        // A partnership moves like one player, so faster than either partner
+        // would individually.
+        let ratings = [rating(1500, 200, None); 4];
+        let individual = system.rate(&ratings, Team::B, now);
+        assert!(
+            even[1].rating.rating - 1500 > individual[1].rating.rating - 1500
+        );
+    }
+
+    #[test]
+    fn idle_inflates_deviation() {
+        let now = Utc::now();
+        let recent = Some(now - Duration::hours(1));
+        let long_ago = Some(now - Duration::days(365));
+        let mk = |last| {
+            [
+                rating(1500, 60, last),
+                rating(1500, 60, recent),
+                rating(1500, 60, recent),
+                rating(1500, 60, recent),
+            ]
+        };
+        let system = Glicko2::default();
+        let active = system.rate(&mk(recent), Team::A, now);
+        let returning = system.rate(&mk(long_ago), Team::A, now);
+        assert!(returning[0].rating.deviation > active[0].rating.deviation);
+        assert!(returning[0].rating.rating > active[0].rating.rating);
+    }
+}
@@ -0,0 +1,174 @@
+use std::env;
+use std::str::FromStr;
+
+use super::{Team, UserRating};
+
+// Limits on what a rated game can do to a rating, applied on top of any
+// RatingSystem.  Mostly protects established players from provisional ones,
+// whose ratings (new accounts, and smurfs) say little about how they play.
+#[derive(Clone, Debug)]
+pub struct RatingGuard {
+    // How much a provisional player counts, from 0 to 1, when rating an
+    // established player they played with or against.
+    pub provisional_weight: f64,
+    // Most an established player can lose in a game against a provisional
+    // opponent
+    pub max_provisional_loss: Option<i16>,
+    // No game takes a rating below this
+    pub floor: Option<i16>,
+}
+
+impl Default for RatingGuard {
+    fn default() -> Self {
+        RatingGuard {
+            provisional_weight: 0.5,
+            max_provisional_loss: None,
+            floor: None,
+        }
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str) -> Option<T> {
+    let val = env::var(name).ok()?;
+    let parsed = val.parse::<T>().ok();
+    if parsed.is_none() {
+        eprintln!("Ignoring invalid {}={}", name, val);
+    }
+    parsed
+}
+
+impl RatingGuard {
+    // PROVISIONAL_WEIGHT, MAX_PROVISIONAL_LOSS and RATING_FLOOR
+    pub fn from_env() -> Self {
+        let default = RatingGuard::default();
+        RatingGuard {
+            provisional_weight: parse_env("PROVISIONAL_WEIGHT")
+                .map_or(default.provisional_weight, |w: f64| {
+                    w.max(0f64).min(1f64)
+                }),
+            max_provisional_loss: parse_env("MAX_PROVISIONAL_LOSS"),
+            floor: parse_env("RATING_FLOOR"),
+        }
+    }
+
+    // `before` and `after` a RatingSystem rated a game, in [aw, ab, bw, bb]
+    // order.  Only ratings change, not deviations.
+    pub fn apply(&self, before: &[UserRating; 4], after: &mut [UserRating; 4]) {
+        for (i, rating) in after.iter_mut().enumerate() {
+            let prev = before[i].rating.rating;
+            let mut delta = (rating.rating.rating - prev) as f64;
+            if !before[i].is_provisional() {
+                let others = (0..4).filter(|j| *j != i);
+                let weight: f64 = others
+                    .clone()
+                    .map(|j| {
+                        if before[j].is_provisional() {
+                            self.provisional_weight
+                        } else {
+                            1f64
+                        }
+                    })
+                    .sum();
+                delta *= weight / 3f64;
+                let provisional_opp = others.clone().any(|j| {
+                    Team::of(j) != Team::of(i) && before[j].is_provisional()
+                });
+                if let (true, Some(max_loss)) =
+                    (provisional_opp, self.max_provisional_loss)
+                {
+                    delta = delta.max(-(max_loss as f64));
+                }
+            }
+            let mut new = prev + delta.round() as i16;
+            if let Some(floor) = self.floor {
+                // Don't lift anyone already below a newly raised floor
+                new = new.max(floor.min(prev));
+            }
+            rating.rating.rating = new;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rating::{Rating, PROVISIONAL_GAMES};
+    use uuid::Uuid;
+
+    fn rating(r: i16, games: i32) -> UserRating {
+        UserRating {
+            games,
+            ..UserRating::new(Uuid::new_v4(), Rating::new(r, 100))
+        }
+    }
+
+    fn rated(before: &[UserRating; 4], deltas: [i16; 4]) -> [UserRating; 4] {
+        let mut after = *before;
+        for (i, rating) in after.iter_mut().enumerate() {
+            rating.rating.rating += deltas[i];
+        }
+        after
+    }
+
+    #[test]
+    fn established_only() {
+        let guard = RatingGuard {
+            max_provisional_loss: Some(5),
+            floor: Some(1000),
+            ..RatingGuard::default()
+        };
+        let before = [
+            rating(1500, PROVISIONAL_GAMES),
+            rating(1500, 100),
+            rating(1500, 100),
+            rating(1500, 100),
+        ];
+        let mut after = rated(&before, [12, -12, -12, 12]);
+        guard.apply(&before, &mut after);
+        let ratings: Vec<i16> = after.iter().map(|r| r.rating.rating).collect();
+        assert!(ratings == vec![1512, 1488, 1488, 1512]);
+    }
+
+    #[test]
+    fn provisional_counts_less() {
+        let guard = RatingGuard {
+            max_provisional_loss: Some(5),
+            ..RatingGuard::default()
+        };
+        // Team A's bb is a provisional smurf
+        let before = [
+            rating(1500, 100),
+            rating(1500, 100),
+            rating(1500, 100),
+            rating(1500, 0),
+        ];
+        let mut after = rated(&before, [12, -12, -12, 60]);
+        guard.apply(&before, &mut after);
+        // Partner of the smurf: 12 * (0.5 + 1 + 1) / 3
+        assert!(after[0].rating.rating == 1510);
+        // Opponents of the smurf: capped loss
+        assert!(after[1].rating.rating == 1495);
+        assert!(after[2].rating.rating == 1495);
+        // Provisional ratings move freely
+        assert!(after[3].rating.rating == 1560);
+    }
+
+    #[test]
+    fn floor() {
+        let guard = RatingGuard {
+            floor: Some(1000),
+            ..RatingGuard::default()
+        };
+        let before = [
+            rating(1010, 100),
+            rating(990, 100),
+            rating(1500, 100),
+            rating(1500, 100),
+        ];
+        let mut after = rated(&before, [-20, -20, 20, 20]);
+        guard.apply(&before, &mut after);
+        assert!(after[0].rating.rating == 1000);
+        assert!(after[1].rating.rating == 990);
+        assert!(after[2].rating.rating == 1520);
+    }
+}
@@ -0,0 +1,226 @@
+// See ratings.md in top-level docs folder in this repo
+pub mod category;
+pub mod fics;
+pub mod glicko2;
+pub mod guard;
+pub mod partnership;
+pub mod replay;
+
+use bughouse::{BoardID, Color};
+use chrono::prelude::*;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+use crate::game::{Game, GameResult};
+use crate::users::UserID;
+
+pub use category::RatingCategory;
+pub use guard::RatingGuard;
+pub use partnership::{Partnership, PartnershipRating};
+
+pub const INIT_RATING: i16 = 1500;
+pub const INIT_DEVIATION: i16 = 350;
+pub const INIT_VOLATILITY: f64 = 0.06;
+// Ratings are provisional (shown with a "?") for a category's first this many
+// rated games
+pub const PROVISIONAL_GAMES: i32 = 20;
+
+#[derive(PartialEq, Eq, Ord, PartialOrd, Copy, Clone, Debug, Hash)]
+pub struct Rating {
+    pub rating: i16,
+    pub deviation: i16,
+}
+
+impl Rating {
+    pub fn new(rating: i16, deviation: i16) -> Self {
+        Rating { rating, deviation }
+    }
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating::new(INIT_RATING, INIT_DEVIATION)
+    }
+}
+
+// Team A is board A's white and board B's black. Team B is the other two.
+#[derive(PartialEq, Eq, Ord, PartialOrd, Copy, Clone, Debug)]
+pub enum Team {
+    A,
+    B,
+}
+
+impl Team {
+    // Index into [aw, ab, bw, bb]
+    pub fn of(idx: usize) -> Team {
+        if idx == 0 || idx == 3 {
+            Team::A
+        } else {
+            Team::B
+        }
+    }
+
+    // Index into [team A, team B]
+    pub fn of_partnership(idx: usize) -> Team {
+        if idx == 0 {
+            Team::A
+        } else {
+            Team::B
+        }
+    }
+
+    pub fn winner(result: &GameResult) -> Team {
+        if (result.board == BoardID::A) == (result.winner == Color::White) {
+            Team::A
+        } else {
+            Team::B
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UserRating {
+    pub uid: UserID,
+    pub rating: Rating,
+    pub volatility: f64,
+    // Rated games played in this category
+    pub games: i32,
+    // None if they've never played a rated game
+    pub last_rated: Option<DateTime<Utc>>,
+}
+
+impl UserRating {
+    pub fn new(uid: UserID, rating: Rating) -> Self {
+        UserRating {
+            uid,
+            rating,
+            volatility: INIT_VOLATILITY,
+            games: 0,
+            last_rated: None,
+        }
+    }
+
+    pub fn to_row(&self) -> (i16, i16, UserID) {
+        (self.rating.rating, self.rating.deviation, self.uid)
+    }
+
+    pub fn idle_days(&self, now: DateTime<Utc>) -> f64 {
+        idle_days(self.last_rated, now)
+    }
+
+    pub fn is_provisional(&self) -> bool {
+        self.games < PROVISIONAL_GAMES
+    }
+
+    // Pre-game ratings, in the game's category, in [aw, ab, bw, bb] order
+    // from the in-memory users.  Volatility, games and last_rated have to
+    // come from the DB.
+    pub fn from_game(game: &Game) -> [UserRating; 4] {
+        let category = game.get_rating_category();
+        let [[aw, ab], [bw, bb]] = &game.players;
+        let flat_players = [aw, ab, bw, bb];
+        let mut user_ratings: [UserRating; 4] = [UserRating::default(); 4];
+        for (idx, player) in flat_players.iter().enumerate() {
+            let rplayer = player.as_ref().unwrap().read().unwrap();
+            user_ratings[idx] = UserRating::new(
+                *rplayer.get_uid(),
+                rplayer.get_rating(category),
+            );
+        }
+        user_ratings
+    }
+}
+
+impl Default for UserRating {
+    fn default() -> Self {
+        UserRating::new(Uuid::nil(), Rating::default())
+    }
+}
+
+// Days since `last_rated`.  0 if they've never been rated
+pub fn idle_days(last_rated: Option<DateTime<Utc>>, now: DateTime<Utc>) -> f64 {
+    match last_rated {
+        Some(t) => (now - t).num_seconds().max(0) as f64 / 86400f64,
+        None => 0f64,
+    }
+}
+
+pub trait RatingSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // `ratings` in [aw, ab, bw, bb] order.  Returns the post-game ratings
+    // with last_rated set to `now`.  Doesn't touch `games`.
+    fn rate(
+        &self,
+        ratings: &[UserRating; 4],
+        winners: Team,
+        now: DateTime<Utc>,
+    ) -> [UserRating; 4];
+
+    // `ratings` in [team A, team B] order, each partnership rated as a
+    // single player.  Same contract as `rate` otherwise.
+    fn rate_partnerships(
+        &self,
+        ratings: &[PartnershipRating; 2],
+        winners: Team,
+        now: DateTime<Utc>,
+    ) -> [PartnershipRating; 2];
+}
+
+pub fn from_name(name: &str) -> Option<Box<dyn RatingSystem>> {
+    match name {
+        fics::NAME => Some(Box::new(fics::Fics::default())),
+        glicko2::NAME => Some(Box::new(glicko2::Glicko2::default())),
+        _ => None,
+    }
+}
+
+// RATING_SYSTEM=fics (default)|glicko2
+pub fn from_env() -> Box<dyn RatingSystem> {
+    let name = std::env::var("RATING_SYSTEM")
+        .unwrap_or_else(|_| fics::NAME.to_string());
+    from_name(&name).unwrap_or_else(|| {
+        eprintln!("Unknown RATING_SYSTEM {}, using {}", name, fics::NAME);
+        Box::new(fics::Fics::default())
+    })
+}
+
+// Games are rated as of their start, live or replayed (see Replay), so a
+// replay comes up with the same ratings the server did
+pub fn rated_at(game: &Game) -> DateTime<Utc> {
+    game.get_start().unwrap_or_else(Utc::now)
+}
+
+pub fn get_updated_ratings(
+    system: &dyn RatingSystem,
+    guard: &RatingGuard,
+    game: Arc<RwLock<Game>>,
+    ratings: &[UserRating; 4],
+) -> [UserRating; 4] {
+    let rgame = game.read().unwrap();
+    let winners = Team::winner(&rgame.get_result().unwrap());
+    let mut updated = system.rate(ratings, winners, rated_at(&rgame));
+    guard.apply(ratings, &mut updated);
+    for rating in updated.iter_mut() {
+        rating.games += 1;
+    }
+    updated
+}
+
+pub fn get_updated_partnerships(
+    system: &dyn RatingSystem,
+    game: Arc<RwLock<Game>>,
+    ratings: &[PartnershipRating; 2],
+) -> [PartnershipRating; 2] {
+    let rgame = game.read().unwrap();
+    let winners = Team::winner(&rgame.get_result().unwrap());
+    let mut updated =
+        system.rate_partnerships(ratings, winners, rated_at(&rgame));
+    for (i, rating) in updated.iter_mut().enumerate() {
+        rating.games += 1;
+        if Team::of_partnership(i) == winners {
+            rating.wins += 1;
+        }
+    }
+    updated
+}
@@ -0,0 +1,72 @@
+use chrono::prelude::*;
+
+use super::{idle_days, Rating, INIT_VOLATILITY};
+use crate::game::Game;
+use crate::users::UserID;
+
+// An unordered pair of partners.  Always stored lowest uid first so (a, b)
+// and (b, a) are the same partnership.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Partnership {
+    pub uids: [UserID; 2],
+}
+
+impl Partnership {
+    pub fn new(a: UserID, b: UserID) -> Self {
+        let uids = if a <= b { [a, b] } else { [b, a] };
+        Partnership { uids }
+    }
+
+    pub fn contains(&self, uid: &UserID) -> bool {
+        self.uids.contains(uid)
+    }
+
+    // The other half of the partnership, if `uid` is in it
+    pub fn partner_of(&self, uid: &UserID) -> Option<UserID> {
+        match self.uids {
+            [a, b] if a == *uid => Some(b),
+            [a, b] if b == *uid => Some(a),
+            _ => None,
+        }
+    }
+
+    // [team A, team B] of a game.  Team A is board A's white and board B's
+    // black.
+    pub fn from_game(game: &Game) -> [Partnership; 2] {
+        let [[aw, ab], [bw, bb]] = &game.players;
+        [
+            Partnership::new(Game::uid(aw), Game::uid(bb)),
+            Partnership::new(Game::uid(ab), Game::uid(bw)),
+        ]
+    }
+}
+
+// A partnership's rating is its own, independent of either partner's
+// individual rating.  It's rated as one player against the other team.
+#[derive(Clone, Copy, Debug)]
+pub struct PartnershipRating {
+    pub partners: Partnership,
+    pub rating: Rating,
+    pub volatility: f64,
+    pub games: i32,
+    pub wins: i32,
+    // None if they've never played a rated game together
+    pub last_rated: Option<DateTime<Utc>>,
+}
+
+impl PartnershipRating {
+    pub fn new(partners: Partnership) -> Self {
+        PartnershipRating {
+            partners,
+            rating: Rating::default(),
+            volatility: INIT_VOLATILITY,
+            games: 0,
+            wins: 0,
+            last_rated: None,
+        }
+    }
+
+    pub fn idle_days(&self, now: DateTime<Utc>) -> f64 {
+        idle_days(self.last_rated, now)
+    }
+}
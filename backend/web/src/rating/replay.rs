@@ -0,0 +1,192 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::HashMap;
+
+use super::{
+    Partnership, PartnershipRating, Rating, RatingCategory, RatingGuard,
+    RatingSystem, Team, UserRating,
+};
+use crate::db::TableSnapshot;
+use crate::game::GameID;
+use crate::game_row::GameRow;
+use crate::time_control::TimeControl;
+use crate::users::UserID;
+
+// What replaying needs from a finished, rated row of bughouse.games
+#[derive(Clone, Debug)]
+pub struct ReplayGame {
+    pub id: GameID,
+    pub start: DateTime<Utc>,
+    pub category: RatingCategory,
+    // [aw, ab, bw, bb]
+    pub players: [UserID; 4],
+    pub winners: Team,
+}
+
+impl ReplayGame {
+    // None for games that never finished or are missing a player
+    pub fn new(
+        id: GameID,
+        start_time: Duration,
+        result: i16,
+        time_ctrl: &TimeControl,
+        players: &TableSnapshot,
+    ) -> Option<Self> {
+        let ((aw, ab), (bw, bb)) = players;
+        let players = [aw.uid, ab.uid, bw.uid, bb.uid];
+        if result < 0 || players.iter().any(|uid| uid.is_nil()) {
+            return None;
+        }
+        Some(ReplayGame {
+            id,
+            start: Utc.timestamp_millis(start_time.num_milliseconds()),
+            category: RatingCategory::of(time_ctrl),
+            players,
+            winners: Team::winner(&GameRow::deserialize_result(result)),
+        })
+    }
+
+    fn partnerships(&self) -> [Partnership; 2] {
+        let [aw, ab, bw, bb] = self.players;
+        [Partnership::new(aw, bb), Partnership::new(ab, bw)]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub uid: UserID,
+    pub category: RatingCategory,
+    pub time: DateTime<Utc>,
+    pub rating: Rating,
+    pub volatility: f64,
+}
+
+// Rebuilds every rating from nothing by running rated games through a
+// RatingSystem in the order they were played.  Nothing touches the DB.
+pub struct Replay<'a> {
+    system: &'a dyn RatingSystem,
+    guard: &'a RatingGuard,
+    pub ratings: HashMap<(UserID, RatingCategory), UserRating>,
+    pub partnerships: HashMap<(Partnership, RatingCategory), PartnershipRating>,
+    pub history: Vec<HistoryEntry>,
+    pub games: usize,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(system: &'a dyn RatingSystem, guard: &'a RatingGuard) -> Self {
+        Replay {
+            system,
+            guard,
+            ratings: HashMap::new(),
+            partnerships: HashMap::new(),
+            history: Vec::new(),
+            games: 0,
+        }
+    }
+
+    // Sorts `games` chronologically first
+    pub fn play_all(&mut self, games: &mut Vec<ReplayGame>) {
+        games.sort_by_key(|game| (game.start, game.id));
+        for game in games.iter() {
+            self.play(game);
+        }
+    }
+
+    pub fn play(&mut self, game: &ReplayGame) {
+        let category = game.category;
+        let mut ratings = [UserRating::default(); 4];
+        for (i, uid) in game.players.iter().enumerate() {
+            ratings[i] = *self
+                .ratings
+                .entry((*uid, category))
+                .or_insert_with(|| UserRating::new(*uid, Rating::default()));
+        }
+        let mut updated = self.system.rate(&ratings, game.winners, game.start);
+        self.guard.apply(&ratings, &mut updated);
+        for rating in updated.iter_mut() {
+            rating.games += 1;
+            self.history.push(HistoryEntry {
+                uid: rating.uid,
+                category,
+                time: game.start,
+                rating: rating.rating,
+                volatility: rating.volatility,
+            });
+            self.ratings.insert((rating.uid, category), *rating);
+        }
+
+        let partners = game.partnerships();
+        let mut partnerships = [
+            PartnershipRating::new(partners[0]),
+            PartnershipRating::new(partners[1]),
+        ];
+        for (i, partnership) in partnerships.iter_mut().enumerate() {
+            if let Some(prev) = self.partnerships.get(&(partners[i], category))
+            {
+                *partnership = *prev;
+            }
+        }
+        let updated = self.system.rate_partnerships(
+            &partnerships,
+            game.winners,
+            game.start,
+        );
+        for (i, partnership) in updated.iter().enumerate() {
+            let mut partnership = *partnership;
+            partnership.games += 1;
+            if Team::of_partnership(i) == game.winners {
+                partnership.wins += 1;
+            }
+            self.partnerships
+                .insert((partnership.partners, category), partnership);
+        }
+        self.games += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rating::glicko2::Glicko2;
+    use uuid::Uuid;
+
+    #[test]
+    fn replay() {
+        let system = Glicko2::default();
+        let players = [
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        ];
+        let start = Utc::now();
+        let game = |mins, winners| ReplayGame {
+            id: Uuid::new_v4(),
+            start: start + Duration::minutes(mins),
+            category: RatingCategory::Blitz,
+            players,
+            winners,
+        };
+        // Out of order on purpose
+        let mut games = vec![game(10, Team::A), game(0, Team::A)];
+        let guard = RatingGuard::default();
+        let mut replay = Replay::new(&system, &guard);
+        replay.play_all(&mut games);
+
+        assert!(replay.games == 2);
+        assert!(replay.history.len() == 8);
+        assert!(replay.ratings.len() == 4);
+        let aw = replay.ratings[&(players[0], RatingCategory::Blitz)];
+        let ab = replay.ratings[&(players[1], RatingCategory::Blitz)];
+        assert!(aw.games == 2 && ab.games == 2);
+        assert!(aw.rating.rating > ab.rating.rating);
+        assert!(aw.last_rated == Some(start + Duration::minutes(10)));
+
+        let team_a = Partnership::new(players[0], players[3]);
+        let team_b = Partnership::new(players[1], players[2]);
+        let a = replay.partnerships[&(team_a, RatingCategory::Blitz)];
+        let b = replay.partnerships[&(team_b, RatingCategory::Blitz)];
+        assert!(a.games == 2 && a.wins == 2);
+        assert!(b.games == 2 && b.wins == 0);
+    }
+}
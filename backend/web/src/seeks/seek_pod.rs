@@ -30,11 +30,35 @@ impl SeekPod {
         )
     }
 
+    // Partners seeking together.  They only ever join pods as a pair.
+    pub fn pair(seek: Arc<Seek>, partner_seek: Arc<Seek>) -> Self {
+        let constraint = seek.constraint.merge(&partner_seek.constraint);
+        let seeks = cons(seek, cons(partner_seek, PersistentList::new()));
+        SeekPod::new(seeks, constraint)
+    }
+
     pub fn passes(&self, s: &Seek) -> bool {
         self.constraint.passes(s.user_rating)
             && self.constraint.merge(&s.constraint).width() > 0
     }
 
+    // Combine two pods if they fit at one table and every seek is
+    // acceptable to the other pod.
+    pub fn merge(&self, pod: &SeekPod) -> Option<SeekPod> {
+        if self.seeks.len() + pod.seeks.len() > 4
+            || !pod.seeks.iter().all(|s| self.passes(s))
+            || !self.seeks.iter().all(|s| pod.passes(s))
+        {
+            return None;
+        }
+        let constraint = self.constraint.merge(&pod.constraint);
+        let seeks = pod
+            .seeks
+            .iter()
+            .fold(self.seeks.clone(), |seeks, s| cons(s.clone(), seeks));
+        Some(Self::new(seeks, constraint))
+    }
+
     pub fn form_new_pod(&self, pod: Arc<Seek>) -> Option<SeekPod> {
         if self.is_full() || !self.passes(pod.as_ref()) {
             return None;
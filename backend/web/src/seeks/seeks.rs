@@ -3,6 +3,7 @@ use std::sync::{Arc, RwLock};
 
 use crate::error::Error;
 use crate::game::GamePlayers;
+use crate::rating::RatingCategory;
 use crate::time_control::TimeControl;
 use crate::users::{UserID, Users};
 
@@ -19,11 +20,19 @@ impl SeekPool {
     pub fn new(time_ctrl: TimeControl, rated: bool) -> Self {
         SeekPool { rated, time_ctrl }
     }
+
+    pub fn get_rating_category(&self) -> RatingCategory {
+        RatingCategory::of(&self.time_ctrl)
+    }
 }
 
 #[derive(Debug)]
 pub struct Seek {
     pub uid: UserID,
+    // Seeking as a team.  Both partners have to seek with each other.
+    pub partner: Option<UserID>,
+    // In the pool's rating category.  The partnership's rating instead when
+    // partners ask to be matched on their team rating.
     pub user_rating: i16,
     pub constraint: SeekConstraint,
     // pools: HashSet<SeekPodID>,
@@ -37,10 +46,23 @@ impl Seek {
     ) -> Self {
         Seek {
             uid,
+            partner: None,
             user_rating,
             constraint,
         }
     }
+
+    pub fn with_partner(
+        uid: UserID,
+        partner: UserID,
+        user_rating: i16,
+        constraint: SeekConstraint,
+    ) -> Self {
+        Seek {
+            partner: Some(partner),
+            ..Seek::new(uid, user_rating, constraint)
+        }
+    }
 }
 
 pub struct Seeks {
@@ -115,8 +137,7 @@ impl Seeks {
         // In preparation for game_start, remove user_seeks, and existing references to the
         // Seek/SeekConstraint from other pools
         self.clean_seeks(seek_pool, pod.clone());
-        let uids = pod.seeks.iter().map(|s| &s.uid);
-        let game_players: Vec<&UserID> = uids.collect();
+        let game_players = Self::seat_partners(&pod);
         if let [aw, ab, bw, bb] = &game_players[0..4] {
             let [awp, abp, bwp, bbp] = [
                 self.users.get(aw),
@@ -136,6 +157,29 @@ impl Seeks {
         None
     }
 
+    // Order a full pod's uids as [aw, ab, bw, bb], putting partners on the
+    // same team (board A white and board B black).
+    fn seat_partners(pod: &SeekPod) -> Vec<&UserID> {
+        let uids: Vec<&UserID> = pod.seeks.iter().map(|s| &s.uid).collect();
+        let pair = pod.seeks.iter().find_map(|s| {
+            s.partner
+                .as_ref()
+                .filter(|partner| uids.contains(partner))
+                .map(|partner| (&s.uid, partner))
+        });
+        match pair {
+            Some((a, b)) => {
+                let others: Vec<&UserID> = uids
+                    .iter()
+                    .filter(|uid| **uid != a && **uid != b)
+                    .copied()
+                    .collect();
+                vec![a, others[0], others[1], b]
+            }
+            None => uids,
+        }
+    }
+
     pub fn get_user_pool(&self, uid: &UserID) -> Option<SeekPool> {
         let wuser_seeks = self.user_seeks.read().unwrap();
         if let Some((pool, _seek)) = wuser_seeks.get(&uid) {
@@ -173,6 +217,59 @@ impl Seeks {
         wpods.push(Arc::new(SeekPod::single(seek)));
     }
 
+    fn add_pair_pods(&self, seek_pool: &SeekPool, pair: SeekPod) {
+        self.ensure_queue(seek_pool);
+        let rqueue = self.pod_queues.read().unwrap();
+        let mut wpods = rqueue.get(seek_pool).unwrap().write().unwrap();
+        let len = wpods.len();
+        for idx in 0..len {
+            if let Some(new_pod) = wpods[idx].merge(&pair) {
+                wpods.push(Arc::new(new_pod));
+            }
+        }
+        wpods.push(Arc::new(pair));
+    }
+
+    // Seek as a team with `partner`.  Nothing is matched until the partner
+    // seeks with us too, in the same pool.
+    pub fn add_partner_seeker(
+        &self,
+        seek_pool: &SeekPool,
+        uid: UserID,
+        partner: UserID,
+        team_rating: Option<i16>,
+        constraint: SeekConstraint,
+    ) -> Result<(), Error> {
+        if partner == uid {
+            return Err(Error::SelfPartner);
+        }
+        let user = self.users.get(&uid).ok_or(Error::InvalidUser(uid))?;
+        let mut wuser_seeks = self.user_seeks.write().unwrap();
+        if wuser_seeks.contains_key(&uid) {
+            return Err(Error::AlreadySeeking());
+        }
+        let rating = team_rating.unwrap_or_else(|| {
+            user.read()
+                .unwrap()
+                .get_rating(seek_pool.get_rating_category())
+                .rating
+        });
+        let seek =
+            Arc::new(Seek::with_partner(uid, partner, rating, constraint));
+        wuser_seeks.insert(uid, (seek_pool.clone(), seek.clone()));
+        let partner_seek = match wuser_seeks.get(&partner) {
+            Some((pool, partner_seek))
+                if pool == seek_pool && partner_seek.partner == Some(uid) =>
+            {
+                partner_seek.clone()
+            }
+            _ => return Ok(()),
+        };
+        drop(wuser_seeks);
+        self.add_pair_pods(seek_pool, SeekPod::pair(seek, partner_seek));
+        Ok(())
+    }
+
     pub fn add_seeker(
         &self,
         seek_pool: &SeekPool,
@@ -186,7 +283,8 @@ impl Seeks {
         }
 
         let ruser = user.read().unwrap();
-        let seek = Arc::new(Seek::new(ruser.id, ruser.rating, constraint));
+        let rating = ruser.get_rating(seek_pool.get_rating_category());
+        let seek = Arc::new(Seek::new(ruser.id, rating.rating, constraint));
         wuser_seeks.insert(uid, (seek_pool.clone(), seek.clone()));
         self.add_new_pods(seek_pool, seek);
         eprintln!("pods: {:?}", self.pod_queues);
@@ -0,0 +1,87 @@
+// Private messages ("tells", see docs/FICS/tells.md).  Every tell is stored
+// under both users as their history.  Tells to someone offline are also
+// queued in pending_messages and sent when they next connect.
+use chrono::prelude::*;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::b66::B66;
+use crate::error::Error;
+use crate::users::UserID;
+
+pub type TellID = Uuid;
+
+pub const MAX_TELL_LEN: usize = 400;
+
+// Who a tell is addressed to
+#[derive(Clone, Debug)]
+pub enum TellRecipient {
+    Handle(String),
+    Uid(UserID),
+}
+
+#[derive(Clone, Debug)]
+pub struct Tell {
+    pub id: TellID,
+    pub sender: UserID,
+    pub recipient: UserID,
+    pub body: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl Tell {
+    // Trimmed `body`, if it's sendable
+    pub fn validate_body(body: &str) -> Result<String, Error> {
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(Error::InvalidTell("Empty message".into()));
+        }
+        if body.chars().count() > MAX_TELL_LEN {
+            return Err(Error::InvalidTell(format!(
+                "Longer than {} characters",
+                MAX_TELL_LEN
+            )));
+        }
+        if body.chars().any(|c| c.is_control() && c != '\n') {
+            return Err(Error::InvalidTell("Control characters".into()));
+        }
+        Ok(body.to_string())
+    }
+
+    // The "tell" message both the sender and recipient's sockets get
+    pub fn to_json(
+        &self,
+        sender_handle: &str,
+        recipient_handle: &str,
+    ) -> Value {
+        json!({
+            "kind": "tell",
+            "id": B66::encode_uuid(&self.id),
+            "from": {
+                "uid": B66::encode_uuid(&self.sender),
+                "handle": sender_handle,
+            },
+            "to": {
+                "uid": B66::encode_uuid(&self.recipient),
+                "handle": recipient_handle,
+            },
+            "body": self.body,
+            "time": self.sent_at.timestamp_millis(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_body() {
+        assert!(Tell::validate_body("  hi  ").unwrap() == "hi");
+        assert!(Tell::validate_body("line\nline").is_ok());
+        assert!(Tell::validate_body(" \n ").is_err());
+        assert!(Tell::validate_body("bell\u{7}").is_err());
+        assert!(Tell::validate_body(&"x".repeat(MAX_TELL_LEN)).is_ok());
+        assert!(Tell::validate_body(&"x".repeat(MAX_TELL_LEN + 1)).is_err());
+    }
+}
@@ -28,6 +28,11 @@ impl TimeControl {
         self.inc * 1000
     }
 
+    // Rough length of a game in seconds, assuming 40 moves on a board
+    pub fn get_estimated_secs(&self) -> i32 {
+        (self.base as i32) * 60 + 40 * (self.inc as i32)
+    }
+
     pub fn get_id(&self) -> TimeID {
         format!("{}", self)
     }
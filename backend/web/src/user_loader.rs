@@ -0,0 +1,95 @@
+// Batched, cached uid => handle lookups for game rows, game histories and
+// GraphQL.  Online users are read from Users.  Everyone else is read from
+// bughouse.users in one query per batch and cached, so anything that changes
+// a handle (see BughouseServer::set_handle) must update the cache.
+use async_graphql::dataloader::Loader;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::db::Db;
+use crate::error::Error;
+use crate::users::{UserID, Users};
+
+// Keys per `IN` query
+const MAX_BATCH: usize = 100;
+// The cache is emptied when it would grow past this
+const DEFAULT_CAPACITY: usize = 100_000;
+
+pub struct UserLoader {
+    db: Arc<Db>,
+    users: Arc<Users>,
+    handles: RwLock<HashMap<UserID, String>>,
+    capacity: usize,
+}
+
+impl UserLoader {
+    pub fn new(db: Arc<Db>, users: Arc<Users>) -> Self {
+        UserLoader {
+            db,
+            users,
+            handles: RwLock::new(HashMap::new()),
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+
+    // Unknown uids are left out
+    pub async fn get_handles(
+        &self,
+        uids: &[UserID],
+    ) -> Result<HashMap<UserID, String>, Error> {
+        let mut found = HashMap::new();
+        let mut missing = vec![];
+        {
+            let cache = self.handles.read().unwrap();
+            let unique: HashSet<&UserID> = uids.iter().collect();
+            for uid in unique.into_iter() {
+                if let Some(user) = self.users.get(uid) {
+                    found.insert(*uid, user.read().unwrap().handle.clone());
+                } else if let Some(handle) = cache.get(uid) {
+                    found.insert(*uid, handle.clone());
+                } else {
+                    missing.push(*uid);
+                }
+            }
+        }
+        for chunk in missing.chunks(MAX_BATCH) {
+            let loaded = self.db.get_handles(chunk.to_vec()).await?;
+            let mut cache = self.handles.write().unwrap();
+            if cache.len() + loaded.len() > self.capacity {
+                cache.clear();
+            }
+            for (uid, handle) in loaded.into_iter() {
+                cache.insert(uid, handle.clone());
+                found.insert(uid, handle);
+            }
+        }
+        Ok(found)
+    }
+
+    pub async fn get_handle(&self, uid: &UserID) -> Result<String, Error> {
+        let mut handles = self.get_handles(&[*uid]).await?;
+        handles.remove(uid).ok_or(Error::UnknownUID(*uid))
+    }
+
+    // The next lookup of `uid` reads it again
+    pub fn invalidate(&self, uid: &UserID) {
+        self.handles.write().unwrap().remove(uid);
+    }
+}
+
+// Batches the handle lookups of a GraphQL query's resolvers (see
+// GraphQLGame::players) into UserLoader::get_handles calls
+pub struct HandleLoader(pub Arc<UserLoader>);
+
+#[async_trait::async_trait]
+impl Loader<UserID> for HandleLoader {
+    type Value = String;
+    type Error = Arc<Error>;
+
+    async fn load(
+        &self,
+        keys: &[UserID],
+    ) -> Result<HashMap<UserID, String>, Self::Error> {
+        self.0.get_handles(keys).await.map_err(Arc::new)
+    }
+}
@@ -1,14 +1,26 @@
 use num;
 use scylla::cql_to_rust::FromCqlVal;
-use scylla::macros::{FromRow, FromUserType, IntoUserType};
+use scylla::macros::FromRow;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 
 use crate::db::Db;
+use crate::error::Error;
+use crate::rating::{Rating, RatingCategory, UserRating};
 
 pub type UserID = Uuid;
 
+// Shown in place of the handle of a deleted account
+pub const DELETED_HANDLE: &str = "[deleted]";
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_PHOTO_URL_LEN: usize = 512;
+const MAX_PREFERENCES: usize = 32;
+const MAX_PREFERENCE_KEY_LEN: usize = 32;
+const MAX_PREFERENCE_VALUE_LEN: usize = 256;
+
 #[derive(Clone, Debug, FromPrimitive)]
 pub enum UserRole {
     Guest = 0,
@@ -16,27 +28,95 @@ pub enum UserRole {
     Admin = 2,
 }
 
-#[derive(Clone, Debug, FromRow, IntoUserType, FromUserType)]
+// A row of bughouse.users.  Ratings live in bughouse.ratings
+#[derive(Clone, Debug, FromRow)]
+pub struct UserRow {
+    pub id: UserID,
+    pub firebase_id: Option<String>,
+    pub email: Option<String>,
+    pub guest: bool,
+    pub handle: String,
+    pub name: Option<String>,
+    pub photo_url: Option<String>,
+    pub role: i8,
+}
+
+#[derive(Clone, Debug)]
 pub struct User {
     pub id: UserID,
-    pub firebase_id: String,
-    pub deviation: i16,
+    pub firebase_id: Option<String>,
     pub email: Option<String>,
     pub guest: bool,
     pub handle: String,
     pub name: Option<String>,
     pub photo_url: Option<String>,
-    pub rating: i16,
     pub role: i8,
+    // Categories they haven't played a rated game in are missing
+    pub ratings: HashMap<RatingCategory, UserRating>,
 }
 
 impl User {
+    pub fn new(
+        row: UserRow,
+        ratings: HashMap<RatingCategory, UserRating>,
+    ) -> Self {
+        User {
+            id: row.id,
+            firebase_id: row.firebase_id,
+            email: row.email,
+            guest: row.guest,
+            handle: row.handle,
+            name: row.name,
+            photo_url: row.photo_url,
+            role: row.role,
+            ratings,
+        }
+    }
+
     pub fn get_uid(&self) -> &UserID {
         &self.id
     }
 
-    pub fn get_deviation(&self) -> i16 {
-        self.deviation
+    // Deleted accounts keep their uid (for their games) but nothing else
+    pub fn is_deleted(&self) -> bool {
+        self.handle == DELETED_HANDLE
+    }
+
+    pub fn get_rating(&self, category: RatingCategory) -> Rating {
+        self.get_user_rating(category).rating
+    }
+
+    pub fn get_user_rating(&self, category: RatingCategory) -> UserRating {
+        self.ratings
+            .get(&category)
+            .copied()
+            .unwrap_or_else(|| UserRating::new(self.id, Rating::default()))
+    }
+
+    pub fn is_provisional(&self, category: RatingCategory) -> bool {
+        self.get_user_rating(category).is_provisional()
+    }
+
+    pub fn set_rating(&mut self, category: RatingCategory, rating: UserRating) {
+        self.ratings.insert(category, rating);
+    }
+
+    // {"bullet": {"rating": 1500, "deviation": 350, "provisional": true},
+    //  "blitz": ...}
+    pub fn get_ratings_json(&self) -> Value {
+        let mut ratings = serde_json::Map::new();
+        for category in RatingCategory::ALL.iter() {
+            let rating = self.get_user_rating(*category);
+            ratings.insert(
+                category.to_string(),
+                json!({
+                    "rating": rating.rating.rating,
+                    "deviation": rating.rating.deviation,
+                    "provisional": rating.is_provisional(),
+                }),
+            );
+        }
+        Value::Object(ratings)
     }
 
     pub fn get_photo_url(&self) -> Option<String> {
@@ -54,6 +134,72 @@ impl User {
             UserRole::User
         }
     }
+
+    // Empty clears the name
+    pub fn validate_name(name: &str) -> Result<(), Error> {
+        if name.chars().count() > MAX_NAME_LEN {
+            return Err(Error::InvalidProfile(format!(
+                "name is longer than {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+        if name.chars().any(|c| c.is_control()) {
+            return Err(Error::InvalidProfile(
+                "name has control characters".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Empty clears the photo
+    pub fn validate_photo_url(url: &str) -> Result<(), Error> {
+        if url.is_empty() {
+            return Ok(());
+        }
+        if url.len() > MAX_PHOTO_URL_LEN {
+            return Err(Error::InvalidProfile(format!(
+                "photo URL is longer than {} characters",
+                MAX_PHOTO_URL_LEN
+            )));
+        }
+        if !url.starts_with("https://")
+            || url.chars().any(|c| c.is_whitespace())
+        {
+            return Err(Error::InvalidProfile(
+                "photo URL must be an https URL".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    // `count` is how many preferences they'd have afterwards
+    pub fn validate_preference(
+        key: &str,
+        value: Option<&str>,
+        count: usize,
+    ) -> Result<(), Error> {
+        let valid_key = !key.is_empty()
+            && key.len() <= MAX_PREFERENCE_KEY_LEN
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+        if !valid_key {
+            return Err(Error::InvalidPreference(key.to_string()));
+        }
+        if value.map_or(false, |v| v.len() > MAX_PREFERENCE_VALUE_LEN) {
+            return Err(Error::InvalidPreference(format!(
+                "{}: value is longer than {} characters",
+                key, MAX_PREFERENCE_VALUE_LEN
+            )));
+        }
+        if count > MAX_PREFERENCES {
+            return Err(Error::InvalidPreference(format!(
+                "more than {} preferences",
+                MAX_PREFERENCES
+            )));
+        }
+        Ok(())
+    }
 }
 
 // online users
@@ -97,3 +243,36 @@ impl Users {
         new_user
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_profile() {
+        assert!(User::validate_name("").is_ok());
+        assert!(User::validate_name("José Raúl Capablanca").is_ok());
+        assert!(User::validate_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+        assert!(User::validate_name("tab\there").is_err());
+
+        assert!(User::validate_photo_url("").is_ok());
+        assert!(User::validate_photo_url("https://a.com/me.png").is_ok());
+        assert!(User::validate_photo_url("http://a.com/me.png").is_err());
+        assert!(User::validate_photo_url("javascript:alert(1)").is_err());
+        assert!(User::validate_photo_url("https://a.com/m e.png").is_err());
+    }
+
+    #[test]
+    fn validate_preference() {
+        assert!(
+            User::validate_preference("board.theme", Some("blue"), 1).is_ok()
+        );
+        assert!(User::validate_preference("sound", None, 0).is_ok());
+        assert!(User::validate_preference("", Some("x"), 1).is_err());
+        assert!(User::validate_preference("a b", Some("x"), 1).is_err());
+        let long = "x".repeat(MAX_PREFERENCE_VALUE_LEN + 1);
+        assert!(User::validate_preference("theme", Some(&long), 1).is_err());
+        let count = MAX_PREFERENCES + 1;
+        assert!(User::validate_preference("theme", Some("x"), count).is_err());
+    }
+}
@@ -1,7 +1,9 @@
 import Button from "@mui/material/Button";
 import QuickMessages, {
+  QuickMessageSignals,
   QuickMessagesText,
   QuickMessagesPiece,
+  quickFromSignal,
 } from "./QuickMessages";
 import React, { useContext, useEffect, useRef, useState } from "react";
 import TextField from "@mui/material/TextField";
@@ -58,6 +60,7 @@ const BUTTON_GROUPS = [
     QuickMessages.EXCHANGE,
     QuickMessages.MATES,
     QuickMessages.STALL,
+    QuickMessages.GO,
     QuickMessages.WATCH_TIME,
   ],
 ];
@@ -75,7 +78,6 @@ const GameMessages = ({ playerColor, gameID }) => {
     const newHandle = input.value;
     socket.sendEvent("game_msg", {
       id: gameID,
-      sender: uid,
       type: "text",
       text: textInput.current.querySelector("input").value,
     });
@@ -91,7 +93,9 @@ const GameMessages = ({ playerColor, gameID }) => {
         console.error(`game_msg; ${gameID} != ${id}`);
       }
       let message =
-        type === "text" ? { text: data.text } : { quick: data.quick }; // TODO - enum?
+        type === "text"
+          ? { text: data.text }
+          : { quick: quickFromSignal(data) };
 
       messages.current.push({
         self: sender === uid,
@@ -117,9 +121,7 @@ const GameMessages = ({ playerColor, gameID }) => {
           onClick={(e) => {
             socket.sendEvent("game_msg", {
               id: gameID,
-              sender: uid,
-              type: "quick",
-              quick: key,
+              ...QuickMessageSignals[key],
             });
           }}
         >
@@ -12,6 +12,7 @@ const QuickMessages = {
   EXCHANGE: "EXCHANGE",
   MATES: "MATES",
   STALL: "STALL",
+  GO: "GO",
   WATCH_TIME: "WATCH_TIME",
 };
 export default QuickMessages;
@@ -34,5 +35,31 @@ export const QuickMessagesText = {
   MATES: "#",
   // STALL: "\u{23f8}",       // pause
   STALL: "\u{270b}", // raised hand
+  GO: "\u{1f3c3}", // runner
   WATCH_TIME: "\u{1f551}", // clock two-o-clock
 };
+
+// Partner signals the server relays for each button
+export const QuickMessageSignals = {
+  NEED_PAWN: { type: "need", piece: "p" },
+  NEED_KNIGHT: { type: "need", piece: "n" },
+  NEED_BISHOP: { type: "need", piece: "b" },
+  NEED_ROOK: { type: "need", piece: "r" },
+  NEED_QUEEN: { type: "need", piece: "q" },
+  NO_PAWN: { type: "no", piece: "p" },
+  NO_KNIGHT: { type: "no", piece: "n" },
+  NO_BISHOP: { type: "no", piece: "b" },
+  NO_ROOK: { type: "no", piece: "r" },
+  NO_QUEEN: { type: "no", piece: "q" },
+  EXCHANGE: { type: "trade_ok" },
+  MATES: { type: "mates" },
+  STALL: { type: "sit" },
+  GO: { type: "go" },
+  WATCH_TIME: { type: "watch_time" },
+};
+
+export const quickFromSignal = ({ type, piece }) =>
+  Object.keys(QuickMessageSignals).find((key) => {
+    const signal = QuickMessageSignals[key];
+    return signal.type === type && signal.piece === piece;
+  });
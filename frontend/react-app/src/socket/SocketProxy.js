@@ -259,7 +259,8 @@ class SocketProxy extends EventEmitter {
   }
 
   _authenticate() {
-    if (this._idToken != null) {
+    // Sockets opened with a session cookie are authenticated by the server
+    if (!this._authenticated && this._idToken != null) {
       // TODO: ditch the socket auth - and rely on the response from _postAuth
       this._send("auth", { firebase_token: this._idToken });
     }
@@ -269,6 +270,8 @@ class SocketProxy extends EventEmitter {
     console.log(`_postAuth`);
     const xhr = new XMLHttpRequest();
     xhr.open("POST", AUTH_URL, true);
+    // So the session cookie is set, and sent, when the API is elsewhere
+    xhr.withCredentials = true;
     xhr.setRequestHeader('Content-Type', 'application/json');
     xhr.setRequestHeader('Access-Control-Allow-Origin', '*');
     const self = this;
@@ -335,6 +338,11 @@ class SocketProxy extends EventEmitter {
 
   logout() {
     console.log(`${this._gcn()}.logout`);
+    const xhr = new XMLHttpRequest();
+    xhr.open("POST", `${AUTH_URL}/logout`, true);
+    // Without the session cookie the server's session outlives the logout
+    xhr.withCredentials = true;
+    xhr.send();
     this._logout();
   }
 
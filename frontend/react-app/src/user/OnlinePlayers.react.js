@@ -96,7 +96,10 @@ const OnlinePlayers = () => {
                 <TableCell component="th" scope="row">
                   {user.handle}
                 </TableCell>
-                <TableCell align="right">{user.rating}</TableCell>
+                <TableCell align="right">
+                  {user.rating}
+                  {user.rating != null && user.provisional ? "?" : ""}
+                </TableCell>
               </StyledTableRow>
             ))}
           </TableBody>
@@ -95,8 +95,8 @@ class OnlineUsers extends EventEmitter {
       if (players == null) {
         return;
       }
-      for (const [uid, handle, rating] of players) {
-        this._users[uid] = { uid, handle, rating };
+      for (const [uid, handle, rating, provisional] of players) {
+        this._users[uid] = { uid, handle, rating, provisional };
       }
       this.emit("value", this._users);
     };
@@ -108,8 +108,8 @@ class OnlineUsers extends EventEmitter {
         delete this._users[uid];
       }
       for (const user of data.online) {
-        const [uid, handle, rating] = user;
-        this._users[uid] = { uid, handle, rating };
+        const [uid, handle, rating, provisional] = user;
+        this._users[uid] = { uid, handle, rating, provisional };
       }
       this.emit("value", this._users);
     };